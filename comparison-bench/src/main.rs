@@ -0,0 +1,167 @@
+use std::hint::black_box;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use bpe::tiktoken::{self, TiktokenBpe};
+use bpe::{Tokenize, Tokenizer};
+use clap::Parser;
+
+// comparison benchmark
+//
+// The same workloads, on the same text and the same vocabulary (cl100k_base),
+// through this crate, tiktoken-rs and Hugging Face tokenizers. Each row is
+// the best of several runs, and the token counts are printed beside the
+// throughput so it is plain the libraries did the same work.
+//
+// tiktoken-rs bundles cl100k_base; this crate reads the published
+// `cl100k_base.tiktoken` file and tokenizers a `tokenizer.json` of the same
+// encoding, such as Xenova/gpt-4's. A library whose file isn't given is left
+// out. This crate runs twice: as `TiktokenBpe`, which encodes by the ranks
+// themselves, and as its own `Tokenizer` over the merges converted from
+// them, whose ids are numbered differently but count the same.
+
+#[derive(Parser)]
+struct Args {
+    /// Text to encode
+    #[arg(long, default_value = "a-man-like-him.txt")]
+    text: PathBuf,
+    /// cl100k_base.tiktoken, for this crate's `TiktokenBpe` and `Tokenizer`
+    #[arg(long)]
+    tiktoken_file: Option<PathBuf>,
+    /// A cl100k_base tokenizer.json, for Hugging Face tokenizers
+    #[arg(long)]
+    hf_tokenizer: Option<PathBuf>,
+    /// Runs of each workload; the fastest is reported
+    #[arg(long, default_value_t = 10)]
+    runs: usize,
+}
+
+/// What a library is asked to do with the text.
+#[derive(Clone, Copy)]
+enum Workload {
+    /// Encode the whole text in one call.
+    EncodeText,
+    /// Encode each line in its own call, like many short prompts.
+    EncodeLines,
+    /// Decode the ids of the whole text.
+    Decode,
+}
+
+impl Workload {
+    const ALL: [Workload; 3] = [
+        Workload::EncodeText,
+        Workload::EncodeLines,
+        Workload::Decode,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Workload::EncodeText => "encode text",
+            Workload::EncodeLines => "encode lines",
+            Workload::Decode => "decode",
+        }
+    }
+}
+
+type Encode = Box<dyn Fn(&str) -> Vec<u32>>;
+type Decode = Box<dyn Fn(&[u32]) -> String>;
+
+/// A library under test: encoding to ids and decoding them back.
+struct Library {
+    name: &'static str,
+    encode: Encode,
+    decode: Decode,
+}
+
+impl Library {
+    /// One of this crate's tokenizers.
+    fn bpe(name: &'static str, tokenizer: impl Tokenize + 'static) -> Library {
+        let tokenizer = std::rc::Rc::new(tokenizer);
+        let decoder = tokenizer.clone();
+        Library {
+            name,
+            encode: Box::new(move |text| tokenizer.encode(text)),
+            decode: Box::new(move |ids| decoder.decode(ids)),
+        }
+    }
+}
+
+fn libraries(args: &Args) -> io::Result<Vec<Library>> {
+    let mut libraries = vec![];
+    if let Some(path) = &args.tiktoken_file {
+        libraries.push(Library::bpe(
+            "bpe",
+            Tokenizer::new(tiktoken::load_model(path, None)?)?,
+        ));
+        libraries.push(Library::bpe("bpe tiktoken", TiktokenBpe::load(path)?));
+    }
+    let core = std::rc::Rc::new(tiktoken_rs::cl100k_base().map_err(io::Error::other)?);
+    let decoder = core.clone();
+    libraries.push(Library {
+        name: "tiktoken-rs",
+        encode: Box::new(move |text| core.encode_ordinary(text)),
+        decode: Box::new(move |ids| decoder.decode(ids.to_vec()).unwrap_or_default()),
+    });
+    if let Some(path) = &args.hf_tokenizer {
+        let tokenizer =
+            std::rc::Rc::new(tokenizers::Tokenizer::from_file(path).map_err(io::Error::other)?);
+        let decoder = tokenizer.clone();
+        libraries.push(Library {
+            name: "tokenizers",
+            encode: Box::new(move |text| {
+                let encoding = tokenizer.encode(text, false).expect("encoding failed");
+                encoding.get_ids().to_vec()
+            }),
+            decode: Box::new(move |ids| decoder.decode(ids, false).unwrap_or_default()),
+        });
+    }
+    Ok(libraries)
+}
+
+/// The fastest of `runs` runs of `f`, and what it returned.
+fn best<T>(runs: usize, mut f: impl FnMut() -> T) -> (Duration, T) {
+    let mut fastest = Duration::MAX;
+    let mut result = None;
+    for _ in 0..runs.max(1) {
+        let start = Instant::now();
+        let value = black_box(f());
+        fastest = fastest.min(start.elapsed());
+        result = Some(value);
+    }
+    (fastest, result.expect("ran at least once"))
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+    let text = std::fs::read_to_string(&args.text)?;
+    let mb = text.len() as f64 / 1e6;
+    println!("{} bytes of {}", text.len(), args.text.display());
+    println!(
+        "{:<12} {:<13} {:>10} {:>10}",
+        "library", "workload", "MB/s", "tokens"
+    );
+    for library in libraries(&args)? {
+        let ids = (library.encode)(&text);
+        for workload in Workload::ALL {
+            let (time, tokens) = match workload {
+                Workload::EncodeText => best(args.runs, || (library.encode)(&text).len()),
+                Workload::EncodeLines => best(args.runs, || {
+                    text.lines().map(|line| (library.encode)(line).len()).sum()
+                }),
+                Workload::Decode => {
+                    let (time, _) = best(args.runs, || (library.decode)(&ids));
+                    (time, ids.len())
+                }
+            };
+            println!(
+                "{:<12} {:<13} {:>10.1} {:>10}",
+                library.name,
+                workload.name(),
+                mb / time.as_secs_f64(),
+                tokens
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+// initial alphabet
+//
+// What BPE training builds its merges from. Models always have the 256 byte
+// tokens, which is what keeps encoding lossless, so a smaller or larger
+// alphabet is expressed through the training options already there: bytes
+// left out of a restricted alphabet are forbidden from every merge and stay
+// single byte tokens, the fallback for text outside the alphabet, while a
+// character alphabet reserves the first merges for every character of the
+// corpus, as for protected tokens, so that training starts from whole
+// characters.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Alphabet {
+    /// All 256 bytes
+    #[default]
+    Bytes,
+    /// Printable ASCII, tab and newline; other bytes are never merged and
+    /// stay single byte tokens
+    Printable,
+    /// Every Unicode scalar value of the corpus, for character-level BPE
+    Chars,
+}
+
+impl Alphabet {
+    /// Byte sequences no merged token may contain.
+    pub fn forbidden(self) -> Vec<Vec<u8>> {
+        match self {
+            Alphabet::Printable => (0..=255u8)
+                .filter(|&b| !is_printable(b))
+                .map(|b| vec![b])
+                .collect(),
+            Alphabet::Bytes | Alphabet::Chars => vec![],
+        }
+    }
+}
+
+fn is_printable(b: u8) -> bool {
+    b.is_ascii_graphic() || matches!(b, b' ' | b'\t' | b'\n')
+}
+
+/// The multi-byte characters of words, as byte ids with their counts, most
+/// frequent first and equal counts in byte order. Bytes that aren't valid
+/// UTF-8 are left out.
+pub fn chars(words: &[(Vec<u32>, u32)]) -> Vec<Vec<u8>> {
+    let mut counts: HashMap<char, u64> = HashMap::new();
+    let mut bytes = vec![];
+    for (ids, n) in words {
+        bytes.clear();
+        bytes.extend(ids.iter().map(|&id| id as u8));
+        for chunk in bytes.utf8_chunks() {
+            for c in chunk.valid().chars().filter(|c| c.len_utf8() > 1) {
+                *counts.entry(c).or_default() += *n as u64;
+            }
+        }
+    }
+    let mut chars: Vec<(char, u64)> = counts.into_iter().collect();
+    chars.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    chars
+        .into_iter()
+        .map(|(c, _)| c.to_string().into_bytes())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forbidden() {
+        let forbidden = Alphabet::Printable.forbidden();
+        assert_eq!(forbidden.len(), 256 - 95 - 2);
+        assert!(forbidden.contains(&vec![0xc3]));
+        assert!(!forbidden.contains(&vec![b'\n']));
+        assert!(Alphabet::Chars.forbidden().is_empty());
+    }
+
+    #[test]
+    fn test_chars() {
+        let ids = |s: &[u8]| s.iter().map(|&b| b.into()).collect();
+        let words = vec![
+            (ids("café".as_bytes()), 1),
+            (ids("€é".as_bytes()), 2),
+            (ids(b"\xff\xc3"), 5),
+        ];
+        assert_eq!(chars(&words), ["é".as_bytes(), "€".as_bytes()]);
+    }
+}
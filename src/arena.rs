@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+
+// token arena
+//
+// The bytes of every token stored back to back in one buffer, with an
+// offsets table indexed by id marking where each token starts. Looking a
+// token up is two array reads, and decoding copies one slice per id instead
+// of chasing a separate allocation per token. No token is empty, so an id
+// with an empty span is one the vocabulary doesn't use.
+
+pub struct TokenArena {
+    bytes: Vec<u8>,
+    /// Start of token `id` at `offsets[id]`, end at `offsets[id + 1]`.
+    offsets: Vec<u32>,
+}
+
+impl TokenArena {
+    pub fn new(vocab: &HashMap<u32, Vec<u8>>) -> TokenArena {
+        let len = vocab.keys().max().map_or(0, |&id| id as usize + 1);
+        let mut bytes = Vec::with_capacity(vocab.values().map(Vec::len).sum());
+        let mut offsets = Vec::with_capacity(len + 1);
+        for id in 0..len as u32 {
+            offsets.push(bytes.len() as u32);
+            if let Some(token) = vocab.get(&id) {
+                bytes.extend(token);
+            }
+        }
+        offsets.push(bytes.len() as u32);
+        TokenArena { bytes, offsets }
+    }
+
+    /// The bytes of token `id`, if the vocabulary has it.
+    pub fn get(&self, id: u32) -> Option<&[u8]> {
+        let id = id as usize;
+        let (&start, &end) = (self.offsets.get(id)?, self.offsets.get(id + 1)?);
+        (start < end).then(|| &self.bytes[start as usize..end as usize])
+    }
+
+    /// Every `(id, bytes)` entry, in increasing id order.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &[u8])> {
+        (0..self.offsets.len() as u32 - 1).filter_map(|id| Some((id, self.get(id)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arena() {
+        let vocab = HashMap::from([(0, b"a".to_vec()), (1, b"bc".to_vec()), (3, b"d".to_vec())]);
+        let arena = TokenArena::new(&vocab);
+        assert_eq!(arena.get(1), Some(&b"bc"[..]));
+        assert_eq!(arena.get(2), None);
+        assert_eq!(arena.get(4), None);
+        let ids: Vec<u32> = arena.iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![0, 1, 3]);
+    }
+}
@@ -0,0 +1,454 @@
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::export::{self, Dtype};
+
+// Arrow IPC files
+//
+// Encoded documents as an Arrow table, one row per document with columns
+// `doc_id` (uint64), `ids` (list of uint16 or uint32) and `num_tokens`
+// (uint32), in the IPC file format that Feather v2 also names. pyarrow,
+// polars, DuckDB and Spark read it without a conversion step.
+//
+// A file is the magic `ARROW1`, a stream of messages (the schema, then the
+// record batches, then an end-of-stream marker), a footer locating the
+// batches, the footer's length and the magic again. Each message is a
+// flatbuffer of metadata followed by a body of column buffers, all
+// little-endian and padded to 8 bytes. The flatbuffers are written by the
+// small encoder below, front to back, so every table is followed by the
+// tables, vectors and strings it refers to.
+
+const MAGIC: &[u8] = b"ARROW1";
+/// Metadata version V5, current since Arrow 1.0.
+const METADATA_VERSION: i16 = 4;
+/// Documents per record batch; a batch also ends before its list offsets
+/// would overflow an i32.
+const BATCH_ROWS: usize = 1 << 16;
+
+// MessageHeader and Type union tags, from the Arrow flatbuffer schemas
+const HEADER_SCHEMA: u8 = 1;
+const HEADER_RECORD_BATCH: u8 = 3;
+const TYPE_INT: u8 = 2;
+const TYPE_LIST: u8 = 12;
+
+/// Writes documents' ids as an Arrow IPC file, with ids stored as `dtype`.
+pub fn write_arrow(path: &Path, docs: &[Vec<u32>], dtype: Dtype) -> io::Result<()> {
+    let batches = batches(docs)?;
+    export::write_file(path, |w| {
+        w.write_all(MAGIC)?;
+        w.write_all(&[0; 2])?;
+        let mut pos = 8;
+        let (len, _) = write_message(w, &message(HEADER_SCHEMA, schema(dtype), 0), &[])?;
+        pos += len;
+        let mut blocks = vec![];
+        let mut first_doc = 0;
+        for batch in batches {
+            let (meta, body) = record_batch(first_doc as u64, batch, dtype)?;
+            let (meta_len, body_len) = write_message(w, &meta, &body)?;
+            blocks.push(block(pos, meta_len, body_len));
+            pos += meta_len + body_len;
+            first_doc += batch.len();
+        }
+        // end of stream: a continuation marker and zero-length metadata
+        w.write_all(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0])?;
+        let footer = Object::Table(vec![
+            (0, Field::I16(METADATA_VERSION)),
+            (1, Field::Offset(schema(dtype))),
+            (2, Field::Offset(Object::Structs(24, vec![]))),
+            (3, Field::Offset(Object::Structs(24, blocks.concat()))),
+        ]);
+        let footer = finish(&footer);
+        w.write_all(&footer)?;
+        w.write_all(&(footer.len() as i32).to_le_bytes())?;
+        w.write_all(MAGIC)
+    })
+}
+
+/// Splits documents into record batches whose list offsets fit in an i32.
+fn batches(docs: &[Vec<u32>]) -> io::Result<Vec<&[Vec<u32>]>> {
+    let mut batches = vec![];
+    let (mut start, mut ids) = (0, 0usize);
+    for (i, doc) in docs.iter().enumerate() {
+        if doc.len() > i32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("document {} has too many ids for an Arrow list", i),
+            ));
+        }
+        if i - start == BATCH_ROWS || ids + doc.len() > i32::MAX as usize {
+            batches.push(&docs[start..i]);
+            (start, ids) = (i, 0);
+        }
+        ids += doc.len();
+    }
+    if start < docs.len() || docs.is_empty() {
+        batches.push(&docs[start..]);
+    }
+    Ok(batches)
+}
+
+/// Writes an encapsulated message: a continuation marker, the metadata
+/// length, the metadata padded to 8 bytes and the body. Returns the length
+/// of everything before the body, and of the body.
+fn write_message(w: &mut impl Write, meta: &Object, body: &[u8]) -> io::Result<(usize, usize)> {
+    let mut meta = finish(meta);
+    meta.resize((8 + meta.len()).next_multiple_of(8) - 8, 0);
+    w.write_all(&[0xff; 4])?;
+    w.write_all(&(meta.len() as i32).to_le_bytes())?;
+    w.write_all(&meta)?;
+    w.write_all(body)?;
+    Ok((8 + meta.len(), body.len()))
+}
+
+fn message(header_type: u8, header: Object, body_len: usize) -> Object {
+    Object::Table(vec![
+        (0, Field::I16(METADATA_VERSION)),
+        (1, Field::U8(header_type)),
+        (2, Field::Offset(header)),
+        (3, Field::I64(body_len as i64)),
+    ])
+}
+
+fn schema(dtype: Dtype) -> Object {
+    let id_bits = match dtype {
+        Dtype::U16 => 16,
+        Dtype::U32 => 32,
+    };
+    let fields = vec![
+        field("doc_id", int(64), vec![]),
+        field(
+            "ids",
+            (TYPE_LIST, Object::Table(vec![])),
+            vec![field("item", int(id_bits), vec![])],
+        ),
+        field("num_tokens", int(32), vec![]),
+    ];
+    Object::Table(vec![
+        // little-endian
+        (0, Field::I16(0)),
+        (1, Field::Offset(Object::Tables(fields))),
+    ])
+}
+
+fn field(name: &str, (type_tag, type_table): (u8, Object), children: Vec<Object>) -> Object {
+    Object::Table(vec![
+        (0, Field::Offset(Object::String(name.to_string()))),
+        (1, Field::Bool(false)),
+        (2, Field::U8(type_tag)),
+        (3, Field::Offset(type_table)),
+        (5, Field::Offset(Object::Tables(children))),
+    ])
+}
+
+/// An unsigned integer type.
+fn int(bits: i32) -> (u8, Object) {
+    let table = Object::Table(vec![(0, Field::I32(bits)), (1, Field::Bool(false))]);
+    (TYPE_INT, table)
+}
+
+/// The metadata and body of a record batch of documents numbered from
+/// `first_doc`.
+fn record_batch(first_doc: u64, docs: &[Vec<u32>], dtype: Dtype) -> io::Result<(Object, Vec<u8>)> {
+    let rows = docs.len();
+    let num_ids: usize = docs.iter().map(Vec::len).sum();
+    let mut doc_ids = vec![];
+    let mut offsets = vec![];
+    let mut values = vec![];
+    let mut num_tokens = vec![];
+    let mut offset = 0i32;
+    offsets.extend(offset.to_le_bytes());
+    for (i, doc) in docs.iter().enumerate() {
+        doc_ids.extend((first_doc + i as u64).to_le_bytes());
+        offset += doc.len() as i32;
+        offsets.extend(offset.to_le_bytes());
+        num_tokens.extend((doc.len() as u32).to_le_bytes());
+        dtype.write_ids(&mut values, doc)?;
+    }
+    // no validity bitmaps, as no value is null
+    let mut body = vec![];
+    let mut buffers = vec![];
+    for buffer in [
+        &[][..],
+        &doc_ids,
+        &[],
+        &offsets,
+        &[],
+        &values,
+        &[],
+        &num_tokens,
+    ] {
+        buffers.push(pair(body.len() as i64, buffer.len() as i64));
+        body.extend_from_slice(buffer);
+        body.resize(body.len().next_multiple_of(8), 0);
+    }
+    let nodes = [rows, rows, num_ids, rows].map(|len| pair(len as i64, 0));
+    let batch = Object::Table(vec![
+        (0, Field::I64(rows as i64)),
+        (1, Field::Offset(Object::Structs(16, nodes.concat()))),
+        (2, Field::Offset(Object::Structs(16, buffers.concat()))),
+    ]);
+    Ok((message(HEADER_RECORD_BATCH, batch, body.len()), body))
+}
+
+/// A FieldNode (length, null count) or Buffer (offset, length) struct.
+fn pair(a: i64, b: i64) -> Vec<u8> {
+    [a.to_le_bytes(), b.to_le_bytes()].concat()
+}
+
+/// A footer Block struct: the message's offset in the file, its length up to
+/// the body, and the body's length.
+fn block(offset: usize, meta_len: usize, body_len: usize) -> Vec<u8> {
+    let mut block = (offset as i64).to_le_bytes().to_vec();
+    block.extend((meta_len as i32).to_le_bytes());
+    block.extend([0; 4]);
+    block.extend((body_len as i64).to_le_bytes());
+    block
+}
+
+/// A table field value.
+enum Field {
+    Bool(bool),
+    U8(u8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    Offset(Object),
+}
+
+impl Field {
+    fn size(&self) -> usize {
+        match self {
+            Field::Bool(_) | Field::U8(_) => 1,
+            Field::I16(_) => 2,
+            Field::I32(_) | Field::Offset(_) => 4,
+            Field::I64(_) => 8,
+        }
+    }
+}
+
+/// A flatbuffer object that fields and vectors refer to by offset.
+enum Object {
+    /// Fields by id.
+    Table(Vec<(u16, Field)>),
+    Tables(Vec<Object>),
+    /// A vector of structs of the given size, already laid out. The structs
+    /// used here all hold i64s, so are aligned to 8 bytes.
+    Structs(usize, Vec<u8>),
+    String(String),
+}
+
+/// Encodes a flatbuffer with `root` as its root table.
+fn finish(root: &Object) -> Vec<u8> {
+    let mut buf = vec![0; 4];
+    let root = encode(&mut buf, root);
+    buf[..4].copy_from_slice(&(root as u32).to_le_bytes());
+    buf
+}
+
+/// Appends an object, then the objects it refers to, and returns where it
+/// starts.
+fn encode(buf: &mut Vec<u8>, object: &Object) -> usize {
+    let pad_to = |buf: &mut Vec<u8>, align: usize| buf.resize(buf.len().next_multiple_of(align), 0);
+    match object {
+        Object::Table(fields) => {
+            // lay out the fields after the vtable offset, largest first so
+            // each is aligned
+            let mut order: Vec<&(u16, Field)> = fields.iter().collect();
+            order.sort_by_key(|(_, field)| std::cmp::Reverse(field.size()));
+            let mut field_offsets = vec![
+                0u16;
+                fields
+                    .iter()
+                    .map(|(id, _)| *id as usize + 1)
+                    .max()
+                    .unwrap_or(0)
+            ];
+            let mut size: usize = 4;
+            for (id, field) in &order {
+                size = size.next_multiple_of(field.size());
+                field_offsets[*id as usize] = size as u16;
+                size += field.size();
+            }
+            pad_to(buf, 2);
+            let vtable = buf.len();
+            buf.extend((4 + 2 * field_offsets.len() as u16).to_le_bytes());
+            buf.extend((size as u16).to_le_bytes());
+            for offset in &field_offsets {
+                buf.extend(offset.to_le_bytes());
+            }
+            pad_to(buf, 8);
+            let table = buf.len();
+            buf.extend(((table - vtable) as i32).to_le_bytes());
+            buf.resize(table + size, 0);
+            let mut children = vec![];
+            for (id, field) in fields {
+                let at = table + field_offsets[*id as usize] as usize;
+                match field {
+                    Field::Bool(b) => buf[at] = *b as u8,
+                    Field::U8(n) => buf[at] = *n,
+                    Field::I16(n) => buf[at..at + 2].copy_from_slice(&n.to_le_bytes()),
+                    Field::I32(n) => buf[at..at + 4].copy_from_slice(&n.to_le_bytes()),
+                    Field::I64(n) => buf[at..at + 8].copy_from_slice(&n.to_le_bytes()),
+                    Field::Offset(child) => children.push((at, child)),
+                }
+            }
+            for (at, child) in children {
+                let start = encode(buf, child);
+                patch(buf, at, start);
+            }
+            table
+        }
+        Object::Tables(tables) => {
+            pad_to(buf, 4);
+            let start = buf.len();
+            buf.extend((tables.len() as u32).to_le_bytes());
+            buf.resize(start + 4 + 4 * tables.len(), 0);
+            for (i, table) in tables.iter().enumerate() {
+                let child = encode(buf, table);
+                patch(buf, start + 4 + 4 * i, child);
+            }
+            start
+        }
+        Object::Structs(size, bytes) => {
+            // the length goes right before the first struct, which is aligned
+            pad_to(buf, 4);
+            if buf.len().is_multiple_of(8) {
+                buf.extend([0; 4]);
+            }
+            let start = buf.len();
+            buf.extend(((bytes.len() / size) as u32).to_le_bytes());
+            buf.extend(bytes);
+            start
+        }
+        Object::String(s) => {
+            pad_to(buf, 4);
+            let start = buf.len();
+            buf.extend((s.len() as u32).to_le_bytes());
+            buf.extend(s.as_bytes());
+            buf.push(0);
+            start
+        }
+    }
+}
+
+/// Points the offset at `at` to `target`, which follows it.
+fn patch(buf: &mut [u8], at: usize, target: usize) {
+    buf[at..at + 4].copy_from_slice(&((target - at) as u32).to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads the flatbuffers back, as far as the test needs.
+    struct Flatbuffer<'a>(&'a [u8]);
+
+    impl Flatbuffer<'_> {
+        fn u32(&self, at: usize) -> usize {
+            u32::from_le_bytes(self.0[at..at + 4].try_into().unwrap()) as usize
+        }
+
+        fn i64(&self, at: usize) -> i64 {
+            i64::from_le_bytes(self.0[at..at + 8].try_into().unwrap())
+        }
+
+        fn root(&self) -> usize {
+            self.u32(0)
+        }
+
+        /// Where a table's field is, if it is present.
+        fn field(&self, table: usize, id: usize) -> Option<usize> {
+            let vtable = table - self.u32(table);
+            let size = u16::from_le_bytes([self.0[vtable], self.0[vtable + 1]]) as usize;
+            let at = vtable + 4 + 2 * id;
+            let offset = (at < vtable + size)
+                .then(|| u16::from_le_bytes([self.0[at], self.0[at + 1]]) as usize)?;
+            (offset != 0).then_some(table + offset)
+        }
+
+        /// Follows the offset field `id` of a table.
+        fn child(&self, table: usize, id: usize) -> usize {
+            let at = self.field(table, id).unwrap();
+            at + self.u32(at)
+        }
+
+        fn string(&self, at: usize) -> &str {
+            std::str::from_utf8(&self.0[at + 4..at + 4 + self.u32(at)]).unwrap()
+        }
+    }
+
+    // The layout is checked field by field against the Arrow format
+    // specification (Schema.fbs, Message.fbs, File.fbs), not against a file
+    // written by pyarrow or arrow-rs: neither is a dependency here.
+    #[test]
+    fn test_write_arrow() {
+        let path = std::env::temp_dir().join(format!("bpe-test-{}.arrow", std::process::id()));
+        let docs = vec![vec![1, 2, 3], vec![], vec![300]];
+        write_arrow(&path, &docs, Dtype::U16).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(&bytes[..8], b"ARROW1\0\0");
+        assert_eq!(&bytes[bytes.len() - 6..], b"ARROW1");
+        let footer_end = bytes.len() - 10;
+        let footer_len = u32::from_le_bytes(bytes[footer_end..][..4].try_into().unwrap());
+        let footer = Flatbuffer(&bytes[footer_end - footer_len as usize..footer_end]);
+        let schema = footer.child(footer.root(), 1);
+        let fields = footer.child(schema, 1);
+        let names: Vec<&str> = (0..footer.u32(fields))
+            .map(|i| {
+                let at = fields + 4 + 4 * i;
+                footer.string(footer.child(at + footer.u32(at), 0))
+            })
+            .collect();
+        assert_eq!(names, ["doc_id", "ids", "num_tokens"]);
+
+        // one record batch, located by the footer
+        let blocks = footer.child(footer.root(), 3);
+        assert_eq!(footer.u32(blocks), 1);
+        let offset = footer.i64(blocks + 4) as usize;
+        assert_eq!(&bytes[offset..offset + 4], &[0xff; 4]);
+        let meta_len = u32::from_le_bytes(bytes[offset + 4..][..4].try_into().unwrap()) as usize;
+        assert_eq!((offset + 8 + meta_len) % 8, 0);
+        let message = Flatbuffer(&bytes[offset + 8..offset + 8 + meta_len]);
+        let header_type = message.field(message.root(), 1).unwrap();
+        assert_eq!(message.0[header_type], HEADER_RECORD_BATCH);
+        let batch = message.child(message.root(), 2);
+        assert_eq!(message.i64(message.field(batch, 0).unwrap()), 3);
+
+        // buffers 3, 5 and 7 hold the list offsets, the ids and the counts
+        let buffers = message.child(batch, 2);
+        assert_eq!(message.u32(buffers), 8);
+        let body = &bytes[offset + 8 + meta_len..];
+        let buffer = |i: usize| {
+            let at = buffers + 4 + 16 * i;
+            let start = message.i64(at) as usize;
+            &body[start..start + message.i64(at + 8) as usize]
+        };
+        assert_eq!(buffer(3), [0, 0, 0, 0, 3, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0]);
+        assert_eq!(buffer(5), [1, 0, 2, 0, 3, 0, 44, 1]);
+        assert_eq!(buffer(7), [3, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+        assert!(buffer(0).is_empty());
+        assert_eq!(
+            &bytes[footer_end - footer_len as usize - 8..][..8],
+            [0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_write_arrow_overflow() {
+        let path =
+            std::env::temp_dir().join(format!("bpe-test-{}-overflow.arrow", std::process::id()));
+        let err = write_arrow(&path, &[vec![1], vec![70000]], Dtype::U16).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_batches() {
+        let docs = vec![vec![1]; BATCH_ROWS + 1];
+        let sizes: Vec<usize> = batches(&docs).unwrap().iter().map(|b| b.len()).collect();
+        assert_eq!(sizes, [BATCH_ROWS, 1]);
+        assert_eq!(batches(&[]).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,95 @@
+// batch encoding
+//
+// Model runtimes take a batch as one rectangular matrix, so sequences are
+// truncated to a maximum length and the shorter ones padded, with an
+// attention mask marking which positions hold real tokens.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaddingSide {
+    Left,
+    Right,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PaddedBatch {
+    /// One row per input, all of the same length.
+    pub ids: Vec<Vec<u32>>,
+    /// 1 where `ids` holds a token, 0 where it holds padding.
+    pub attention_mask: Vec<Vec<u8>>,
+}
+
+#[cfg(feature = "ndarray")]
+impl PaddedBatch {
+    /// The ids and attention mask as `(batch, width)` arrays, ready for
+    /// tensor libraries such as tch, candle or burn.
+    pub fn into_arrays(self) -> (ndarray::Array2<u32>, ndarray::Array2<u8>) {
+        (to_array(self.ids), to_array(self.attention_mask))
+    }
+}
+
+#[cfg(feature = "ndarray")]
+fn to_array<T>(rows: Vec<Vec<T>>) -> ndarray::Array2<T> {
+    let width = rows.first().map_or(0, Vec::len);
+    let shape = (rows.len(), width);
+    let flat: Vec<T> = rows.into_iter().flatten().collect();
+    ndarray::Array2::from_shape_vec(shape, flat).expect("rows have the same length")
+}
+
+/// Truncates each sequence to `max_len` and pads all of them to `max_len`,
+/// or to the longest sequence when there is no maximum.
+pub fn pad(
+    seqs: Vec<Vec<u32>>,
+    max_len: Option<usize>,
+    side: PaddingSide,
+    pad_id: u32,
+) -> PaddedBatch {
+    let width = max_len.unwrap_or_else(|| seqs.iter().map(Vec::len).max().unwrap_or(0));
+    let mut batch = PaddedBatch {
+        ids: Vec::with_capacity(seqs.len()),
+        attention_mask: Vec::with_capacity(seqs.len()),
+    };
+    for mut seq in seqs {
+        seq.truncate(width);
+        let len = seq.len();
+        let padding = width - len;
+        let (row, mask) = match side {
+            PaddingSide::Right => {
+                seq.resize(width, pad_id);
+                (seq, [vec![1; len], vec![0; padding]].concat())
+            }
+            PaddingSide::Left => {
+                let mut row = vec![pad_id; padding];
+                row.extend(seq);
+                (row, [vec![0; padding], vec![1; len]].concat())
+            }
+        };
+        batch.ids.push(row);
+        batch.attention_mask.push(mask);
+    }
+    batch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pad() {
+        let seqs = vec![vec![1, 2, 3], vec![4]];
+        let batch = pad(seqs.clone(), None, PaddingSide::Right, 0);
+        assert_eq!(batch.ids, vec![vec![1, 2, 3], vec![4, 0, 0]]);
+        assert_eq!(batch.attention_mask, vec![vec![1, 1, 1], vec![1, 0, 0]]);
+        let batch = pad(seqs, Some(2), PaddingSide::Left, 9);
+        assert_eq!(batch.ids, vec![vec![1, 2], vec![9, 4]]);
+        assert_eq!(batch.attention_mask, vec![vec![1, 1], vec![0, 1]]);
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn test_into_arrays() {
+        let batch = pad(vec![vec![1, 2, 3], vec![4]], None, PaddingSide::Right, 0);
+        let (ids, mask) = batch.into_arrays();
+        assert_eq!(ids, ndarray::array![[1, 2, 3], [4, 0, 0]]);
+        assert_eq!(mask, ndarray::array![[1, 1, 1], [1, 0, 0]]);
+    }
+}
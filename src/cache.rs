@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::fetch;
+use crate::render;
+use crate::tokenizer::Tokenizer;
+
+// encode cache
+//
+// Natural text repeats the same words over and over, and a pre-tokenized
+// chunk always encodes to the same ids, so caching chunk encodings skips
+// most of the merge work. The cache can be saved and preloaded, letting
+// jobs over similar corpora start warm. A chunk's encoding depends only on
+// the merges, so the file records a fingerprint of them and a cache saved
+// for other merges is ignored. Files hold the most used entries first:
+//
+//   bpe cache v1
+//   <sha-256 of the merges>
+//   <hits> <id> <id> ...\t<escaped chunk>
+
+pub const HEADER: &str = "bpe cache v1";
+
+struct Entry {
+    ids: Vec<u32>,
+    hits: u64,
+}
+
+pub struct EncodeCache {
+    fingerprint: String,
+    entries: HashMap<String, Entry>,
+    /// Chunks beyond this many are encoded but not cached.
+    capacity: usize,
+}
+
+impl EncodeCache {
+    pub fn new(tokenizer: &Tokenizer, capacity: usize) -> EncodeCache {
+        EncodeCache {
+            fingerprint: fingerprint(tokenizer.merges()),
+            entries: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Preloads a saved cache, or starts empty if the file doesn't exist or
+    /// was saved for different merges.
+    pub fn load(path: &Path, tokenizer: &Tokenizer, capacity: usize) -> io::Result<EncodeCache> {
+        let mut cache = EncodeCache::new(tokenizer, capacity);
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(cache),
+            Err(e) => return Err(e),
+        };
+        let mut lines = BufReader::new(file).lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(invalid("missing encode cache header".into()));
+        }
+        if lines.next().transpose()?.as_deref() != Some(cache.fingerprint.as_str()) {
+            warn!(path = %path.display(), "encode cache is for other merges, ignoring it");
+            return Ok(cache);
+        }
+        for line in lines.take(capacity) {
+            let line = line?;
+            let (ids, chunk) = line
+                .split_once('\t')
+                .ok_or_else(|| invalid(format!("invalid cache entry {:?}", line)))?;
+            let chunk = render::parse(chunk, render::PieceStyle::Escaped)
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or_else(|| invalid(format!("invalid cached chunk {:?}", chunk)))?;
+            let mut fields = ids.split(' ');
+            let hits = fields.next().and_then(|n| n.parse().ok());
+            let ids: Option<Vec<u32>> = fields.map(|n| n.parse().ok()).collect();
+            let (Some(hits), Some(ids)) = (hits, ids) else {
+                return Err(invalid(format!("invalid cache entry {:?}", line)));
+            };
+            cache.entries.insert(chunk, Entry { ids, hits });
+        }
+        Ok(cache)
+    }
+
+    /// Writes the most used entries first, so a smaller capacity on load
+    /// keeps the most valuable ones.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by(|a, b| b.1.hits.cmp(&a.1.hits).then(a.0.cmp(b.0)));
+        let mut w = BufWriter::new(File::create(path)?);
+        writeln!(w, "{}", HEADER)?;
+        writeln!(w, "{}", self.fingerprint)?;
+        for (chunk, entry) in entries {
+            write!(w, "{}", entry.hits)?;
+            for id in &entry.ids {
+                write!(w, " {}", id)?;
+            }
+            writeln!(w, "\t{}", render::escape(chunk.as_bytes()))?;
+        }
+        w.flush()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Encodes like `Tokenizer::encode`, looking each chunk up first.
+    pub fn encode(&mut self, tokenizer: &Tokenizer, text: &str) -> Vec<u32> {
+        let mut ids = vec![];
+        for chunk in tokenizer.chunks(text) {
+            if let Some(entry) = self.entries.get_mut(chunk) {
+                entry.hits += 1;
+                ids.extend(&entry.ids);
+                continue;
+            }
+            let chunk_ids = tokenizer.encode_chunk(chunk);
+            ids.extend(&chunk_ids);
+            if self.entries.len() < self.capacity {
+                let entry = Entry {
+                    ids: chunk_ids,
+                    hits: 1,
+                };
+                self.entries.insert(chunk.to_string(), entry);
+            }
+        }
+        ids
+    }
+}
+
+fn fingerprint(merges: &HashMap<(u32, u32), u32>) -> String {
+    let mut ranked: Vec<_> = merges.iter().map(|(&p, &idx)| (idx, p)).collect();
+    ranked.sort();
+    let mut hasher = Sha256::new();
+    for (idx, (p0, p1)) in ranked {
+        for n in [idx, p0, p1] {
+            hasher.update(n.to_le_bytes());
+        }
+    }
+    fetch::hex(&hasher.finalize())
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    fn bpe(merges: HashMap<(u32, u32), u32>) -> Tokenizer {
+        Tokenizer::new(Model {
+            merges,
+            pattern: Some(r"\s*\S+".to_string()),
+            special_tokens: HashMap::new(),
+            whitespace_marker: false,
+            metadata: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_save_load() {
+        let tokenizer = bpe(HashMap::from([((104, 105), 256)]));
+        let mut cache = EncodeCache::new(&tokenizer, 3);
+        let text = "hi hi\thi\n a";
+        assert_eq!(cache.encode(&tokenizer, text), tokenizer.encode(text));
+        // "hi", " hi", "\thi" and "\n a" are distinct chunks; three fit
+        assert_eq!(cache.len(), 3);
+
+        let path = std::env::temp_dir().join(format!("bpe-test-{}.cache", std::process::id()));
+        cache.save(&path).unwrap();
+        let loaded = EncodeCache::load(&path, &tokenizer, 10).unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded.entries["\thi"].ids, vec![9, 256]);
+        let other = bpe(HashMap::from([((104, 105), 256), ((256, 33), 257)]));
+        assert!(EncodeCache::load(&path, &other, 10).unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+        assert!(EncodeCache::load(&path, &tokenizer, 10).unwrap().is_empty());
+    }
+}
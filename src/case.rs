@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+// case markers
+//
+// An uncased vocabulary that still decodes to the original capitalization.
+// Words are lowercased before BPE, and a special token in front of a word
+// records how it was cased: `<|cap|>` for a capital first letter, `<|upper|>`
+// for all capitals. Mixed-case words are split at case changes ("McDonald"
+// is cap "mc", cap "donald"; "HTTPServer" is upper "http", cap "server").
+// Words that wouldn't come back exactly (such as "İ", whose lowercase is an
+// "i" and a combining dot) are left as they are.
+//
+// Folded text is a list of segments, each starting with the gap before its
+// marked word and running up to the next one:
+//
+//   "Hello big World!"  ->  <|cap|> "hello big", <|cap|> " world!"
+
+/// Marks a word whose first letter is a capital.
+pub const CAPITALIZED: &str = "<|cap|>";
+/// Marks a word written in capitals.
+pub const UPPERCASE: &str = "<|upper|>";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Case {
+    Capitalized,
+    Upper,
+}
+
+impl Case {
+    pub fn marker(self) -> &'static str {
+        match self {
+            Case::Capitalized => CAPITALIZED,
+            Case::Upper => UPPERCASE,
+        }
+    }
+}
+
+/// The ids of the two markers in a vocabulary.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Markers {
+    pub capitalized: u32,
+    pub upper: u32,
+}
+
+impl Markers {
+    /// Finds the markers among a model's special tokens; a model uses case
+    /// markers if it has both.
+    pub fn find(special_tokens: &HashMap<String, u32>) -> Option<Markers> {
+        Some(Markers {
+            capitalized: *special_tokens.get(CAPITALIZED)?,
+            upper: *special_tokens.get(UPPERCASE)?,
+        })
+    }
+
+    pub fn id(self, case: Case) -> u32 {
+        match case {
+            Case::Capitalized => self.capitalized,
+            Case::Upper => self.upper,
+        }
+    }
+
+    pub fn case(self, id: u32) -> Option<Case> {
+        if id == self.capitalized {
+            Some(Case::Capitalized)
+        } else if id == self.upper {
+            Some(Case::Upper)
+        } else {
+            None
+        }
+    }
+}
+
+/// Lowercases text into segments, each with the case of its first word.
+/// The first segment has no marker unless the text starts with a word that
+/// needs one.
+pub fn fold(text: &str) -> Vec<(Option<Case>, String)> {
+    let mut segments = vec![(None, String::new())];
+    let mut gap_start = 0;
+    for (start, word) in words(text) {
+        let gap = &text[gap_start..start];
+        gap_start = start + word.len();
+        let Some(parts) = fold_word(word) else {
+            let current = &mut segments.last_mut().unwrap().1;
+            current.push_str(gap);
+            current.push_str(word);
+            continue;
+        };
+        let mut gap = Some(gap);
+        for (case, part) in parts {
+            if case.is_some() {
+                segments.push((case, String::new()));
+            }
+            let current = &mut segments.last_mut().unwrap().1;
+            current.push_str(gap.take().unwrap_or(""));
+            current.push_str(&part);
+        }
+    }
+    segments.last_mut().unwrap().1.push_str(&text[gap_start..]);
+    if segments.len() > 1 && segments[0].1.is_empty() {
+        segments.remove(0);
+    }
+    segments
+}
+
+/// Restores the case of a folded segment: the first letter, or the first
+/// run of letters, of its first word.
+pub fn unfold(case: Case, segment: &str) -> String {
+    let Some(start) = segment.find(char::is_alphabetic) else {
+        return segment.to_string();
+    };
+    let end = match case {
+        Case::Capitalized => start + segment[start..].chars().next().unwrap().len_utf8(),
+        Case::Upper => segment[start..]
+            .find(|c: char| !c.is_alphabetic())
+            .map_or(segment.len(), |i| start + i),
+    };
+    let mut text = segment[..start].to_string();
+    text.extend(segment[start..end].chars().flat_map(char::to_uppercase));
+    text.push_str(&segment[end..]);
+    text
+}
+
+/// The maximal runs of letters in text, with their byte offsets.
+fn words(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut rest = text.char_indices().peekable();
+    std::iter::from_fn(move || {
+        let (start, _) = rest.find(|&(_, c)| c.is_alphabetic())?;
+        let mut end = text.len();
+        while let Some(&(i, c)) = rest.peek() {
+            if !c.is_alphabetic() {
+                end = i;
+                break;
+            }
+            rest.next();
+        }
+        Some((start, &text[start..end]))
+    })
+}
+
+/// Splits a word at case changes and lowercases the parts, or returns `None`
+/// if unfolding them wouldn't give the word back.
+fn fold_word(word: &str) -> Option<Vec<(Option<Case>, String)>> {
+    let chars: Vec<(usize, char)> = word.char_indices().collect();
+    let mut cuts = vec![0];
+    for i in 1..chars.len() {
+        let (prev, c) = (chars[i - 1].1, chars[i].1);
+        let next_lower = chars.get(i + 1).is_some_and(|&(_, n)| n.is_lowercase());
+        if c.is_uppercase() && (!prev.is_uppercase() || next_lower) {
+            cuts.push(chars[i].0);
+        }
+    }
+    cuts.push(word.len());
+    let mut parts = vec![];
+    let mut unfolded = String::new();
+    for span in cuts.windows(2) {
+        let part = &word[span[0]..span[1]];
+        let lower = part.to_lowercase();
+        let case = if lower == part {
+            None
+        } else if part.chars().skip(1).any(char::is_uppercase) {
+            Some(Case::Upper)
+        } else {
+            Some(Case::Capitalized)
+        };
+        unfolded.push_str(&case.map_or(lower.clone(), |case| unfold(case, &lower)));
+        parts.push((case, lower));
+    }
+    (unfolded == word).then_some(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fold() {
+        let cap = Some(Case::Capitalized);
+        let upper = Some(Case::Upper);
+        assert_eq!(
+            fold("Hello big World!"),
+            vec![(cap, "hello big".into()), (cap, " world!".into())]
+        );
+        assert_eq!(
+            fold("see HTTPServer, McDonald"),
+            vec![
+                (None, "see".into()),
+                (upper, " http".into()),
+                (cap, "server".into()),
+                (cap, ", mc".into()),
+                (cap, "donald".into())
+            ]
+        );
+        assert_eq!(
+            fold("iPhone"),
+            vec![(None, "i".into()), (cap, "phone".into())]
+        );
+        // "İ" lowercases to "i" and a combining dot, which uppercase to "I" and the dot
+        assert_eq!(fold("İstanbul 1"), vec![(None, "İstanbul 1".into())]);
+        assert_eq!(fold(""), vec![(None, "".into())]);
+
+        for text in [
+            "Hello big World!",
+            "see HTTPServer, McDonald",
+            "ÉCOLE d'Été A B",
+            "STRAßE",
+            "ǅemal",
+        ] {
+            let unfolded: String = fold(text)
+                .into_iter()
+                .map(|(case, segment)| case.map_or(segment.clone(), |case| unfold(case, &segment)))
+                .collect();
+            assert_eq!(unfolded, text);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+// decode cleanup
+//
+// Optional touch-ups of decoded text for display, each off by default so a
+// plain decode still gives back exactly what was encoded. Conventions such
+// as prefix spaces and whitespace markers can leave a space at the start of
+// a text or doubled between pieces; special tokens such as `<|endoftext|>`
+// are usually not meant to be read at all.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DecodeOptions {
+    /// Leave out special tokens.
+    pub skip_special_tokens: bool,
+    /// Remove one space from the start of the text.
+    pub strip_prefix_space: bool,
+    /// Replace each run of spaces with a single space.
+    pub collapse_spaces: bool,
+}
+
+impl DecodeOptions {
+    /// Applies the text cleanups, stripping the prefix space before spaces
+    /// are collapsed.
+    pub fn clean(&self, mut text: String) -> String {
+        if self.strip_prefix_space && text.starts_with(' ') {
+            text.remove(0);
+        }
+        if self.collapse_spaces {
+            let mut collapsed = String::with_capacity(text.len());
+            for c in text.chars() {
+                if !(c == ' ' && collapsed.ends_with(' ')) {
+                    collapsed.push(c);
+                }
+            }
+            text = collapsed;
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean() {
+        let text = || "  a  b \n  c".to_string();
+        assert_eq!(DecodeOptions::default().clean(text()), text());
+        let strip = DecodeOptions {
+            strip_prefix_space: true,
+            ..DecodeOptions::default()
+        };
+        assert_eq!(strip.clean(text()), " a  b \n  c");
+        let collapse = DecodeOptions {
+            collapse_spaces: true,
+            ..DecodeOptions::default()
+        };
+        assert_eq!(collapse.clean(text()), " a b \n c");
+        let both = DecodeOptions {
+            strip_prefix_space: true,
+            ..collapse
+        };
+        assert_eq!(both.clean(text()), " a b \n c");
+        assert_eq!(both.clean(" x".into()), "x");
+    }
+}
@@ -0,0 +1,123 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::alphabet::Alphabet;
+use crate::corpus::{Compression, Dedup, InputEncoding};
+use crate::model::Format;
+use crate::tokenizer::Algorithm;
+use crate::MergeScore;
+
+// training config files
+//
+// Every key mirrors the `bpe train` flag of the same name; flags given on
+// the command line take precedence over the file. Relative paths are
+// resolved against the directory containing the config file.
+//
+//   vocab_size = 4096
+//   jsonl = "data/train.jsonl.zst"
+//   field = "text"
+//   pattern = "gpt4"
+//   special_tokens = ["<|endoftext|>"]
+//   max_chunk_repeats = 1000
+//   output = "models/run1.bpe"
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrainConfig {
+    pub vocab_size: Option<u32>,
+    pub num_merges: Option<u32>,
+    pub algorithm: Option<Algorithm>,
+    pub input: Option<PathBuf>,
+    pub jsonl: Option<PathBuf>,
+    pub parquet: Option<PathBuf>,
+    pub field: Option<String>,
+    pub compression: Option<Compression>,
+    pub input_encoding: Option<InputEncoding>,
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub no_ignore: bool,
+    #[serde(default)]
+    pub binary: bool,
+    pub output: Option<PathBuf>,
+    pub model_format: Option<Format>,
+    pub dedup: Option<Dedup>,
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub special_tokens: Vec<String>,
+    #[serde(default)]
+    pub case_markers: bool,
+    #[serde(default)]
+    pub whitespace_marker: bool,
+    pub max_chunk_repeats: Option<u32>,
+    pub merge_score: Option<MergeScore>,
+    pub min_pair_count: Option<u32>,
+    #[serde(default)]
+    pub forbid: Vec<String>,
+    #[serde(default)]
+    pub protect: Vec<String>,
+    pub alphabet: Option<Alphabet>,
+    pub seed_vocab: Option<PathBuf>,
+    pub threads: Option<usize>,
+    pub reader_threads: Option<usize>,
+    pub stop_when_gain_below: Option<f64>,
+    pub sample_bytes: Option<String>,
+    pub sample_lines: Option<usize>,
+    pub seed: Option<u64>,
+}
+
+impl TrainConfig {
+    pub fn load(path: &Path) -> io::Result<TrainConfig> {
+        let mut config = TrainConfig::parse(&fs::read_to_string(path)?)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for p in [
+            &mut config.input,
+            &mut config.jsonl,
+            &mut config.parquet,
+            &mut config.output,
+            &mut config.seed_vocab,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if p.is_relative() && !crate::fetch::is_url(p) && !crate::corpus::is_stdin(p) {
+                *p = dir.join(&*p);
+            }
+        }
+        Ok(config)
+    }
+
+    fn parse(text: &str) -> io::Result<TrainConfig> {
+        toml::from_str(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = TrainConfig::parse(
+            r#"
+            vocab_size = 4096
+            algorithm = "unigram"
+            jsonl = "train.jsonl"
+            field = "text"
+            compression = "zstd"
+            dedup = "paragraph"
+            special_tokens = ["<|endoftext|>"]
+            sample_bytes = "100M"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.vocab_size, Some(4096));
+        assert_eq!(config.algorithm, Some(Algorithm::Unigram));
+        assert_eq!(config.compression, Some(Compression::Zstd));
+        assert_eq!(config.dedup, Some(Dedup::Paragraph));
+        assert_eq!(config.special_tokens, vec!["<|endoftext|>"]);
+        assert!(TrainConfig::parse("vocab = 1").is_err());
+    }
+}
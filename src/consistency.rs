@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+use crate::{build_vocab, encode_bytes};
+
+// training/encoding consistency
+//
+// Training applies each merge to every chunk as soon as it is learned,
+// while encoding replays the merges by rank on a chunk's bytes. A merge
+// only joins ids older than itself, so the two always end on the same ids;
+// when they don't, the trainer and the encoder have drifted apart. This
+// re-encodes every trained chunk from its bytes and reports those whose ids
+// differ.
+
+/// A chunk whose encoding differs from the ids training left it as.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub bytes: Vec<u8>,
+    pub trained: Vec<u32>,
+    pub encoded: Vec<u32>,
+    /// How often the chunk occurs in the training words.
+    pub count: u64,
+}
+
+/// The trained words that `merges` encode differently, most frequent first.
+pub fn check(merges: &HashMap<(u32, u32), u32>, words: &[(Vec<u32>, u32)]) -> Vec<Divergence> {
+    let vocab = build_vocab(merges);
+    let mut distinct: HashMap<&[u32], u64> = HashMap::new();
+    for (ids, n) in words {
+        *distinct.entry(ids).or_default() += *n as u64;
+    }
+    let mut divergences: Vec<Divergence> = distinct
+        .into_iter()
+        .filter_map(|(trained, count)| {
+            let bytes: Vec<u8> = trained.iter().flat_map(|id| &vocab[id]).copied().collect();
+            let encoded = encode_bytes(merges, &bytes);
+            (encoded != trained).then(|| Divergence {
+                bytes,
+                trained: trained.to_vec(),
+                encoded,
+                count,
+            })
+        })
+        .collect();
+    divergences.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.bytes.cmp(&b.bytes)));
+    divergences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use crate::{train_words_segmented, TrainOptions};
+
+    #[test]
+    fn test_check() {
+        let words: Vec<(Vec<u32>, u32)> = ["abab", "aab", "abcab", "b"]
+            .iter()
+            .map(|w| (w.bytes().map(u32::from).collect(), 2))
+            .collect();
+        let options = TrainOptions {
+            threads: 2,
+            ..TrainOptions::default()
+        };
+        let trained = train_words_segmented(words, 4, &options, &mut Metrics::new(0, None));
+        assert!(check(&trained.merges, &trained.words).is_empty());
+
+        // a word left unmerged, as a trainer that skipped it would
+        let merges = HashMap::from([((97, 98), 256)]);
+        let words = vec![(vec![256, 256], 1), (vec![97, 98, 99], 3)];
+        assert_eq!(
+            check(&merges, &words),
+            vec![Divergence {
+                bytes: b"abc".to_vec(),
+                trained: vec![97, 98, 99],
+                encoded: vec![256, 99],
+                count: 3,
+            }]
+        );
+    }
+}
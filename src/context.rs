@@ -0,0 +1,87 @@
+use std::io;
+use std::path::Path;
+
+// error context
+//
+// Errors about a place in an input say which file, at which byte, and show
+// the text around it, so a bad byte or id deep in a large corpus can be
+// found without bisecting the file:
+//
+//   corpus.txt: invalid UTF-8 at byte 1048581 near "the quick br\xffown fox"
+//
+// Valid text in a snippet is shown as it is, with control characters
+// escaped; bytes that aren't UTF-8 are shown as `\xNN`.
+
+/// Bytes of context shown on each side of a position.
+const CONTEXT: usize = 24;
+
+/// The text around byte `at` of `bytes`, quoted and escaped, with `...`
+/// where it was cut.
+pub fn near(bytes: &[u8], at: usize) -> String {
+    let at = at.min(bytes.len());
+    let start = at.saturating_sub(CONTEXT);
+    let end = bytes.len().min(at + CONTEXT);
+    let mut s = String::new();
+    if start > 0 {
+        s.push_str("...");
+    }
+    s.push('"');
+    // a cut through a character shows as escaped bytes, which is harmless
+    for chunk in bytes[start..end].utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c.is_control() || c == '"' || c == '\\' {
+                s.extend(c.escape_debug());
+            } else {
+                s.push(c);
+            }
+        }
+        for b in chunk.invalid() {
+            s.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    s.push('"');
+    if end < bytes.len() {
+        s.push_str("...");
+    }
+    s
+}
+
+/// An error for invalid UTF-8 at byte `at` of `bytes`, which start at
+/// byte `offset` of the input.
+pub fn invalid_utf8(bytes: &[u8], at: usize, offset: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "invalid UTF-8 at byte {} near {}",
+            offset + at,
+            near(bytes, at)
+        ),
+    )
+}
+
+/// Names the file an error is about, keeping its kind.
+pub fn in_file(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_near() {
+        assert_eq!(near(b"a\"b\n\xffc", 4), r#""a\"b\n\xffc""#);
+        let long = [b"x".repeat(30), b"\xff".to_vec(), b"y".repeat(30)].concat();
+        assert_eq!(
+            near(&long, 30),
+            format!(r#"..."{}\xff{}"..."#, "x".repeat(24), "y".repeat(23))
+        );
+        assert_eq!(near("héllo".as_bytes(), 2), r#""héllo""#);
+        let e = in_file(Path::new("a.txt"), invalid_utf8(b"ab\xff", 2, 10));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            e.to_string(),
+            r#"a.txt: invalid UTF-8 at byte 12 near "ab\xff""#
+        );
+    }
+}
@@ -0,0 +1,687 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use flate2::read::MultiGzDecoder;
+use memmap2::Mmap;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::context;
+use crate::ignore::IgnoreRules;
+use crate::rng::Rng;
+
+// corpus sources
+
+pub enum Source {
+    /// A plain text file, used as a single document.
+    Text(PathBuf),
+    /// A plain text file of NUL-separated documents, as `find -print0` and
+    /// `xargs -0` use, so documents can hold newlines.
+    Records(PathBuf),
+    /// A directory, each text file under it used as a document.
+    Tree { path: PathBuf, walk: Walk },
+    /// A JSONL file, one document per line taken from the given string field.
+    Jsonl { path: PathBuf, field: String },
+    /// A Parquet file, one document per row taken from the given string column.
+    #[cfg(feature = "parquet")]
+    Parquet { path: PathBuf, field: String },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Pick the decoder from the file extension (`.gz`, `.zst`)
+    Auto,
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn resolve(self, path: &Path) -> Compression {
+        if self != Compression::Auto {
+            return self;
+        }
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst" | "zstd") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// The character encoding of text and JSONL inputs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputEncoding {
+    /// UTF-16 or UTF-8 as announced by a byte order mark, else UTF-8
+    #[default]
+    Auto,
+    #[value(name = "utf-8")]
+    #[serde(rename = "utf-8")]
+    Utf8,
+    /// ISO 8859-1, each byte being the code point of the same value
+    #[value(name = "latin-1")]
+    #[serde(rename = "latin-1")]
+    Latin1,
+    /// Latin-1 with printable characters in place of the 0x80-0x9f controls
+    #[value(name = "windows-1252")]
+    #[serde(rename = "windows-1252")]
+    Windows1252,
+    #[value(name = "utf-16le")]
+    #[serde(rename = "utf-16le")]
+    Utf16Le,
+    #[value(name = "utf-16be")]
+    #[serde(rename = "utf-16be")]
+    Utf16Be,
+}
+
+/// Windows-1252 characters for bytes 0x80-0x9f; the five bytes it leaves
+/// undefined map to the Latin-1 control characters, as in WHATWG's decoder.
+const WINDOWS_1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+/// Transcodes a reader to UTF-8, dropping any byte order mark. UTF-8 input
+/// is passed through as it streams; other encodings are decoded in memory.
+pub fn decode(
+    mut reader: Box<dyn BufRead>,
+    encoding: InputEncoding,
+) -> io::Result<Box<dyn BufRead>> {
+    let encoding = match encoding {
+        InputEncoding::Auto => {
+            let head = reader.fill_buf()?;
+            if head.starts_with(b"\xef\xbb\xbf") {
+                reader.consume(3);
+                return Ok(reader);
+            } else if head.starts_with(b"\xff\xfe") {
+                InputEncoding::Utf16Le
+            } else if head.starts_with(b"\xfe\xff") {
+                InputEncoding::Utf16Be
+            } else {
+                return Ok(reader);
+            }
+        }
+        InputEncoding::Utf8 => return Ok(reader),
+        encoding => encoding,
+    };
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    let text: String = match encoding {
+        InputEncoding::Latin1 => bytes.iter().map(|&b| b as char).collect(),
+        InputEncoding::Windows1252 => bytes
+            .iter()
+            .map(|&b| match b {
+                0x80..=0x9f => WINDOWS_1252[b as usize - 0x80],
+                _ => b as char,
+            })
+            .collect(),
+        InputEncoding::Utf16Le | InputEncoding::Utf16Be => {
+            if bytes.len() % 2 != 0 {
+                return Err(invalid("UTF-16 input has an odd number of bytes".into()));
+            }
+            let units = bytes.chunks_exact(2).map(|pair| match encoding {
+                InputEncoding::Utf16Le => u16::from_le_bytes([pair[0], pair[1]]),
+                _ => u16::from_be_bytes([pair[0], pair[1]]),
+            });
+            let text: String = char::decode_utf16(units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect();
+            match text.strip_prefix('\u{feff}') {
+                Some(rest) => rest.to_string(),
+                None => text,
+            }
+        }
+        InputEncoding::Auto | InputEncoding::Utf8 => unreachable!(),
+    };
+    Ok(Box::new(io::Cursor::new(text.into_bytes())))
+}
+
+/// Opens a file (or standard input for `-`) for reading, decompressing it
+/// on the fly if needed.
+pub fn open(path: &Path, compression: Compression) -> io::Result<Box<dyn BufRead>> {
+    let file: Box<dyn Read> = if is_stdin(path) {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    Ok(match compression.resolve(path) {
+        Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::new(file)?)),
+        Compression::Auto | Compression::None => Box::new(BufReader::new(file)),
+    })
+}
+
+/// A plain UTF-8 text file mapped into memory, to be used in place.
+pub struct MappedText {
+    map: Mmap,
+    /// Length of a byte order mark to skip.
+    bom: usize,
+}
+
+impl MappedText {
+    pub fn bytes(&self) -> &[u8] {
+        &self.map[self.bom..]
+    }
+}
+
+/// Maps a text input that needs neither decompressing nor transcoding, so
+/// it can be encoded without being read into memory. Standard input and
+/// other inputs give `None`; `open` reads them instead.
+pub fn map_text(
+    path: &Path,
+    compression: Compression,
+    encoding: InputEncoding,
+) -> io::Result<Option<MappedText>> {
+    let plain = matches!(
+        compression.resolve(path),
+        Compression::None | Compression::Auto
+    );
+    let utf8 = matches!(encoding, InputEncoding::Auto | InputEncoding::Utf8);
+    if is_stdin(path) || !plain || !utf8 {
+        return Ok(None);
+    }
+    let file = File::open(path)?;
+    // SAFETY: the map is read-only; like every mmap user we rely on the
+    // file not being truncated or rewritten while it is open.
+    let map = unsafe { Mmap::map(&file)? };
+    let bom = match encoding {
+        InputEncoding::Auto if map.starts_with(b"\xef\xbb\xbf") => 3,
+        // UTF-16 has to be transcoded
+        InputEncoding::Auto if map.starts_with(b"\xff\xfe") || map.starts_with(b"\xfe\xff") => {
+            return Ok(None)
+        }
+        _ => 0,
+    };
+    Ok(Some(MappedText { map, bom }))
+}
+
+pub fn is_stdin(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// How a directory input is walked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Walk {
+    /// Skip what `.gitignore` and `.ignore` files exclude, and `.git`.
+    pub ignore_files: bool,
+    /// Skip files that look binary.
+    pub skip_binary: bool,
+}
+
+impl Default for Walk {
+    fn default() -> Walk {
+        Walk {
+            ignore_files: true,
+            skip_binary: true,
+        }
+    }
+}
+
+/// Every file under a directory, recursively, in path order, less those
+/// the ignore files exclude. Symbolic links to files are included; linked
+/// directories aren't followed, so a link cycle can't loop.
+pub fn walk(root: &Path, options: Walk) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![(root.to_path_buf(), String::new(), IgnoreRules::default())];
+    while let Some((dir, base, mut rules)) = dirs.pop() {
+        if options.ignore_files {
+            rules.add_dir(&dir, &base)?;
+        }
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let relative = format!("{}{}", base, name);
+            let file_type = entry.file_type()?;
+            let is_dir = file_type.is_dir();
+            if options.ignore_files
+                && ((is_dir && name == ".git") || rules.is_ignored(&relative, is_dir))
+            {
+                continue;
+            }
+            if is_dir {
+                dirs.push((entry.path(), relative + "/", rules.clone()));
+            } else if file_type.is_file() || entry.path().is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// How far into a file to look for a NUL byte when deciding it's binary,
+/// as git and ripgrep do.
+const BINARY_PROBE: usize = 8 << 10;
+
+/// Reads a file under a directory input as one document. When binary files
+/// are skipped, one with a NUL byte near its start or that isn't valid text
+/// gives `None` (NULs are looked for after decompressing and transcoding, so
+/// UTF-16 text isn't binary); otherwise invalid UTF-8 is replaced.
+pub fn read_tree_file(
+    path: &Path,
+    compression: Compression,
+    encoding: InputEncoding,
+    options: Walk,
+) -> io::Result<Option<String>> {
+    let context = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+    let mut reader =
+        decode(open(path, compression).map_err(context)?, encoding).map_err(context)?;
+    let mut bytes = vec![];
+    if options.skip_binary {
+        (&mut reader)
+            .take(BINARY_PROBE as u64)
+            .read_to_end(&mut bytes)
+            .map_err(context)?;
+        if bytes.contains(&0) {
+            return Ok(None);
+        }
+    }
+    reader.read_to_end(&mut bytes).map_err(context)?;
+    Ok(match String::from_utf8(bytes) {
+        Ok(text) => Some(text),
+        Err(_) if options.skip_binary => None,
+        Err(e) => Some(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+    })
+}
+
+pub fn read_documents(
+    source: &Source,
+    compression: Compression,
+    encoding: InputEncoding,
+) -> io::Result<Vec<String>> {
+    match source {
+        Source::Text(path) => Ok(vec![read_text(path, compression, encoding)?]),
+        Source::Records(path) => Ok(split_records(read_text(path, compression, encoding)?)),
+        Source::Tree {
+            path,
+            walk: options,
+        } => {
+            let mut docs = vec![];
+            for file in walk(path, *options)? {
+                docs.extend(read_tree_file(&file, compression, encoding, *options)?);
+            }
+            Ok(docs)
+        }
+        Source::Jsonl { path, field } => {
+            read_jsonl(decode(open(path, compression)?, encoding)?, field)
+                .map_err(|e| context::in_file(path, e))
+        }
+        #[cfg(feature = "parquet")]
+        Source::Parquet { path, field } => read_parquet(File::open(path)?, field),
+    }
+}
+
+fn read_text(path: &Path, compression: Compression, encoding: InputEncoding) -> io::Result<String> {
+    let mut bytes = vec![];
+    decode(open(path, compression)?, encoding)?.read_to_end(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| {
+        let at = e.utf8_error().valid_up_to();
+        context::in_file(path, context::invalid_utf8(e.as_bytes(), at, 0))
+    })
+}
+
+/// Splits text into NUL-separated records; a NUL after the last record
+/// doesn't start another.
+pub fn split_records(text: String) -> Vec<String> {
+    let text = text.strip_suffix('\0').unwrap_or(&text);
+    if text.is_empty() {
+        return vec![];
+    }
+    text.split('\0').map(str::to_string).collect()
+}
+
+fn read_jsonl(mut reader: impl BufRead, field: &str) -> io::Result<Vec<String>> {
+    let mut docs = Vec::new();
+    let mut bytes = vec![];
+    let mut offset = 0;
+    for i in 0.. {
+        bytes.clear();
+        let n = reader.read_until(b'\n', &mut bytes)?;
+        if n == 0 {
+            break;
+        }
+        let line = std::str::from_utf8(&bytes).map_err(|e| {
+            let shown = bytes.strip_suffix(b"\n").unwrap_or(&bytes);
+            let e = context::invalid_utf8(shown, e.valid_up_to(), offset);
+            invalid(format!("line {}: {}", i + 1, e))
+        })?;
+        offset += n;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Value =
+            serde_json::from_str(line).map_err(|e| invalid(format!("line {}: {}", i + 1, e)))?;
+        match record.get(field) {
+            Some(Value::String(text)) => docs.push(text.clone()),
+            Some(Value::Null) | None => {}
+            Some(_) => {
+                return Err(invalid(format!(
+                    "line {}: field {:?} is not a string",
+                    i + 1,
+                    field
+                )))
+            }
+        }
+    }
+    Ok(docs)
+}
+
+#[cfg(feature = "parquet")]
+fn read_parquet(file: File, field: &str) -> io::Result<Vec<String>> {
+    use parquet::file::reader::SerializedFileReader;
+    use parquet::record::Field;
+
+    let reader = SerializedFileReader::new(file).map_err(|e| invalid(e.to_string()))?;
+    let mut docs = Vec::new();
+    for (i, row) in reader.into_iter().enumerate() {
+        let row = row.map_err(|e| invalid(format!("row {}: {}", i, e)))?;
+        match row
+            .get_column_iter()
+            .find(|(name, _)| name.as_str() == field)
+        {
+            Some((_, Field::Str(text))) => docs.push(text.clone()),
+            Some((_, Field::Null)) => {}
+            Some(_) => {
+                return Err(invalid(format!(
+                    "row {}: column {:?} is not a string",
+                    i, field
+                )))
+            }
+            None => return Err(invalid(format!("no column named {:?}", field))),
+        }
+    }
+    Ok(docs)
+}
+
+// deduplication
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dedup {
+    /// Drop lines seen before (blank lines are kept)
+    Line,
+    /// Drop blank-line separated paragraphs whose hash was seen before
+    Paragraph,
+}
+
+/// Removes repeated lines or paragraphs across all documents, keeping the
+/// first occurrence, so boilerplate doesn't dominate the pair counts.
+pub fn dedup(docs: Vec<String>, mode: Dedup) -> Vec<String> {
+    let mut seen = HashSet::new();
+    docs.into_iter()
+        .map(|doc| match mode {
+            Dedup::Line => doc
+                .split_inclusive('\n')
+                .filter(|line| line.trim().is_empty() || seen.insert(hash(line.trim_end())))
+                .collect(),
+            Dedup::Paragraph => doc
+                .split_inclusive("\n\n")
+                .filter(|para| para.trim().is_empty() || seen.insert(hash(para.trim())))
+                .collect(),
+        })
+        .collect()
+}
+
+// sampling
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleLimit {
+    Lines(usize),
+    Bytes(usize),
+}
+
+/// Draws a uniform random sample of lines across all documents, bounded by
+/// a line count or a byte budget, in a single pass. Every line gets a random
+/// key and the lines with the smallest keys are kept, which is reservoir
+/// sampling generalized to a byte budget. Sampled lines keep their original
+/// order and each becomes its own document.
+pub fn sample(docs: &[String], limit: SampleLimit, seed: u64) -> Vec<String> {
+    let mut rng = Rng::new(seed);
+    let mut heap = BinaryHeap::new();
+    let mut bytes = 0;
+    let lines = docs.iter().flat_map(|doc| doc.split_inclusive('\n'));
+    for (i, line) in lines.enumerate() {
+        heap.push((rng.next_u64(), i, line));
+        bytes += line.len();
+        while match limit {
+            SampleLimit::Lines(n) => heap.len() > n,
+            SampleLimit::Bytes(n) => bytes > n,
+        } {
+            let (_, _, dropped) = heap.pop().unwrap();
+            bytes -= dropped.len();
+        }
+    }
+    let mut kept = heap.into_vec();
+    kept.sort_by_key(|&(_, i, _)| i);
+    kept.into_iter()
+        .map(|(_, _, line)| line.to_string())
+        .collect()
+}
+
+fn hash(s: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    s.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_jsonl() {
+        let input = "{\"text\": \"hello\", \"id\": 1}\n\n{\"id\": 2}\n{\"text\": \"world\"}\n";
+        let docs = read_jsonl(input.as_bytes(), "text").unwrap();
+        assert_eq!(docs, vec!["hello", "world"]);
+        assert!(read_jsonl("{\"text\": 3}".as_bytes(), "text").is_err());
+        let err = read_jsonl(&b"{}\n{\"text\": \"\xff\"}\n"[..], "text").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"line 2: invalid UTF-8 at byte 13 near "{\"text\": \"\xff\"}""#
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        let decoded = |bytes: &[u8], encoding| {
+            let mut text = String::new();
+            let reader: Box<dyn BufRead> = Box::new(io::Cursor::new(bytes.to_vec()));
+            decode(reader, encoding)
+                .unwrap()
+                .read_to_string(&mut text)
+                .unwrap();
+            text
+        };
+        assert_eq!(decoded(b"caf\xe9", InputEncoding::Latin1), "café");
+        assert_eq!(
+            decoded(b"\x93hi\x94 \x80", InputEncoding::Windows1252),
+            "“hi” €"
+        );
+        assert_eq!(decoded(b"\xef\xbb\xbfhi", InputEncoding::Auto), "hi");
+        assert_eq!(decoded(b"\xff\xfeh\x00\xe9\x00", InputEncoding::Auto), "hé");
+        assert_eq!(decoded(b"\x00h\x00i", InputEncoding::Utf16Be), "hi");
+        assert_eq!(decoded("héllo".as_bytes(), InputEncoding::Auto), "héllo");
+    }
+
+    #[test]
+    fn test_map_text() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("bpe-test-{}-map.txt", std::process::id()));
+        std::fs::write(&path, b"\xef\xbb\xbfhi").unwrap();
+        let mapped = map_text(&path, Compression::Auto, InputEncoding::Auto).unwrap();
+        assert_eq!(mapped.unwrap().bytes(), b"hi");
+        let latin1 = map_text(&path, Compression::Auto, InputEncoding::Latin1).unwrap();
+        assert!(latin1.is_none());
+        std::fs::write(&path, b"\xff\xfeh\x00").unwrap();
+        assert!(map_text(&path, Compression::Auto, InputEncoding::Auto)
+            .unwrap()
+            .is_none());
+        std::fs::remove_file(&path).unwrap();
+        let gz = dir.join("corpus.txt.gz");
+        assert!(map_text(&gz, Compression::Auto, InputEncoding::Auto)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_walk() {
+        let dir = std::env::temp_dir().join(format!("bpe-test-{}-walk", std::process::id()));
+        std::fs::create_dir_all(dir.join("b/c")).unwrap();
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        for file in ["b/c/d.txt", "a.txt", "b/e.txt", "b/f.log", ".git/HEAD"] {
+            std::fs::write(dir.join(file), "x").unwrap();
+        }
+        std::fs::write(dir.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(dir.join("b/.ignore"), "c/\n").unwrap();
+        std::fs::write(dir.join("a.bin"), b"\x7fELF\x00\x01").unwrap();
+        let relative = |options| {
+            let files = walk(&dir, options).unwrap();
+            let files: Vec<String> = files
+                .iter()
+                .map(|f| f.strip_prefix(&dir).unwrap().display().to_string())
+                .collect();
+            files.join(" ")
+        };
+        let all = Walk {
+            ignore_files: false,
+            skip_binary: false,
+        };
+        assert_eq!(
+            relative(Walk::default()),
+            ".gitignore a.bin a.txt b/.ignore b/e.txt"
+        );
+        assert_eq!(
+            relative(all),
+            ".git/HEAD .gitignore a.bin a.txt b/.ignore b/c/d.txt b/e.txt b/f.log"
+        );
+
+        let read = |file, options| {
+            read_tree_file(
+                &dir.join(file),
+                Compression::Auto,
+                InputEncoding::Auto,
+                options,
+            )
+            .unwrap()
+        };
+        assert_eq!(read("a.bin", Walk::default()), None);
+        assert_eq!(read("a.bin", all).unwrap().len(), 6);
+        std::fs::write(dir.join("b.bin"), b"\xff\xd8\xff").unwrap();
+        assert_eq!(read("b.bin", Walk::default()), None);
+        assert_eq!(
+            read("b.bin", all).as_deref(),
+            Some("\u{fffd}\u{fffd}\u{fffd}")
+        );
+        assert_eq!(read("a.txt", Walk::default()).as_deref(), Some("x"));
+        std::fs::write(dir.join("utf16.txt"), b"\xff\xfeh\x00i\x00").unwrap();
+        assert_eq!(read("utf16.txt", Walk::default()).as_deref(), Some("hi"));
+        let source = Source::Tree {
+            path: dir.clone(),
+            walk: Walk::default(),
+        };
+        let docs = read_documents(&source, Compression::Auto, InputEncoding::Auto).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(docs, vec!["*.log\n", "x", "c/\n", "x", "hi"]);
+    }
+
+    #[test]
+    fn test_dedup() {
+        let docs = vec!["a\nb\n\na\n".to_string(), "b\nc".to_string()];
+        assert_eq!(dedup(docs.clone(), Dedup::Line), vec!["a\nb\n\n", "c"]);
+        let docs = vec!["x\ny\n\nz\n\nx\ny\n\n".to_string()];
+        assert_eq!(dedup(docs, Dedup::Paragraph), vec!["x\ny\n\nz\n\n"]);
+    }
+
+    #[test]
+    fn test_sample() {
+        let docs = vec!["a\nbb\nccc\n".to_string(), "dddd\neeeee\n".to_string()];
+        let lines = sample(&docs, SampleLimit::Lines(2), 7);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines, sample(&docs, SampleLimit::Lines(2), 7));
+        let sampled = sample(&docs, SampleLimit::Bytes(8), 7);
+        assert!(sampled.iter().map(String::len).sum::<usize>() <= 8);
+        assert_eq!(
+            sample(&docs, SampleLimit::Lines(10), 7).concat(),
+            docs.concat()
+        );
+    }
+
+    #[test]
+    fn test_split_records() {
+        let records = |text: &str| split_records(text.to_string());
+        assert_eq!(records("a\nb\0c\0"), vec!["a\nb", "c"]);
+        assert_eq!(records("a\0\0b"), vec!["a", "", "b"]);
+        assert_eq!(records("a"), vec!["a"]);
+        assert_eq!(records(""), Vec::<String>::new());
+        assert_eq!(records("\0"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_open_compressed() {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir();
+        let gz = dir.join(format!("bpe-test-{}.txt.gz", std::process::id()));
+        let mut encoder =
+            flate2::write::GzEncoder::new(File::create(&gz).unwrap(), Default::default());
+        encoder.write_all(b"hello gzip").unwrap();
+        encoder.finish().unwrap();
+        let zst = dir.join(format!("bpe-test-{}.txt.zst", std::process::id()));
+        std::fs::write(&zst, zstd::encode_all(&b"hello zstd"[..], 0).unwrap()).unwrap();
+
+        let read = |path: &PathBuf| {
+            let source = Source::Text(path.clone());
+            read_documents(&source, Compression::Auto, InputEncoding::Auto).unwrap()
+        };
+        let (gz_docs, zst_docs) = (read(&gz), read(&zst));
+        std::fs::remove_file(gz).unwrap();
+        std::fs::remove_file(zst).unwrap();
+        assert_eq!(gz_docs, vec!["hello gzip"]);
+        assert_eq!(zst_docs, vec!["hello zstd"]);
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_read_parquet() {
+        use std::sync::Arc;
+
+        use parquet::data_type::{ByteArray, ByteArrayType};
+        use parquet::file::writer::SerializedFileWriter;
+        use parquet::schema::parser::parse_message_type;
+
+        let path = std::env::temp_dir().join(format!("bpe-test-{}.parquet", std::process::id()));
+        let schema = Arc::new(
+            parse_message_type("message doc { REQUIRED BYTE_ARRAY text (UTF8); }").unwrap(),
+        );
+        let mut writer =
+            SerializedFileWriter::new(File::create(&path).unwrap(), schema, Default::default())
+                .unwrap();
+        let mut row_group = writer.next_row_group().unwrap();
+        let mut column = row_group.next_column().unwrap().unwrap();
+        let values = [ByteArray::from("hello"), ByteArray::from("world")];
+        column
+            .typed::<ByteArrayType>()
+            .write_batch(&values, None, None)
+            .unwrap();
+        column.close().unwrap();
+        row_group.close().unwrap();
+        writer.close().unwrap();
+
+        let docs = read_parquet(File::open(&path).unwrap(), "text").unwrap();
+        assert!(read_parquet(File::open(&path).unwrap(), "missing").is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(docs, vec!["hello", "world"]);
+    }
+}
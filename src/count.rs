@@ -0,0 +1,235 @@
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+// token counts
+//
+// Per-file token counts over a directory tree, for budgeting what a dataset
+// will cost to train or fine-tune on. Files are listed most tokens first,
+// as an aligned table with a grand total, as JSON, or as CSV. Counts
+// estimated from a sample carry the half-width of their 95% confidence
+// interval, and the total's combines them as independent errors.
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum CountFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FileCount {
+    pub path: String,
+    pub bytes: usize,
+    pub tokens: usize,
+    /// Bytes per token.
+    pub ratio: f64,
+    /// For an estimate, how far off it may be, at 95% confidence.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub margin: Option<usize>,
+}
+
+impl FileCount {
+    pub fn new(path: String, bytes: usize, tokens: usize) -> FileCount {
+        FileCount {
+            path,
+            bytes,
+            tokens,
+            ratio: bytes as f64 / tokens.max(1) as f64,
+            margin: None,
+        }
+    }
+
+    pub fn with_margin(self, margin: Option<usize>) -> FileCount {
+        FileCount { margin, ..self }
+    }
+}
+
+#[derive(Serialize)]
+struct Total {
+    files: usize,
+    bytes: usize,
+    tokens: usize,
+    ratio: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    margin: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    files: &'a [FileCount],
+    total: Total,
+}
+
+/// Sorts counts by tokens, most first (ties by path), and writes them, each
+/// line ended by `terminator` (a newline, or NUL for `-0`). The CSV has no
+/// total row, leaving every row a file.
+pub fn write(
+    w: &mut impl Write,
+    counts: &mut [FileCount],
+    format: CountFormat,
+    terminator: &[u8],
+) -> io::Result<()> {
+    counts.sort_by(|a, b| b.tokens.cmp(&a.tokens).then_with(|| a.path.cmp(&b.path)));
+    let bytes = counts.iter().map(|c| c.bytes).sum();
+    let tokens = counts.iter().map(|c| c.tokens).sum();
+    let mut total = FileCount::new(format!("total ({} files)", counts.len()), bytes, tokens);
+    if counts.iter().any(|c| c.margin.is_some()) {
+        let variance: f64 = counts
+            .iter()
+            .map(|c| c.margin.unwrap_or(0).pow(2) as f64)
+            .sum();
+        total = total.with_margin(Some(variance.sqrt().round() as usize));
+    }
+    match format {
+        CountFormat::Table => {
+            let width = |n: usize| n.to_string().len();
+            // "tokens" or, for estimates, "tokens ± margin"
+            let tokens = |count: &FileCount| match count.margin {
+                Some(margin) => format!("{} ± {}", count.tokens, margin),
+                None => count.tokens.to_string(),
+            };
+            let tokens_width = counts
+                .iter()
+                .chain([&total])
+                .map(|c| tokens(c).chars().count())
+                .max()
+                .unwrap_or(0)
+                .max("tokens".len());
+            let bytes_width = width(total.bytes).max("bytes".len());
+            write!(
+                w,
+                "{:>tokens_width$}  {:>bytes_width$}  {:>6}  path",
+                "tokens", "bytes", "ratio"
+            )?;
+            w.write_all(terminator)?;
+            for count in counts.iter().chain([&total]) {
+                write!(
+                    w,
+                    "{:>tokens_width$}  {:>bytes_width$}  {:>6.2}  {}",
+                    tokens(count),
+                    count.bytes,
+                    count.ratio,
+                    count.path
+                )?;
+                w.write_all(terminator)?;
+            }
+        }
+        CountFormat::Json => {
+            let report = Report {
+                files: counts,
+                total: Total {
+                    files: counts.len(),
+                    bytes: total.bytes,
+                    tokens: total.tokens,
+                    ratio: total.ratio,
+                    margin: total.margin,
+                },
+            };
+            write!(w, "{}", serde_json::to_string_pretty(&report)?)?;
+            w.write_all(terminator)?;
+        }
+        CountFormat::Csv => {
+            let margins = total.margin.is_some();
+            let header = if margins { ",margin" } else { "" };
+            write!(w, "path,bytes,tokens,ratio{}", header)?;
+            w.write_all(terminator)?;
+            for count in counts.iter() {
+                write!(
+                    w,
+                    "\"{}\",{},{},{:.4}",
+                    count.path.replace('"', "\"\""),
+                    count.bytes,
+                    count.tokens,
+                    count.ratio
+                )?;
+                if let (Some(margin), true) = (count.margin, margins) {
+                    write!(w, ",{}", margin)?;
+                }
+                w.write_all(terminator)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let counts = vec![
+            FileCount::new("a.txt".into(), 10, 4),
+            FileCount::new("b/\"c\".md".into(), 300, 100),
+            FileCount::new("empty".into(), 0, 0),
+        ];
+        let written = |format| {
+            let mut out = vec![];
+            write(&mut out, &mut counts.clone(), format, b"\n").unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            written(CountFormat::Table),
+            "tokens  bytes   ratio  path\n\
+             \x20  100    300    3.00  b/\"c\".md\n\
+             \x20    4     10    2.50  a.txt\n\
+             \x20    0      0    0.00  empty\n\
+             \x20  104    310    2.98  total (3 files)\n"
+        );
+        assert_eq!(
+            written(CountFormat::Csv),
+            "path,bytes,tokens,ratio\n\"b/\"\"c\"\".md\",300,100,3.0000\n\"a.txt\",10,4,2.5000\n\"empty\",0,0,0.0000\n"
+        );
+        let json: serde_json::Value = serde_json::from_str(&written(CountFormat::Json)).unwrap();
+        assert_eq!(json["files"][1]["path"], "a.txt");
+        assert_eq!(json["total"]["tokens"], 104);
+        assert_eq!(json["total"]["files"], 3);
+        assert!(json["total"].get("margin").is_none());
+    }
+
+    #[test]
+    fn test_write_margins() {
+        let counts = vec![
+            FileCount::new("a".into(), 1000, 300).with_margin(Some(30)),
+            FileCount::new("b".into(), 2000, 500).with_margin(Some(40)),
+        ];
+        let written = |format| {
+            let mut out = vec![];
+            write(&mut out, &mut counts.clone(), format, b"\n").unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            written(CountFormat::Table),
+            "  tokens  bytes   ratio  path\n\
+             500 ± 40   2000    4.00  b\n\
+             300 ± 30   1000    3.33  a\n\
+             800 ± 50   3000    3.75  total (2 files)\n"
+        );
+        assert_eq!(
+            written(CountFormat::Csv),
+            "path,bytes,tokens,ratio,margin\n\"b\",2000,500,4.0000,40\n\"a\",1000,300,3.3333,30\n"
+        );
+        let json: serde_json::Value = serde_json::from_str(&written(CountFormat::Json)).unwrap();
+        assert_eq!(json["total"]["margin"], 50);
+    }
+
+    #[test]
+    fn test_write_null() {
+        let mut counts = vec![
+            FileCount::new("a\nb".into(), 10, 4),
+            FileCount::new("c".into(), 30, 10),
+        ];
+        let mut out = vec![];
+        write(&mut out, &mut counts, CountFormat::Csv, b"\0").unwrap();
+        assert_eq!(
+            out,
+            b"path,bytes,tokens,ratio\0\"c\",30,10,3.0000\0\"a\nb\",10,4,2.5000\0"
+        );
+        out.clear();
+        write(&mut out, &mut counts, CountFormat::Table, b"\0").unwrap();
+        assert_eq!(out.iter().filter(|&&b| b == 0).count(), 4);
+        assert!(out.ends_with(b"total (2 files)\0"));
+    }
+}
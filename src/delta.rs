@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::history::MergeRecord;
+use crate::metrics::Metrics;
+use crate::{
+    build_vocab, count_pairs, merge_words, train_words_from, MergeScore, TrainOptions, Trained,
+};
+
+// delta training
+//
+// Updating a BPE model when its corpus grows, without retraining from
+// scratch. Training can save a state beside the model: the distinct chunks
+// it trained on with their counts, and for each merge how often its pair
+// occurred and how often the runner-up did. To add documents, the merges
+// are replayed on the new chunks alone. Merge i is what training on the
+// whole corpus would pick as long as no other pair gains enough in the new
+// chunks to overtake it, which the old runner-up count bounds; from the
+// first merge where that can't be ruled out, the old and new chunks are
+// combined and the tail of the merge list is trained again.
+//
+// The merges kept are exactly those of retraining on everything. The bound
+// is conservative, so part of the tail may be retrained for nothing. Only
+// frequency-scored training can be updated, since other scores of a pair
+// depend on counts the state doesn't keep.
+
+/// What an update needs of a past training run.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrainState {
+    /// Distinct chunks trained on, as bytes, with how often each occurred.
+    pub words: Vec<(Vec<u8>, u32)>,
+    pub history: Vec<MergeRecord>,
+    /// Leading merges that build protected tokens, kept whatever the counts.
+    pub reserved: u32,
+    pub num_merges: u32,
+    pub min_pair_count: u32,
+    /// Byte sequences no token may contain, as in `TrainOptions`.
+    pub forbidden: Vec<Vec<u8>>,
+    /// How new documents are split and marked, as for the old ones.
+    pub pattern: Option<String>,
+    pub case_markers: bool,
+    pub whitespace_marker: bool,
+    pub special_tokens: Vec<String>,
+}
+
+impl TrainState {
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let bytes = postcard::to_stdvec(self).map_err(io::Error::other)?;
+        fs::write(path, bytes)
+    }
+
+    pub fn load(path: &Path) -> io::Result<TrainState> {
+        let bytes = fs::read(path)?;
+        postcard::from_bytes(&bytes).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: not a training state: {}", path.display(), e),
+            )
+        })
+    }
+}
+
+/// The chunks of trained words, as training left them, as bytes.
+pub fn word_bytes(
+    words: &[(Vec<u32>, u32)],
+    merges: &HashMap<(u32, u32), u32>,
+) -> Vec<(Vec<u8>, u32)> {
+    let vocab = build_vocab(merges);
+    words
+        .iter()
+        .map(|(ids, n)| (ids.iter().flat_map(|id| vocab[id].clone()).collect(), *n))
+        .collect()
+}
+
+/// The result of an update.
+pub struct Update {
+    pub trained: Trained,
+    /// Leading merges kept from the old training run.
+    pub kept: u32,
+    /// The state after the update, covering old and new chunks.
+    pub state: TrainState,
+}
+
+/// Adds chunks of new documents, as bytes with their counts, to a past
+/// training run and re-derives the merges from the first that they could
+/// change.
+pub fn update(
+    state: TrainState,
+    new: Vec<(Vec<u8>, u32)>,
+    options: &TrainOptions,
+    metrics: &mut Metrics,
+) -> io::Result<Update> {
+    if options.score != MergeScore::Frequency || options.early_stopping.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only frequency-scored training without early stopping can be updated",
+        ));
+    }
+    let to_ids = |words: &[(Vec<u8>, u32)]| -> Vec<(Vec<u32>, u32)> {
+        words
+            .iter()
+            .map(|(bytes, n)| (bytes.iter().map(|&b| b.into()).collect(), *n))
+            .collect()
+    };
+    let threads = options.threads.max(1);
+    let mut new_words = to_ids(&new);
+    let new_shard = new_words.len().div_ceil(threads).max(1);
+    let kept = replay(&state.history, state.reserved, &mut new_words, new_shard);
+
+    let mut words = to_ids(&state.words);
+    let shard_len = words.len().div_ceil(threads).max(1);
+    for record in &state.history[..kept] {
+        merge_words(&mut words, shard_len, record.pair, 256 + record.rank);
+    }
+    words.extend(new_words);
+    let history = state.history[..kept].to_vec();
+    let trained = train_words_from(words, history, state.num_merges, options, metrics);
+
+    let mut counts: HashMap<Vec<u8>, u32> = HashMap::new();
+    for (bytes, n) in state.words.into_iter().chain(new) {
+        *counts.entry(bytes).or_default() += n;
+    }
+    let state = TrainState {
+        words: counts.into_iter().collect(),
+        history: trained.history.clone(),
+        ..state
+    };
+    Ok(Update {
+        trained,
+        kept: kept as u32,
+        state,
+    })
+}
+
+/// Merges the new words with the recorded merges, in order, for as long as
+/// each is sure to still be chosen, and returns how many were. The first
+/// `reserved` are always kept.
+fn replay(
+    history: &[MergeRecord],
+    reserved: u32,
+    words: &mut [(Vec<u32>, u32)],
+    shard_len: usize,
+) -> usize {
+    for (i, record) in history.iter().enumerate() {
+        if i < reserved as usize {
+            merge_words(words, shard_len, record.pair, 256 + record.rank);
+            continue;
+        }
+        let stats = count_pairs(words, shard_len, false).pairs;
+        let total = record.count as u64 + stats.get(&record.pair).copied().unwrap_or(0) as u64;
+        // no other pair occurred more than the runner-up before; ties go to
+        // the smallest pair
+        let overtaken = stats.iter().any(|(&pair, &count)| {
+            let bound = record.runner_up as u64 + count as u64;
+            pair != record.pair && (bound > total || (bound == total && pair < record.pair))
+        });
+        if overtaken {
+            return i;
+        }
+        merge_words(words, shard_len, record.pair, 256 + record.rank);
+    }
+    history.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::train_words_segmented;
+
+    fn words(text: &[(&str, u32)]) -> Vec<(Vec<u8>, u32)> {
+        text.iter()
+            .map(|(w, n)| (w.as_bytes().to_vec(), *n))
+            .collect()
+    }
+
+    fn train(words: &[(Vec<u8>, u32)], num_merges: u32) -> Trained {
+        let ids = words
+            .iter()
+            .map(|(b, n)| (b.iter().map(|&b| b.into()).collect(), *n))
+            .collect();
+        let mut metrics = Metrics::new(0, None);
+        train_words_segmented(ids, num_merges, &TrainOptions::default(), &mut metrics)
+    }
+
+    fn state(words: Vec<(Vec<u8>, u32)>, num_merges: u32) -> TrainState {
+        TrainState {
+            history: train(&words, num_merges).history,
+            words,
+            reserved: 0,
+            num_merges,
+            min_pair_count: 1,
+            forbidden: vec![],
+            pattern: None,
+            case_markers: false,
+            whitespace_marker: false,
+            special_tokens: vec![],
+        }
+    }
+
+    #[test]
+    fn test_update() {
+        let old = words(&[("hello", 10), ("help", 6), ("yellow", 3), ("low", 2)]);
+        let new = words(&[("hello", 2), ("slow", 2), ("yelp", 1)]);
+        let update = update_all(state(old.clone(), 6), new.clone());
+        let all: Vec<_> = old.iter().chain(&new).cloned().collect();
+        assert_eq!(update.trained.merges, train(&all, 6).merges);
+        // "el" stays first; "lo" may then overtake "hel", and does
+        assert_eq!(update.kept, 1);
+        assert_eq!(update.trained.history[1].pair, (b'l'.into(), b'o'.into()));
+        assert_eq!(update.state.words.len(), 6);
+        assert_eq!(update.state.history, update.trained.history);
+
+        // nothing new keeps everything
+        let update = update_all(state(old.clone(), 6), vec![]);
+        assert_eq!(update.kept, 6);
+        assert_eq!(update.trained.merges, train(&old, 6).merges);
+
+        let pmi = TrainOptions {
+            score: MergeScore::Pmi,
+            ..TrainOptions::default()
+        };
+        let mut metrics = Metrics::new(0, None);
+        assert!(super::update(state(old, 6), new, &pmi, &mut metrics).is_err());
+    }
+
+    #[test]
+    fn test_reserved() {
+        // "yz" is built first whatever the counts say
+        let old = words(&[("hello", 10), ("yz", 1)]);
+        let reserved = crate::protected::merges(&[b"yz".to_vec()]);
+        let mut ids: Vec<(Vec<u32>, u32)> = old
+            .iter()
+            .map(|(b, n)| (b.iter().map(|&b| b.into()).collect(), *n))
+            .collect();
+        crate::protected::apply(&mut ids, &reserved);
+        let mut metrics = Metrics::new(0, None);
+        let trained = train_words_from(ids, reserved, 3, &TrainOptions::default(), &mut metrics);
+        let state = TrainState {
+            history: trained.history,
+            reserved: 1,
+            ..state(old, 3)
+        };
+        let update = update_all(state, words(&[("qq", 50)]));
+        assert!(update.kept >= 1);
+        assert_eq!(update.trained.history[0].pair, (b'y'.into(), b'z'.into()));
+        assert_eq!(update.trained.history[1].pair, (b'q'.into(), b'q'.into()));
+    }
+
+    fn update_all(state: TrainState, new: Vec<(Vec<u8>, u32)>) -> Update {
+        let mut metrics = Metrics::new(0, None);
+        update(state, new, &TrainOptions::default(), &mut metrics).unwrap()
+    }
+
+    #[test]
+    fn test_state_file() {
+        let path = std::env::temp_dir().join(format!("bpe-test-{}.state", std::process::id()));
+        let state = state(words(&[("abab", 2)]), 2);
+        state.save(&path).unwrap();
+        assert_eq!(TrainState::load(&path).unwrap(), state);
+        std::fs::write(&path, b"\xff\xff").unwrap();
+        assert!(TrainState::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+use std::ops::Range;
+
+// token diffs
+//
+// How an edit to a text changes its tokens: the two id sequences are
+// aligned on a longest common subsequence and what doesn't line up is
+// reported as hunks, each replacing a range of old ids with a range of new
+// ones. An edit usually changes only the tokens around it, so the common
+// prefix and suffix are set aside before aligning what remains.
+
+/// Middles longer than this many cells (old ids times new ids) aren't
+/// aligned; they become a single hunk.
+const MAX_CELLS: usize = 1 << 24;
+
+/// Old ids `old` replaced by new ids `new`; an empty range is an insertion
+/// or a deletion.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hunk {
+    pub old: Range<usize>,
+    pub new: Range<usize>,
+}
+
+impl Hunk {
+    pub fn is_insert(&self) -> bool {
+        self.old.is_empty()
+    }
+
+    pub fn is_delete(&self) -> bool {
+        self.new.is_empty()
+    }
+}
+
+/// The hunks turning `old` into `new`, in order.
+pub fn diff(old: &[u32], new: &[u32]) -> Vec<Hunk> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+    if a.is_empty() && b.is_empty() {
+        return vec![];
+    }
+    if a.len().saturating_mul(b.len()) > MAX_CELLS {
+        return vec![Hunk {
+            old: prefix..prefix + a.len(),
+            new: prefix..prefix + b.len(),
+        }];
+    }
+
+    // lcs[i * width + j]: length of the longest common subsequence of a[i..]
+    // and b[j..]
+    let width = b.len() + 1;
+    let mut lcs = vec![0u32; (a.len() + 1) * width];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i * width + j] = if a[i] == b[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+    let mut hunks: Vec<Hunk> = vec![];
+    let mut push = |i: usize, j: usize, di: usize, dj: usize| {
+        let (i, j) = (prefix + i, prefix + j);
+        match hunks.last_mut() {
+            Some(hunk) if hunk.old.end == i && hunk.new.end == j => {
+                hunk.old.end += di;
+                hunk.new.end += dj;
+            }
+            _ => hunks.push(Hunk {
+                old: i..i + di,
+                new: j..j + dj,
+            }),
+        }
+    };
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if j == b.len()
+            || (i < a.len() && lcs[(i + 1) * width + j] >= lcs[i * width + j + 1])
+        {
+            push(i, j, 1, 0);
+            i += 1;
+        } else {
+            push(i, j, 0, 1);
+            j += 1;
+        }
+    }
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hunk(old: Range<usize>, new: Range<usize>) -> Hunk {
+        Hunk { old, new }
+    }
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(diff(&[1, 2, 3], &[1, 2, 3]), vec![]);
+        assert_eq!(diff(&[1, 2, 3], &[1, 9, 3]), vec![hunk(1..2, 1..2)]);
+        assert_eq!(diff(&[1, 2, 3], &[1, 3]), vec![hunk(1..2, 1..1)]);
+        assert_eq!(diff(&[], &[4, 5]), vec![hunk(0..0, 0..2)]);
+        // two separate edits, one changing a token and one inserting two
+        assert_eq!(
+            diff(&[1, 2, 3, 4, 5, 6], &[1, 7, 3, 4, 8, 9, 5, 6]),
+            vec![hunk(1..2, 1..2), hunk(4..4, 4..6)]
+        );
+        let hunks = diff(&[5, 1, 2], &[1, 2, 5]);
+        assert!(hunks[0].is_delete() && hunks[1].is_insert());
+    }
+}
@@ -0,0 +1,96 @@
+// rich encodings
+//
+// The ids of a text together with what model runtimes and annotation tools
+// need beside them: where in the text each token came from, which tokens a
+// template added, and what was cut off to fit a maximum length. New fields
+// can be added without breaking callers, which can't build an `Encoding`
+// with a struct literal or match on all its fields.
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct Encoding {
+    pub ids: Vec<u32>,
+    /// Type (segment) id per position, 0 throughout for a single sequence.
+    pub type_ids: Vec<u32>,
+    /// Byte range of the text each token came from. Tokens a template adds
+    /// are `(0, 0)`; case markers are empty ranges where their word starts.
+    pub offsets: Vec<(usize, usize)>,
+    /// 1 per position, as a padded batch marks real tokens.
+    pub attention_mask: Vec<u8>,
+    /// 1 where a template added a special token, 0 for tokens of the text.
+    pub special_tokens_mask: Vec<u8>,
+    /// The windows that didn't fit when encoding with truncation, in order,
+    /// each with its own template tokens.
+    pub overflowing: Vec<Encoding>,
+}
+
+impl Encoding {
+    /// A single sequence of the text's tokens, with no template applied.
+    pub fn from_tokens(ids: Vec<u32>, offsets: Vec<(usize, usize)>) -> Encoding {
+        let len = ids.len();
+        Encoding {
+            ids,
+            type_ids: vec![0; len],
+            offsets,
+            attention_mask: vec![1; len],
+            special_tokens_mask: vec![0; len],
+            overflowing: vec![],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// The positions `range` of this encoding, without overflow windows.
+    pub fn slice(&self, range: std::ops::Range<usize>) -> Encoding {
+        Encoding {
+            ids: self.ids[range.clone()].to_vec(),
+            type_ids: self.type_ids[range.clone()].to_vec(),
+            offsets: self.offsets[range.clone()].to_vec(),
+            attention_mask: self.attention_mask[range.clone()].to_vec(),
+            special_tokens_mask: self.special_tokens_mask[range].to_vec(),
+            overflowing: vec![],
+        }
+    }
+
+    /// Appends the positions of `other`, without its overflow windows.
+    pub fn extend(&mut self, other: &Encoding) {
+        self.ids.extend(&other.ids);
+        self.type_ids.extend(&other.type_ids);
+        self.offsets.extend(&other.offsets);
+        self.attention_mask.extend(&other.attention_mask);
+        self.special_tokens_mask.extend(&other.special_tokens_mask);
+    }
+
+    /// Appends a special token added by a template.
+    pub fn push_special(&mut self, id: u32, type_id: u32) {
+        self.ids.push(id);
+        self.type_ids.push(type_id);
+        self.offsets.push((0, 0));
+        self.attention_mask.push(1);
+        self.special_tokens_mask.push(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slice_extend() {
+        let encoding = Encoding::from_tokens(vec![7, 8, 9], vec![(0, 1), (1, 3), (3, 4)]);
+        let mut joined = Encoding::default();
+        joined.push_special(1, 0);
+        joined.extend(&encoding.slice(1..3));
+        assert_eq!(joined.ids, vec![1, 8, 9]);
+        assert_eq!(joined.offsets, vec![(0, 0), (1, 3), (3, 4)]);
+        assert_eq!(joined.special_tokens_mask, vec![1, 0, 0]);
+        assert_eq!(joined.attention_mask, vec![1, 1, 1]);
+        assert_eq!(joined.len(), 3);
+    }
+}
@@ -0,0 +1,283 @@
+use clap::ValueEnum;
+
+use crate::rng::Rng;
+use crate::selftest::SAMPLES;
+use crate::Tokenize;
+
+// approximate token counts
+//
+// An exact count encodes every byte. Interactive tools that only need a
+// rough budget can do with less, in one of two ways:
+//
+// - Rates: tokens per byte, calibrated once per model by encoding some text,
+//   the start of the input or else the built-in multilingual samples. ASCII
+//   and other bytes get rates of their own, fitted by least squares over
+//   the lines, since text outside ASCII takes several bytes a character and
+//   usually more tokens per byte. Estimating is then a pass counting bytes,
+//   with no encoding at all. It is off when the rest of the text is unlike
+//   what was calibrated on: a model trained on one domain packs that domain
+//   into far fewer tokens than the samples.
+// - Sampling: encode one line in every n and scale the tokens by the bytes
+//   left out. It follows the text itself, at 1/n of the cost of encoding;
+//   lines are encoded on their own, so tokens that span a line break are
+//   split at it.
+//
+// For huge inputs where the error matters, `sampled_interval` draws lines at
+// random instead, each with the same probability, and reports a confidence
+// interval with the estimate. It is a ratio estimator: the sampled lines'
+// tokens per byte times all the bytes, with the variance of a Bernoulli
+// sample, (1 - p) / p² times the sum of the squared residuals, each line's
+// tokens less the ratio times its bytes.
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Method {
+    /// Tokens per byte calibrated for the model; no encoding at all
+    Rates,
+    /// Encode one line in every `--sample-every` and scale up
+    Sample,
+}
+
+/// Tokens per byte of a model, for ASCII and other bytes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rates {
+    pub ascii: f64,
+    pub other: f64,
+}
+
+impl Rates {
+    /// Calibrates on the built-in samples.
+    pub fn new(tokenizer: &dyn Tokenize) -> Rates {
+        let texts: Vec<&str> = SAMPLES.iter().map(|&(_, text)| text).collect();
+        Rates::calibrate(tokenizer, &texts)
+    }
+
+    /// Calibrates on texts: the rates that best predict their token counts
+    /// from their byte counts.
+    pub fn calibrate(tokenizer: &dyn Tokenize, texts: &[&str]) -> Rates {
+        // normal equations of tokens = ascii * a + other * b
+        let (mut aa, mut ab, mut bb, mut at, mut bt) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        let (mut bytes, mut tokens) = (0.0, 0.0);
+        for text in texts {
+            let (a, b) = split_bytes(text);
+            let t = tokenizer.encode(text).len() as f64;
+            aa += a * a;
+            ab += a * b;
+            bb += b * b;
+            at += a * t;
+            bt += b * t;
+            bytes += a + b;
+            tokens += t;
+        }
+        let det = aa * bb - ab * ab;
+        if det.abs() > f64::EPSILON * aa * bb {
+            let ascii = (at * bb - bt * ab) / det;
+            let other = (aa * bt - ab * at) / det;
+            if ascii > 0.0 && other > 0.0 {
+                return Rates { ascii, other };
+            }
+        }
+        // texts all of one kind, or a fit that makes no sense: one rate
+        let rate = tokens / bytes.max(1.0);
+        Rates {
+            ascii: rate,
+            other: rate,
+        }
+    }
+
+    /// Calibrates on the lines of texts up to about `max_bytes`, or on the
+    /// built-in samples if they're empty.
+    pub fn calibrate_on_start(
+        tokenizer: &dyn Tokenize,
+        texts: &[String],
+        max_bytes: usize,
+    ) -> Rates {
+        let mut bytes = 0;
+        let lines: Vec<&str> = texts
+            .iter()
+            .flat_map(|text| text.split_inclusive('\n'))
+            .take_while(|line| {
+                bytes += line.len();
+                bytes - line.len() < max_bytes
+            })
+            .collect();
+        match lines.is_empty() {
+            true => Rates::new(tokenizer),
+            false => Rates::calibrate(tokenizer, &lines),
+        }
+    }
+
+    pub fn estimate(&self, text: &str) -> usize {
+        let (a, b) = split_bytes(text);
+        (a * self.ascii + b * self.other).round() as usize
+    }
+}
+
+/// The ASCII and other bytes of a text.
+fn split_bytes(text: &str) -> (f64, f64) {
+    let ascii = text.bytes().filter(u8::is_ascii).count();
+    (ascii as f64, (text.len() - ascii) as f64)
+}
+
+/// 95% of normally distributed estimates fall within this many standard
+/// deviations.
+const Z_95: f64 = 1.96;
+
+/// An estimated count and the half-width of its 95% confidence interval.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Interval {
+    pub tokens: usize,
+    pub margin: usize,
+}
+
+/// Estimates the tokens of texts by encoding each line with probability
+/// `fraction`. Texts too small for any line to be drawn are encoded whole.
+pub fn sampled_interval(
+    tokenizer: &dyn Tokenize,
+    texts: &[String],
+    fraction: f64,
+    rng: &mut Rng,
+) -> Interval {
+    let threshold = (fraction.clamp(0.0, 1.0) * u64::MAX as f64) as u64;
+    let mut bytes = 0.0;
+    // sums over the sampled lines of bytes b and tokens t
+    let (mut b, mut t, mut tt, mut tb, mut bb) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for line in texts.iter().flat_map(|text| text.split_inclusive('\n')) {
+        bytes += line.len() as f64;
+        if rng.next_u64() <= threshold {
+            let (lb, lt) = (line.len() as f64, tokenizer.encode(line).len() as f64);
+            b += lb;
+            t += lt;
+            tt += lt * lt;
+            tb += lt * lb;
+            bb += lb * lb;
+        }
+    }
+    if b == 0.0 {
+        return Interval {
+            tokens: texts.iter().map(|text| tokenizer.encode(text).len()).sum(),
+            margin: 0,
+        };
+    }
+    let ratio = t / b;
+    let residuals = (tt - 2.0 * ratio * tb + ratio * ratio * bb).max(0.0);
+    let variance = (1.0 - fraction) / (fraction * fraction) * residuals;
+    Interval {
+        tokens: (ratio * bytes).round() as usize,
+        margin: (Z_95 * variance.sqrt()).round() as usize,
+    }
+}
+
+/// Estimates the tokens of texts by encoding their first line and every
+/// `every`th after it.
+pub fn sampled(tokenizer: &dyn Tokenize, texts: &[String], every: usize) -> usize {
+    let (mut bytes, mut sampled_bytes, mut sampled_tokens) = (0, 0, 0);
+    let lines = texts.iter().flat_map(|text| text.split_inclusive('\n'));
+    for (i, line) in lines.enumerate() {
+        bytes += line.len();
+        if i % every.max(1) == 0 {
+            sampled_bytes += line.len();
+            sampled_tokens += tokenizer.encode(line).len();
+        }
+    }
+    if sampled_bytes == 0 {
+        return 0;
+    }
+    (sampled_tokens as f64 * bytes as f64 / sampled_bytes as f64).round() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One token per character.
+    struct Chars;
+
+    impl Tokenize for Chars {
+        fn encode(&self, text: &str) -> Vec<u32> {
+            text.chars().map(|c| c as u32 % 256).collect()
+        }
+
+        fn decode(&self, _ids: &[u32]) -> String {
+            String::new()
+        }
+
+        fn vocab_size(&self) -> usize {
+            256
+        }
+    }
+
+    #[test]
+    fn test_rates() {
+        let rates = Rates::new(&Chars);
+        assert!((rates.ascii - 1.0).abs() < 0.05, "{:?}", rates);
+        assert!(rates.other < 0.6, "{:?}", rates);
+        let text = "東京は日本の首都です。".repeat(20);
+        let exact = Chars.encode(&text).len() as f64;
+        assert!((rates.estimate(&text) as f64 - exact).abs() / exact < 0.2);
+        assert_eq!(rates.estimate(""), 0);
+
+        let rates = Rates::calibrate(&Chars, &["plain ascii", "only"]);
+        assert_eq!(
+            rates,
+            Rates {
+                ascii: 1.0,
+                other: 1.0
+            }
+        );
+        let texts = vec!["ascii\né\n".to_string(), "ignored ü".to_string()];
+        let rates = Rates::calibrate_on_start(&Chars, &texts, 7);
+        assert!((rates.ascii - 1.0).abs() < 1e-9, "{:?}", rates);
+        assert!((rates.other - 0.5).abs() < 1e-9, "{:?}", rates);
+        assert_eq!(
+            Rates::calibrate_on_start(&Chars, &[], 6),
+            Rates::new(&Chars)
+        );
+    }
+
+    #[test]
+    fn test_sampled() {
+        let texts = vec!["one\ntwo\nthree\n".to_string(), "four\nfive".to_string()];
+        // every line
+        assert_eq!(sampled(&Chars, &texts, 1), 23);
+        // lines 0 and 3, "one\n" and "four\n": 9 tokens for 9 of 23 bytes
+        assert_eq!(sampled(&Chars, &texts, 3), 23);
+        let texts = vec!["é\nab\né\nab".to_string()];
+        // "é\n" twice: 4 tokens for 6 of 10 bytes
+        assert_eq!(sampled(&Chars, &texts, 2), 7);
+        assert_eq!(sampled(&Chars, &[], 3), 0);
+    }
+
+    #[test]
+    fn test_sampled_interval() {
+        let mut rng = Rng::new(0);
+        // lines of 1 to 40 ASCII characters, then 3-byte characters
+        let mut text: String = (0..4000).map(|i| "x".repeat(i % 40) + "\n").collect();
+        text += &"東\n".repeat(2000);
+        let texts = vec![text];
+        let exact = Chars.encode(&texts[0]).len();
+        let interval = sampled_interval(&Chars, &texts, 0.1, &mut rng);
+        assert!(interval.margin > 0);
+        let error = interval.tokens.abs_diff(exact);
+        assert!(error <= 2 * interval.margin, "{:?} vs {}", interval, exact);
+
+        // everything sampled is exact
+        let interval = sampled_interval(&Chars, &texts, 1.0, &mut rng);
+        assert_eq!(
+            interval,
+            Interval {
+                tokens: exact,
+                margin: 0
+            }
+        );
+        // so is a text too small for a sample
+        let small = vec!["tiny".to_string()];
+        let interval = sampled_interval(&Chars, &small, 1e-9, &mut rng);
+        assert_eq!(
+            interval,
+            Interval {
+                tokens: 4,
+                margin: 0
+            }
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+// id exports
+//
+// Encoded corpora written in the formats training pipelines load directly,
+// so tokenizing can happen once, ahead of training.
+
+/// The integer type ids are stored as.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dtype {
+    U16,
+    U32,
+}
+
+impl Dtype {
+    /// The narrowest type that holds every id of a vocabulary this size.
+    pub fn for_vocab(vocab_size: usize) -> Dtype {
+        if vocab_size <= 1 << 16 {
+            Dtype::U16
+        } else {
+            Dtype::U32
+        }
+    }
+
+    /// The NumPy type descriptor, little-endian.
+    fn descr(self) -> &'static str {
+        match self {
+            Dtype::U16 => "<u2",
+            Dtype::U32 => "<u4",
+        }
+    }
+
+    pub(crate) fn write_ids(self, w: &mut impl Write, ids: &[u32]) -> io::Result<()> {
+        for &id in ids {
+            match self {
+                Dtype::U16 => {
+                    let id = u16::try_from(id).map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("token id {} does not fit in 16 bits", id),
+                        )
+                    })?;
+                    w.write_all(&id.to_le_bytes())?;
+                }
+                Dtype::U32 => w.write_all(&id.to_le_bytes())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates `path` and fills it with `write`, removing the file again if
+/// writing fails, such as on an id too wide for the dtype, so a failed
+/// export never leaves a truncated file behind.
+pub(crate) fn write_file(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    let result = write(&mut w).and_then(|()| w.flush());
+    if result.is_err() {
+        drop(w);
+        let _ = std::fs::remove_file(path);
+    }
+    result
+}
+
+/// Identifies `.bin` files, as in llm.c's data loaders.
+pub const BIN_MAGIC: u32 = 20240520;
+const BIN_HEADER_INTS: usize = 256;
+
+/// Writes ids as a little-endian binary stream after a header of 256 i32s:
+/// the magic, a version giving the width (1 for u16, 2 for u32, as in
+/// llm.c), and the number of ids; the rest is zero. nanoGPT-style loaders
+/// map the ids from byte 1024 on.
+pub fn write_bin(path: &Path, ids: &[u32], dtype: Dtype) -> io::Result<()> {
+    let count = i32::try_from(ids.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many ids for a .bin file"))?;
+    let version = match dtype {
+        Dtype::U16 => 1,
+        Dtype::U32 => 2,
+    };
+    let mut header = [0_i32; BIN_HEADER_INTS];
+    header[..3].copy_from_slice(&[BIN_MAGIC as i32, version, count]);
+    write_file(path, |w| {
+        for n in header {
+            w.write_all(&n.to_le_bytes())?;
+        }
+        dtype.write_ids(w, ids)
+    })
+}
+
+/// Writes ids as a one-dimensional `.npy` array (format version 1.0), which
+/// `np.load` reads and `np.load(..., mmap_mode="r")` maps in place.
+pub fn write_npy(path: &Path, ids: &[u32], dtype: Dtype) -> io::Result<()> {
+    write_npy_shaped(path, ids, &format!("({},)", ids.len()), dtype)
+}
+
+/// Writes ids as a two-dimensional `.npy` array of rows of `cols` ids, such
+/// as packed blocks.
+pub fn write_npy_2d(path: &Path, ids: &[u32], cols: usize, dtype: Dtype) -> io::Result<()> {
+    if cols == 0 || !ids.len().is_multiple_of(cols) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} ids don't make rows of {}", ids.len(), cols),
+        ));
+    }
+    write_npy_shaped(
+        path,
+        ids,
+        &format!("({}, {})", ids.len() / cols, cols),
+        dtype,
+    )
+}
+
+fn write_npy_shaped(path: &Path, ids: &[u32], shape: &str, dtype: Dtype) -> io::Result<()> {
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        dtype.descr(),
+        shape
+    );
+    // the magic, version and length take 10 bytes; the data must start
+    // 64-byte aligned, after a header ending in a newline
+    let len = 10 + header.len() + 1;
+    header.push_str(&" ".repeat(len.next_multiple_of(64) - len));
+    header.push('\n');
+    write_file(path, |w| {
+        w.write_all(b"\x93NUMPY\x01\x00")?;
+        w.write_all(&(header.len() as u16).to_le_bytes())?;
+        w.write_all(header.as_bytes())?;
+        dtype.write_ids(w, ids)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_npy() {
+        let path = std::env::temp_dir().join(format!("bpe-test-{}.npy", std::process::id()));
+        write_npy(&path, &[1, 300, 65535], Dtype::U16).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = std::str::from_utf8(&bytes[10..10 + header_len]).unwrap();
+        assert!(header.starts_with("{'descr': '<u2', 'fortran_order': False, 'shape': (3,), }"));
+        assert!(header.ends_with(" \n"));
+        assert_eq!(&bytes[10 + header_len..], &[1, 0, 44, 1, 255, 255]);
+
+        assert!(write_npy(&path, &[70000], Dtype::U16).is_err());
+        assert!(!path.exists());
+        assert_eq!(Dtype::for_vocab(70000), Dtype::U32);
+
+        write_npy_2d(&path, &[1, 2, 3, 4, 5, 6], 3, Dtype::U32).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        let header = std::str::from_utf8(&bytes[10..10 + header_len]).unwrap();
+        assert!(header.contains("'shape': (2, 3), "));
+        assert_eq!(bytes.len(), 10 + header_len + 24);
+        assert!(write_npy_2d(&path, &[1, 2, 3], 2, Dtype::U32).is_err());
+    }
+
+    #[test]
+    fn test_write_bin() {
+        let path = std::env::temp_dir().join(format!("bpe-test-{}.bin", std::process::id()));
+        write_bin(&path, &[1, 70000], Dtype::U32).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let int = |i: usize| i32::from_le_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap());
+        assert_eq!((int(0), int(1), int(2), int(3)), (20240520, 2, 2, 0));
+        assert_eq!(bytes.len(), 1024 + 8);
+        assert_eq!(&bytes[1024..], &[1, 0, 0, 0, 0x70, 0x11, 1, 0]);
+
+        // an id too wide for u16 leaves no file behind
+        let err = write_bin(&path, &[1, 70000], Dtype::U16).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!path.exists());
+    }
+}
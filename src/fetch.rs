@@ -0,0 +1,285 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::corpus;
+
+// remote inputs
+
+pub fn is_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|s| s.starts_with("https://") || s.starts_with("http://"))
+}
+
+/// Returns a local path for `path`, downloading it first if it is a URL.
+/// When `sha256` is given the (local or downloaded) file must match it, and
+/// an earlier download that does is used again.
+pub fn resolve(path: &Path, sha256: Option<&str>) -> io::Result<PathBuf> {
+    if !is_url(path) {
+        if let Some(expected) = sha256 {
+            if corpus::is_stdin(path) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "cannot verify the checksum of standard input",
+                ));
+            }
+            verify(path, expected)?;
+        }
+        return Ok(path.to_path_buf());
+    }
+    let url = path.to_str().unwrap();
+    let name = url
+        .rsplit('/')
+        .next()
+        .filter(|s| !s.is_empty())
+        .unwrap_or("download");
+    let dir = std::env::temp_dir().join("bpe");
+    fs::create_dir_all(&dir)?;
+    let dest = dir.join(format!("{}-{}", &hex(&Sha256::digest(url))[..16], name));
+    if let Some(expected) = sha256 {
+        if dest.exists() && verify(&dest, expected).is_ok() {
+            info!(url, path = %dest.display(), "already downloaded");
+            return Ok(dest);
+        }
+    }
+    download(url, &dest, sha256)?;
+    Ok(dest)
+}
+
+fn download(url: &str, dest: &Path, sha256: Option<&str>) -> io::Result<()> {
+    // write to a partial file so an interrupted download is never mistaken
+    // for a complete one, named for this process so that concurrent runs
+    // don't write the same file, and removed if anything goes wrong
+    let partial = dest.with_extension(format!("{}.part", std::process::id()));
+    let result = download_to(url, &partial, sha256).and_then(|()| fs::rename(&partial, dest));
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    result
+}
+
+fn download_to(url: &str, partial: &Path, sha256: Option<&str>) -> io::Result<()> {
+    let mut response = ureq::get(url).call().map_err(io::Error::other)?;
+    let total = response.body().content_length();
+    let mut reader = response.body_mut().as_reader();
+    let mut out = File::create(partial)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut done = 0u64;
+    let mut progress = Progress::new(url, total);
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        out.write_all(&buf[..n])?;
+        hasher.update(&buf[..n]);
+        done += n as u64;
+        progress.update(done);
+    }
+    out.flush()?;
+    info!(url, bytes = done, "downloaded");
+
+    let actual = hex(&hasher.finalize());
+    if let Some(expected) = sha256 {
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(mismatch(url, expected, &actual));
+        }
+    }
+    Ok(())
+}
+
+/// Logs a download's progress every tenth of the way, or every
+/// `PROGRESS_STEP` bytes when the server doesn't give the size.
+struct Progress<'a> {
+    url: &'a str,
+    total: Option<u64>,
+    next: u64,
+}
+
+const PROGRESS_STEP: u64 = 16 << 20;
+
+impl Progress<'_> {
+    fn new(url: &str, total: Option<u64>) -> Progress<'_> {
+        let mut progress = Progress {
+            url,
+            total: total.filter(|&total| total > 0),
+            next: 0,
+        };
+        progress.next = progress.step();
+        progress
+    }
+
+    fn step(&self) -> u64 {
+        self.total.map_or(PROGRESS_STEP, |total| total.div_ceil(10))
+    }
+
+    fn update(&mut self, done: u64) {
+        if done < self.next {
+            return;
+        }
+        let mib = |n: u64| format!("{:.1} MiB", n as f64 / (1024.0 * 1024.0));
+        match self.total {
+            Some(total) => info!(
+                url = self.url,
+                done = mib(done),
+                total = mib(total),
+                percent = 100 * done / total,
+                "downloading"
+            ),
+            None => info!(url = self.url, done = mib(done), "downloading"),
+        }
+        self.next = (done / self.step() + 1) * self.step();
+    }
+}
+
+/// The SHA-256 of a file's contents, in hex.
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Checks a file against an expected SHA-256.
+pub fn verify(path: &Path, expected: &str) -> io::Result<()> {
+    let actual = sha256_file(path)?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(mismatch(&path.display().to_string(), expected, &actual));
+    }
+    Ok(())
+}
+
+/// The SHA-256 published beside a URL as `<url>.sha256`, in the format
+/// `sha256sum` writes, or `None` if the server has no such file. A checksum
+/// that can't be fetched for another reason, such as a server error, is
+/// treated as missing, with a warning; one that is fetched must be valid.
+pub fn published_sha256(url: &str) -> io::Result<Option<String>> {
+    let checksum_url = format!("{}.sha256", url);
+    let mut response = match ureq::get(&checksum_url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::StatusCode(404 | 410)) => return Ok(None),
+        Err(e) => {
+            warn!(
+                url = %checksum_url,
+                error = %e,
+                "can't fetch the published checksum; downloading without it"
+            );
+            return Ok(None);
+        }
+    };
+    let text = response
+        .body_mut()
+        .with_config()
+        .limit(64 << 10)
+        .read_to_string()
+        .map_err(io::Error::other)?;
+    parse_checksum(&text)
+        .map(Some)
+        .ok_or_else(|| invalid(format!("{}: not a SHA-256 checksum file", checksum_url)))
+}
+
+/// The hash in a checksum file: the first word of its first line, which
+/// must be 64 hex digits.
+pub fn parse_checksum(text: &str) -> Option<String> {
+    let hash = text.split_whitespace().next()?;
+    (hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| hash.to_ascii_lowercase())
+}
+
+fn mismatch(what: &str, expected: &str, actual: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "checksum mismatch for {}: expected sha256 {}, got {}",
+            what, expected, actual
+        ),
+    )
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_local() {
+        let path = std::env::temp_dir().join(format!("bpe-test-{}.txt", std::process::id()));
+        fs::write(&path, "abc").unwrap();
+        let sha = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let ok = resolve(&path, Some(sha));
+        let bad = resolve(&path, Some(&sha.replace('b', "c")));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(ok.unwrap(), path);
+        assert!(bad.is_err());
+        assert!(is_url(Path::new("https://example.com/corpus.txt")));
+        assert!(!is_url(&path));
+    }
+
+    /// Serves one response on a local port: `head` then `body`, and closes.
+    fn serve_once(head: String, body: &'static [u8]) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(body);
+        });
+        format!("http://{}/bpe-test-{}.txt", addr, std::process::id())
+    }
+
+    #[test]
+    fn test_download() {
+        let sha = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let ok = "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\n";
+        let url = serve_once(ok.to_string(), b"abc");
+        let path = resolve(Path::new(&url), Some(sha)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"abc");
+        // a verified download is used again, with no server to fetch from
+        assert_eq!(resolve(Path::new(&url), Some(sha)).unwrap(), path);
+        fs::remove_file(&path).unwrap();
+
+        // cut off mid-stream, with nothing left behind
+        let short = "HTTP/1.1 200 OK\r\nContent-Length: 100\r\nConnection: close\r\n\r\n";
+        let url = serve_once(short.to_string(), b"abc");
+        assert!(resolve(Path::new(&url), None).is_err());
+        let dir = std::env::temp_dir().join("bpe");
+        let prefix = &hex(&Sha256::digest(&url))[..16];
+        let left: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(prefix))
+            .collect();
+        assert!(left.is_empty());
+    }
+
+    #[test]
+    fn test_parse_checksum() {
+        let sha = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD";
+        let parsed = parse_checksum(&format!("{}  model.bpe\n", sha));
+        assert_eq!(parsed, Some(sha.to_ascii_lowercase()));
+        assert_eq!(parse_checksum("<html>not found</html>"), None);
+        assert_eq!(parse_checksum(""), None);
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::model::Model;
+use crate::pretokenize::GPT2_PATTERN;
+
+// GPT-2 vocab.json / merges.txt files
+//
+// These files store tokens as strings, so GPT-2 maps every byte to a
+// printable character: printable Latin-1 bytes stand for themselves and the
+// rest (space, newline, control bytes...) are shifted to U+0100 and up,
+// which is why a leading space shows up as `Ġ` and a newline as `Ċ`.
+//
+// merges.txt lists one `left right` pair per line in rank order, after an
+// optional `#version` line; vocab.json maps each token string to its id.
+// Imported ids are renumbered to this crate's layout (bytes, then merges in
+// rank order, then special tokens), so they may differ from vocab.json.
+
+fn byte_chars() -> &'static [char; 256] {
+    static CHARS: OnceLock<[char; 256]> = OnceLock::new();
+    CHARS.get_or_init(|| {
+        let mut chars = ['\0'; 256];
+        let mut shifted = 0;
+        for b in 0..=255u8 {
+            let printable = matches!(b, b'!'..=b'~' | 0xa1..=0xac | 0xae..=0xff);
+            chars[b as usize] = if printable {
+                b as char
+            } else {
+                shifted += 1;
+                char::from_u32(255 + shifted).unwrap()
+            };
+        }
+        chars
+    })
+}
+
+fn char_bytes() -> &'static HashMap<char, u8> {
+    static BYTES: OnceLock<HashMap<char, u8>> = OnceLock::new();
+    BYTES.get_or_init(|| (0..=255u8).map(|b| (byte_to_char(b), b)).collect())
+}
+
+/// The printable character GPT-2 uses for a byte.
+pub fn byte_to_char(b: u8) -> char {
+    byte_chars()[b as usize]
+}
+
+/// The byte a GPT-2 character stands for, if it is one.
+pub fn char_to_byte(c: char) -> Option<u8> {
+    char_bytes().get(&c).copied()
+}
+
+/// Maps token bytes to their GPT-2 string form.
+pub fn encode_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| byte_to_char(b)).collect()
+}
+
+/// Maps a GPT-2 token string back to bytes, or `None` if it contains a
+/// character outside the mapping.
+pub fn decode_str(s: &str) -> Option<Vec<u8>> {
+    s.chars().map(char_to_byte).collect()
+}
+
+/// Writes `vocab.json` and `merges.txt` into `dir`.
+pub fn save(dir: &Path, model: &Model) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let vocab = crate::build_vocab(&model.merges);
+    let mut tokens: Vec<(u32, String)> = vocab
+        .iter()
+        .map(|(&id, bytes)| (id, encode_bytes(bytes)))
+        .chain(model.special_tokens.iter().map(|(s, &id)| (id, s.clone())))
+        .collect();
+    tokens.sort();
+
+    let mut w = BufWriter::new(File::create(dir.join("vocab.json"))?);
+    write!(w, "{{")?;
+    for (i, (id, token)) in tokens.iter().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        write!(w, "{}{}:{}", sep, serde_json::to_string(token)?, id)?;
+    }
+    writeln!(w, "}}")?;
+    w.flush()?;
+
+    let mut merges: Vec<_> = model.merges.iter().map(|(&p, &idx)| (idx, p)).collect();
+    merges.sort();
+    let mut w = BufWriter::new(File::create(dir.join("merges.txt"))?);
+    writeln!(w, "#version: 0.2")?;
+    for (_, (p0, p1)) in merges {
+        writeln!(
+            w,
+            "{} {}",
+            encode_bytes(&vocab[&p0]),
+            encode_bytes(&vocab[&p1])
+        )?;
+    }
+    w.flush()
+}
+
+/// Reads a GPT-2 style model. Tokens in `vocab.json` that are neither single
+/// bytes nor produced by a merge become special tokens. The pattern is set to
+/// GPT-2's, since these files don't record one.
+pub fn load(vocab: &Path, merges: &Path) -> io::Result<Model> {
+    let vocab: HashMap<String, u32> = serde_json::from_reader(BufReader::new(File::open(vocab)?))?;
+    let merges = read_merges(BufReader::new(File::open(merges)?))?;
+    from_parts(vocab, merges, Some(GPT2_PATTERN.to_string()))
+}
+
+/// Builds a model from a token-string vocabulary and numbered merges of
+/// token strings in rank order, as both GPT-2 files and `tokenizer.json`
+/// hold them.
+pub fn from_parts(
+    vocab: HashMap<String, u32>,
+    merges: Vec<((String, String), usize)>,
+    pattern: Option<String>,
+) -> io::Result<Model> {
+    let mut ids: HashMap<String, u32> = (0..=255u8)
+        .map(|b| (byte_to_char(b).to_string(), b as u32))
+        .collect();
+    let mut model_merges = HashMap::new();
+    for ((left, right), n) in merges {
+        let lookup = |token: &str| {
+            ids.get(token).copied().ok_or_else(|| {
+                invalid(format!(
+                    "merge on line {} references unknown token {:?}",
+                    n, token
+                ))
+            })
+        };
+        let pair = (lookup(&left)?, lookup(&right)?);
+        let idx = 256 + model_merges.len() as u32;
+        model_merges.entry(pair).or_insert(idx);
+        ids.entry(left + &right).or_insert(idx);
+    }
+
+    let mut special: Vec<_> = vocab
+        .into_iter()
+        .filter(|(token, _)| !ids.contains_key(token))
+        .map(|(token, id)| (id, token))
+        .collect();
+    special.sort();
+    let first = 256 + model_merges.len() as u32;
+    Ok(Model {
+        merges: model_merges,
+        pattern,
+        special_tokens: special
+            .into_iter()
+            .zip(first..)
+            .map(|((_, token), id)| (token, id))
+            .collect(),
+        whitespace_marker: false,
+        metadata: None,
+    })
+}
+
+fn read_merges(reader: impl BufRead) -> io::Result<Vec<((String, String), usize)>> {
+    let mut merges = vec![];
+    for (line, n) in reader.lines().zip(1..) {
+        let line = line?;
+        if line.is_empty() || line.starts_with("#version") {
+            continue;
+        }
+        let (left, right) = line
+            .split_once(' ')
+            .ok_or_else(|| invalid(format!("bad merge on line {}: {:?}", n, line)))?;
+        merges.push(((left.to_string(), right.to_string()), n));
+    }
+    Ok(merges)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_mapping() {
+        assert_eq!(encode_bytes(b" the\n"), "\u{120}the\u{10a}");
+        assert_eq!(byte_to_char(b'a'), 'a');
+        for b in 0..=255u8 {
+            assert_eq!(char_to_byte(byte_to_char(b)), Some(b));
+        }
+        assert_eq!(decode_str("\u{120}hi").unwrap(), b" hi");
+        assert_eq!(decode_str("\u{4e2d}"), None);
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = std::env::temp_dir().join(format!("bpe-test-gpt2-{}", std::process::id()));
+        let model = Model {
+            merges: HashMap::from([((32, 116), 256), ((256, 104), 257), ((10, 10), 258)]),
+            pattern: None,
+            special_tokens: HashMap::from([("<|endoftext|>".to_string(), 259)]),
+            whitespace_marker: false,
+            metadata: None,
+        };
+        save(&dir, &model).unwrap();
+        let merges = fs::read_to_string(dir.join("merges.txt")).unwrap();
+        assert_eq!(merges, "#version: 0.2\nĠ t\nĠt h\nĊ Ċ\n");
+
+        let loaded = load(&dir.join("vocab.json"), &dir.join("merges.txt")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded.merges, model.merges);
+        assert_eq!(loaded.special_tokens, model.special_tokens);
+        assert_eq!(loaded.pattern.as_deref(), Some(GPT2_PATTERN));
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+// token healing
+//
+// A prompt ending mid-word ("https:") is encoded with a token boundary the
+// model rarely saw in training, which skews the first generated tokens.
+// Healing backs off the prompt's last tokens and constrains generation to
+// reproduce their text, so the model is free to pick a better boundary
+// ("://" instead of ":" followed by "//").
+
+pub struct Healing {
+    /// The prompt with the backed-off tokens removed.
+    pub ids: Vec<u32>,
+    /// Text of the removed tokens that generation still has to reproduce.
+    pub prefix: Vec<u8>,
+}
+
+impl Healing {
+    /// Whether the removed text has been reproduced and generation is
+    /// unconstrained again.
+    pub fn is_done(&self) -> bool {
+        self.prefix.is_empty()
+    }
+
+    /// Accounts for a generated token's bytes, which must be allowed by
+    /// `PrefixIndex::allowed` for the current prefix.
+    pub fn advance(&mut self, token: &[u8]) {
+        let n = token.len().min(self.prefix.len());
+        self.prefix.drain(..n);
+    }
+}
+
+/// Token bytes sorted so that all tokens starting with a given prefix form
+/// one contiguous range.
+pub struct PrefixIndex {
+    tokens: Vec<(Vec<u8>, u32)>,
+}
+
+impl PrefixIndex {
+    pub fn new(vocab: &HashMap<u32, Vec<u8>>, special: impl Fn(u32) -> bool) -> PrefixIndex {
+        let mut tokens: Vec<(Vec<u8>, u32)> = vocab
+            .iter()
+            .filter(|&(&id, _)| !special(id))
+            .map(|(&id, bytes)| (bytes.clone(), id))
+            .collect();
+        tokens.sort();
+        PrefixIndex { tokens }
+    }
+
+    /// Tokens that keep generation consistent with `prefix`: those that
+    /// start with it, and those that are a shorter prefix of it.
+    pub fn allowed(&self, prefix: &[u8]) -> Vec<u32> {
+        let start = self
+            .tokens
+            .partition_point(|(bytes, _)| bytes.as_slice() < prefix);
+        let extending = self.tokens[start..]
+            .iter()
+            .take_while(|(bytes, _)| bytes.starts_with(prefix));
+        let mut ids: Vec<u32> = extending.map(|&(_, id)| id).collect();
+        for len in 1..prefix.len() {
+            let part = &prefix[..len];
+            let i = self
+                .tokens
+                .partition_point(|(bytes, _)| bytes.as_slice() < part);
+            ids.extend(
+                self.tokens[i..]
+                    .iter()
+                    .take_while(|(bytes, _)| bytes == part)
+                    .map(|&(_, id)| id),
+            );
+        }
+        ids.sort_unstable();
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed() {
+        let vocab = HashMap::from([
+            (0, b":".to_vec()),
+            (1, b"://".to_vec()),
+            (2, b"/".to_vec()),
+            (3, b"a".to_vec()),
+            (4, b":/".to_vec()),
+            (5, b"<|end|>".to_vec()),
+        ]);
+        let index = PrefixIndex::new(&vocab, |id| id == 5);
+        assert_eq!(index.allowed(b":"), vec![0, 1, 4]);
+        assert_eq!(index.allowed(b":/"), vec![0, 1, 4]);
+        assert_eq!(index.allowed(b"<"), Vec::<u32>::new());
+
+        let mut healing = Healing {
+            ids: vec![],
+            prefix: b":/".to_vec(),
+        };
+        healing.advance(b":");
+        assert_eq!(healing.prefix, b"/");
+        healing.advance(b"//");
+        assert!(healing.is_done());
+    }
+}
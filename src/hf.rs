@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use serde_json::{json, Map, Value};
+
+use crate::gpt2;
+use crate::model::Model;
+use crate::pretokenize::GPT2_PATTERN;
+
+// Hugging Face tokenizer.json files
+//
+// The `tokenizers` library keeps a whole pipeline in one JSON file. A
+// byte-level BPE model fits it as a `BPE` model whose vocab and merges are
+// GPT-2 token strings, a `Split` pre-tokenizer with the pattern followed by
+// `ByteLevel` without its own regex, and a `ByteLevel` decoder; special
+// tokens are added tokens. Reading accepts the same layout, or a
+// `ByteLevel` pre-tokenizer with its regex on, which splits as GPT-2 does.
+// Normalizers and post-processors have no counterpart here: they are left
+// empty when writing and ignored when reading. Ids are renumbered to this
+// crate's layout, as for GPT-2 files.
+
+/// Writes a model as a `tokenizer.json` file.
+pub fn save(path: &Path, model: &Model) -> io::Result<()> {
+    if model.whitespace_marker {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "tokenizer.json can't hold the whitespace marker",
+        ));
+    }
+    let vocab = crate::build_vocab(&model.merges);
+    let mut tokens: Vec<(u32, String)> = vocab
+        .iter()
+        .map(|(&id, bytes)| (id, gpt2::encode_bytes(bytes)))
+        .collect();
+    tokens.sort();
+    let tokens: Map<String, Value> = tokens
+        .into_iter()
+        .map(|(id, token)| (token, json!(id)))
+        .collect();
+    let mut ranked: Vec<_> = model.merges.iter().map(|(&p, &idx)| (idx, p)).collect();
+    ranked.sort();
+    let merges: Vec<String> = ranked
+        .into_iter()
+        .map(|(_, (p0, p1))| {
+            format!(
+                "{} {}",
+                gpt2::encode_bytes(&vocab[&p0]),
+                gpt2::encode_bytes(&vocab[&p1])
+            )
+        })
+        .collect();
+    let mut special: Vec<_> = model.special_tokens.iter().collect();
+    special.sort_by_key(|&(_, id)| id);
+    let added_tokens: Vec<Value> = special
+        .into_iter()
+        .map(|(token, id)| {
+            json!({
+                "id": id,
+                "content": token,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true,
+            })
+        })
+        .collect();
+    let byte_level = json!({
+        "type": "ByteLevel",
+        "add_prefix_space": false,
+        "trim_offsets": true,
+        "use_regex": false,
+    });
+    let pre_tokenizer = match &model.pattern {
+        Some(pattern) => json!({
+            "type": "Sequence",
+            "pretokenizers": [
+                {
+                    "type": "Split",
+                    "pattern": { "Regex": pattern },
+                    "behavior": "Isolated",
+                    "invert": false,
+                },
+                byte_level,
+            ],
+        }),
+        None => byte_level.clone(),
+    };
+    let tokenizer = json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": null,
+        "pre_tokenizer": pre_tokenizer,
+        "post_processor": null,
+        "decoder": byte_level,
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": null,
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": false,
+            "byte_fallback": false,
+            "vocab": tokens,
+            "merges": merges,
+        },
+    });
+    fs::write(path, serde_json::to_string_pretty(&tokenizer)? + "\n")
+}
+
+/// Reads a byte-level BPE `tokenizer.json` file.
+pub fn load(path: &Path) -> io::Result<Model> {
+    let tokenizer: Value = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    let model = &tokenizer["model"];
+    if model["type"] != "BPE" {
+        return Err(invalid(format!(
+            "{}: not a BPE tokenizer (model type {})",
+            path.display(),
+            model["type"]
+        )));
+    }
+    let mut vocab: HashMap<String, u32> = serde_json::from_value(model["vocab"].clone())?;
+    if let Some(token) = vocab.keys().find(|t| gpt2::decode_str(t).is_none()) {
+        let added = tokenizer["added_tokens"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(|t| t["content"] == token.as_str());
+        if !added {
+            return Err(invalid(format!(
+                "{}: not a byte-level BPE tokenizer: token {:?} isn't in GPT-2's byte mapping",
+                path.display(),
+                token
+            )));
+        }
+    }
+    let merges = model["merges"]
+        .as_array()
+        .ok_or_else(|| invalid(format!("{}: no merges", path.display())))?
+        .iter()
+        .zip(1..)
+        .map(|(merge, n)| {
+            let pair = match merge {
+                Value::String(s) => s
+                    .split_once(' ')
+                    .map(|(l, r)| (l.to_string(), r.to_string())),
+                Value::Array(parts) => match &parts[..] {
+                    [Value::String(l), Value::String(r)] => Some((l.clone(), r.clone())),
+                    _ => None,
+                },
+                _ => None,
+            };
+            pair.map(|pair| (pair, n))
+                .ok_or_else(|| invalid(format!("bad merge {}: {}", n, merge)))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    for token in tokenizer["added_tokens"].as_array().into_iter().flatten() {
+        if let (Some(content), Some(id)) = (token["content"].as_str(), token["id"].as_u64()) {
+            vocab.insert(content.to_string(), id as u32);
+        }
+    }
+    gpt2::from_parts(vocab, merges, pattern(&tokenizer["pre_tokenizer"]))
+}
+
+/// The split pattern of a pre-tokenizer: that of its `Split` step, or
+/// GPT-2's for a `ByteLevel` step using its own regex.
+fn pattern(pre_tokenizer: &Value) -> Option<String> {
+    match pre_tokenizer["type"].as_str()? {
+        "Sequence" => pre_tokenizer["pretokenizers"]
+            .as_array()?
+            .iter()
+            .find_map(pattern),
+        "Split" => Some(pre_tokenizer["pattern"]["Regex"].as_str()?.to_string()),
+        "ByteLevel" if pre_tokenizer["use_regex"] != false => Some(GPT2_PATTERN.to_string()),
+        _ => None,
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let path =
+            std::env::temp_dir().join(format!("bpe-test-{}-tokenizer.json", std::process::id()));
+        let model = Model {
+            merges: HashMap::from([((32, 116), 256), ((256, 104), 257), ((10, 10), 258)]),
+            pattern: Some(GPT2_PATTERN.to_string()),
+            special_tokens: HashMap::from([("<|endoftext|>".to_string(), 259)]),
+            whitespace_marker: false,
+            metadata: None,
+        };
+        save(&path, &model).unwrap();
+        let json: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["model"]["merges"][1], "Ġt h");
+        assert_eq!(json["model"]["vocab"]["Ġth"], 257);
+        assert_eq!(json["added_tokens"][0]["id"], 259);
+
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.merges, model.merges);
+        assert_eq!(loaded.special_tokens, model.special_tokens);
+        assert_eq!(loaded.pattern, model.pattern);
+    }
+
+    #[test]
+    fn test_pattern() {
+        let split = json!({"type": "Split", "pattern": {"Regex": "\\s+"}});
+        let byte_level = json!({"type": "ByteLevel", "use_regex": false});
+        let sequence = json!({"type": "Sequence", "pretokenizers": [split, byte_level]});
+        assert_eq!(pattern(&sequence).as_deref(), Some("\\s+"));
+        assert_eq!(pattern(&byte_level), None);
+        let gpt2 = json!({"type": "ByteLevel", "use_regex": true});
+        assert_eq!(pattern(&gpt2).as_deref(), Some(GPT2_PATTERN));
+        assert_eq!(pattern(&Value::Null), None);
+    }
+}
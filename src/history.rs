@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::render;
+use crate::{build_vocab, lowest_rank_pair, merge};
+
+// merge history
+//
+// What training decided at each step, kept so the way a vocabulary formed
+// can be analysed afterwards. Logs are CSV or JSON Lines, picked by the file
+// extension, with token bytes rendered in the escaped piece style. A trained
+// model alone is also enough to replay the merges that built a token.
+
+/// One merge as it was chosen during training.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MergeRecord {
+    pub rank: u32,
+    pub pair: (u32, u32),
+    /// How often the pair occurred when it was merged.
+    pub count: u32,
+    /// How often the most frequent other pair occurred then, 0 if none.
+    pub runner_up: u32,
+}
+
+#[derive(Serialize)]
+struct Row {
+    rank: u32,
+    left: u32,
+    right: u32,
+    id: u32,
+    count: u32,
+    token: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Csv,
+    Jsonl,
+}
+
+impl LogFormat {
+    pub fn from_path(path: &Path) -> io::Result<LogFormat> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => Ok(LogFormat::Csv),
+            Some("jsonl") => Ok(LogFormat::Jsonl),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: merge logs must be .csv or .jsonl", path.display()),
+            )),
+        }
+    }
+}
+
+pub fn write_log(path: &Path, records: &[MergeRecord]) -> io::Result<()> {
+    let format = LogFormat::from_path(path)?;
+    let merges: HashMap<(u32, u32), u32> = records.iter().map(|r| (r.pair, 256 + r.rank)).collect();
+    let vocab = build_vocab(&merges);
+    let mut w = BufWriter::new(File::create(path)?);
+    if format == LogFormat::Csv {
+        writeln!(w, "rank,left,right,id,count,token")?;
+    }
+    for record in records {
+        let id = 256 + record.rank;
+        let row = Row {
+            rank: record.rank,
+            left: record.pair.0,
+            right: record.pair.1,
+            id,
+            count: record.count,
+            token: render::escape(&vocab[&id]),
+        };
+        match format {
+            LogFormat::Csv => writeln!(
+                w,
+                "{},{},{},{},{},\"{}\"",
+                row.rank,
+                row.left,
+                row.right,
+                row.id,
+                row.count,
+                row.token.replace('"', "\"\"")
+            )?,
+            LogFormat::Jsonl => writeln!(w, "{}", serde_json::to_string(&row)?)?,
+        }
+    }
+    w.flush()
+}
+
+/// One merge applied while encoding a chunk.
+#[derive(Debug, PartialEq)]
+pub struct Step {
+    pub pair: (u32, u32),
+    pub id: u32,
+    /// The chunk's ids after the merge.
+    pub ids: Vec<u32>,
+}
+
+/// Encodes a chunk like `encode`, recording every merge as it is applied,
+/// lowest rank first.
+pub fn encode_steps(merges: &HashMap<(u32, u32), u32>, chunk: &str) -> Vec<Step> {
+    let mut ids: Vec<u32> = chunk.as_bytes().iter().map(|&b| b.into()).collect();
+    let mut steps = vec![];
+    while let Some((i, id)) = lowest_rank_pair(&ids, |pair| merges.get(&pair).copied()) {
+        let pair = (ids[i], ids[i + 1]);
+        ids = merge(&ids, pair, id);
+        steps.push(Step {
+            pair,
+            id,
+            ids: ids.clone(),
+        });
+    }
+    steps
+}
+
+/// The merges that built token `id`, in pre-order: the token, then the tree
+/// of its left part, then of its right part, as `(depth, id)` entries.
+/// Single bytes and tokens that aren't merges (such as special tokens) are
+/// leaves.
+pub fn merge_tree(merges: &HashMap<(u32, u32), u32>, id: u32) -> Vec<(usize, u32)> {
+    let parts: HashMap<u32, (u32, u32)> = merges.iter().map(|(&p, &idx)| (idx, p)).collect();
+    let mut tree = vec![];
+    let mut stack = vec![(0, id)];
+    while let Some((depth, id)) = stack.pop() {
+        tree.push((depth, id));
+        if let Some(&(left, right)) = parts.get(&id) {
+            stack.push((depth + 1, right));
+            stack.push((depth + 1, left));
+        }
+    }
+    tree
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_log() {
+        let records = vec![
+            MergeRecord {
+                rank: 0,
+                pair: (34, 32),
+                count: 7,
+                runner_up: 3,
+            },
+            MergeRecord {
+                rank: 1,
+                pair: (256, 10),
+                count: 3,
+                runner_up: 0,
+            },
+        ];
+        let dir = std::env::temp_dir();
+        let csv = dir.join(format!("bpe-test-{}.csv", std::process::id()));
+        write_log(&csv, &records).unwrap();
+        let text = std::fs::read_to_string(&csv).unwrap();
+        std::fs::remove_file(&csv).unwrap();
+        assert_eq!(
+            text,
+            "rank,left,right,id,count,token\n0,34,32,256,7,\"\"\" \"\n1,256,10,257,3,\"\"\" \\u000a\"\n"
+        );
+
+        let jsonl = dir.join(format!("bpe-test-{}.jsonl", std::process::id()));
+        write_log(&jsonl, &records).unwrap();
+        let text = std::fs::read_to_string(&jsonl).unwrap();
+        std::fs::remove_file(&jsonl).unwrap();
+        let first: serde_json::Value = serde_json::from_str(text.lines().next().unwrap()).unwrap();
+        assert_eq!(first["token"], "\" ");
+        assert_eq!(first["count"], 7);
+
+        assert!(write_log(&dir.join("merges.txt"), &records).is_err());
+    }
+
+    #[test]
+    fn test_encode_steps() {
+        let merges = HashMap::from([((97, 98), 256), ((256, 256), 257), ((98, 97), 258)]);
+        let steps = encode_steps(&merges, "ababa");
+        let ids: Vec<&[u32]> = steps.iter().map(|s| s.ids.as_slice()).collect();
+        assert_eq!(ids, vec![&[256, 256, 97][..], &[257, 97]]);
+        assert_eq!(steps[1].pair, (256, 256));
+        assert_eq!(steps.last().unwrap().ids, crate::encode(&merges, "ababa"));
+    }
+
+    #[test]
+    fn test_merge_tree() {
+        // "abab" = ("ab", "ab"), "ab" = ('a', 'b')
+        let merges = HashMap::from([((97, 98), 256), ((256, 256), 257)]);
+        assert_eq!(
+            merge_tree(&merges, 257),
+            vec![
+                (0, 257),
+                (1, 256),
+                (2, 97),
+                (2, 98),
+                (1, 256),
+                (2, 97),
+                (2, 98)
+            ]
+        );
+        assert_eq!(merge_tree(&merges, 97), vec![(0, 97)]);
+    }
+}
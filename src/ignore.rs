@@ -0,0 +1,174 @@
+use std::io;
+use std::path::Path;
+
+// ignore files
+//
+// The `.gitignore` and `.ignore` files met while walking a directory, read
+// the way git reads them: a line is a glob, `!` re-includes what an earlier
+// line excluded, a trailing `/` matches only directories, and a pattern
+// with a `/` before its end is anchored to the file's directory while one
+// without matches at any depth. `*` and `?` don't match `/`, `**` does, and
+// `[a-z]` classes work as in the shell. The last matching rule wins, rules
+// of deeper directories after those of their parents, and `.ignore` after
+// `.gitignore` in the same directory, as ripgrep does.
+
+pub const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+#[derive(Clone, Debug)]
+struct Rule {
+    /// Directory of the ignore file relative to the walk's root, with a
+    /// trailing `/`, or empty at the root.
+    base: String,
+    glob: Vec<char>,
+    negate: bool,
+    dir_only: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    /// Adds the rules of the ignore files in `dir`, which is `base` relative
+    /// to the root of the walk.
+    pub fn add_dir(&mut self, dir: &Path, base: &str) -> io::Result<()> {
+        for name in IGNORE_FILES {
+            match std::fs::read_to_string(dir.join(name)) {
+                Ok(text) => self.add(&text, base),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the rules of one ignore file in directory `base`.
+    pub fn add(&mut self, text: &str, base: &str) {
+        let base = match base.trim_end_matches('/') {
+            "" => String::new(),
+            base => format!("{}/", base),
+        };
+        for line in text.lines() {
+            let mut pattern = line.trim_end();
+            if pattern.is_empty() || pattern.starts_with('#') {
+                continue;
+            }
+            let negate = pattern.starts_with('!');
+            if negate {
+                pattern = &pattern[1..];
+            }
+            let dir_only = pattern.ends_with('/');
+            pattern = pattern.trim_end_matches('/');
+            if pattern.is_empty() {
+                continue;
+            }
+            let glob = match pattern.strip_prefix('/') {
+                Some(anchored) => anchored.to_string(),
+                None if pattern.contains('/') => pattern.to_string(),
+                None => format!("**/{}", pattern),
+            };
+            self.rules.push(Rule {
+                base: base.clone(),
+                glob: glob.chars().collect(),
+                negate,
+                dir_only,
+            });
+        }
+    }
+
+    /// Whether a path, relative to the root of the walk and separated by
+    /// `/`, is ignored.
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        let matched = self.rules.iter().rev().find(|rule| {
+            (is_dir || !rule.dir_only)
+                && path.strip_prefix(rule.base.as_str()).is_some_and(|rest| {
+                    let rest: Vec<char> = rest.chars().collect();
+                    glob(&rule.glob, &rest)
+                })
+        });
+        matched.is_some_and(|rule| !rule.negate)
+    }
+}
+
+fn glob(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', '/', rest @ ..] => {
+            // zero or more whole directories
+            (0..=text.len()).any(|i| (i == 0 || text[i - 1] == '/') && glob(rest, &text[i..]))
+        }
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|i| glob(rest, &text[i..])),
+        ['*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != '/')
+            .any(|i| glob(rest, &text[i..])),
+        ['?', rest @ ..] => matches!(text, [c, ..] if *c != '/') && glob(rest, &text[1..]),
+        ['[', class @ ..] => match class_end(class) {
+            Some(end) => match text {
+                [c, ..] if *c != '/' && in_class(&class[..end], *c) => {
+                    glob(&class[end + 1..], &text[1..])
+                }
+                _ => false,
+            },
+            None => text.first() == Some(&'[') && glob(class, &text[1..]),
+        },
+        ['\\', c, rest @ ..] => text.first() == Some(c) && glob(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+/// The index of the `]` closing a class, whose first character may be a
+/// literal `]`.
+fn class_end(class: &[char]) -> Option<usize> {
+    let start = match class.first() {
+        Some('!' | '^') => 1,
+        _ => 0,
+    };
+    (start + 1..class.len()).find(|&i| class[i] == ']')
+}
+
+fn in_class(class: &[char], c: char) -> bool {
+    let (negate, class) = match class {
+        ['!' | '^', rest @ ..] => (true, rest),
+        _ => (false, class),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            found |= (class[i]..=class[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    found != negate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_ignored() {
+        let mut rules = IgnoreRules::default();
+        rules.add(
+            "# build output\ntarget/\n*.log\n!keep.log\n/notes.txt\ndocs/*.md\n**/gen/**\n[a-c]?.tmp\n",
+            "",
+        );
+        rules.add("*.txt\n!/readme.txt\n", "sub");
+        let ignored = |path, is_dir| rules.is_ignored(path, is_dir);
+        assert!(ignored("target", true) && ignored("a/target", true));
+        assert!(!ignored("target", false));
+        assert!(ignored("x.log", false) && ignored("a/b/x.log", false));
+        assert!(!ignored("keep.log", false) && !ignored("a/keep.log", false));
+        assert!(ignored("notes.txt", false) && !ignored("a/notes.txt", false));
+        assert!(ignored("docs/a.md", false) && !ignored("docs/a/b.md", false));
+        assert!(ignored("gen/x.rs", false) && ignored("a/gen/b/x.rs", false));
+        assert!(ignored("b1.tmp", false) && !ignored("d1.tmp", false));
+        assert!(ignored("sub/a.txt", false) && ignored("sub/x/a.txt", false));
+        assert!(!ignored("sub/readme.txt", false) && ignored("sub/x/readme.txt", false));
+        assert!(!ignored("a.txt", false) && !ignored("src/main.rs", false));
+    }
+}
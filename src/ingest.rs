@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::context;
+use crate::corpus::{self, Compression, InputEncoding, Source, Walk};
+use crate::pretokenize::{self, Splitter, GPT2_PATTERN, GPT4_PATTERN};
+use crate::tiktoken::{CL100K_PATTERN, O200K_PATTERN, R50K_PATTERN};
+
+// training ingestion
+//
+// Reading, pre-tokenizing and counting a corpus as a pipeline: reader
+// threads send documents, or blocks of a long text, to splitter threads,
+// which count the chunks of each and pass the counts on to be merged. The
+// channels between the stages are bounded, so readers that get ahead wait
+// for the splitters instead of holding the corpus in memory, and reading,
+// splitting and counting overlap rather than running one after another.
+//
+// A plain text file is sent a block at a time, each cut after a line break
+// that is followed by something other than whitespace. None of the split
+// patterns shipped here puts a chunk across such a break, so the chunks are
+// those of the whole text. Other patterns can, and nothing short of running
+// them on the whole text tells, so with a custom pattern, or none, where the
+// text is a single chunk, a text file is sent whole.
+
+/// Bytes of a plain text file read at a time.
+const BLOCK: usize = 1 << 20;
+
+/// The patterns a text file can be cut into blocks for: those shipped here.
+const BLOCK_PATTERNS: &[&str] = &[
+    GPT2_PATTERN,
+    GPT4_PATTERN,
+    R50K_PATTERN,
+    CL100K_PATTERN,
+    O200K_PATTERN,
+];
+
+/// Distinct chunks a splitter counts before passing the counts on.
+const FLUSH: usize = 1 << 16;
+
+/// How many times each distinct chunk occurs.
+pub type ChunkCounts = HashMap<String, u32>;
+
+/// Threads and channel sizes of the pipeline.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ingest {
+    /// Threads reading files; a directory's files are shared out among
+    /// them, other inputs are read by one.
+    pub readers: usize,
+    pub splitters: usize,
+    /// Messages a channel holds before its senders wait.
+    pub capacity: usize,
+}
+
+impl Default for Ingest {
+    fn default() -> Ingest {
+        Ingest {
+            readers: 2,
+            splitters: thread::available_parallelism().map_or(1, |n| n.get()),
+            capacity: 64,
+        }
+    }
+}
+
+/// What one reader reads.
+enum Job {
+    Source(Source),
+    TreeFile(PathBuf, Walk),
+}
+
+/// Counts the chunks of every document of `source`.
+pub fn count_chunks(
+    source: Source,
+    compression: Compression,
+    encoding: InputEncoding,
+    splitter: Option<&Splitter>,
+    ingest: Ingest,
+) -> io::Result<ChunkCounts> {
+    let jobs = match source {
+        Source::Tree { path, walk } => corpus::walk(&path, walk)?
+            .into_iter()
+            .map(|file| Job::TreeFile(file, walk))
+            .collect(),
+        source => vec![Job::Source(source)],
+    };
+    let jobs = Mutex::new(jobs.into_iter());
+    let (doc_tx, doc_rx) = sync_channel::<String>(ingest.capacity);
+    let doc_rx = Mutex::new(doc_rx);
+    let (count_tx, count_rx) = sync_channel::<ChunkCounts>(ingest.capacity);
+    let blocks = splitter.is_some_and(|s| BLOCK_PATTERNS.contains(&s.pattern()));
+    thread::scope(|s| {
+        let (jobs, doc_rx) = (&jobs, &doc_rx);
+        let readers: Vec<_> = (0..ingest.readers.max(1))
+            .map(|_| {
+                let tx = doc_tx.clone();
+                s.spawn(move || -> io::Result<()> {
+                    loop {
+                        let job = jobs.lock().expect("job queue poisoned").next();
+                        let Some(job) = job else {
+                            return Ok(());
+                        };
+                        read(job, compression, encoding, blocks, &tx)?;
+                    }
+                })
+            })
+            .collect();
+        drop(doc_tx);
+        for _ in 0..ingest.splitters.max(1) {
+            let tx = count_tx.clone();
+            s.spawn(move || {
+                let mut counts = ChunkCounts::new();
+                loop {
+                    let doc = doc_rx.lock().expect("document channel poisoned").recv();
+                    let Ok(doc) = doc else {
+                        break;
+                    };
+                    count_into(&mut counts, splitter, &doc);
+                    if counts.len() >= FLUSH && tx.send(std::mem::take(&mut counts)).is_err() {
+                        return;
+                    }
+                }
+                let _ = tx.send(counts);
+            });
+        }
+        drop(count_tx);
+        let mut total = ChunkCounts::new();
+        for counts in count_rx {
+            for (chunk, n) in counts {
+                *total.entry(chunk).or_default() += n;
+            }
+        }
+        for reader in readers {
+            reader.join().expect("reader thread panicked")?;
+        }
+        Ok(total)
+    })
+}
+
+/// Counts the chunks of texts already in memory, such as the lines of a
+/// corpus not held out, on `ingest.splitters` threads.
+pub fn count_texts(texts: &[&str], splitter: Option<&Splitter>, ingest: Ingest) -> ChunkCounts {
+    let per_thread = texts.len().div_ceil(ingest.splitters.max(1)).max(1);
+    thread::scope(|s| {
+        let threads: Vec<_> = texts
+            .chunks(per_thread)
+            .map(|texts| {
+                s.spawn(move || {
+                    let mut counts = ChunkCounts::new();
+                    for text in texts {
+                        count_into(&mut counts, splitter, text);
+                    }
+                    counts
+                })
+            })
+            .collect();
+        let mut total = ChunkCounts::new();
+        for thread in threads {
+            for (chunk, n) in thread.join().expect("splitter thread panicked") {
+                *total.entry(chunk).or_default() += n;
+            }
+        }
+        total
+    })
+}
+
+fn count_into(counts: &mut ChunkCounts, splitter: Option<&Splitter>, text: &str) {
+    for chunk in pretokenize::split(splitter, text) {
+        match counts.get_mut(chunk) {
+            Some(n) => *n += 1,
+            None => {
+                counts.insert(chunk.to_string(), 1);
+            }
+        }
+    }
+}
+
+fn read(
+    job: Job,
+    compression: Compression,
+    encoding: InputEncoding,
+    blocks: bool,
+    tx: &SyncSender<String>,
+) -> io::Result<()> {
+    let send = |doc: String| {
+        tx.send(doc)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "splitter threads stopped"))
+    };
+    match job {
+        Job::TreeFile(path, walk) => {
+            match corpus::read_tree_file(&path, compression, encoding, walk)? {
+                Some(doc) => send(doc),
+                None => Ok(()),
+            }
+        }
+        Job::Source(Source::Text(path)) if blocks => {
+            let reader = corpus::decode(corpus::open(&path, compression)?, encoding)?;
+            read_blocks(reader, &path, send)
+        }
+        Job::Source(Source::Records(path)) => {
+            let mut reader = corpus::decode(corpus::open(&path, compression)?, encoding)?;
+            let mut bytes = vec![];
+            let mut offset = 0;
+            loop {
+                bytes.clear();
+                let n = reader.read_until(b'\0', &mut bytes)?;
+                if n == 0 {
+                    return Ok(());
+                }
+                let record = bytes.strip_suffix(b"\0").unwrap_or(&bytes);
+                let record = String::from_utf8(record.to_vec()).map_err(|e| {
+                    let at = e.utf8_error().valid_up_to();
+                    context::in_file(&path, context::invalid_utf8(e.as_bytes(), at, offset))
+                })?;
+                send(record)?;
+                offset += n;
+            }
+        }
+        Job::Source(source) => {
+            for doc in corpus::read_documents(&source, compression, encoding)? {
+                send(doc)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Sends text read from `reader` a block at a time, each block cut where
+/// `cut` finds a place.
+fn read_blocks(
+    mut reader: impl Read,
+    path: &Path,
+    mut send: impl FnMut(String) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut buf = vec![];
+    let mut offset = 0;
+    loop {
+        let len = buf.len();
+        buf.resize(len + BLOCK, 0);
+        let n = loop {
+            match reader.read(&mut buf[len..]) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        buf.truncate(len + n);
+        let eof = n == 0;
+        let valid = match std::str::from_utf8(&buf) {
+            Ok(_) => buf.len(),
+            // a character split by the end of the block
+            Err(e) if e.error_len().is_none() && !eof => e.valid_up_to(),
+            Err(e) => {
+                let e = context::invalid_utf8(&buf, e.valid_up_to(), offset);
+                return Err(context::in_file(path, e));
+            }
+        };
+        let text = std::str::from_utf8(&buf[..valid]).expect("checked above");
+        let end = if eof {
+            text.len()
+        } else {
+            cut(text).unwrap_or(0)
+        };
+        if end > 0 {
+            send(text[..end].to_string())?;
+        }
+        buf.drain(..end);
+        offset += end;
+        if eof {
+            return Ok(());
+        }
+    }
+}
+
+/// Where a block of `text` can end: after its last line break that is
+/// followed by something other than whitespace.
+fn cut(text: &str) -> Option<usize> {
+    text.rmatch_indices('\n').map(|(i, _)| i + 1).find(|&end| {
+        text[end..]
+            .chars()
+            .next()
+            .is_some_and(|c| !c.is_whitespace())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pretokenize::GPT4_PATTERN;
+
+    #[test]
+    fn test_cut() {
+        assert_eq!(cut("a\nb\n\nc d\n"), Some(5));
+        assert_eq!(cut("a\n b\n\n"), None);
+        assert_eq!(cut("abc"), None);
+    }
+
+    #[test]
+    fn test_count_chunks() {
+        let path = std::env::temp_dir().join(format!("bpe-test-{}-ingest.txt", std::process::id()));
+        // more than a block, with lines that a cut must not break apart
+        let text: String = (0..30_000)
+            .map(|i| format!("line {} of the text,\n\n  indented {}\n", i, i % 7))
+            .collect();
+        std::fs::write(&path, &text).unwrap();
+        let splitter = Splitter::new(GPT4_PATTERN).unwrap();
+        let ingest = Ingest {
+            readers: 2,
+            splitters: 3,
+            capacity: 2,
+        };
+        let counts = count_chunks(
+            Source::Text(path.clone()),
+            Compression::None,
+            InputEncoding::Utf8,
+            Some(&splitter),
+            ingest,
+        )
+        .unwrap();
+        let mut expected = ChunkCounts::new();
+        for chunk in splitter.split(&text) {
+            *expected.entry(chunk.to_string()).or_default() += 1;
+        }
+        assert_eq!(counts, expected);
+
+        // a custom pattern can put a chunk across the line breaks blocks
+        // are cut at
+        let splitter = Splitter::new("[^ ]+| +").unwrap();
+        let counts = count_chunks(
+            Source::Text(path.clone()),
+            Compression::None,
+            InputEncoding::Utf8,
+            Some(&splitter),
+            ingest,
+        )
+        .unwrap();
+        let mut expected = ChunkCounts::new();
+        for chunk in splitter.split(&text) {
+            *expected.entry(chunk.to_string()).or_default() += 1;
+        }
+        assert_eq!(counts, expected);
+        std::fs::remove_file(&path).unwrap();
+
+        // without a pattern, each record is one chunk
+        let path = std::env::temp_dir().join(format!("bpe-test-{}-ingest.nul", std::process::id()));
+        std::fs::write(&path, "a\nb\0c\0a\nb\0").unwrap();
+        let counts = count_chunks(
+            Source::Records(path.clone()),
+            Compression::None,
+            InputEncoding::Utf8,
+            None,
+            ingest,
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!((counts["a\nb"], counts["c"]), (2, 1));
+    }
+
+    #[test]
+    fn test_count_texts() {
+        let splitter = Splitter::new(GPT4_PATTERN).unwrap();
+        let texts = ["a b a", "b c", "", "a"];
+        let ingest = Ingest {
+            splitters: 3,
+            ..Ingest::default()
+        };
+        let counts = count_texts(&texts, Some(&splitter), ingest);
+        assert_eq!(counts.len(), 5);
+        assert_eq!((counts["a"], counts[" a"], counts[" b"]), (2, 1, 1));
+        assert_eq!((counts["b"], counts[" c"]), (1, 1));
+        assert!(count_texts(&[], None, ingest).is_empty());
+    }
+}
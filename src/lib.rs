@@ -0,0 +1,993 @@
+pub mod alphabet;
+pub mod arena;
+pub mod arrow;
+pub mod batch;
+pub mod cache;
+pub mod case;
+pub mod cleanup;
+pub mod config;
+pub mod consistency;
+pub mod context;
+pub mod corpus;
+pub mod count;
+pub mod delta;
+pub mod diff;
+pub mod encoding;
+pub mod estimate;
+pub mod export;
+pub mod fetch;
+pub mod gpt2;
+pub mod healing;
+pub mod hf;
+pub mod history;
+pub mod ignore;
+pub mod ingest;
+pub mod memory;
+pub mod metadata;
+pub mod metrics;
+pub mod mmap;
+pub mod model;
+pub mod pack;
+pub mod pretokenize;
+pub mod protected;
+pub mod prune;
+pub mod random_ids;
+pub mod render;
+pub mod rng;
+pub mod selftest;
+pub mod shard;
+pub mod store;
+pub mod streaming;
+pub mod template;
+pub mod text_splitter;
+pub mod tiktoken;
+pub mod tokenizer;
+pub mod unigram;
+pub mod validate;
+pub mod wordpiece;
+
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use tracing::{debug, debug_span, info, info_span, trace, Level};
+
+use history::MergeRecord;
+use metrics::Metrics;
+use pretokenize::Splitter;
+
+pub use encoding::Encoding;
+pub use text_splitter::TextSplitter;
+pub use tokenizer::{Tokenize, Tokenizer};
+pub use unigram::Unigram;
+pub use wordpiece::WordPiece;
+
+// training
+
+pub fn train(
+    docs: &[Vec<u32>],
+    num_merges: u32,
+    metrics: &mut Metrics,
+) -> HashMap<(u32, u32), u32> {
+    let words: Vec<(Vec<u32>, u32)> = docs.iter().map(|ids| (ids.clone(), 1)).collect();
+    train_words(words, num_merges, metrics)
+}
+
+/// How the pair merged at each step is chosen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeScore {
+    /// The most frequent pair (classic BPE)
+    #[default]
+    Frequency,
+    /// Pointwise mutual information, log(p(ab) / (p(a) p(b))): pairs that
+    /// occur together more than their parts' frequencies predict
+    Pmi,
+    /// Pair frequency divided by the merged token's length in bytes
+    Normalized,
+}
+
+#[derive(Clone, Debug)]
+pub struct TrainOptions {
+    pub score: MergeScore,
+    /// Pairs seen fewer times are never merged; PMI needs this to avoid
+    /// merging rare pairs first.
+    pub min_pair_count: u32,
+    /// Chunks are split into this many shards whose pairs are counted and
+    /// merged on separate threads. The shards' counts are summed in order,
+    /// so the merges learned don't depend on it.
+    pub threads: usize,
+    pub early_stopping: Option<EarlyStopping>,
+    /// Byte sequences no merged token may contain, such as `". "` so that
+    /// no token spans the end of a sentence, or `"\0"`. Pairs that would
+    /// make one are never merged.
+    pub forbidden: Vec<Vec<u8>>,
+}
+
+impl Default for TrainOptions {
+    fn default() -> TrainOptions {
+        TrainOptions {
+            score: MergeScore::Frequency,
+            min_pair_count: 1,
+            threads: 1,
+            early_stopping: None,
+            forbidden: vec![],
+        }
+    }
+}
+
+/// Stops training once merges barely improve compression of held-out text.
+#[derive(Clone, Debug)]
+pub struct EarlyStopping {
+    /// Held-out chunks as byte ids, with how often each occurs.
+    pub holdout: Vec<(Vec<u32>, u32)>,
+    /// Training stops when the held-out bytes per token improved by less
+    /// than this per merge, on average over the last `GAIN_WINDOW` merges.
+    pub min_gain: f64,
+}
+
+/// Merges between held-out compression checks.
+pub const GAIN_WINDOW: u32 = 64;
+
+impl EarlyStopping {
+    fn bytes_per_token(&self, bytes: usize) -> f64 {
+        let tokens: usize = self
+            .holdout
+            .iter()
+            .map(|(ids, n)| ids.len() * *n as usize)
+            .sum();
+        bytes as f64 / tokens.max(1) as f64
+    }
+}
+
+/// Pair and token counts over some of the training words.
+#[derive(Default)]
+struct PairCounts {
+    pairs: HashMap<(u32, u32), u32>,
+    /// Only filled in when the score needs token frequencies.
+    ids: HashMap<u32, u64>,
+    scanned: usize,
+}
+
+impl PairCounts {
+    fn new(words: &[(Vec<u32>, u32)], count_ids: bool) -> PairCounts {
+        let mut counts = PairCounts::default();
+        for (ids, n) in words {
+            counts.scanned += ids.len();
+            for (pair, count) in get_stats(ids) {
+                *counts.pairs.entry(pair).or_default() += count * n;
+            }
+            if count_ids {
+                for &id in ids {
+                    *counts.ids.entry(id).or_default() += *n as u64;
+                }
+            }
+        }
+        counts
+    }
+
+    fn add(&mut self, other: PairCounts) {
+        for (pair, count) in other.pairs {
+            *self.pairs.entry(pair).or_default() += count;
+        }
+        for (id, count) in other.ids {
+            *self.ids.entry(id).or_default() += count;
+        }
+        self.scanned += other.scanned;
+    }
+}
+
+/// Counts pairs shard by shard on scoped threads, then sums the shards in
+/// order.
+fn count_pairs(words: &[(Vec<u32>, u32)], shard_len: usize, count_ids: bool) -> PairCounts {
+    if shard_len >= words.len() {
+        return PairCounts::new(words, count_ids);
+    }
+    let shards: Vec<PairCounts> = thread::scope(|s| {
+        let handles: Vec<_> = words
+            .chunks(shard_len)
+            .map(|shard| s.spawn(move || PairCounts::new(shard, count_ids)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("counting thread panicked"))
+            .collect()
+    });
+    let mut total = PairCounts::default();
+    for shard in shards {
+        total.add(shard);
+    }
+    total
+}
+
+fn merge_words(words: &mut [(Vec<u32>, u32)], shard_len: usize, pair: (u32, u32), idx: u32) {
+    let merge_shard = |shard: &mut [(Vec<u32>, u32)]| {
+        for (ids, _) in shard {
+            *ids = merge(ids, pair, idx);
+        }
+    };
+    if shard_len >= words.len() {
+        return merge_shard(words);
+    }
+    thread::scope(|s| {
+        for shard in words.chunks_mut(shard_len) {
+            s.spawn(move || merge_shard(shard));
+        }
+    });
+}
+
+/// What a pool thread is asked to do with its shard.
+enum Job {
+    Count { count_ids: bool },
+    Merge { pair: (u32, u32), idx: u32 },
+    HeldOutTokens,
+}
+
+enum Reply {
+    Pairs(PairCounts),
+    Tokens(usize),
+}
+
+/// A thread's share of the training words and of the held-out words.
+struct Shard {
+    words: Vec<(Vec<u32>, u32)>,
+    holdout: Vec<(Vec<u32>, u32)>,
+}
+
+struct Worker<'scope> {
+    jobs: mpsc::Sender<Job>,
+    replies: mpsc::Receiver<Reply>,
+    handle: thread::ScopedJoinHandle<'scope, Shard>,
+}
+
+/// Threads that each keep a shard of the words for a whole training run and
+/// count or merge it when asked, so a merge costs a few messages per thread
+/// rather than two rounds of spawning them.
+struct Pool<'scope> {
+    workers: Vec<Worker<'scope>>,
+}
+
+impl<'scope> Pool<'scope> {
+    fn start<'env>(
+        s: &'scope thread::Scope<'scope, 'env>,
+        words: Vec<(Vec<u32>, u32)>,
+        holdout: Vec<(Vec<u32>, u32)>,
+        threads: usize,
+    ) -> Pool<'scope> {
+        let threads = threads.min(words.len()).max(1);
+        let shard_len = words.len().div_ceil(threads).max(1);
+        let holdout_len = holdout.len().div_ceil(threads).max(1);
+        let (mut words, mut holdout) = (words.into_iter(), holdout.into_iter());
+        let workers = (0..threads)
+            .map(|_| {
+                let mut shard = Shard {
+                    words: words.by_ref().take(shard_len).collect(),
+                    holdout: holdout.by_ref().take(holdout_len).collect(),
+                };
+                let (jobs, job_rx) = mpsc::channel();
+                let (reply_tx, replies) = mpsc::channel();
+                let handle = s.spawn(move || {
+                    for job in job_rx {
+                        let reply = match job {
+                            Job::Count { count_ids } => {
+                                Reply::Pairs(PairCounts::new(&shard.words, count_ids))
+                            }
+                            Job::Merge { pair, idx } => {
+                                for (ids, _) in shard.words.iter_mut().chain(&mut shard.holdout) {
+                                    *ids = merge(ids, pair, idx);
+                                }
+                                continue;
+                            }
+                            Job::HeldOutTokens => Reply::Tokens(
+                                shard
+                                    .holdout
+                                    .iter()
+                                    .map(|(ids, n)| ids.len() * *n as usize)
+                                    .sum(),
+                            ),
+                        };
+                        if reply_tx.send(reply).is_err() {
+                            break;
+                        }
+                    }
+                    shard
+                });
+                Worker {
+                    jobs,
+                    replies,
+                    handle,
+                }
+            })
+            .collect();
+        Pool { workers }
+    }
+
+    /// Asks every thread, then collects the replies in shard order.
+    fn ask(&self, job: impl Fn() -> Job) -> impl Iterator<Item = Reply> + '_ {
+        for worker in &self.workers {
+            worker.jobs.send(job()).expect("training thread panicked");
+        }
+        self.workers
+            .iter()
+            .map(|worker| worker.replies.recv().expect("training thread panicked"))
+    }
+
+    /// Counts pairs shard by shard, then sums the shards in order.
+    fn count_pairs(&self, count_ids: bool) -> PairCounts {
+        let mut total = PairCounts::default();
+        for reply in self.ask(|| Job::Count { count_ids }) {
+            if let Reply::Pairs(counts) = reply {
+                total.add(counts);
+            }
+        }
+        total
+    }
+
+    /// Merges the pair in the words and held-out words, without waiting.
+    fn merge(&self, pair: (u32, u32), idx: u32) {
+        for worker in &self.workers {
+            let job = Job::Merge { pair, idx };
+            worker.jobs.send(job).expect("training thread panicked");
+        }
+    }
+
+    fn held_out_tokens(&self) -> usize {
+        self.ask(|| Job::HeldOutTokens)
+            .map(|reply| match reply {
+                Reply::Tokens(n) => n,
+                Reply::Pairs(_) => 0,
+            })
+            .sum()
+    }
+
+    /// Stops the threads and returns the words, in their original order.
+    fn finish(self) -> Vec<(Vec<u32>, u32)> {
+        let mut words = vec![];
+        for worker in self.workers {
+            drop(worker.jobs);
+            let shard = worker.handle.join().expect("training thread panicked");
+            words.extend(shard.words);
+        }
+        words
+    }
+}
+
+/// Trains on distinct chunks paired with how often each occurs, which gives
+/// the same merges as `train` on the repeated chunks in far less memory.
+pub fn train_words(
+    words: Vec<(Vec<u32>, u32)>,
+    num_merges: u32,
+    metrics: &mut Metrics,
+) -> HashMap<(u32, u32), u32> {
+    train_words_with(words, num_merges, &TrainOptions::default(), metrics)
+}
+
+pub fn train_words_with(
+    words: Vec<(Vec<u32>, u32)>,
+    num_merges: u32,
+    options: &TrainOptions,
+    metrics: &mut Metrics,
+) -> HashMap<(u32, u32), u32> {
+    train_words_recorded(words, num_merges, options, metrics).0
+}
+
+/// Like `train_words_with`, also returning each merge with the count its
+/// pair had when it was chosen.
+pub fn train_words_recorded(
+    words: Vec<(Vec<u32>, u32)>,
+    num_merges: u32,
+    options: &TrainOptions,
+    metrics: &mut Metrics,
+) -> (HashMap<(u32, u32), u32>, Vec<MergeRecord>) {
+    let trained = train_words_segmented(words, num_merges, options, metrics);
+    (trained.merges, trained.history)
+}
+
+/// What training produced: the merges, the record of each, and the words
+/// as the merges left them.
+pub struct Trained {
+    pub merges: HashMap<(u32, u32), u32>,
+    pub history: Vec<MergeRecord>,
+    pub words: Vec<(Vec<u32>, u32)>,
+}
+
+/// Like `train_words_recorded`, also returning the trained words, e.g. for
+/// `consistency::check`.
+pub fn train_words_segmented(
+    words: Vec<(Vec<u32>, u32)>,
+    num_merges: u32,
+    options: &TrainOptions,
+    metrics: &mut Metrics,
+) -> Trained {
+    train_words_from(words, vec![], num_merges, options, metrics)
+}
+
+/// Continues training after the merges of `history`, which the words must
+/// already have been merged with, until there are `num_merges`.
+pub fn train_words_from(
+    words: Vec<(Vec<u32>, u32)>,
+    mut history: Vec<MergeRecord>,
+    num_merges: u32,
+    options: &TrainOptions,
+    metrics: &mut Metrics,
+) -> Trained {
+    let num_ids: usize = words.iter().map(|(ids, n)| ids.len() * *n as usize).sum();
+    let threads = options.threads.max(1);
+    let _span = info_span!(
+        "train",
+        words = words.len(),
+        ids = num_ids,
+        num_merges,
+        threads
+    )
+    .entered();
+    info!("training started");
+    let mut merges: HashMap<(u32, u32), u32> =
+        history.iter().map(|r| (r.pair, 256 + r.rank)).collect();
+    let early_stopping = options.early_stopping.as_ref();
+    let (holdout_bytes, mut last_ratio) = match early_stopping {
+        Some(stop) => {
+            let bytes = stop
+                .holdout
+                .iter()
+                .map(|(ids, n)| ids.len() * *n as usize)
+                .sum();
+            (bytes, stop.bytes_per_token(bytes))
+        }
+        None => (0, 0.0),
+    };
+    let holdout = early_stopping.map_or(vec![], |stop| stop.holdout.clone());
+    // token lengths in bytes, indexed by id
+    let mut lengths = vec![1; 256];
+    for record in &history {
+        lengths.push(lengths[record.pair.0 as usize] + lengths[record.pair.1 as usize]);
+    }
+    // token bytes, indexed by id, and whether each pair seen so far would
+    // make a forbidden token; only kept when something is forbidden
+    let mut tokens: Vec<Vec<u8>> = vec![];
+    let mut denied: HashMap<(u32, u32), bool> = HashMap::new();
+    if !options.forbidden.is_empty() {
+        tokens = (0..=255u8).map(|b| vec![b]).collect();
+        for record in &history {
+            let (a, b) = record.pair;
+            tokens.push([&tokens[a as usize][..], &tokens[b as usize]].concat());
+        }
+    }
+    let words = thread::scope(|s| {
+        let pool = Pool::start(s, words, holdout, threads);
+        for i in history.len() as u32..num_merges {
+            let start = Instant::now();
+            let PairCounts {
+                pairs: stats,
+                ids: counts,
+                scanned,
+            } = pool.count_pairs(options.score == MergeScore::Pmi);
+            trace!(rank = i, pairs = stats.len(), elapsed = ?start.elapsed(), "stats pass");
+            let total = scanned as f64;
+            let score = |&(pair, count): &(&(u32, u32), &u32)| -> f64 {
+                let count = *count as f64;
+                match options.score {
+                    MergeScore::Frequency => count,
+                    MergeScore::Pmi => {
+                        let (a, b) = (counts[&pair.0] as f64, counts[&pair.1] as f64);
+                        (count * total / (a * b)).ln()
+                    }
+                    MergeScore::Normalized => {
+                        count / (lengths[pair.0 as usize] + lengths[pair.1 as usize]) as f64
+                    }
+                }
+            };
+            if !tokens.is_empty() {
+                for &(a, b) in stats.keys() {
+                    denied.entry((a, b)).or_insert_with(|| {
+                        let merged = [&tokens[a as usize][..], &tokens[b as usize]].concat();
+                        options
+                            .forbidden
+                            .iter()
+                            .any(|seq| merged.windows(seq.len().max(1)).any(|w| w == &seq[..]))
+                    });
+                }
+            }
+            // equal scores go to the smallest pair, so the merges learned don't
+            // depend on the order the counts happen to be iterated in
+            let best = stats
+                .iter()
+                .filter(|&(_, &count)| count >= options.min_pair_count)
+                .filter(|&(pair, _)| !denied.get(pair).copied().unwrap_or(false))
+                .max_by(|a, b| score(a).total_cmp(&score(b)).then(b.0.cmp(a.0)));
+            if let Some((&pair, &count)) = best {
+                let idx = 256 + i;
+                debug!(rank = i, ?pair, count, idx, "merge");
+                let runner_up = stats
+                    .iter()
+                    .filter(|&(&other, _)| other != pair)
+                    .map(|(_, &count)| count)
+                    .max()
+                    .unwrap_or(0);
+                pool.merge(pair, idx);
+                merges.insert(pair, idx);
+                history.push(MergeRecord {
+                    rank: i,
+                    pair,
+                    count,
+                    runner_up,
+                });
+                lengths.push(lengths[pair.0 as usize] + lengths[pair.1 as usize]);
+                if !tokens.is_empty() {
+                    tokens.push([&tokens[pair.0 as usize][..], &tokens[pair.1 as usize]].concat());
+                }
+                metrics.record_merge(scanned);
+                if let Some(stop) = early_stopping {
+                    if (i + 1) % GAIN_WINDOW == 0 {
+                        let ratio = holdout_bytes as f64 / pool.held_out_tokens().max(1) as f64;
+                        let gain = (ratio - last_ratio) / GAIN_WINDOW as f64;
+                        debug!(merges = i + 1, ratio, gain, "held-out compression");
+                        if gain < stop.min_gain {
+                            info!(merges = i + 1, ratio, gain, "compression gain plateaued");
+                            break;
+                        }
+                        last_ratio = ratio;
+                    }
+                }
+            } else {
+                info!(merges = i, "no pairs left to merge");
+                break;
+            }
+        }
+        pool.finish()
+    });
+    info!(merges = merges.len(), "training finished");
+    Trained {
+        merges,
+        history,
+        words,
+    }
+}
+
+pub fn build_vocab(merges: &HashMap<(u32, u32), u32>) -> HashMap<u32, Vec<u8>> {
+    let mut vocab = HashMap::new();
+    for idx in 0..256_u32 {
+        vocab.insert(idx, vec![idx as u8]);
+    }
+    let mut merges: Vec<_> = merges.iter().map(|(&p, &idx)| (idx, p.0, p.1)).collect();
+    merges.sort_by_key(|&(idx, _, _)| idx);
+    for &(idx, p0, p1) in &merges {
+        let mut merged = vec![];
+        merged.extend(&vocab[&p0]);
+        merged.extend(&vocab[&p1]);
+        vocab.insert(idx, merged);
+    }
+    vocab
+}
+
+pub fn get_stats(ids: &[u32]) -> HashMap<(u32, u32), u32> {
+    let mut counts = HashMap::new();
+    for pair in ids.windows(2) {
+        *counts.entry((pair[0], pair[1])).or_default() += 1;
+    }
+    counts
+}
+
+/// Replaces each occurrence of `pair` with `idx`, scanning left to right so
+/// that of two overlapping occurrences the left one is merged.
+pub fn merge(ids: &[u32], pair: (u32, u32), idx: u32) -> Vec<u32> {
+    let mut new_ids = Vec::with_capacity(ids.len());
+    let mut i = 0;
+    while let Some(found) = find(&ids[i..], pair.0) {
+        let j = i + found;
+        new_ids.extend_from_slice(&ids[i..j]);
+        if ids.get(j + 1) == Some(&pair.1) {
+            new_ids.push(idx);
+            i = j + 2;
+        } else {
+            new_ids.push(ids[j]);
+            i = j + 1;
+        }
+    }
+    new_ids.extend_from_slice(&ids[i..]);
+    new_ids
+}
+
+/// Ids compared at once by `find`.
+const LANES: usize = 16;
+
+/// Position of the first `id` in `ids`. Most of the sequence doesn't hold
+/// the pair being merged, so this is the hottest loop of training. Each
+/// block of ids is compared into a bit mask without branching, which the
+/// compiler turns into SIMD compares, and runs without a match are copied
+/// in one go.
+fn find(ids: &[u32], id: u32) -> Option<usize> {
+    let mut blocks = ids.chunks_exact(LANES);
+    for (i, block) in blocks.by_ref().enumerate() {
+        let mask = block
+            .iter()
+            .enumerate()
+            .fold(0_u32, |mask, (lane, &x)| mask | ((x == id) as u32) << lane);
+        if mask != 0 {
+            return Some(i * LANES + mask.trailing_zeros() as usize);
+        }
+    }
+    let rest = blocks.remainder();
+    let at = rest.iter().position(|&x| x == id)?;
+    Some(ids.len() - rest.len() + at)
+}
+
+// encoding
+//
+// A chunk is encoded as minbpe encodes it: starting from its bytes, the pair
+// with the lowest rank (the earliest merge, so the smallest merged id) is
+// merged wherever it occurs, until no adjacent pair has a merge. A pair has
+// one rank, so ties are only between occurrences of the same pair, and they
+// are resolved leftmost first: with only `a a` merged, "aaa" becomes
+// "aa" "a", never "a" "aa".
+
+/// The position of the leftmost pair with the lowest rank, and the id it
+/// merges into, given the merged id of each pair that has one.
+pub fn lowest_rank_pair(
+    ids: &[u32],
+    merged: impl Fn((u32, u32)) -> Option<u32>,
+) -> Option<(usize, u32)> {
+    let mut best: Option<(usize, u32)> = None;
+    for (i, pair) in ids.windows(2).enumerate() {
+        if let Some(idx) = merged((pair[0], pair[1])) {
+            // strictly lower, so an equal rank further right never wins
+            if best.is_none_or(|(_, best)| idx < best) {
+                best = Some((i, idx));
+            }
+        }
+    }
+    best
+}
+
+pub fn encode(merges: &HashMap<(u32, u32), u32>, text: &str) -> Vec<u32> {
+    encode_bytes(merges, text.as_bytes())
+}
+
+/// Encodes bytes that need not be UTF-8, such as a chunk rebuilt from ids.
+pub fn encode_bytes(merges: &HashMap<(u32, u32), u32>, bytes: &[u8]) -> Vec<u32> {
+    let mut ids: Vec<u32> = bytes.iter().map(|&b| b.into()).collect();
+    while let Some((i, idx)) = lowest_rank_pair(&ids, |pair| merges.get(&pair).copied()) {
+        ids = merge(&ids, (ids[i], ids[i + 1]), idx);
+    }
+    ids
+}
+
+/// Encodes text by first splitting it into pre-tokenized chunks.
+pub fn encode_text(
+    merges: &HashMap<(u32, u32), u32>,
+    splitter: Option<&Splitter>,
+    text: &str,
+) -> Vec<u32> {
+    let mut ids = Vec::new();
+    encode_text_into(merges, splitter, text, &mut ids);
+    ids
+}
+
+/// Like `encode_text`, but appends the ids to an existing vector.
+pub fn encode_text_into(
+    merges: &HashMap<(u32, u32), u32>,
+    splitter: Option<&Splitter>,
+    text: &str,
+    ids: &mut Vec<u32>,
+) {
+    let _span = debug_span!("encode", bytes = text.len()).entered();
+    let start = Instant::now();
+    let timed = tracing::enabled!(Level::TRACE);
+    let first = ids.len();
+    for chunk in pretokenize::split(splitter, text) {
+        let chunk_start = timed.then(Instant::now);
+        ids.extend(encode(merges, chunk));
+        if let Some(chunk_start) = chunk_start {
+            trace!(bytes = chunk.len(), elapsed = ?chunk_start.elapsed(), "chunk encoded");
+        }
+    }
+    debug!(tokens = ids.len() - first, elapsed = ?start.elapsed(), "encoded");
+}
+
+// decoding
+
+pub fn decode(vocab: &HashMap<u32, Vec<u8>>, ids: &[u32]) -> String {
+    let tokens: Vec<_> = ids.iter().flat_map(|idx| vocab[idx].clone()).collect();
+    String::from_utf8_lossy(&tokens).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_stats() {
+        let ids = vec![1, 2, 3, 1, 2];
+        let stats = get_stats(&ids);
+        assert_eq!(stats[&(1, 2)], 2);
+        assert_eq!(stats[&(2, 3)], 1);
+        assert_eq!(stats[&(3, 1)], 1);
+    }
+
+    #[test]
+    fn test_merge() {
+        let ids = vec![1, 2, 3, 1, 2];
+        let new_ids = merge(&ids, (1, 2), 4);
+        assert_eq!(new_ids, vec![4, 3, 4])
+    }
+
+    #[test]
+    fn test_merge_long() {
+        // matches on both sides of block boundaries, overlapping pairs and
+        // a first id at the very end
+        let mut ids = vec![0; 40];
+        for i in [3, 15, 16, 31, 34, 35, 36, 39] {
+            ids[i] = 1;
+        }
+        let naive = |ids: &[u32], pair: (u32, u32)| {
+            let mut out = vec![];
+            let mut i = 0;
+            while i < ids.len() {
+                if i + 1 < ids.len() && (ids[i], ids[i + 1]) == pair {
+                    out.push(9);
+                    i += 2;
+                } else {
+                    out.push(ids[i]);
+                    i += 1;
+                }
+            }
+            out
+        };
+        for pair in [(1, 0), (0, 1), (1, 1), (2, 0)] {
+            assert_eq!(merge(&ids, pair, 9), naive(&ids, pair));
+        }
+        assert_eq!(find(&ids, 1), Some(3));
+        assert_eq!(find(&ids[32..], 1), Some(2));
+        assert_eq!(find(&ids, 2), None);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let text =
+            "The girl, unlike most people photographed for fashion magazines, was not beautiful.";
+        let tokens: Vec<u32> = text.as_bytes().iter().map(|&b| b.into()).collect();
+        let merges = train(&[tokens], 512, &mut Metrics::new(text.len(), None));
+        let vocab = build_vocab(&merges);
+        assert_eq!(decode(&vocab, &encode(&merges, text)), text);
+    }
+
+    /// minbpe's `encode`, line for line: the pair to merge is the first in
+    /// first-occurrence order among those of the lowest rank, and
+    /// `merge` replaces occurrences left to right.
+    fn minbpe_encode(merges: &HashMap<(u32, u32), u32>, text: &str) -> Vec<u32> {
+        let mut ids: Vec<u32> = text.bytes().map(u32::from).collect();
+        while ids.len() >= 2 {
+            let mut stats: Vec<(u32, u32)> = vec![];
+            for pair in ids.windows(2) {
+                if !stats.contains(&(pair[0], pair[1])) {
+                    stats.push((pair[0], pair[1]));
+                }
+            }
+            let rank = |pair: &(u32, u32)| merges.get(pair).copied().unwrap_or(u32::MAX);
+            // Python's min keeps the first of equal keys
+            let pair = stats
+                .iter()
+                .copied()
+                .reduce(|a, b| if rank(&b) < rank(&a) { b } else { a })
+                .unwrap();
+            let Some(&idx) = merges.get(&pair) else { break };
+            let mut new_ids = vec![];
+            let mut i = 0;
+            while i < ids.len() {
+                if i < ids.len() - 1 && (ids[i], ids[i + 1]) == pair {
+                    new_ids.push(idx);
+                    i += 2;
+                } else {
+                    new_ids.push(ids[i]);
+                    i += 1;
+                }
+            }
+            ids = new_ids;
+        }
+        ids
+    }
+
+    #[test]
+    fn test_encode_ties() {
+        let (a, b, c) = (97, 98, 99);
+        // occurrences of the lowest pair overlap: the leftmost is merged, so
+        // "aaa" is "aa" "a" and (a, aa) never applies
+        let merges = HashMap::from([((a, a), 256), ((a, 256), 257)]);
+        assert_eq!(encode(&merges, "aaa"), vec![256, a]);
+        assert_eq!(encode(&merges, "aaaaa"), vec![256, 256, a]);
+        assert_eq!(
+            lowest_rank_pair(&[a, a, a], |p| merges.get(&p).copied()),
+            Some((0, 256))
+        );
+        // the lowest rank wins over the leftmost position
+        let merges = HashMap::from([((b, c), 256), ((a, b), 257)]);
+        assert_eq!(encode(&merges, "abc"), vec![a, 256]);
+        assert_eq!(encode(&merges, "abcab"), vec![a, 256, 257]);
+        // every occurrence is merged in the same step, before later ranks
+        let merges = HashMap::from([((a, b), 256), ((256, 256), 257), ((b, 256), 258)]);
+        assert_eq!(encode(&merges, "ababab"), vec![257, 256]);
+        assert_eq!(encode(&merges, "bab"), vec![258]);
+    }
+
+    #[test]
+    fn test_encode_matches_minbpe() {
+        let mut rng = rng::Rng::new(3);
+        let alphabet = b"aab ";
+        let mut text = || -> String {
+            let len = rng.next_u64() % 40;
+            (0..len)
+                .map(|_| alphabet[(rng.next_u64() % alphabet.len() as u64) as usize] as char)
+                .collect()
+        };
+        let corpus: Vec<String> = (0..20).map(|_| text()).collect();
+        let docs: Vec<Vec<u32>> = corpus
+            .iter()
+            .map(|doc| doc.bytes().map(u32::from).collect())
+            .collect();
+        let merges = train(&docs, 40, &mut Metrics::new(0, None));
+        assert!(merges.len() > 10);
+        for doc in corpus.iter().cloned().chain((0..200).map(|_| text())) {
+            assert_eq!(
+                encode(&merges, &doc),
+                minbpe_encode(&merges, &doc),
+                "{:?}",
+                doc
+            );
+        }
+    }
+
+    /// Ids from minbpe's `BasicTokenizer.encode`, for merges its `train`
+    /// learned, as written by `tests/fixtures/minbpe_encode.py`.
+    #[test]
+    fn test_encode_matches_minbpe_fixture() {
+        #[derive(serde::Deserialize)]
+        struct Fixture {
+            merges: Vec<(u32, u32, u32)>,
+            cases: Vec<(String, Vec<u32>)>,
+        }
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/minbpe-encode.json");
+        let fixture: Fixture = serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+        let merges = fixture
+            .merges
+            .iter()
+            .map(|&(a, b, idx)| ((a, b), idx))
+            .collect();
+        assert_eq!(fixture.cases.len(), 112);
+        for (text, ids) in &fixture.cases {
+            assert_eq!(&encode(&merges, text), ids, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_train_ties() {
+        // every pair occurs once, so each merge is a tie
+        let docs: Vec<Vec<u32>> = ["dcba", "zyx"]
+            .iter()
+            .map(|doc| doc.bytes().map(u32::from).collect())
+            .collect();
+        let merges = train(&docs, 3, &mut Metrics::new(0, None));
+        assert_eq!(merges[&(b'b' as u32, b'a' as u32)], 256);
+        assert_eq!(merges[&(b'c' as u32, 256)], 257);
+        assert_eq!(merges[&(b'd' as u32, 257)], 258);
+        for _ in 0..8 {
+            assert_eq!(train(&docs, 3, &mut Metrics::new(0, None)), merges);
+        }
+    }
+
+    #[test]
+    fn test_sharding() {
+        let text = "the cat sat on the mat with the hat that the rat ate";
+        let words: Vec<(Vec<u32>, u32)> = text
+            .split(' ')
+            .zip(1..)
+            .map(|(word, n)| (word.bytes().map(u32::from).collect(), n))
+            .collect();
+        let whole = count_pairs(&words, words.len(), true);
+        let sharded = count_pairs(&words, 3, true);
+        assert_eq!(sharded.pairs, whole.pairs);
+        assert_eq!(sharded.ids, whole.ids);
+        assert_eq!(sharded.scanned, whole.scanned);
+
+        let mut merged = words.clone();
+        merge_words(&mut merged, 3, (97, 116), 256);
+        let expected: Vec<_> = words
+            .iter()
+            .map(|(ids, n)| (merge(ids, (97, 116), 256), *n))
+            .collect();
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn test_train_threads() {
+        let text = "the cat sat on the mat with the hat that the rat ate at the mall";
+        let words: Vec<(Vec<u32>, u32)> = text
+            .split(' ')
+            .zip(1..)
+            .map(|(word, n)| (word.bytes().map(u32::from).collect(), n))
+            .collect();
+        let train = |threads| {
+            let options = TrainOptions {
+                threads,
+                early_stopping: Some(EarlyStopping {
+                    holdout: vec![(b"that hat".iter().map(|&b| b.into()).collect(), 2)],
+                    min_gain: 0.0,
+                }),
+                ..TrainOptions::default()
+            };
+            train_words_segmented(words.clone(), 20, &options, &mut Metrics::new(0, None))
+        };
+        let one = train(1);
+        // more threads than words start one per word
+        for threads in [3, 40] {
+            let many = train(threads);
+            assert_eq!(many.merges, one.merges);
+            assert_eq!(many.history, one.history);
+            assert_eq!(many.words, one.words);
+        }
+    }
+
+    #[test]
+    fn test_early_stopping() {
+        // every two-letter word, for plenty of merges
+        let words: Vec<(Vec<u32>, u32)> = (97..123)
+            .flat_map(|a| (97..123).map(move |b| (vec![a, b], 1)))
+            .collect();
+        let train = |holdout: &str, min_gain| {
+            let options = TrainOptions {
+                early_stopping: Some(EarlyStopping {
+                    holdout: vec![(holdout.bytes().map(u32::from).collect(), 1)],
+                    min_gain,
+                }),
+                ..TrainOptions::default()
+            };
+            train_words_with(words.clone(), 200, &options, &mut Metrics::new(0, None)).len()
+        };
+        // no merge helps digits, so the first window gains nothing
+        assert_eq!(train("0123456789", 0.001), GAIN_WINDOW as usize);
+        assert_eq!(train("0123456789", -1.0), 200);
+    }
+
+    #[test]
+    fn test_forbidden() {
+        let words: Vec<(Vec<u32>, u32)> = ["a. b. c.", "x\0y\0"]
+            .iter()
+            .map(|w| (w.bytes().map(u32::from).collect(), 3))
+            .collect();
+        let options = TrainOptions {
+            forbidden: vec![b". ".to_vec(), b"\0".to_vec()],
+            ..TrainOptions::default()
+        };
+        let merges = train_words_with(words, 20, &options, &mut Metrics::new(0, None));
+        let vocab = build_vocab(&merges);
+        assert!(!merges.is_empty());
+        for token in vocab.values().filter(|t| t.len() > 1) {
+            assert!(!token.windows(2).any(|w| w == b". "), "{:?}", token);
+            assert!(!token.contains(&0), "{:?}", token);
+        }
+    }
+
+    #[test]
+    fn test_merge_scores() {
+        // "ab" is the most frequent pair, but "xy" never occurs apart
+        let words = vec![
+            (vec![97, 98], 20),
+            (vec![97, 99], 10),
+            (vec![100, 98], 10),
+            (vec![120, 121], 5),
+        ];
+        let train = |score| {
+            let options = TrainOptions {
+                score,
+                ..TrainOptions::default()
+            };
+            let merges = train_words_with(words.clone(), 1, &options, &mut Metrics::new(0, None));
+            merges.into_keys().next().unwrap()
+        };
+        assert_eq!(train(MergeScore::Frequency), (97, 98));
+        assert_eq!(train(MergeScore::Pmi), (120, 121));
+    }
+}
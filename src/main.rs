@@ -1,155 +1,2797 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io;
-use std::io::{BufReader, Read};
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+use bpe::alphabet::{self, Alphabet};
+use bpe::arrow;
+use bpe::cache::EncodeCache;
+use bpe::case::{self, Case};
+use bpe::cleanup::DecodeOptions;
+use bpe::config::TrainConfig;
+use bpe::context;
+use bpe::corpus::{self, Compression, Dedup, InputEncoding, MappedText, SampleLimit, Source, Walk};
+use bpe::count::{self, CountFormat, FileCount};
+use bpe::delta::{self, TrainState};
+use bpe::diff;
+use bpe::estimate::{self, Rates};
+use bpe::export::{self, Dtype};
+use bpe::ingest::{self, ChunkCounts, Ingest};
+use bpe::memory::{self, Representation};
+use bpe::metadata::{self, Fingerprint, Metadata};
+use bpe::metrics::Metrics;
+use bpe::mmap;
+use bpe::model::{self, Model};
+use bpe::pack;
+use bpe::pretokenize::{self, Splitter};
+use bpe::protected;
+use bpe::prune;
+use bpe::random_ids::IdSampler;
+use bpe::render::{self, PieceStyle};
+use bpe::rng::Rng;
+use bpe::selftest;
+use bpe::shard::{self, Manifest, Shard, ShardWriter};
+use bpe::streaming::{StreamOptions, StreamingTrainer};
+use bpe::tokenizer::{self, Algorithm};
+use bpe::validate;
+use bpe::{
+    build_vocab, consistency, encode_text, fetch, gpt2, hf, history, store, tiktoken,
+    train_words_from, train_words_with, unigram, wordpiece, EarlyStopping, MergeScore, Tokenize,
+    Tokenizer, TrainOptions, Trained, Unigram, WordPiece,
+};
 
 const VOCAB_SIZE: u32 = 1024;
-const NUM_MERGES: u32 = VOCAB_SIZE - 256;
-
-// training
-
-fn train(ids: &[u32], num_merges: u32) -> HashMap<(u32, u32), u32> {
-    println!("training: ids={}, num_merges={}", ids.len(), num_merges);
-    let mut merges = HashMap::new();
-    let mut ids = Vec::from(ids);
-    for i in 0..num_merges {
-        let stats = get_stats(&ids);
-        if let Some((&pair, &_count)) = stats.iter().max_by_key(|&(_, v)| v) {
-            // println!("merge:{}, pair:{:?}, count:{}", i, pair, _count);
-            let idx = 256 + i;
-            ids = merge(&ids, pair, idx);
-            merges.insert(pair, idx);
-        } else {
-            break;
+const DEFAULT_INPUT: &str = "a-man-like-him.txt";
+/// With early stopping, every n-th chunk is held out of training.
+const HOLDOUT_EVERY: usize = 10;
+/// Input `count --estimate rates` encodes to calibrate on.
+const CALIBRATION_BYTES: usize = 64 * 1024;
+
+// command line
+
+#[derive(Parser)]
+#[command(name = "bpe", about = "Byte Pair Encoding tokenizer")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Train merges on a corpus and print a few sample encodings
+    Train(Box<TrainArgs>),
+    /// Approximate BPE training in one pass over a text stream in bounded
+    /// memory, stopping once the vocabulary is full (experimental)
+    TrainStream(TrainStreamArgs),
+    /// Add documents to a training run saved with `train --save-state`,
+    /// retraining only the merges they change
+    Update(UpdateArgs),
+    /// Count the tokens of a corpus with a trained model
+    Count(CountArgs),
+    /// Encode a corpus, printing one line of ids per document or writing
+    /// all of them to a file
+    Encode(EncodeArgs),
+    /// Encode a whole corpus into `.bin` shards of a fixed number of ids,
+    /// with a manifest, for pretraining
+    Prepare(PrepareArgs),
+    /// Decode lines of ids, as `encode` prints them, back to text
+    Decode(DecodeArgs),
+    /// Print a model's vocabulary, one `id piece` line per token
+    Vocab(VocabArgs),
+    /// Drop merges rarely used on a reference corpus and renumber the rest
+    Prune(PruneArgs),
+    /// Write a memory-mapped model without the bytes of tokens encoding never
+    /// emits, keeping their merges
+    Strip(StripArgs),
+    /// Convert a model between this crate's formats, tokenizer.json,
+    /// tiktoken rank files and GPT-2 vocab.json/merges.txt
+    Convert(ConvertArgs),
+    /// Check a model's ids, merges and token bytes for structural problems
+    Validate(ValidateArgs),
+    /// Show a model's size, pattern and special tokens, and how it was
+    /// trained
+    Inspect(InspectArgs),
+    /// Run round-trip, determinism and special token checks on built-in
+    /// multilingual texts
+    Selftest(SelftestArgs),
+    /// Show the tree of merges that built a token, with their ranks
+    History(HistoryArgs),
+    /// Show each merge applied, in order, while encoding a text
+    Explain(ExplainArgs),
+    /// Compare two BPE models' token counts and segmentations on a corpus
+    Compare(CompareArgs),
+    /// Train once to the largest vocabulary size and report held-out
+    /// compression at each smaller size
+    Sweep(SweepArgs),
+    /// Compare merge-order and greedy longest-match encoding of a corpus
+    CompareStrategies(CompareStrategiesArgs),
+    /// Show how the tokens of a text change between two versions of it
+    Difftok(DifftokArgs),
+    /// Print random sequences of valid ids, as `decode` reads them, to
+    /// stress-test decoders
+    RandomIds(RandomIdsArgs),
+    /// Manage the models installed under short names for `--model`
+    #[command(subcommand)]
+    Models(ModelsCommand),
+}
+
+#[derive(Subcommand)]
+enum ModelsCommand {
+    /// List the installed models
+    List,
+    /// Install a model file, or the model at a URL, under a name
+    Add {
+        /// Name to use with `--model`
+        name: String,
+        /// Model file written by `bpe train`, of any algorithm (or an
+        /// http(s) URL)
+        source: PathBuf,
+        /// Expected SHA-256 of the model file
+        #[arg(long)]
+        sha256: Option<String>,
+    },
+    /// Remove an installed model
+    Remove { name: String },
+}
+
+#[derive(Args)]
+struct TrainArgs {
+    /// Read training settings from a TOML file; flags given here override it
+    #[arg(long)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    input: InputArgs,
+    /// Tokenization algorithm to train [default: bpe]
+    #[arg(long, value_enum)]
+    algorithm: Option<Algorithm>,
+    /// Write the trained model to this file
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+    /// Format of the BPE model written to --output [default: text]
+    #[arg(long, value_enum)]
+    model_format: Option<model::Format>,
+    /// Drop repeated lines or paragraphs before computing statistics
+    #[arg(long, value_enum)]
+    dedup: Option<Dedup>,
+    /// Pre-tokenizer regex (or `gpt2` / `gpt4`); documents are not split if omitted
+    #[arg(long)]
+    pattern: Option<String>,
+    /// Let each distinct pre-tokenized chunk contribute to pair counts at most this many times
+    #[arg(long)]
+    max_chunk_repeats: Option<u32>,
+    /// Train on a random sample of lines totalling at most this size (e.g. 100M)
+    #[arg(long, value_parser = parse_size, conflicts_with = "sample_lines")]
+    sample_bytes: Option<usize>,
+    /// Train on a random sample of this many lines
+    #[arg(long)]
+    sample_lines: Option<usize>,
+    /// Seed for random sampling [default: 0]
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Special token added to the vocabulary after the merges (repeatable)
+    #[arg(long = "special-token")]
+    special_tokens: Vec<String>,
+    /// Learn an uncased vocabulary, adding `<|cap|>` and `<|upper|>` tokens
+    /// that restore capitalization on decode
+    #[arg(long)]
+    case_markers: bool,
+    /// Replace spaces with SentencePiece's `▁` after splitting, so tokens
+    /// carry the marker instead of a space
+    #[arg(long)]
+    whitespace_marker: bool,
+    /// How BPE picks the pair to merge [default: frequency]
+    #[arg(long, value_enum)]
+    merge_score: Option<MergeScore>,
+    /// Never merge pairs seen fewer times than this [default: 1]
+    #[arg(long)]
+    min_pair_count: Option<u32>,
+    /// Never learn a token containing these bytes, written as `bpe vocab`
+    /// prints them, e.g. `. ` or `\x00` (repeatable)
+    #[arg(long, value_parser = parse_piece)]
+    forbid: Vec<Vec<u8>>,
+    /// Make this string a token of its own, built by the first merges so
+    /// that no learned merge splits it, written as for --forbid; a string
+    /// that shares bytes with an earlier one may lose them to it (repeatable)
+    #[arg(long, value_parser = parse_piece)]
+    protect: Vec<Vec<u8>>,
+    /// Alphabet merges are built from [default: bytes]
+    #[arg(long, value_enum)]
+    alphabet: Option<Alphabet>,
+    /// Reserve ids for the tokens of this file, one per line as `bpe vocab`
+    /// prints them, after any --protect ones, and learn merges around them
+    #[arg(long)]
+    seed_vocab: Option<PathBuf>,
+    /// Count pairs and apply merges on this many threads [default: the
+    /// number of CPUs]
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Read input files on this many threads while the others pre-tokenize
+    /// them [default: 2]
+    #[arg(long)]
+    reader_threads: Option<usize>,
+    /// Hold out every tenth chunk and stop once merges improve its bytes per
+    /// token by less than this, per merge, over a window of merges
+    #[arg(long)]
+    stop_when_gain_below: Option<f64>,
+    /// Bound the estimated training working set (e.g. 4G), switching to
+    /// word counts if needed and failing early if the corpus can't fit
+    #[arg(long, value_parser = parse_size)]
+    max_memory: Option<usize>,
+    /// Log training throughput and memory every this many merges
+    #[arg(long)]
+    metrics_interval: Option<u32>,
+    /// Write the final training metrics as JSON to this file
+    #[arg(long)]
+    metrics_out: Option<PathBuf>,
+    /// Log every merge (rank, pair, token and count) to a .csv or .jsonl file
+    #[arg(long)]
+    merge_log: Option<PathBuf>,
+    /// Re-encode the training chunks with the learned merges and fail if any
+    /// ends on other ids than training left it with
+    #[arg(long)]
+    check_consistency: bool,
+    /// Save the chunk counts and merge statistics to this file, so `bpe
+    /// update` can add documents later without retraining from scratch
+    #[arg(long)]
+    save_state: Option<PathBuf>,
+    /// Vocabulary size including the 256 byte tokens and excluding special
+    /// tokens, e.g. 4096 or 16K [default: 1024]
+    #[arg(long, value_parser = parse_vocab_size, conflicts_with = "num_merges")]
+    vocab_size: Option<u32>,
+    /// Number of merges to learn, the same as a vocabulary size of 256 + n
+    #[arg(long)]
+    num_merges: Option<u32>,
+}
+
+impl TrainArgs {
+    /// The vocabulary size from `--vocab-size` or `--num-merges`, which must
+    /// agree when a config file sets both.
+    fn checked_vocab_size(&self) -> io::Result<u32> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let size = match (self.vocab_size, self.num_merges) {
+            (Some(size), Some(n)) if 256 + n as u64 != size as u64 => {
+                return Err(invalid(format!(
+                    "vocab_size {} and num_merges {} disagree; vocab_size is 256 + num_merges",
+                    size, n
+                )))
+            }
+            (Some(size), _) => size,
+            (None, Some(n)) => n
+                .checked_add(256)
+                .ok_or_else(|| invalid(format!("too many merges: {}", n)))?,
+            (None, None) => VOCAB_SIZE,
+        };
+        if size < 256 {
+            return Err(invalid(format!(
+                "vocab_size must be at least 256, got {}",
+                size
+            )));
         }
+        Ok(size)
     }
-    merges
-}
 
-fn build_vocab(merges: &HashMap<(u32, u32), u32>) -> HashMap<u32, Vec<u8>> {
-    let mut vocab = HashMap::new();
-    for idx in 0..256_u32 {
-        vocab.insert(idx, vec![idx as u8]);
+    /// The settings that shape the merges, as recorded in the model, leaving
+    /// out those not given that have no default.
+    fn params(&self, vocab_size: u32) -> Vec<(String, String)> {
+        let params = [
+            ("vocab-size", Some(vocab_size.to_string())),
+            (
+                "merge-score",
+                Some(value_name(self.merge_score.unwrap_or_default())),
+            ),
+            (
+                "min-pair-count",
+                Some(self.min_pair_count.unwrap_or(1).to_string()),
+            ),
+            (
+                "alphabet",
+                Some(value_name(self.alphabet.unwrap_or_default())),
+            ),
+            (
+                "max-chunk-repeats",
+                self.max_chunk_repeats.map(|n| n.to_string()),
+            ),
+            (
+                "stop-when-gain-below",
+                self.stop_when_gain_below.map(|g| g.to_string()),
+            ),
+            ("dedup", self.dedup.map(value_name)),
+            ("sample-bytes", self.sample_bytes.map(|n| n.to_string())),
+            ("sample-lines", self.sample_lines.map(|n| n.to_string())),
+            ("seed", self.seed.map(|n| n.to_string())),
+            (
+                "forbidden",
+                (!self.forbid.is_empty()).then(|| self.forbid.len().to_string()),
+            ),
+            (
+                "protected",
+                (!self.protect.is_empty()).then(|| self.protect.len().to_string()),
+            ),
+            (
+                "case-markers",
+                self.case_markers.then(|| "true".to_string()),
+            ),
+        ];
+        params
+            .into_iter()
+            .filter_map(|(key, value)| Some((key.to_string(), value?)))
+            .collect()
     }
-    let mut merges: Vec<_> = merges.iter().map(|(&p, &idx)| (idx, p.0, p.1)).collect();
-    merges.sort_by_key(|&(idx, _, _)| idx);
-    for &(idx, p0, p1) in &merges {
-        let mut merged = vec![];
-        merged.extend(&vocab[&p0]);
-        merged.extend(&vocab[&p1]);
-        vocab.insert(idx, merged);
+
+    fn apply_config(&mut self, config: TrainConfig) -> io::Result<()> {
+        self.input.apply_config(&config)?;
+        self.algorithm = self.algorithm.or(config.algorithm);
+        self.output = self.output.take().or(config.output);
+        self.model_format = self.model_format.or(config.model_format);
+        self.dedup = self.dedup.or(config.dedup);
+        self.pattern = self.pattern.take().or(config.pattern);
+        self.max_chunk_repeats = self.max_chunk_repeats.or(config.max_chunk_repeats);
+        self.merge_score = self.merge_score.or(config.merge_score);
+        self.min_pair_count = self.min_pair_count.or(config.min_pair_count);
+        if self.forbid.is_empty() {
+            self.forbid = config
+                .forbid
+                .iter()
+                .map(|s| parse_piece(s))
+                .collect::<Result<_, _>>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        if self.protect.is_empty() {
+            self.protect = config
+                .protect
+                .iter()
+                .map(|s| parse_piece(s))
+                .collect::<Result<_, _>>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        self.alphabet = self.alphabet.or(config.alphabet);
+        self.seed_vocab = self.seed_vocab.take().or(config.seed_vocab);
+        self.threads = self.threads.or(config.threads);
+        self.reader_threads = self.reader_threads.or(config.reader_threads);
+        self.stop_when_gain_below = self.stop_when_gain_below.or(config.stop_when_gain_below);
+        if self.sample_bytes.is_none() && self.sample_lines.is_none() {
+            self.sample_bytes = config
+                .sample_bytes
+                .as_deref()
+                .map(parse_size)
+                .transpose()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            self.sample_lines = config.sample_lines;
+        }
+        self.seed = self.seed.or(config.seed);
+        if self.special_tokens.is_empty() {
+            self.special_tokens = config.special_tokens;
+        }
+        self.case_markers |= config.case_markers;
+        self.whitespace_marker |= config.whitespace_marker;
+        if self.vocab_size.is_none() && self.num_merges.is_none() {
+            self.vocab_size = config.vocab_size;
+            self.num_merges = config.num_merges;
+        }
+        Ok(())
     }
-    vocab
 }
 
-fn get_stats(ids: &[u32]) -> HashMap<(u32, u32), u32> {
-    let mut counts = HashMap::new();
-    for pair in ids.windows(2) {
-        *counts.entry((pair[0], pair[1])).or_default() += 1;
+/// The name of a value as given on the command line.
+fn value_name(value: impl ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map_or_else(String::new, |v| v.get_name().to_string())
+}
+
+/// Parses bytes written in the escaped piece style, such as `\x00`.
+fn parse_piece(s: &str) -> Result<Vec<u8>, String> {
+    match render::parse(s, PieceStyle::Escaped) {
+        Some(bytes) if !bytes.is_empty() => Ok(bytes),
+        _ => Err(format!("invalid byte sequence: {:?}", s)),
     }
-    counts
 }
 
-fn merge(ids: &[u32], pair: (u32, u32), idx: u32) -> Vec<u32> {
-    let mut new_ids = Vec::new();
-    let mut i = 0;
-    while i < ids.len() {
-        if i < ids.len() - 1 && ids[i] == pair.0 && ids[i + 1] == pair.1 {
-            new_ids.push(idx);
-            i += 2;
-        } else {
-            new_ids.push(ids[i]);
-            i += 1;
-        }
+/// Parses a fraction written as a percentage, such as `5%`, or as a number
+/// such as `0.05`.
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    let fraction = match s.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().map(|p| p / 100.0),
+        None => s.parse::<f64>(),
+    }
+    .map_err(|_| format!("invalid fraction: {}", s))?;
+    match fraction > 0.0 && fraction <= 1.0 {
+        true => Ok(fraction),
+        false => Err(format!("{} is not between 0 and 100%", s)),
     }
-    new_ids
 }
 
-// encoding
+/// Parses a vocabulary size such as `4096` or `16K`.
+fn parse_vocab_size(s: &str) -> Result<u32, String> {
+    u32::try_from(parse_size(s)?).map_err(|_| format!("vocabulary size too large: {}", s))
+}
 
-fn encode(merges: &HashMap<(u32, u32), u32>, text: &str) -> Vec<u32> {
-    let mut ids: Vec<u32> = text.as_bytes().iter().map(|&b| b.into()).collect();
-    while ids.len() >= 2 {
-        let pairs: Vec<(u32, u32)> = ids.windows(2).map(|p| (p[0], p[1])).collect();
-        if let Some(&pair) = pairs
-            .iter()
-            .filter(|&k| merges.contains_key(k))
-            .min_by_key(|&k| merges.get(k)) {
-            ids = merge(&ids, pair, merges[&pair]);
-        } else {
-            break;
+/// Parses a byte size such as `4096`, `512K`, `100M` or `2G`.
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let n: usize = digits.parse().map_err(|_| format!("invalid size: {}", s))?;
+    let scale = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(format!("invalid size unit: {}", unit)),
+    };
+    n.checked_mul(scale)
+        .ok_or_else(|| format!("size too large: {}", s))
+}
+
+#[derive(Args)]
+struct TrainStreamArgs {
+    /// Plain text input, read a line at a time (local path, http(s) URL, or
+    /// `-` for stdin)
+    #[arg(long, default_value = "-")]
+    input: PathBuf,
+    /// Decompression applied to the input [default: auto]
+    #[arg(long, value_enum)]
+    compression: Option<Compression>,
+    /// Write the trained model to this file
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+    /// Format of the model written to --output [default: text]
+    #[arg(long, value_enum)]
+    model_format: Option<model::Format>,
+    /// Pre-tokenizer regex (or `gpt2` / `gpt4`); lines are not split if omitted
+    #[arg(long)]
+    pattern: Option<String>,
+    /// Vocabulary size including the 256 byte tokens and excluding special
+    /// tokens, e.g. 4096 or 16K
+    #[arg(long, value_parser = parse_vocab_size, default_value = "1024")]
+    vocab_size: u32,
+    /// Special token added to the vocabulary after the merges (repeatable)
+    #[arg(long = "special-token")]
+    special_tokens: Vec<String>,
+    /// Bytes of text counted before each round of merges (e.g. 1M)
+    #[arg(long, value_parser = parse_size, default_value = "1M")]
+    window_bytes: usize,
+    /// Merges made from the pair counts of each window
+    #[arg(long, default_value_t = 4)]
+    merges_per_window: u32,
+    /// Distinct pairs counted before the least frequent are dropped
+    #[arg(long, value_parser = parse_size, default_value = "1M")]
+    max_pairs: usize,
+    /// Never merge pairs seen fewer times than this in a window
+    #[arg(long, default_value_t = 2)]
+    min_pair_count: u32,
+}
+
+#[derive(Args)]
+struct UpdateArgs {
+    #[command(flatten)]
+    input: InputArgs,
+    /// Training state written by `train --save-state`; it is rewritten to
+    /// cover the new documents too
+    #[arg(long)]
+    state: PathBuf,
+    /// Write the updated model to this file
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+    /// Format of the model written to --output [default: text]
+    #[arg(long, value_enum)]
+    model_format: Option<model::Format>,
+    /// Count pairs and apply merges on this many threads [default: the
+    /// number of CPUs]
+    #[arg(long)]
+    threads: Option<usize>,
+}
+
+#[derive(Args)]
+struct CountArgs {
+    #[command(flatten)]
+    input: InputArgs,
+    /// Model file written by `bpe train`, of any algorithm, an http(s) URL,
+    /// or the name of an installed model (see `bpe models`)
+    #[arg(long, short)]
+    model: PathBuf,
+    /// Expected SHA-256 of the model file
+    #[arg(long)]
+    model_sha256: Option<String>,
+    /// Preload chunk encodings from this file and save them back afterwards
+    /// (BPE models only)
+    #[arg(long)]
+    cache: Option<PathBuf>,
+    /// Maximum number of chunks kept in the cache
+    #[arg(long, default_value_t = 100_000)]
+    cache_size: usize,
+    /// Estimate the counts instead of encoding every byte, for a quick
+    /// rough figure
+    #[arg(long, value_enum, conflicts_with = "cache")]
+    estimate: Option<estimate::Method>,
+    /// With `--estimate sample`, encode one line in this many
+    #[arg(long, default_value_t = 20)]
+    sample_every: usize,
+    /// Encode a random sample of the lines, e.g. 5% or 0.05, and report the
+    /// extrapolated count with its 95% confidence interval
+    #[arg(long, value_parser = parse_fraction, conflicts_with_all = ["cache", "estimate"])]
+    sample: Option<f64>,
+    /// Seed for --sample [default: 0]
+    #[arg(long, requires = "sample")]
+    seed: Option<u64>,
+    /// How counts are printed; a directory input is counted per file, most
+    /// tokens first, with a grand total
+    #[arg(long, value_enum, default_value = "table")]
+    format: CountFormat,
+}
+
+#[derive(Args)]
+struct EncodeArgs {
+    #[command(flatten)]
+    input: InputArgs,
+    /// Encode this text instead of an input file
+    #[arg(long, conflicts_with_all = ["input", "structured"])]
+    text: Option<String>,
+    /// Model file written by `bpe train`, of any algorithm, an http(s) URL,
+    /// or the name of an installed model (see `bpe models`) [default: a
+    /// small English demo model built into bpe]
+    #[arg(long, short)]
+    model: Option<PathBuf>,
+    /// Expected SHA-256 of the model file
+    #[arg(long, requires = "model")]
+    model_sha256: Option<String>,
+    /// Write the ids of all documents, concatenated, to a NumPy `.npy` file
+    /// or a `.bin` stream with an llm.c-style header; or one row per
+    /// document to an Arrow IPC `.arrow` or `.feather` file
+    #[arg(long)]
+    out: Option<PathBuf>,
+    /// Integer type of the ids written to --out [default: u16 if the
+    /// vocabulary fits, else u32]
+    #[arg(long, value_enum, requires = "out")]
+    dtype: Option<Dtype>,
+    /// Pack the documents, each followed by --eos-id, into blocks of this
+    /// many ids (e.g. 2048), dropping what doesn't fill a last block; a
+    /// `.npy` output is then two-dimensional
+    #[arg(long, requires = "eos_id")]
+    pack: Option<usize>,
+    /// Id to follow each packed document, such as that of `<|endoftext|>`
+    #[arg(long, requires = "pack")]
+    eos_id: Option<u32>,
+    /// Encode each document as if it followed a space, like GPT-2 and
+    /// RoBERTa's `add_prefix_space` (BPE models only)
+    #[arg(long)]
+    add_prefix_space: bool,
+    /// Encode plain text inputs on this many threads [default: the number
+    /// of CPUs]
+    #[arg(long)]
+    threads: Option<usize>,
+}
+
+#[derive(Args)]
+struct PrepareArgs {
+    #[command(flatten)]
+    input: InputArgs,
+    /// Model file written by `bpe train`, of any algorithm, an http(s) URL,
+    /// or the name of an installed model (see `bpe models`)
+    #[arg(long, short)]
+    model: PathBuf,
+    /// Expected SHA-256 of the model file
+    #[arg(long)]
+    model_sha256: Option<String>,
+    /// Directory to write the shards and `manifest.json` to
+    #[arg(long)]
+    out: PathBuf,
+    /// Ids per shard (e.g. 100M); documents continue into the next shard
+    #[arg(long, value_parser = parse_size, default_value = "100M")]
+    shard_tokens: usize,
+    /// Write this many shards, their sizes differing by at most one id,
+    /// instead of shards of --shard-tokens ids (fewer if there are fewer
+    /// ids)
+    #[arg(long, conflicts_with = "shard_tokens")]
+    num_shards: Option<usize>,
+    /// Id to append after each document, such as that of `<|endoftext|>`
+    #[arg(long)]
+    separator_id: Option<u32>,
+    /// Shuffle the documents before encoding them
+    #[arg(long)]
+    shuffle: bool,
+    /// Seed for --shuffle [default: 0]
+    #[arg(long, requires = "shuffle")]
+    seed: Option<u64>,
+    /// Put this fraction of the documents (e.g. 0.01) in `val/` and the
+    /// rest in `train/`, each with its own shards and manifest
+    #[arg(long)]
+    val_ratio: Option<f64>,
+    /// Integer type of the written ids [default: u16 if the vocabulary
+    /// fits, else u32]
+    #[arg(long, value_enum)]
+    dtype: Option<Dtype>,
+    /// Encode plain text inputs on this many threads [default: the number
+    /// of CPUs]
+    #[arg(long)]
+    threads: Option<usize>,
+}
+
+#[derive(Args)]
+struct DecodeArgs {
+    /// Model file written by `bpe train`, of any algorithm, an http(s) URL,
+    /// or the name of an installed model (see `bpe models`) [default: a
+    /// small English demo model built into bpe]
+    #[arg(long, short)]
+    model: Option<PathBuf>,
+    /// File of space-separated ids, one sequence per line (`-` for stdin)
+    #[arg(long, default_value = "-")]
+    input: PathBuf,
+    /// Decode a file of sequences in bulk, such as a dump of generations:
+    /// one per line, as a JSON array or space-separated ids; each text is
+    /// written as a JSONL record `{"text": ...}` (`-` for stdin)
+    #[arg(long, conflicts_with_all = ["input", "null", "verbose"])]
+    ids_file: Option<PathBuf>,
+    /// Read sequences separated by NUL bytes rather than newlines, and end
+    /// each text with a NUL rather than a newline, so texts holding
+    /// newlines survive a pipeline
+    #[arg(long = "null", short = '0')]
+    null: bool,
+    /// Leave special tokens out of the text
+    #[arg(long)]
+    skip_special_tokens: bool,
+    /// Remove one space from the start of each text
+    #[arg(long)]
+    strip_prefix_space: bool,
+    /// Replace each run of spaces with a single space
+    #[arg(long)]
+    collapse_spaces: bool,
+    /// Before each text, print every id with its merge rank, byte length
+    /// and piece
+    #[arg(long, short)]
+    verbose: bool,
+}
+
+#[derive(Args)]
+struct VocabArgs {
+    /// Model file written by `bpe train`
+    #[arg(long, short)]
+    model: PathBuf,
+    /// How token bytes are shown
+    #[arg(long, value_enum, default_value = "escaped")]
+    style: PieceStyle,
+}
+
+#[derive(Args)]
+struct PruneArgs {
+    #[command(flatten)]
+    input: InputArgs,
+    /// BPE model file written by `bpe train`
+    #[arg(long, short)]
+    model: PathBuf,
+    /// Keep merges applied at least this many times on the corpus
+    #[arg(long, default_value_t = 1)]
+    min_count: u64,
+    /// Drop exactly this many of the least used merged tokens instead
+    #[arg(long, conflicts_with = "min_count")]
+    drop: Option<usize>,
+    /// Write the pruned model to this file
+    #[arg(long, short)]
+    output: PathBuf,
+    /// Write an `old_id new_id` line for each kept token to this file, in new
+    /// id order, for shrinking embedding matrices to match
+    #[arg(long)]
+    remap: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct StripArgs {
+    /// BPE model file written by `bpe train`
+    #[arg(long, short)]
+    model: PathBuf,
+    /// Write the memory-mapped model to this file
+    #[arg(long, short)]
+    output: PathBuf,
+}
+
+#[derive(Args)]
+struct ConvertArgs {
+    /// Model to convert: a model file, tokenizer.json, a .tiktoken file or
+    /// a directory holding vocab.json and merges.txt
+    input: PathBuf,
+    /// Where to write the converted model, in the format its name implies
+    #[arg(long)]
+    to: PathBuf,
+    /// Format to write, instead of the one the name implies
+    #[arg(long, value_enum)]
+    to_format: Option<ConvertFormat>,
+    /// Split pattern of a .tiktoken input, which doesn't record one (`gpt2`,
+    /// `gpt4` or a regex) [default: that of the published encoding of its
+    /// size]
+    #[arg(long)]
+    pattern: Option<String>,
+}
+
+#[derive(Args)]
+struct ValidateArgs {
+    /// Model to check, in any format `convert` reads
+    model: PathBuf,
+    /// Split pattern of a .tiktoken model, as for `convert`
+    #[arg(long)]
+    pattern: Option<String>,
+}
+
+#[derive(Args)]
+struct InspectArgs {
+    /// Model to show, in any format `convert` reads
+    model: PathBuf,
+    /// Split pattern of a .tiktoken model, as for `convert`
+    #[arg(long)]
+    pattern: Option<String>,
+}
+
+#[derive(Args)]
+struct SelftestArgs {
+    /// Model file of any algorithm, or the name of an installed model
+    #[arg(long, short)]
+    model: PathBuf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum ConvertFormat {
+    /// Text model file (any other name)
+    Text,
+    /// Binary model file (.bin)
+    Binary,
+    /// Memory-mapped model file (.bpem)
+    Mapped,
+    /// Hugging Face tokenizer.json (.json)
+    Hf,
+    /// tiktoken rank file, without special tokens (.tiktoken)
+    Tiktoken,
+    /// Directory of GPT-2 vocab.json and merges.txt (an existing directory
+    /// or a name ending in /)
+    Gpt2,
+}
+
+impl ConvertFormat {
+    fn of(path: &Path) -> ConvertFormat {
+        if path.is_dir() || path.to_string_lossy().ends_with('/') {
+            return ConvertFormat::Gpt2;
+        }
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => ConvertFormat::Hf,
+            Some("tiktoken") => ConvertFormat::Tiktoken,
+            Some("bpem") => ConvertFormat::Mapped,
+            Some("bin") => ConvertFormat::Binary,
+            _ => ConvertFormat::Text,
         }
     }
-    ids
 }
 
-// decoding
+#[derive(Args)]
+struct HistoryArgs {
+    /// BPE model file written by `bpe train`
+    model: PathBuf,
+    /// Id of the token to explain
+    #[arg(long)]
+    token: u32,
+    /// How token bytes are shown
+    #[arg(long, value_enum, default_value = "escaped")]
+    style: PieceStyle,
+}
 
-fn decode(vocab: &HashMap<u32, Vec<u8>>, ids: &[u32]) -> String {
-    let tokens: Vec<_> = ids.iter().flat_map(|idx| vocab[idx].clone()).collect();
-    String::from_utf8_lossy(&tokens).into()
+#[derive(Args)]
+struct ExplainArgs {
+    /// BPE model file written by `bpe train`
+    #[arg(long, short)]
+    model: PathBuf,
+    /// Text to encode
+    #[arg(long)]
+    text: String,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+#[derive(Args)]
+struct CompareArgs {
+    #[command(flatten)]
+    input: InputArgs,
+    /// First BPE model file
+    a: PathBuf,
+    /// Second BPE model file
+    b: PathBuf,
+    /// Show the segmentations of at most this many lines
+    #[arg(long, default_value_t = 5)]
+    max_examples: usize,
+}
 
-    #[test]
-    fn test_get_stats() {
-        let ids = vec![1, 2, 3, 1, 2];
-        let stats = get_stats(&ids);
-        assert_eq!(stats[&(1, 2)], 2);
-        assert_eq!(stats[&(2, 3)], 1);
-        assert_eq!(stats[&(3, 1)], 1);
+#[derive(Args)]
+struct SweepArgs {
+    #[command(flatten)]
+    input: InputArgs,
+    /// Vocabulary sizes to evaluate, e.g. 1k,2k,4k
+    #[arg(long, required = true, value_delimiter = ',', value_parser = parse_size)]
+    sizes: Vec<usize>,
+    /// Regex (or `gpt2` / `gpt4`) splitting text into chunks before training
+    #[arg(long)]
+    pattern: Option<String>,
+    /// Hold out every n-th line of the corpus for evaluation
+    #[arg(long, default_value_t = 10)]
+    holdout_every: usize,
+}
+
+#[derive(Args)]
+struct CompareStrategiesArgs {
+    #[command(flatten)]
+    input: InputArgs,
+    /// BPE model file written by `bpe train`
+    #[arg(long, short)]
+    model: PathBuf,
+    /// Print at most this many differing chunks
+    #[arg(long, default_value_t = 10)]
+    max_examples: usize,
+}
+
+#[derive(Args)]
+struct RandomIdsArgs {
+    /// Model file written by `bpe train`, of any algorithm, an http(s) URL,
+    /// or the name of an installed model (see `bpe models`)
+    #[arg(long, short)]
+    model: PathBuf,
+    /// Number of sequences to print
+    #[arg(long, default_value_t = 10)]
+    sequences: usize,
+    /// Ids per sequence
+    #[arg(long, default_value_t = 64)]
+    len: usize,
+    /// Draw ids as often as they appear when encoding this text file
+    /// (each id still gets a chance) instead of uniformly
+    #[arg(long)]
+    weights_from: Option<PathBuf>,
+    /// Leave special tokens out
+    #[arg(long)]
+    skip_special_tokens: bool,
+    /// Seed for the random ids [default: 0]
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(Args)]
+struct DifftokArgs {
+    /// The text before the edit
+    old: PathBuf,
+    /// The text after the edit
+    new: PathBuf,
+    /// Model file written by `bpe train`, of any algorithm, an http(s) URL,
+    /// or the name of an installed model (see `bpe models`)
+    #[arg(long, short)]
+    model: PathBuf,
+}
+
+#[derive(Args)]
+struct InputArgs {
+    /// Plain text input file (local path, http(s) URL, or `-` for stdin),
+    /// or a directory whose text files are each read as a document
+    #[arg(long, alias = "file", conflicts_with = "structured")]
+    input: Option<PathBuf>,
+    /// JSONL input file, one document per line (`-` for stdin)
+    #[arg(long, requires = "field", group = "structured")]
+    jsonl: Option<PathBuf>,
+    /// Parquet input file, one document per row
+    #[cfg(feature = "parquet")]
+    #[arg(long, requires = "field", group = "structured")]
+    parquet: Option<PathBuf>,
+    /// Field (or column) of each record holding the document text
+    #[arg(long)]
+    field: Option<String>,
+    /// Decompression applied to text and JSONL inputs [default: auto]
+    #[arg(long, value_enum)]
+    compression: Option<Compression>,
+    /// Character encoding of text and JSONL inputs [default: auto]
+    #[arg(long, value_enum)]
+    input_encoding: Option<InputEncoding>,
+    /// Expected SHA-256 of the input file, checked before it is read
+    #[arg(long)]
+    sha256: Option<String>,
+    /// With a directory input, also read what `.gitignore` and `.ignore`
+    /// files exclude
+    #[arg(long)]
+    no_ignore: bool,
+    /// With a directory input, also read files that look binary
+    #[arg(long)]
+    binary: bool,
+    /// Read a plain text input as documents separated by NUL bytes, as
+    /// written by `find -print0` or `bpe decode -0`; `bpe encode` then also
+    /// ends each line of ids with a NUL, and `bpe count` each output line
+    #[arg(long = "null", short = '0')]
+    null: bool,
+}
+
+impl InputArgs {
+    fn apply_config(&mut self, config: &TrainConfig) -> io::Result<()> {
+        #[cfg(feature = "parquet")]
+        let given = self.input.is_some() || self.jsonl.is_some() || self.parquet.is_some();
+        #[cfg(not(feature = "parquet"))]
+        let given = self.input.is_some() || self.jsonl.is_some();
+        if !given {
+            self.input = config.input.clone();
+            self.jsonl = config.jsonl.clone();
+            #[cfg(feature = "parquet")]
+            {
+                self.parquet = config.parquet.clone();
+            }
+            #[cfg(not(feature = "parquet"))]
+            if config.parquet.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "parquet input requires the `parquet` feature",
+                ));
+            }
+        }
+        self.field = self.field.take().or(config.field.clone());
+        self.compression = self.compression.or(config.compression);
+        self.input_encoding = self.input_encoding.or(config.input_encoding);
+        self.sha256 = self.sha256.take().or(config.sha256.clone());
+        self.no_ignore |= config.no_ignore;
+        self.binary |= config.binary;
+        Ok(())
     }
 
-    #[test]
-    fn test_merge() {
-        let ids = vec![1, 2, 3, 1, 2];
-        let new_ids = merge(&ids, (1, 2), 4);
-        assert_eq!(new_ids, vec![4, 3, 4])
+    fn read_documents(&self) -> io::Result<Vec<String>> {
+        let compression = self.compression.unwrap_or(Compression::Auto);
+        let encoding = self.input_encoding.unwrap_or_default();
+        corpus::read_documents(&self.source()?, compression, encoding)
     }
 
-    #[test]
-    fn test_encode_decode() {
-        let text = "The girl, unlike most people photographed for fashion magazines, was not beautiful.";
-        let tokens: Vec<u32> = text.as_bytes().iter().map(|&b| b.into()).collect();
-        let ids = tokens.clone();
-        let merges = train(&ids, 512);
-        let vocab = build_vocab(&merges);
-        assert_eq!(decode(&vocab, &encode(&merges, text)), text);
+    /// A plain text input, which can be streamed rather than read into
+    /// documents: mapped in place when possible, else through a reader.
+    fn text_input(&self) -> io::Result<Option<TextInput>> {
+        let Source::Text(path) = self.source()? else {
+            return Ok(None);
+        };
+        let compression = self.compression.unwrap_or(Compression::Auto);
+        let encoding = self.input_encoding.unwrap_or_default();
+        if let Some(mapped) = corpus::map_text(&path, compression, encoding)? {
+            return Ok(Some(TextInput::Mapped(mapped, path)));
+        }
+        let reader = corpus::decode(corpus::open(&path, compression)?, encoding)?;
+        Ok(Some(TextInput::Reader(reader, path)))
+    }
+
+    fn source(&self) -> io::Result<Source> {
+        let sha256 = self.sha256.as_deref();
+        #[cfg(feature = "parquet")]
+        if let (Some(path), Some(field)) = (&self.parquet, &self.field) {
+            let path = fetch::resolve(path, sha256)?;
+            return Ok(Source::Parquet {
+                path,
+                field: field.clone(),
+            });
+        }
+        Ok(match (&self.jsonl, &self.field) {
+            (Some(path), Some(field)) => Source::Jsonl {
+                path: fetch::resolve(path, sha256)?,
+                field: field.clone(),
+            },
+            (Some(_), None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "JSONL input requires a field",
+                ))
+            }
+            (None, _) => {
+                let path = self
+                    .input
+                    .clone()
+                    .unwrap_or_else(|| PathBuf::from(DEFAULT_INPUT));
+                if path.is_dir() {
+                    let walk = Walk {
+                        ignore_files: !self.no_ignore,
+                        skip_binary: !self.binary,
+                    };
+                    return Ok(Source::Tree { path, walk });
+                }
+                let path = fetch::resolve(&path, sha256)?;
+                if self.null {
+                    Source::Records(path)
+                } else {
+                    Source::Text(path)
+                }
+            }
+        })
+    }
+}
+
+enum TextInput {
+    Mapped(MappedText, PathBuf),
+    Reader(Box<dyn BufRead>, PathBuf),
+}
+
+impl TextInput {
+    /// Encodes the text, naming the file in errors about its contents.
+    fn encode(
+        self,
+        tokenizer: &dyn Tokenize,
+        sink: &mut dyn FnMut(&[u32]) -> io::Result<()>,
+    ) -> io::Result<usize> {
+        let (result, path) = match self {
+            TextInput::Mapped(mapped, path) => (tokenizer.encode_slice(mapped.bytes(), sink), path),
+            TextInput::Reader(mut reader, path) => {
+                (tokenizer.encode_reader(&mut reader, sink), path)
+            }
+        };
+        result.map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData => context::in_file(&path, e),
+            _ => e,
+        })
     }
 }
 
 fn main() -> io::Result<()> {
-    let f = File::open("a-man-like-him.txt")?;
-    let mut reader = BufReader::new(f);
-    let mut buffer = Vec::new();
-    reader.read_to_end(&mut buffer)?;
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .with_writer(io::stderr)
+        .init();
+    match Cli::parse().command {
+        Command::Train(args) => run_train(*args),
+        Command::TrainStream(args) => run_train_stream(args),
+        Command::Update(args) => run_update(args),
+        Command::Count(args) => run_count(args),
+        Command::Encode(args) => run_encode(args),
+        Command::Prepare(args) => run_prepare(args),
+        Command::Decode(args) => run_decode(args),
+        Command::Vocab(args) => run_vocab(args),
+        Command::Prune(args) => run_prune(args),
+        Command::Strip(args) => run_strip(args),
+        Command::Convert(args) => run_convert(args),
+        Command::Validate(args) => run_validate(args),
+        Command::Inspect(args) => run_inspect(args),
+        Command::Selftest(args) => run_selftest(args),
+        Command::History(args) => run_history(args),
+        Command::Explain(args) => run_explain(args),
+        Command::Compare(args) => run_compare(args),
+        Command::Sweep(args) => run_sweep(args),
+        Command::CompareStrategies(args) => run_compare_strategies(args),
+        Command::Difftok(args) => run_difftok(args),
+        Command::RandomIds(args) => run_random_ids(args),
+        Command::Models(command) => run_models(command),
+    }
+}
 
-    // train
-    let tokens: Vec<u32> = buffer.iter().map(|&b| b.into()).collect();
-    let ids = tokens.clone();
-    let merges = train(&ids, NUM_MERGES);
-    let vocab = build_vocab(&merges);
-    println!("merges:{}, vocab:{}", merges.len(), vocab.len());
-
-    // encode & decode
-    for text in vec![
+fn run_train(mut args: TrainArgs) -> io::Result<()> {
+    if let Some(path) = args.config.take() {
+        args.apply_config(TrainConfig::load(&path)?)?;
+    }
+    let vocab_size = args.checked_vocab_size()?;
+    if let Some(path) = &args.merge_log {
+        // fail before training rather than after it
+        history::LogFormat::from_path(path)?;
+    }
+    let bpe_only = [
+        (args.merge_log.is_some(), "--merge-log"),
+        (args.check_consistency, "--check-consistency"),
+        (
+            args.stop_when_gain_below.is_some(),
+            "--stop-when-gain-below",
+        ),
+        (args.case_markers, "--case-markers"),
+        (args.whitespace_marker, "--whitespace-marker"),
+        (args.save_state.is_some(), "--save-state"),
+        (!args.protect.is_empty(), "--protect"),
+        (args.seed_vocab.is_some(), "--seed-vocab"),
+        (args.alphabet.is_some(), "--alphabet"),
+    ];
+    for (given, flag) in bpe_only {
+        if given && args.algorithm.unwrap_or_default() != Algorithm::Bpe {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} only applies to BPE training", flag),
+            ));
+        }
+    }
+    if args.save_state.is_some()
+        && (args.merge_score.unwrap_or_default() != MergeScore::Frequency
+            || args.stop_when_gain_below.is_some()
+            || args.max_chunk_repeats.is_some())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--save-state needs frequency-scored training without --stop-when-gain-below or --max-chunk-repeats",
+        ));
+    }
+    match args.alphabet.unwrap_or_default() {
+        // an update would learn the characters of new documents as merges
+        Alphabet::Chars if args.save_state.is_some() => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "--save-state can't be combined with --alphabet chars",
+            ))
+        }
+        // the marker is not ASCII
+        Alphabet::Printable if args.whitespace_marker => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "--alphabet printable can't be combined with --whitespace-marker",
+            ))
+        }
+        _ => {}
+    }
+    if args.case_markers && (!args.protect.is_empty() || args.seed_vocab.is_some()) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--protect and --seed-vocab can't be combined with --case-markers, which lowercases the text trained on",
+        ));
+    }
+    if let Some(path) = &args.seed_vocab {
+        // seed tokens are reserved after the protected ones, the same way
+        let seeds = protected::read_seed_vocab(path)?;
+        info!(path = %path.display(), tokens = seeds.len(), "seed vocabulary read");
+        args.protect.extend(seeds);
+    }
+    if args.case_markers {
+        for case in [Case::Capitalized, Case::Upper] {
+            args.special_tokens.push(case.marker().to_string());
+        }
+    }
+    let splitter = args.pattern.as_deref().map(Splitter::new).transpose()?;
+    // options that need the whole corpus, or its chunks in order, read it
+    // first; otherwise reading, splitting and counting run as a pipeline
+    let pipelined = args.algorithm.unwrap_or_default() == Algorithm::Bpe
+        && args.dedup.is_none()
+        && args.sample_bytes.is_none()
+        && args.sample_lines.is_none()
+        && args.stop_when_gain_below.is_none()
+        && args.max_memory.is_none();
+    if pipelined {
+        let tokenizer = train_bpe_pipelined(&args, splitter, vocab_size)?;
+        print_samples(&tokenizer);
+        return Ok(());
+    }
+    let mut docs = args.input.read_documents()?;
+    if let Some(mode) = args.dedup {
+        let before: usize = docs.iter().map(String::len).sum();
+        docs = corpus::dedup(docs, mode);
+        let after: usize = docs.iter().map(String::len).sum();
+        info!(?mode, before, after, "deduplicated corpus");
+    }
+    let limit = match (args.sample_bytes, args.sample_lines) {
+        (Some(bytes), _) => Some(SampleLimit::Bytes(bytes)),
+        (_, Some(lines)) => Some(SampleLimit::Lines(lines)),
+        _ => None,
+    };
+    if let Some(limit) = limit {
+        docs = corpus::sample(&docs, limit, args.seed.unwrap_or(0));
+        let bytes: usize = docs.iter().map(String::len).sum();
+        info!(lines = docs.len(), bytes, "sampled corpus");
+    }
+    let mut chunks: Vec<&str> = docs
+        .iter()
+        .flat_map(|doc| pretokenize::split(splitter.as_ref(), doc))
+        .collect();
+    if let Some(max_repeats) = args.max_chunk_repeats {
+        let before = chunks.len();
+        chunks = pretokenize::cap_repeats(chunks, max_repeats);
+        info!(before, after = chunks.len(), "capped repeated chunks");
+    }
+    // train on what the tokenizer encodes: chunks lowercased into segments
+    // between case markers, with spaces marked
+    let marked: Vec<String>;
+    if args.case_markers || args.whitespace_marker {
+        marked = chunks
+            .iter()
+            .flat_map(|&chunk| {
+                if args.case_markers {
+                    case::fold(chunk).into_iter().map(|(_, s)| s).collect()
+                } else {
+                    vec![chunk.to_string()]
+                }
+            })
+            .map(|segment| {
+                if args.whitespace_marker {
+                    pretokenize::mark_spaces(&segment)
+                } else {
+                    segment
+                }
+            })
+            .collect();
+        chunks = marked.iter().map(String::as_str).collect();
+    }
+    let tokenizer: Box<dyn Tokenize> = match args.algorithm.unwrap_or_default() {
+        Algorithm::Bpe => Box::new(train_bpe(&args, &docs, chunks, splitter, vocab_size)?),
+        Algorithm::Unigram => Box::new(train_unigram(&args, &chunks, splitter, vocab_size)?),
+        Algorithm::WordPiece => Box::new(train_wordpiece(&args, &docs, vocab_size)?),
+    };
+    print_samples(&*tokenizer);
+    Ok(())
+}
+
+/// Encodes and decodes a few sample texts with a newly trained tokenizer.
+fn print_samples(tokenizer: &dyn Tokenize) {
+    for text in [
         "hello world",
         "In the dusk, a thin mist hung in the air.",
         "The black-clad girl taunted him from the magazine lying open on the floor.",
         "李翊云：我觉得这里是两个问题，雷蒙德·卡佛是一个问题，《纽约客》是另一个问题。",
     ] {
-        let ids = encode(&merges, text);
+        let ids = tokenizer.encode(text);
         let ratio = text.len() as f32 / ids.len() as f32;
-        let decoded = decode(&vocab, &ids);
+        let decoded = tokenizer.decode(&ids);
         println!("\n----------------------------------------");
         println!("text:    {}", text);
         println!("ids:     {:?}", ids);
         println!("ratio:   {:.2}", ratio);
         println!("decoded: {}", decoded);
     }
+}
+
+/// Trains BPE on chunk counts from `ingest::count_chunks`, never holding
+/// the corpus itself in memory.
+fn train_bpe_pipelined(
+    args: &TrainArgs,
+    splitter: Option<Splitter>,
+    vocab_size: u32,
+) -> io::Result<Tokenizer> {
+    let threads = args
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let ingest = Ingest {
+        readers: args.reader_threads.unwrap_or(Ingest::default().readers),
+        splitters: threads,
+        ..Ingest::default()
+    };
+    let mut counts = ingest::count_chunks(
+        args.input.source()?,
+        args.input.compression.unwrap_or(Compression::Auto),
+        args.input.input_encoding.unwrap_or_default(),
+        splitter.as_ref(),
+        ingest,
+    )?;
+    if let Some(max_repeats) = args.max_chunk_repeats {
+        for n in counts.values_mut() {
+            *n = (*n).min(max_repeats);
+        }
+    }
+    let counts = mark_chunks(counts, args.case_markers, args.whitespace_marker);
+    train_bpe_words(args, chunk_words(counts), None, splitter, vocab_size)
+}
+
+/// Chunk counts as words of byte ids to train on.
+fn chunk_words(counts: ChunkCounts) -> Vec<(Vec<u32>, u32)> {
+    let total: u64 = counts.values().map(|&n| n as u64).sum();
+    info!(distinct = counts.len(), chunks = total, "counted chunks");
+    counts
+        .into_iter()
+        .map(|(chunk, n)| (chunk.bytes().map(u32::from).collect(), n))
+        .collect()
+}
+
+/// Chunk counts as the tokenizer encodes the chunks: lowercased into
+/// segments between case markers, with spaces marked.
+fn mark_chunks(counts: ChunkCounts, case_markers: bool, whitespace_marker: bool) -> ChunkCounts {
+    if !case_markers && !whitespace_marker {
+        return counts;
+    }
+    let mut marked = HashMap::new();
+    for (chunk, n) in counts {
+        let segments: Vec<String> = if case_markers {
+            case::fold(&chunk).into_iter().map(|(_, s)| s).collect()
+        } else {
+            vec![chunk]
+        };
+        for segment in segments {
+            let segment = if whitespace_marker {
+                pretokenize::mark_spaces(&segment)
+            } else {
+                segment
+            };
+            *marked.entry(segment).or_default() += n;
+        }
+    }
+    marked
+}
+
+fn train_bpe(
+    args: &TrainArgs,
+    docs: &[String],
+    mut chunks: Vec<&str>,
+    splitter: Option<Splitter>,
+    vocab_size: u32,
+) -> io::Result<Tokenizer> {
+    let representation = match args.max_memory {
+        Some(budget) => {
+            let text_bytes = docs.iter().map(String::len).sum();
+            let estimate = memory::estimate(text_bytes, &chunks, vocab_size - 256);
+            info!(?estimate, budget, "estimated training memory");
+            memory::plan(&estimate, budget)?
+        }
+        None => Representation::Chunks,
+    };
+    let to_ids = |chunk: &str| -> Vec<u32> { chunk.as_bytes().iter().map(|&b| b.into()).collect() };
+
+    // train
+    let mut early_stopping = None;
+    if let Some(min_gain) = args.stop_when_gain_below {
+        let (mut train, mut holdout) = (vec![], vec![]);
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            if (i + 1) % HOLDOUT_EVERY == 0 {
+                holdout.push((to_ids(chunk), 1));
+            } else {
+                train.push(chunk);
+            }
+        }
+        if holdout.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too few chunks to hold any out; split the text with --pattern",
+            ));
+        }
+        chunks = train;
+        early_stopping = Some(EarlyStopping { holdout, min_gain });
+    }
+    let words = match representation {
+        Representation::Chunks => chunks.into_iter().map(|c| (to_ids(c), 1)).collect(),
+        Representation::Words => {
+            info!("training on word counts to fit the memory budget");
+            let mut counts: HashMap<&str, u32> = HashMap::new();
+            for chunk in chunks {
+                *counts.entry(chunk).or_default() += 1;
+            }
+            counts.into_iter().map(|(c, n)| (to_ids(c), n)).collect()
+        }
+    };
+    train_bpe_words(args, words, early_stopping, splitter, vocab_size)
+}
+
+/// Trains BPE on chunks as byte ids with their counts, then logs, checks
+/// and saves the result as the flags ask.
+fn train_bpe_words(
+    args: &TrainArgs,
+    mut words: Vec<(Vec<u32>, u32)>,
+    mut early_stopping: Option<EarlyStopping>,
+    splitter: Option<Splitter>,
+    vocab_size: u32,
+) -> io::Result<Tokenizer> {
+    for token in protected::split_apart(splitter.as_ref(), &args.protect) {
+        warn!(
+            token = ?String::from_utf8_lossy(token),
+            "the pattern splits this reserved token, so encoding never produces it"
+        );
+    }
+    let mut corpus = Fingerprint::default();
+    corpus.add_words(&words);
+    if let Some(early_stopping) = &early_stopping {
+        corpus.add_words(&early_stopping.holdout);
+    }
+    let mut protect: Vec<Vec<u8>> = args
+        .protect
+        .iter()
+        .map(|token| match std::str::from_utf8(token) {
+            Ok(text) if args.whitespace_marker => pretokenize::mark_spaces(text).into_bytes(),
+            _ => token.clone(),
+        })
+        .collect();
+    let alphabet = args.alphabet.unwrap_or_default();
+    if alphabet == Alphabet::Chars {
+        let chars = alphabet::chars(&words);
+        info!(chars = chars.len(), "character alphabet");
+        protect.extend(chars);
+    }
+    let reserved = protected::merges(&protect);
+    if reserved.len() > (vocab_size - 256) as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "reserved tokens and characters need {} merges, more than vocab_size {} leaves",
+                reserved.len(),
+                vocab_size
+            ),
+        ));
+    }
+    for token in protected::not_whole(&protect[..args.protect.len()], &reserved) {
+        warn!(
+            token = ?String::from_utf8_lossy(token),
+            "an earlier reserved token takes some of its bytes, so encoding never produces it"
+        );
+    }
+    protected::apply(&mut words, &reserved);
+    if let Some(early_stopping) = &mut early_stopping {
+        protected::apply(&mut early_stopping.holdout, &reserved);
+    }
+    let num_reserved = reserved.len() as u32;
+    let num_ids = words.iter().map(|(ids, n)| ids.len() * *n as usize).sum();
+    let mut metrics = Metrics::new(num_ids, args.metrics_interval);
+    let options = TrainOptions {
+        score: args.merge_score.unwrap_or_default(),
+        min_pair_count: args.min_pair_count.unwrap_or(1),
+        threads: args
+            .threads
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())),
+        early_stopping,
+        forbidden: [args.forbid.clone(), alphabet.forbidden()].concat(),
+    };
+    let Trained {
+        merges,
+        history: records,
+        words,
+    } = train_words_from(words, reserved, vocab_size - 256, &options, &mut metrics);
+    let report = metrics.report();
+    info!(
+        merges = report.merges,
+        elapsed_secs = report.elapsed_secs,
+        merges_per_sec = report.merges_per_sec,
+        tokens_per_sec = report.tokens_per_sec,
+        peak_rss_bytes = report.peak_rss_bytes,
+        "training metrics"
+    );
+    if let Some(path) = &args.metrics_out {
+        let json = serde_json::to_string_pretty(&report).map_err(io::Error::other)?;
+        std::fs::write(path, json + "\n")?;
+    }
+    if let Some(path) = &args.merge_log {
+        history::write_log(path, &records)?;
+        info!(path = %path.display(), "merge log written");
+    }
+    if let Some(path) = &args.save_state {
+        let state = TrainState {
+            words: delta::word_bytes(&words, &merges),
+            history: records,
+            reserved: num_reserved,
+            num_merges: vocab_size - 256,
+            min_pair_count: options.min_pair_count,
+            forbidden: options.forbidden.clone(),
+            pattern: splitter.as_ref().map(|s| s.pattern().to_string()),
+            case_markers: args.case_markers,
+            whitespace_marker: args.whitespace_marker,
+            special_tokens: args.special_tokens.clone(),
+        };
+        state.save(path)?;
+        info!(path = %path.display(), "training state saved");
+    }
+    if args.check_consistency {
+        let divergences = consistency::check(&merges, &words);
+        for d in divergences.iter().take(10) {
+            warn!(
+                chunk = ?String::from_utf8_lossy(&d.bytes),
+                count = d.count,
+                trained = ?d.trained,
+                encoded = ?d.encoded,
+                "training and encoding disagree"
+            );
+        }
+        if !divergences.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} distinct chunks encode differently than training left them",
+                    divergences.len()
+                ),
+            ));
+        }
+        info!(chunks = words.len(), "encoding matches training");
+    }
+    let mut special_tokens = HashMap::new();
+    for token in args.special_tokens.iter().cloned() {
+        let idx = 256 + (merges.len() + special_tokens.len()) as u32;
+        special_tokens.entry(token).or_insert(idx);
+    }
+    let vocab_len = 256 + merges.len() + special_tokens.len();
+    println!("merges:{}, vocab:{}", merges.len(), vocab_len);
+    let model = Model {
+        merges,
+        pattern: splitter.as_ref().map(|s| s.pattern().to_string()),
+        special_tokens,
+        whitespace_marker: args.whitespace_marker,
+        metadata: Some(Metadata::new(corpus.finish(), args.params(vocab_size))),
+    };
+    if let Some(path) = &args.output {
+        model::save_as(path, &model, args.model_format.unwrap_or_default())?;
+        info!(path = %path.display(), "model saved");
+    }
+    Tokenizer::new(model)
+}
+
+fn run_update(args: UpdateArgs) -> io::Result<()> {
+    let state = TrainState::load(&args.state)?;
+    let splitter = state.pattern.as_deref().map(Splitter::new).transpose()?;
+    let threads = args
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let counts = ingest::count_chunks(
+        args.input.source()?,
+        args.input.compression.unwrap_or(Compression::Auto),
+        args.input.input_encoding.unwrap_or_default(),
+        splitter.as_ref(),
+        Ingest {
+            splitters: threads,
+            ..Ingest::default()
+        },
+    )?;
+    let counts = mark_chunks(counts, state.case_markers, state.whitespace_marker);
+    info!(distinct = counts.len(), "counted new chunks");
+    let new = counts
+        .into_iter()
+        .map(|(c, n)| (c.into_bytes(), n))
+        .collect();
+    let options = TrainOptions {
+        min_pair_count: state.min_pair_count,
+        forbidden: state.forbidden.clone(),
+        threads,
+        ..TrainOptions::default()
+    };
+    let mut metrics = Metrics::new(0, None);
+    let update = delta::update(state, new, &options, &mut metrics)?;
+    let merges = update.trained.merges;
+    info!(
+        kept = update.kept,
+        retrained = merges.len() as u32 - update.kept,
+        "merges updated"
+    );
+    update.state.save(&args.state)?;
+    let mut corpus = Fingerprint::default();
+    for (bytes, n) in &update.state.words {
+        corpus.add(bytes, *n as u64);
+    }
+    let params = vec![
+        (
+            "vocab-size".to_string(),
+            (256 + update.state.num_merges).to_string(),
+        ),
+        (
+            "min-pair-count".to_string(),
+            update.state.min_pair_count.to_string(),
+        ),
+    ];
+    let mut special_tokens = HashMap::new();
+    for token in update.state.special_tokens.iter().cloned() {
+        let idx = 256 + (merges.len() + special_tokens.len()) as u32;
+        special_tokens.entry(token).or_insert(idx);
+    }
+    println!(
+        "merges:{}, vocab:{}",
+        merges.len(),
+        256 + merges.len() + special_tokens.len()
+    );
+    let model = Model {
+        merges,
+        pattern: update.state.pattern.clone(),
+        special_tokens,
+        whitespace_marker: update.state.whitespace_marker,
+        metadata: Some(Metadata::new(corpus.finish(), params)),
+    };
+    if let Some(path) = &args.output {
+        model::save_as(path, &model, args.model_format.unwrap_or_default())?;
+        info!(path = %path.display(), "model saved");
+    }
+    Ok(())
+}
 
+fn run_train_stream(args: TrainStreamArgs) -> io::Result<()> {
+    if args.vocab_size < 256 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("vocab_size must be at least 256, got {}", args.vocab_size),
+        ));
+    }
+    let splitter = args.pattern.as_deref().map(Splitter::new).transpose()?;
+    let options = StreamOptions {
+        window_bytes: args.window_bytes,
+        merges_per_window: args.merges_per_window,
+        max_pairs: args.max_pairs,
+        min_pair_count: args.min_pair_count,
+    };
+    let mut trainer = StreamingTrainer::new(args.vocab_size - 256, options);
+    let path = fetch::resolve(&args.input, None)?;
+    let mut reader = corpus::open(&path, args.compression.unwrap_or(Compression::Auto))?;
+    let mut line = String::new();
+    let mut bytes = 0;
+    let mut corpus = Fingerprint::default();
+    while !trainer.is_done() {
+        line.clear();
+        let n = reader
+            .read_line(&mut line)
+            .map_err(|e| context::in_file(&path, e))?;
+        if n == 0 {
+            break;
+        }
+        bytes += n;
+        for chunk in pretokenize::split(splitter.as_ref(), &line) {
+            corpus.add(chunk.as_bytes(), 1);
+            trainer.add_chunk(chunk);
+        }
+    }
+    let (windows, prunes) = (trainer.windows(), trainer.prunes());
+    let params = [
+        ("vocab-size", args.vocab_size.to_string()),
+        ("min-pair-count", args.min_pair_count.to_string()),
+        ("window-bytes", args.window_bytes.to_string()),
+        ("merges-per-window", args.merges_per_window.to_string()),
+        ("max-pairs", args.max_pairs.to_string()),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value))
+    .collect();
+    let merges = trainer.finish();
+    info!(
+        bytes,
+        windows,
+        prunes,
+        merges = merges.len(),
+        "stream trained"
+    );
+    if merges.len() < (args.vocab_size - 256) as usize {
+        warn!(
+            merges = merges.len(),
+            wanted = args.vocab_size - 256,
+            "the stream ended before the vocabulary was full"
+        );
+    }
+    let mut special_tokens = HashMap::new();
+    for token in args.special_tokens {
+        let idx = 256 + (merges.len() + special_tokens.len()) as u32;
+        special_tokens.entry(token).or_insert(idx);
+    }
+    println!(
+        "merges:{}, vocab:{}",
+        merges.len(),
+        256 + merges.len() + special_tokens.len()
+    );
+    let model = Model {
+        merges,
+        pattern: splitter.as_ref().map(|s| s.pattern().to_string()),
+        special_tokens,
+        whitespace_marker: false,
+        metadata: Some(Metadata::new(corpus.finish(), params)),
+    };
+    if let Some(path) = &args.output {
+        model::save_as(path, &model, args.model_format.unwrap_or_default())?;
+        info!(path = %path.display(), "model saved");
+    }
+    print_samples(&Tokenizer::new(model)?);
     Ok(())
 }
+
+fn train_unigram(
+    args: &TrainArgs,
+    chunks: &[&str],
+    splitter: Option<Splitter>,
+    vocab_size: u32,
+) -> io::Result<Unigram> {
+    if !args.special_tokens.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "special tokens are not supported by unigram models",
+        ));
+    }
+    let model = unigram::train(chunks, vocab_size, splitter);
+    println!("vocab:{}", model.vocab_size());
+    if let Some(path) = &args.output {
+        model.save(path)?;
+        info!(path = %path.display(), "model saved");
+    }
+    Ok(model)
+}
+
+fn train_wordpiece(args: &TrainArgs, docs: &[String], vocab_size: u32) -> io::Result<WordPiece> {
+    if args.pattern.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "wordpiece splits words on whitespace and punctuation and takes no pattern",
+        ));
+    }
+    let mut model = wordpiece::train(docs, vocab_size);
+    model.add_special_tokens(&args.special_tokens);
+    println!("vocab:{}", model.vocab_size());
+    if let Some(path) = &args.output {
+        model.save(path)?;
+        info!(path = %path.display(), "model saved");
+    }
+    Ok(model)
+}
+
+/// How `count` gets its token counts.
+enum Counter {
+    /// Exact, through an encode cache that is saved afterwards.
+    Cached(Box<Tokenizer>, EncodeCache),
+    /// Exact, sampled or estimated, as the flags ask.
+    Plain(Box<dyn Tokenize>),
+}
+
+fn run_count(args: CountArgs) -> io::Result<()> {
+    let path = store::resolve(&args.model, args.model_sha256.as_deref())?;
+    let mut counter = match &args.cache {
+        Some(cache_path) => {
+            let tokenizer = Tokenizer::load_encode_only(&path)?;
+            let cache = EncodeCache::load(cache_path, &tokenizer, args.cache_size)?;
+            info!(entries = cache.len(), "encode cache loaded");
+            Counter::Cached(Box::new(tokenizer), cache)
+        }
+        None => Counter::Plain(tokenizer::load_encode_only(&path)?),
+    };
+    let mut rates = None;
+    let mut rng = Rng::new(args.seed.unwrap_or(0));
+    // tokens, and how far off they may be if sampled
+    let mut count_tokens = |docs: &[String]| -> (usize, Option<usize>) {
+        let tokenizer = match &mut counter {
+            Counter::Cached(tokenizer, cache) => {
+                return (
+                    docs.iter().map(|d| cache.encode(tokenizer, d).len()).sum(),
+                    None,
+                );
+            }
+            Counter::Plain(tokenizer) => &**tokenizer,
+        };
+        if let Some(fraction) = args.sample {
+            let interval = estimate::sampled_interval(tokenizer, docs, fraction, &mut rng);
+            return (interval.tokens, Some(interval.margin));
+        }
+        let tokens = match args.estimate {
+            None => docs.iter().map(|d| tokenizer.encode(d).len()).sum(),
+            Some(estimate::Method::Rates) => {
+                let rates = rates.get_or_insert_with(|| {
+                    let rates = Rates::calibrate_on_start(tokenizer, docs, CALIBRATION_BYTES);
+                    info!(
+                        ascii = rates.ascii,
+                        other = rates.other,
+                        "calibrated tokens per byte"
+                    );
+                    rates
+                });
+                docs.iter().map(|d| rates.estimate(d)).sum()
+            }
+            Some(estimate::Method::Sample) => estimate::sampled(tokenizer, docs, args.sample_every),
+        };
+        (tokens, None)
+    };
+    let mut counts = vec![];
+    let mut docs_read = 0;
+    let tree = match args.input.source()? {
+        Source::Tree { path, walk } => {
+            let compression = args.input.compression.unwrap_or(Compression::Auto);
+            let encoding = args.input.input_encoding.unwrap_or_default();
+            let mut skipped = 0;
+            for file in corpus::walk(&path, walk)? {
+                let Some(doc) = corpus::read_tree_file(&file, compression, encoding, walk)? else {
+                    skipped += 1;
+                    continue;
+                };
+                let name = file
+                    .strip_prefix(&path)
+                    .unwrap_or(&file)
+                    .display()
+                    .to_string();
+                let bytes = doc.len();
+                let (tokens, margin) = count_tokens(&[doc]);
+                counts.push(FileCount::new(name, bytes, tokens).with_margin(margin));
+            }
+            if skipped > 0 {
+                info!(files = skipped, "skipped binary files");
+            }
+            true
+        }
+        source => {
+            let compression = args.input.compression.unwrap_or(Compression::Auto);
+            let encoding = args.input.input_encoding.unwrap_or_default();
+            let docs = corpus::read_documents(&source, compression, encoding)?;
+            docs_read = docs.len();
+            let name = args
+                .input
+                .input
+                .as_ref()
+                .map_or(String::new(), |p| p.display().to_string());
+            let bytes = docs.iter().map(String::len).sum();
+            let (tokens, margin) = count_tokens(&docs);
+            counts.push(FileCount::new(name, bytes, tokens).with_margin(margin));
+            false
+        }
+    };
+    if let (Some(cache_path), Counter::Cached(_, cache)) = (&args.cache, &counter) {
+        cache.save(cache_path)?;
+    }
+    let terminator: &[u8] = if args.input.null { b"\0" } else { b"\n" };
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    if tree || args.format != CountFormat::Table {
+        count::write(&mut stdout, &mut counts, args.format, terminator)?;
+        return stdout.flush();
+    }
+    let count = &counts[0];
+    let tokens = match (args.estimate, count.margin) {
+        (_, Some(margin)) => format!("{} ± {} (95% confidence)", count.tokens, margin),
+        (Some(_), None) => format!("~{}", count.tokens),
+        (None, None) => count.tokens.to_string(),
+    };
+    for line in [
+        format!("docs:    {}", docs_read),
+        format!("bytes:   {}", count.bytes),
+        format!("tokens:  {}", tokens),
+        format!("ratio:   {:.2}", count.ratio),
+    ] {
+        stdout.write_all(line.as_bytes())?;
+        stdout.write_all(terminator)?;
+    }
+    stdout.flush()
+}
+
+fn run_decode(args: DecodeArgs) -> io::Result<()> {
+    let tokenizer = match &args.model {
+        Some(model) => tokenizer::load(&store::resolve(model, None)?)?,
+        None => Box::new(Tokenizer::new(demo_model()?)?),
+    };
+    let options = DecodeOptions {
+        skip_special_tokens: args.skip_special_tokens,
+        strip_prefix_space: args.strip_prefix_space,
+        collapse_spaces: args.collapse_spaces,
+    };
+    let path = args.ids_file.as_ref().unwrap_or(&args.input);
+    let mut reader = corpus::open(path, Compression::Auto)?;
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let invalid =
+        |msg: String| context::in_file(path, io::Error::new(io::ErrorKind::InvalidData, msg));
+    let (delimiter, unit) = if args.null {
+        (b'\0', "record")
+    } else {
+        (b'\n', "line")
+    };
+    let mut bytes = vec![];
+    let mut offset = 0;
+    for i in 0.. {
+        bytes.clear();
+        let n = reader
+            .read_until(delimiter, &mut bytes)
+            .map_err(|e| context::in_file(path, e))?;
+        if n == 0 {
+            break;
+        }
+        let record = bytes.strip_suffix(&[delimiter]).unwrap_or(&bytes);
+        let line = std::str::from_utf8(record).map_err(|e| {
+            let e = context::invalid_utf8(record, e.valid_up_to(), offset);
+            invalid(format!("{} {}: {}", unit, i + 1, e))
+        })?;
+        let ids = if args.ids_file.is_some() && line.trim_start().starts_with('[') {
+            let ids: Vec<u32> = serde_json::from_str(line)
+                .map_err(|e| invalid(format!("{} {}: {}", unit, i + 1, e)))?;
+            if let Some(id) = ids.iter().find(|&&id| !tokenizer.contains(id)) {
+                return Err(invalid(format!(
+                    "{} {}: invalid token id {} (no token has it; the vocabulary has {} ids)",
+                    unit,
+                    i + 1,
+                    id,
+                    tokenizer.vocab_size()
+                )));
+            }
+            ids
+        } else {
+            line.split_whitespace()
+                .map(|id| {
+                    id.parse::<u32>()
+                        .ok()
+                        .filter(|&id| tokenizer.contains(id))
+                        .ok_or_else(|| {
+                            let at = id.as_ptr() as usize - line.as_ptr() as usize;
+                            let msg = format!(
+                                "{} {}, byte {}: invalid token id {:?} (no token has it; the vocabulary has {} ids) near {}",
+                                unit,
+                                i + 1,
+                                offset + at,
+                                id,
+                                tokenizer.vocab_size(),
+                                context::near(line.trim_end().as_bytes(), at)
+                            );
+                            invalid(msg)
+                        })
+                })
+                .collect::<io::Result<Vec<u32>>>()?
+        };
+        offset += n;
+        if args.verbose {
+            if i > 0 {
+                writeln!(stdout)?;
+            }
+            for &id in &ids {
+                let bytes = tokenizer.id_bytes(id);
+                let rank = tokenizer.rank(id).map_or("-".into(), |r| r.to_string());
+                writeln!(
+                    stdout,
+                    "{:>8} {:>8} {:>4}  {:?}",
+                    id,
+                    rank,
+                    bytes.len(),
+                    String::from_utf8_lossy(&bytes)
+                )?;
+            }
+        }
+        let text = tokenizer.decode_with(&ids, &options);
+        if args.ids_file.is_some() {
+            let record = serde_json::json!({ "text": text });
+            writeln!(stdout, "{}", record)?;
+            continue;
+        }
+        write!(stdout, "{}", text)?;
+        stdout.write_all(&[delimiter])?;
+    }
+    stdout.flush()
+}
+
+/// The demo model built into the binary, for commands run without `--model`.
+fn demo_model() -> io::Result<Model> {
+    model::from_bytes(store::bundled(store::DEMO).expect("demo model is bundled"))
+}
+
+/// What `encode --out` writes, by the file's extension.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum IdsFormat {
+    Npy,
+    Bin,
+    Arrow,
+}
+
+impl IdsFormat {
+    fn of(path: &Path) -> io::Result<IdsFormat> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("npy") => Ok(IdsFormat::Npy),
+            Some("bin") => Ok(IdsFormat::Bin),
+            Some("arrow" | "feather") => Ok(IdsFormat::Arrow),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--out must name a .npy, .bin, .arrow or .feather file",
+            )),
+        }
+    }
+}
+
+fn run_encode(args: EncodeArgs) -> io::Result<()> {
+    // a bad --out name fails before any encoding is done
+    let out = match &args.out {
+        Some(path) => Some((path, IdsFormat::of(path)?)),
+        None => None,
+    };
+    let threads = args
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let configure = |tokenizer: Tokenizer| {
+        tokenizer
+            .with_threads(threads)
+            .with_add_prefix_space(args.add_prefix_space)
+    };
+    let tokenizer: Box<dyn Tokenize> = match &args.model {
+        Some(model) => {
+            let path = store::resolve(model, args.model_sha256.as_deref())?;
+            if args.add_prefix_space {
+                Box::new(configure(Tokenizer::load_encode_only(&path)?))
+            } else {
+                tokenizer::load_encode_only_with(&path, configure)?
+            }
+        }
+        None => Box::new(configure(Tokenizer::new_encode_only(demo_model()?)?)),
+    };
+    let text = match args.text {
+        Some(_) => None,
+        None => args.input.text_input()?,
+    };
+    let terminator: &[u8] = if args.input.null { b"\0" } else { b"\n" };
+    if args.out.is_none() && args.pack.is_none() {
+        let mut stdout = BufWriter::new(io::stdout().lock());
+        if let Some(text) = &args.text {
+            let ids: Vec<String> = tokenizer.encode(text).iter().map(u32::to_string).collect();
+            writeln!(stdout, "{}", ids.join(" "))?;
+            return stdout.flush();
+        }
+        if let Some(text) = text {
+            // print the ids of a text file as they are encoded
+            let mut first = true;
+            text.encode(&*tokenizer, &mut |ids| {
+                for id in ids {
+                    if !first {
+                        write!(stdout, " ")?;
+                    }
+                    write!(stdout, "{}", id)?;
+                    first = false;
+                }
+                Ok(())
+            })?;
+            writeln!(stdout)?;
+            return stdout.flush();
+        }
+        for doc in &args.input.read_documents()? {
+            let ids: Vec<String> = tokenizer.encode(doc).iter().map(u32::to_string).collect();
+            write!(stdout, "{}", ids.join(" "))?;
+            stdout.write_all(terminator)?;
+        }
+        return stdout.flush();
+    }
+    let mut docs: Vec<Vec<u32>> = match text {
+        Some(text) => {
+            // a plain text file is one document
+            let mut ids = vec![];
+            text.encode(&*tokenizer, &mut |block| {
+                ids.extend_from_slice(block);
+                Ok(())
+            })?;
+            vec![ids]
+        }
+        None => match &args.text {
+            Some(text) => vec![tokenizer.encode(text)],
+            None => {
+                let docs = args.input.read_documents()?;
+                docs.iter().map(|doc| tokenizer.encode(doc)).collect()
+            }
+        },
+    };
+    if let (Some(block_len), Some(eos)) = (args.pack, args.eos_id) {
+        if eos as usize >= tokenizer.vocab_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("EOS id {} is not in the vocabulary", eos),
+            ));
+        }
+        let (blocks, dropped) = pack::pack(&docs, block_len, eos)?;
+        info!(blocks = blocks.len(), dropped, "documents packed");
+        docs = blocks;
+    }
+    let Some((out, format)) = out else {
+        let mut stdout = BufWriter::new(io::stdout().lock());
+        for ids in &docs {
+            let ids: Vec<String> = ids.iter().map(u32::to_string).collect();
+            write!(stdout, "{}", ids.join(" "))?;
+            stdout.write_all(terminator)?;
+        }
+        return stdout.flush();
+    };
+    let dtype = args
+        .dtype
+        .unwrap_or_else(|| Dtype::for_vocab(tokenizer.vocab_size()));
+    let ids = docs.concat();
+    match format {
+        IdsFormat::Npy => match args.pack {
+            Some(block_len) => export::write_npy_2d(out, &ids, block_len, dtype)?,
+            None => export::write_npy(out, &ids, dtype)?,
+        },
+        IdsFormat::Bin => export::write_bin(out, &ids, dtype)?,
+        IdsFormat::Arrow => arrow::write_arrow(out, &docs, dtype)?,
+    }
+    info!(path = %out.display(), rows = docs.len(), tokens = ids.len(), ?dtype, "ids written");
+    Ok(())
+}
+
+fn run_prepare(args: PrepareArgs) -> io::Result<()> {
+    let path = store::resolve(&args.model, args.model_sha256.as_deref())?;
+    let threads = args
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let tokenizer =
+        tokenizer::load_encode_only_with(&path, |tokenizer| tokenizer.with_threads(threads))?;
+    let vocab_size = tokenizer.vocab_size();
+    if let Some(id) = args.separator_id.filter(|&id| id as usize >= vocab_size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("separator id {} is not in the vocabulary", id),
+        ));
+    }
+    let dtype = args.dtype.unwrap_or_else(|| Dtype::for_vocab(vocab_size));
+    let shuffle_seed = args.shuffle.then(|| args.seed.unwrap_or(0));
+    let model_sha256 = fetch::sha256_file(&path)?;
+    let write_manifest = |dir: &Path, shard_tokens: usize, documents: u64, shards: Vec<Shard>| {
+        let manifest = Manifest {
+            model: args.model.display().to_string(),
+            model_sha256: model_sha256.clone(),
+            vocab_size,
+            dtype,
+            separator: args.separator_id,
+            shuffle_seed,
+            shard_tokens: shard_tokens as u64,
+            tokens: shards.iter().map(|shard| shard.tokens).sum(),
+            documents,
+            shards,
+        };
+        manifest.write(dir)?;
+        info!(
+            dir = %dir.display(),
+            shards = manifest.shards.len(),
+            tokens = manifest.tokens,
+            documents,
+            ?dtype,
+            "shards written"
+        );
+        Ok::<_, io::Error>(())
+    };
+
+    let whole_corpus = args.shuffle || args.num_shards.is_some() || args.val_ratio.is_some();
+    if !whole_corpus {
+        if let Some(text) = args.input.text_input()? {
+            // a plain text file is one document, encoded as it streams in
+            let mut writer =
+                ShardWriter::new(&args.out, args.shard_tokens, dtype, args.separator_id)?;
+            writer.start_document();
+            text.encode(&*tokenizer, &mut |ids| writer.write(ids))?;
+            writer.end_document()?;
+            return write_manifest(&args.out, args.shard_tokens, 1, writer.finish()?);
+        }
+    }
+    let mut docs = args.input.read_documents()?;
+    if let Some(seed) = shuffle_seed {
+        Rng::new(seed).shuffle(&mut docs);
+    }
+    let write_split = |dir: &Path, docs: &[String]| {
+        let (mut writer, encoded) = match args.num_shards {
+            Some(n) => {
+                let encoded: Vec<Vec<u32>> = docs.iter().map(|doc| tokenizer.encode(doc)).collect();
+                let separators = if args.separator_id.is_some() {
+                    docs.len()
+                } else {
+                    0
+                };
+                let tokens = encoded.iter().map(Vec::len).sum::<usize>() + separators;
+                let writer = ShardWriter::with_shards(dir, tokens, n, dtype, args.separator_id)?;
+                (writer, Some(encoded))
+            }
+            None => (
+                ShardWriter::new(dir, args.shard_tokens, dtype, args.separator_id)?,
+                None,
+            ),
+        };
+        let shard_tokens = writer.shard_tokens();
+        match encoded {
+            Some(encoded) => encoded
+                .iter()
+                .try_for_each(|ids| writer.add_document(ids))?,
+            None => docs
+                .iter()
+                .try_for_each(|doc| writer.add_document(&tokenizer.encode(doc)))?,
+        }
+        write_manifest(dir, shard_tokens, docs.len() as u64, writer.finish()?)
+    };
+    match args.val_ratio {
+        Some(ratio) => {
+            let (val, train) = docs.split_at(shard::val_count(docs.len(), ratio)?);
+            write_split(&args.out.join("train"), train)?;
+            write_split(&args.out.join("val"), val)
+        }
+        None => write_split(&args.out, &docs),
+    }
+}
+
+fn run_vocab(args: VocabArgs) -> io::Result<()> {
+    let tokenizer = Tokenizer::load(&args.model)?;
+    for (id, _) in tokenizer.tokens() {
+        println!("{} {}", id, tokenizer.render_token(id, args.style));
+    }
+    Ok(())
+}
+
+fn run_prune(args: PruneArgs) -> io::Result<()> {
+    let model = model::load(&args.model)?;
+    let docs = args.input.read_documents()?;
+    let usage = prune::model_usage(&model, &docs)?;
+    let pruned = match args.drop {
+        Some(k) => prune::trim(&model, &usage, k),
+        None => prune::prune(&model, &usage, args.min_count),
+    };
+    println!(
+        "merges:  {} -> {}",
+        model.merges.len(),
+        pruned.model.merges.len()
+    );
+    model::save(&args.output, &pruned.model)?;
+    info!(path = %args.output.display(), "model saved");
+    if let Some(path) = &args.remap {
+        let mut ids: Vec<(u32, u32)> = pruned.remap.into_iter().collect();
+        ids.sort_unstable_by_key(|&(_, new)| new);
+        let lines: String = ids
+            .into_iter()
+            .map(|(old, new)| format!("{} {}\n", old, new))
+            .collect();
+        std::fs::write(path, lines)?;
+        info!(path = %path.display(), "id remapping written");
+    }
+    Ok(())
+}
+
+fn run_strip(args: StripArgs) -> io::Result<()> {
+    let model = model::load(&args.model)?;
+    let dropped = prune::unreachable(&model.merges);
+    let vocab = build_vocab(&model.merges);
+    let bytes: usize = dropped.iter().map(|id| vocab[id].len()).sum();
+    println!(
+        "unreachable: {} of {} merged tokens",
+        dropped.len(),
+        model.merges.len()
+    );
+    println!("dropped:     {} bytes", bytes);
+    mmap::write_stripped(&args.output, &model, &dropped)?;
+    info!(path = %args.output.display(), "model saved");
+    Ok(())
+}
+
+/// Loads a model in any format `convert` reads.
+fn load_any(input: &Path, pattern: Option<&str>) -> io::Result<Model> {
+    Ok(match ConvertFormat::of(input) {
+        ConvertFormat::Gpt2 => gpt2::load(&input.join("vocab.json"), &input.join("merges.txt"))?,
+        ConvertFormat::Hf => hf::load(input)?,
+        ConvertFormat::Tiktoken => tiktoken::load_model(input, pattern)?,
+        _ => model::load(input)?,
+    })
+}
+
+fn is_mapped(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    let n = std::fs::File::open(path)?.read(&mut magic)?;
+    Ok(&magic[..n] == mmap::MAGIC)
+}
+
+fn run_convert(args: ConvertArgs) -> io::Result<()> {
+    let model = load_any(&args.input, args.pattern.as_deref())?;
+    let format = args
+        .to_format
+        .unwrap_or_else(|| ConvertFormat::of(&args.to));
+    if model.whitespace_marker && !matches!(format, ConvertFormat::Text | ConvertFormat::Binary) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "models with the whitespace marker only convert to text and binary model files",
+        ));
+    }
+    // other tokenizers would keep the markers as plain special tokens and
+    // never lowercase the text, so the vocabulary wouldn't round-trip
+    if case::Markers::find(&model.special_tokens).is_some()
+        && !matches!(format, ConvertFormat::Text | ConvertFormat::Binary)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "models with case markers only convert to text and binary model files",
+        ));
+    }
+    match format {
+        ConvertFormat::Text => model::save_as(&args.to, &model, model::Format::Text)?,
+        ConvertFormat::Binary => model::save_as(&args.to, &model, model::Format::Binary)?,
+        ConvertFormat::Mapped => model::save_as(&args.to, &model, model::Format::Mapped)?,
+        ConvertFormat::Hf => hf::save(&args.to, &model)?,
+        ConvertFormat::Tiktoken => {
+            if !model.special_tokens.is_empty() {
+                warn!(
+                    count = model.special_tokens.len(),
+                    "tiktoken files have no special tokens; leaving them out"
+                );
+            }
+            tiktoken::save(&args.to, &model)?
+        }
+        ConvertFormat::Gpt2 => gpt2::save(&args.to, &model)?,
+    }
+    println!(
+        "merges:  {}\nspecial: {}",
+        model.merges.len(),
+        model.special_tokens.len()
+    );
+    info!(path = %args.to.display(), ?format, "model converted");
+    Ok(())
+}
+
+fn run_validate(args: ValidateArgs) -> io::Result<()> {
+    let model = load_any(&args.model, args.pattern.as_deref())?;
+    let problems = match is_mapped(&args.model) {
+        Ok(true) => validate::check_mapped(&mmap::MappedModel::open(&args.model)?),
+        _ => validate::check(&model, None),
+    };
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {} problems", args.model.display(), problems.len()),
+        ));
+    }
+    println!(
+        "ok: {} merges, {} special tokens",
+        model.merges.len(),
+        model.special_tokens.len()
+    );
+    Ok(())
+}
+
+fn run_inspect(args: InspectArgs) -> io::Result<()> {
+    let model = load_any(&args.model, args.pattern.as_deref())?;
+    println!("merges:            {}", model.merges.len());
+    println!(
+        "vocab:             {}",
+        256 + model.merges.len() + model.special_tokens.len()
+    );
+    println!(
+        "pattern:           {}",
+        model.pattern.as_deref().unwrap_or("(none)")
+    );
+    let mut special: Vec<_> = model.special_tokens.iter().collect();
+    special.sort_by_key(|&(_, id)| id);
+    for (token, id) in special {
+        println!("special token:     {} {}", token, id);
+    }
+    if model.whitespace_marker {
+        println!("whitespace marker: yes");
+    }
+    let Some(metadata) = &model.metadata else {
+        println!("metadata:          (none)");
+        return Ok(());
+    };
+    println!("trained by:        bpe {}", metadata.crate_version);
+    println!(
+        "created:           {}",
+        metadata::format_utc(metadata.created)
+    );
+    println!("corpus:            {}", metadata.corpus);
+    for (key, value) in &metadata.params {
+        println!("{:<18} {}", format!("{}:", key), value);
+    }
+    Ok(())
+}
+
+fn run_selftest(args: SelftestArgs) -> io::Result<()> {
+    let tokenizer = tokenizer::load(&store::resolve(&args.model, None)?)?;
+    let checks = selftest::run(tokenizer.as_ref());
+    for check in &checks {
+        match &check.failure {
+            None => println!("pass  {}", check.name),
+            Some(failure) => println!("FAIL  {}: {}", check.name, failure),
+        }
+    }
+    let failed = checks.iter().filter(|c| !c.passed()).count();
+    println!("{} passed, {} failed", checks.len() - failed, failed);
+    if failed > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {} checks failed", args.model.display(), failed),
+        ));
+    }
+    Ok(())
+}
+
+fn run_history(args: HistoryArgs) -> io::Result<()> {
+    let tokenizer = Tokenizer::load(&args.model)?;
+    if tokenizer.id_to_token(args.token).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("token {} is not in the vocabulary", args.token),
+        ));
+    }
+    for (depth, id) in history::merge_tree(tokenizer.merges(), args.token) {
+        let how = if id < 256 {
+            "byte".to_string()
+        } else if tokenizer.is_special(id) {
+            "special".to_string()
+        } else {
+            format!("rank {}", id - 256)
+        };
+        let piece = tokenizer.render_token(id, args.style);
+        println!("{}{} \"{}\" ({})", "  ".repeat(depth), id, piece, how);
+    }
+    Ok(())
+}
+
+fn run_explain(args: ExplainArgs) -> io::Result<()> {
+    let tokenizer = Tokenizer::load(&args.model)?;
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    explain(&tokenizer, &args.text, &mut stdout)?;
+    stdout.flush()
+}
+
+/// Writes the merge steps that encode each chunk of `text`, run over the
+/// chunk's segments as the merges see them, then the ids.
+fn explain(tokenizer: &Tokenizer, text: &str, w: &mut impl Write) -> io::Result<()> {
+    for chunk in tokenizer.chunks(text) {
+        writeln!(w, "{:?}", chunk)?;
+        for (marker, segment) in tokenizer.segments(chunk) {
+            if let Some(marker) = marker {
+                writeln!(w, "  marker:     {}", pieces(tokenizer, &[marker]))?;
+            }
+            let bytes: Vec<u32> = segment.as_bytes().iter().map(|&b| b.into()).collect();
+            writeln!(w, "  bytes:      {}", pieces(tokenizer, &bytes))?;
+            for step in history::encode_steps(tokenizer.merges(), &segment) {
+                writeln!(
+                    w,
+                    "  rank {:>5}: {}",
+                    step.id - 256,
+                    pieces(tokenizer, &step.ids)
+                )?;
+            }
+        }
+    }
+    writeln!(w, "ids: {:?}", tokenizer.encode(text))
+}
+
+fn run_compare(args: CompareArgs) -> io::Result<()> {
+    let models = [Tokenizer::load(&args.a)?, Tokenizer::load(&args.b)?];
+    let docs = args.input.read_documents()?;
+    let bytes: usize = docs.iter().map(String::len).sum();
+    println!(
+        "{:<8} {:>10} {:>10} {:>8}",
+        "model", "vocab", "tokens", "ratio"
+    );
+    for (name, tokenizer) in ["a", "b"].iter().zip(&models) {
+        let tokens: usize = docs.iter().map(|doc| tokenizer.encode(doc).len()).sum();
+        println!(
+            "{:<8} {:>10} {:>10} {:>8.2}",
+            name,
+            tokenizer.vocab_size(),
+            tokens,
+            bytes as f64 / tokens.max(1) as f64
+        );
+    }
+    let lines = docs
+        .iter()
+        .flat_map(|doc| doc.lines())
+        .filter(|line| !line.trim().is_empty());
+    for line in lines.take(args.max_examples) {
+        let cuts = models.each_ref().map(|t| cuts(t, line));
+        let mut all: Vec<usize> = [cuts[0].clone(), cuts[1].clone()].concat();
+        all.sort_unstable();
+        all.dedup();
+        println!();
+        for (name, cuts) in ["a", "b"].iter().zip(&cuts) {
+            let mut row = String::new();
+            for pair in all.windows(2) {
+                if pair[0] > 0 {
+                    row.push(if cuts.contains(&pair[0]) { '|' } else { ' ' });
+                }
+                // spaces show as `Ġ`, so a blank only ever marks the other
+                // model's cut
+                row.push_str(&gpt2::encode_bytes(&line.as_bytes()[pair[0]..pair[1]]));
+            }
+            println!("{}: {}", name, row);
+        }
+    }
+    Ok(())
+}
+
+fn run_sweep(mut args: SweepArgs) -> io::Result<()> {
+    args.sizes.sort_unstable();
+    args.sizes.dedup();
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_string());
+    if args.sizes[0] < 256 || args.holdout_every < 2 {
+        return Err(invalid(
+            "sizes must be at least 256 and --holdout-every at least 2",
+        ));
+    }
+    let max_size = u32::try_from(*args.sizes.last().unwrap())
+        .map_err(|_| invalid("sizes must fit in 32 bits"))?;
+    let docs = args.input.read_documents()?;
+    let (mut train, mut held) = (vec![], vec![]);
+    for (i, line) in docs
+        .iter()
+        .flat_map(|doc| doc.split_inclusive('\n'))
+        .enumerate()
+    {
+        if (i + 1) % args.holdout_every == 0 {
+            held.push(line);
+        } else {
+            train.push(line);
+        }
+    }
+    let splitter = args.pattern.as_deref().map(Splitter::new).transpose()?;
+    let ingest = Ingest::default();
+    let words = chunk_words(ingest::count_texts(&train, splitter.as_ref(), ingest));
+    let options = TrainOptions {
+        threads: ingest.splitters,
+        ..TrainOptions::default()
+    };
+    let num_ids = train.iter().map(|line| line.len()).sum();
+    let mut metrics = Metrics::new(num_ids, None);
+    let merges = train_words_with(words, max_size - 256, &options, &mut metrics);
+
+    let held_bytes: usize = held.iter().map(|line| line.len()).sum();
+    println!("held-out lines: {} ({} bytes)", held.len(), held_bytes);
+    println!(
+        "{:>8} {:>8} {:>10} {:>8}",
+        "size", "merges", "tokens", "ratio"
+    );
+    for size in args.sizes {
+        // the model at `size` is the first merges of the largest one
+        let snapshot: HashMap<(u32, u32), u32> = merges
+            .iter()
+            .filter(|&(_, &idx)| (idx as usize) < size)
+            .map(|(&pair, &idx)| (pair, idx))
+            .collect();
+        let tokens: usize = held
+            .iter()
+            .map(|line| encode_text(&snapshot, splitter.as_ref(), line).len())
+            .sum();
+        println!(
+            "{:>8} {:>8} {:>10} {:>8.2}",
+            size,
+            snapshot.len(),
+            tokens,
+            held_bytes as f64 / tokens.max(1) as f64
+        );
+    }
+    Ok(())
+}
+
+/// Byte offsets of the token boundaries of `text` as `tokenizer` encodes
+/// it, both ends included. Offsets come from `encode_with_offsets`, as
+/// marked spaces and case markers aren't bytes of the text.
+fn cuts(tokenizer: &Tokenizer, text: &str) -> Vec<usize> {
+    let (_, offsets) = tokenizer.encode_with_offsets(text);
+    let mut cuts = vec![0];
+    // case markers have empty ranges, and the pieces of a character share one
+    cuts.extend(
+        offsets
+            .iter()
+            .filter(|(start, end)| start < end)
+            .map(|&(_, end)| end),
+    );
+    cuts.push(text.len());
+    cuts.dedup();
+    cuts
+}
+
+/// Token pieces separated by `|`, to show where a text was cut.
+fn pieces(tokenizer: &Tokenizer, ids: &[u32]) -> String {
+    let pieces: Vec<String> = ids
+        .iter()
+        .map(|&id| tokenizer.render_token(id, PieceStyle::Escaped))
+        .collect();
+    pieces.join("|")
+}
+
+fn run_compare_strategies(args: CompareStrategiesArgs) -> io::Result<()> {
+    let tokenizer = Tokenizer::load(&args.model)?;
+    let (mut chunks, mut differing, mut merge_tokens, mut greedy_tokens) = (0, 0, 0, 0);
+    for doc in args.input.read_documents()? {
+        for chunk in tokenizer.chunks(&doc) {
+            let merged = tokenizer.encode(chunk);
+            let greedy = tokenizer.encode_greedy(chunk);
+            chunks += 1;
+            merge_tokens += merged.len();
+            greedy_tokens += greedy.len();
+            if merged != greedy {
+                differing += 1;
+                if differing <= args.max_examples {
+                    println!("{:?}", chunk);
+                    println!("  merge:  {}", pieces(&tokenizer, &merged));
+                    println!("  greedy: {}", pieces(&tokenizer, &greedy));
+                }
+            }
+        }
+    }
+    let delta = greedy_tokens as i64 - merge_tokens as i64;
+    println!("chunks:          {}", chunks);
+    println!(
+        "differing:       {} ({:.2}%)",
+        differing,
+        100.0 * differing as f64 / chunks.max(1) as f64
+    );
+    println!("tokens (merge):  {}", merge_tokens);
+    println!("tokens (greedy): {}", greedy_tokens);
+    println!(
+        "delta:           {:+} ({:+.2}%)",
+        delta,
+        100.0 * delta as f64 / merge_tokens.max(1) as f64
+    );
+    Ok(())
+}
+
+fn run_random_ids(args: RandomIdsArgs) -> io::Result<()> {
+    let tokenizer = tokenizer::load(&store::resolve(&args.model, None)?)?;
+    // ids a vocabulary with gaps has no token for are left out
+    let ids: Vec<u32> = (0..tokenizer.vocab_size() as u32)
+        .filter(|&id| !(args.skip_special_tokens && tokenizer.is_special(id)))
+        .filter(|&id| !tokenizer.id_bytes(id).is_empty())
+        .collect();
+    let seed = args.seed.unwrap_or(0);
+    let mut sampler = match &args.weights_from {
+        Some(path) => {
+            let source = Source::Text(path.clone());
+            let docs = corpus::read_documents(&source, Compression::Auto, InputEncoding::Auto)?;
+            let mut counts = HashMap::new();
+            for doc in &docs {
+                for id in tokenizer.encode(doc) {
+                    *counts.entry(id).or_insert(0) += 1;
+                }
+            }
+            IdSampler::weighted(ids, &counts, seed)?
+        }
+        None => IdSampler::uniform(ids, seed)?,
+    };
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    for _ in 0..args.sequences {
+        let ids: Vec<String> = sampler
+            .sequence(args.len)
+            .iter()
+            .map(u32::to_string)
+            .collect();
+        writeln!(stdout, "{}", ids.join(" "))?;
+    }
+    stdout.flush()
+}
+
+fn run_difftok(args: DifftokArgs) -> io::Result<()> {
+    let tokenizer = tokenizer::load(&store::resolve(&args.model, None)?)?;
+    let old = tokenizer.encode(&std::fs::read_to_string(&args.old)?);
+    let new = tokenizer.encode(&std::fs::read_to_string(&args.new)?);
+    let hunks = diff::diff(&old, &new);
+    let show = |ids: &[u32]| {
+        let pieces: Vec<String> = ids
+            .iter()
+            .map(|&id| format!("{:?}", tokenizer.decode(&[id])))
+            .collect();
+        pieces.join(" ")
+    };
+    let (mut inserted, mut deleted, mut changed) = (0, 0, 0);
+    for hunk in &hunks {
+        match (hunk.is_insert(), hunk.is_delete()) {
+            (true, _) => inserted += hunk.new.len(),
+            (_, true) => deleted += hunk.old.len(),
+            _ => changed += 1,
+        }
+        println!(
+            "@@ {}..{} -> {}..{}",
+            hunk.old.start, hunk.old.end, hunk.new.start, hunk.new.end
+        );
+        if !hunk.is_insert() {
+            println!("- {}", show(&old[hunk.old.clone()]));
+        }
+        if !hunk.is_delete() {
+            println!("+ {}", show(&new[hunk.new.clone()]));
+        }
+    }
+    if !hunks.is_empty() {
+        println!();
+    }
+    println!("old:      {} tokens", old.len());
+    println!(
+        "new:      {} tokens ({:+})",
+        new.len(),
+        new.len() as i64 - old.len() as i64
+    );
+    println!("inserted: {}", inserted);
+    println!("deleted:  {}", deleted);
+    println!("changed:  {} runs", changed);
+    Ok(())
+}
+
+fn run_models(command: ModelsCommand) -> io::Result<()> {
+    let dir = store::dir();
+    match command {
+        ModelsCommand::List => {
+            for model in store::list(&dir)? {
+                println!(
+                    "{:<20} {:>10}  {}",
+                    model.name,
+                    model.bytes,
+                    model.path.display()
+                );
+            }
+        }
+        ModelsCommand::Add {
+            name,
+            source,
+            sha256,
+        } => {
+            let path = store::add(&dir, &name, &source, sha256.as_deref())?;
+            info!(name, path = %path.display(), "model installed");
+        }
+        ModelsCommand::Remove { name } => {
+            store::remove(&dir, &name)?;
+            info!(name, "model removed");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("100M"), Ok(100 << 20));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size(&format!("{}G", usize::MAX >> 20)).is_err());
+    }
+
+    #[test]
+    fn test_ids_format() {
+        assert_eq!(IdsFormat::of(Path::new("a.npy")).unwrap(), IdsFormat::Npy);
+        assert_eq!(
+            IdsFormat::of(Path::new("a.feather")).unwrap(),
+            IdsFormat::Arrow
+        );
+        assert!(IdsFormat::of(Path::new("ids.txt")).is_err());
+        assert!(IdsFormat::of(Path::new("ids")).is_err());
+    }
+
+    #[test]
+    fn test_cuts() {
+        // `▁w` is four bytes of token but two of text
+        let marked = Tokenizer::new(Model {
+            merges: HashMap::from([((0xe2, 0x96), 256), ((256, 0x81), 257), ((257, 119), 258)]),
+            pattern: None,
+            special_tokens: HashMap::new(),
+            whitespace_marker: true,
+            metadata: None,
+        })
+        .unwrap();
+        assert_eq!(marked.encode("hi wo")[2], 258);
+        assert_eq!(cuts(&marked, "hi wo"), [0, 1, 2, 4, 5]);
+
+        let cased = Tokenizer::new(Model {
+            merges: HashMap::new(),
+            pattern: None,
+            special_tokens: HashMap::from([
+                (case::CAPITALIZED.to_string(), 256),
+                (case::UPPERCASE.to_string(), 257),
+            ]),
+            whitespace_marker: false,
+            metadata: None,
+        })
+        .unwrap();
+        assert_eq!(cased.encode("Hi OK"), [256, 104, 105, 257, 32, 111, 107]);
+        assert_eq!(cuts(&cased, "Hi OK"), [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_explain() {
+        let tokenizer = Tokenizer::new(Model {
+            merges: HashMap::from([((0xe2, 0x96), 256), ((256, 0x81), 257), ((257, 104), 258)]),
+            pattern: Some(r" ?\w+".to_string()),
+            special_tokens: HashMap::from([
+                (case::CAPITALIZED.to_string(), 259),
+                (case::UPPERCASE.to_string(), 260),
+            ]),
+            whitespace_marker: true,
+            metadata: None,
+        })
+        .unwrap();
+        let mut out = vec![];
+        explain(&tokenizer, "hi Hi", &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        // the last step of the second chunk is the ids after its marker
+        let steps: Vec<&str> = out.lines().collect();
+        assert_eq!(steps[3], "  marker:     <|cap|>");
+        assert_eq!(steps.last(), Some(&"ids: [104, 105, 259, 258, 105]"));
+        let last_step = steps[steps.len() - 2];
+        let expected = pieces(&tokenizer, &[258, 105]);
+        assert!(last_step.ends_with(&expected), "{}", out);
+    }
+
+    #[test]
+    fn test_parse_fraction() {
+        assert_eq!(parse_fraction("5%"), Ok(0.05));
+        assert_eq!(parse_fraction("0.25"), Ok(0.25));
+        assert_eq!(parse_fraction("100%"), Ok(1.0));
+        assert!(parse_fraction("0%").is_err());
+        assert!(parse_fraction("150%").is_err());
+        assert!(parse_fraction("five").is_err());
+    }
+
+    #[test]
+    fn test_vocab_size() {
+        let args = |flags: &[&str]| {
+            let cli = Cli::try_parse_from([&["bpe", "train"], flags].concat()).unwrap();
+            let Command::Train(args) = cli.command else {
+                unreachable!()
+            };
+            args
+        };
+        assert_eq!(args(&[]).checked_vocab_size().unwrap(), VOCAB_SIZE);
+        assert_eq!(
+            args(&["--vocab-size", "4K"]).checked_vocab_size().unwrap(),
+            4096
+        );
+        assert_eq!(
+            args(&["--num-merges", "100"]).checked_vocab_size().unwrap(),
+            356
+        );
+        assert!(args(&["--vocab-size", "200"]).checked_vocab_size().is_err());
+        assert!(
+            Cli::try_parse_from(["bpe", "train", "--vocab-size", "300", "--num-merges", "44"])
+                .is_err()
+        );
+
+        let mut both = args(&[]);
+        both.apply_config(TrainConfig {
+            vocab_size: Some(1000),
+            num_merges: Some(10),
+            ..TrainConfig::default()
+        })
+        .unwrap();
+        assert!(both.checked_vocab_size().is_err());
+    }
+
+    #[test]
+    fn test_cli() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+}
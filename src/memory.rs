@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem::size_of;
+
+// memory budgeting
+//
+// Training keeps every chunk as its own id vector, rebuilt on each merge.
+// When the corpus repeats a lot (it almost always does), collapsing equal
+// chunks into one entry with an occurrence count needs far less memory for
+// the same merges. These are rough estimates of the working set, meant to
+// pick a representation and to fail before hours of training, not to be
+// exact.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Representation {
+    /// One id vector per chunk occurrence.
+    Chunks,
+    /// One id vector per distinct chunk, with its count.
+    Words,
+}
+
+#[derive(Debug)]
+pub struct Estimate {
+    pub chunks: usize,
+    pub words: usize,
+}
+
+const VEC_OVERHEAD: usize = size_of::<Vec<u32>>();
+// key, value and hash table slack for one pair count
+const PAIR_ENTRY: usize = 2 * (size_of::<(u32, u32)>() + size_of::<u32>());
+
+pub fn estimate(text_bytes: usize, chunks: &[&str], num_merges: u32) -> Estimate {
+    // the corpus text and the chunk slices into it are already resident
+    let corpus = text_bytes + std::mem::size_of_val(chunks);
+    // ids are 4 bytes per input byte, and merge() holds the old and new vectors
+    let ids = |bytes: usize| 2 * bytes * size_of::<u32>();
+    let num_ids: usize = chunks.iter().map(|c| c.len()).sum();
+    let alphabet = 256 + num_merges as usize;
+    let stats = num_ids.min(alphabet * alphabet) * PAIR_ENTRY;
+
+    let mut distinct: HashMap<&str, u32> = HashMap::new();
+    for &chunk in chunks {
+        *distinct.entry(chunk).or_default() += 1;
+    }
+    let distinct_ids: usize = distinct.keys().map(|c| c.len()).sum();
+    let counting = distinct.len() * 2 * (size_of::<&str>() + size_of::<u32>());
+    Estimate {
+        chunks: corpus + chunks.len() * VEC_OVERHEAD + ids(num_ids) + stats,
+        words: corpus
+            + counting
+            + distinct.len() * (VEC_OVERHEAD + size_of::<u32>())
+            + ids(distinct_ids)
+            + stats.min(distinct_ids * PAIR_ENTRY),
+    }
+}
+
+/// Picks the cheapest representation that fits in `budget` bytes, preferring
+/// the plain one, or fails with the estimate if neither does.
+pub fn plan(estimate: &Estimate, budget: usize) -> io::Result<Representation> {
+    if estimate.chunks <= budget {
+        Ok(Representation::Chunks)
+    } else if estimate.words <= budget {
+        Ok(Representation::Words)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            format!(
+                "training needs about {} even with word counts, over the {} budget; \
+                 try --sample-bytes, --dedup or --max-chunk-repeats",
+                human(estimate.words),
+                human(budget)
+            ),
+        ))
+    }
+}
+
+fn human(bytes: usize) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if size < 1024.0 {
+            return format!("{:.1} {}", size, unit);
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1} TiB", size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let chunks = vec![" the"; 10_000];
+        let estimate = estimate(40_000, &chunks, 100);
+        assert!(estimate.words < estimate.chunks);
+        assert_eq!(plan(&estimate, usize::MAX).unwrap(), Representation::Chunks);
+        assert_eq!(
+            plan(&estimate, estimate.words).unwrap(),
+            Representation::Words
+        );
+        assert!(plan(&estimate, estimate.words - 1).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::fetch;
+
+// model metadata
+//
+// Where a model came from, saved with it so a file found on a server says
+// how it was made: the version of this crate that trained it, when, the
+// training settings that shape the merges, and a fingerprint of the corpus.
+// The pattern and special tokens are part of the model itself and aren't
+// repeated here.
+//
+// The corpus fingerprint covers the pre-tokenized chunks training counted,
+// whatever their order: each distinct chunk is hashed on its own and the
+// hashes are summed, times the chunk's count, so reading files in another
+// order, on more threads, or counting chunks as words instead of one by one
+// gives the same fingerprint. Two fingerprints match when training saw the
+// same text, split the same way; sampling or deduplicating the corpus
+// changes it.
+//
+// The creation time honors `SOURCE_DATE_EPOCH`, as reproducible builds
+// define it, so training twice on the same corpus can give byte-identical
+// model files.
+
+/// How and when a model was trained.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Version of this crate that trained the model.
+    pub crate_version: String,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    /// Fingerprint of the training chunks, in hex.
+    pub corpus: String,
+    /// Training settings by flag name, such as `vocab-size`, in the order
+    /// they were recorded.
+    pub params: Vec<(String, String)>,
+}
+
+impl Metadata {
+    /// Metadata for a model trained now, or at `$SOURCE_DATE_EPOCH`.
+    pub fn new(corpus: String, params: Vec<(String, String)>) -> Metadata {
+        Metadata {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: created(std::env::var("SOURCE_DATE_EPOCH").ok().as_deref()),
+            corpus,
+            params,
+        }
+    }
+}
+
+/// The creation time: `source_date_epoch` if it is a number of seconds,
+/// else the current time.
+fn created(source_date_epoch: Option<&str>) -> u64 {
+    match source_date_epoch.map(|s| s.trim().parse()) {
+        Some(Ok(secs)) => secs,
+        _ => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+    }
+}
+
+/// Accumulates the corpus fingerprint, chunk by chunk in any order.
+#[derive(Default)]
+pub struct Fingerprint {
+    sum: [u64; 4],
+}
+
+impl Fingerprint {
+    pub fn add(&mut self, chunk: &[u8], count: u64) {
+        let digest = Sha256::digest(chunk);
+        for (lane, bytes) in self.sum.iter_mut().zip(digest.chunks_exact(8)) {
+            let n = u64::from_le_bytes(bytes.try_into().unwrap());
+            *lane = lane.wrapping_add(n.wrapping_mul(count));
+        }
+    }
+
+    /// Adds words as byte ids, before any merge.
+    pub fn add_words(&mut self, words: &[(Vec<u32>, u32)]) {
+        let mut bytes = vec![];
+        for (ids, n) in words {
+            bytes.clear();
+            bytes.extend(ids.iter().map(|&id| id as u8));
+            self.add(&bytes, *n as u64);
+        }
+    }
+
+    pub fn finish(&self) -> String {
+        let mut hasher = Sha256::new();
+        for lane in self.sum {
+            hasher.update(lane.to_le_bytes());
+        }
+        fetch::hex(&hasher.finalize())
+    }
+}
+
+/// A Unix time as a UTC date and time, e.g. `2026-10-15 09:33:52 UTC`.
+pub fn format_utc(secs: u64) -> String {
+    let (days, rest) = (secs / 86400, secs % 86400);
+    // days to a civil date, after Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint() {
+        let fingerprint = |chunks: &[(&str, u64)]| {
+            let mut f = Fingerprint::default();
+            for (chunk, n) in chunks {
+                f.add(chunk.as_bytes(), *n);
+            }
+            f.finish()
+        };
+        let counted = fingerprint(&[("the", 2), (" cat", 1)]);
+        assert_eq!(counted, fingerprint(&[(" cat", 1), ("the", 1), ("the", 1)]));
+        assert_ne!(counted, fingerprint(&[("the", 1), (" cat", 1)]));
+        assert_ne!(counted, fingerprint(&[("the", 2), (" dog", 1)]));
+        assert_eq!(counted.len(), 64);
+    }
+
+    #[test]
+    fn test_created() {
+        assert_eq!(created(Some("1700000000")), 1700000000);
+        assert_eq!(created(Some(" 42\n")), 42);
+        let now = created(None);
+        assert!(now > 1700000000);
+        assert!(created(Some("yesterday")) >= now);
+    }
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_utc(951782400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_utc(1791970432), "2026-10-14 09:33:52 UTC");
+    }
+}
@@ -0,0 +1,95 @@
+use std::time::Instant;
+
+use serde::Serialize;
+use tracing::info;
+
+// training metrics
+
+pub struct Metrics {
+    start: Instant,
+    interval: Option<u32>,
+    input_ids: usize,
+    ids_scanned: u64,
+    merges: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub merges: u32,
+    pub input_ids: usize,
+    pub elapsed_secs: f64,
+    pub merges_per_sec: f64,
+    pub tokens_per_sec: f64,
+    pub peak_rss_bytes: Option<u64>,
+}
+
+impl Metrics {
+    /// Starts the clock; with an `interval`, a progress report is logged
+    /// every that many merges.
+    pub fn new(input_ids: usize, interval: Option<u32>) -> Metrics {
+        Metrics {
+            start: Instant::now(),
+            interval,
+            input_ids,
+            ids_scanned: 0,
+            merges: 0,
+        }
+    }
+
+    /// Records one merge whose statistics pass scanned `ids` tokens.
+    pub fn record_merge(&mut self, ids: usize) {
+        self.merges += 1;
+        self.ids_scanned += ids as u64;
+        if let Some(interval) = self.interval.filter(|&n| n > 0) {
+            if self.merges.is_multiple_of(interval) {
+                let r = self.report();
+                info!(
+                    merges = r.merges,
+                    merges_per_sec = r.merges_per_sec,
+                    tokens_per_sec = r.tokens_per_sec,
+                    peak_rss_bytes = r.peak_rss_bytes,
+                    "training progress"
+                );
+            }
+        }
+    }
+
+    pub fn report(&self) -> Report {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = |n: f64| if elapsed > 0.0 { n / elapsed } else { 0.0 };
+        Report {
+            merges: self.merges,
+            input_ids: self.input_ids,
+            elapsed_secs: elapsed,
+            merges_per_sec: rate(self.merges as f64),
+            tokens_per_sec: rate(self.ids_scanned as f64),
+            peak_rss_bytes: peak_rss(),
+        }
+    }
+}
+
+/// The process's peak resident set size, where the platform reports it.
+pub fn peak_rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut metrics = Metrics::new(100, None);
+        metrics.record_merge(100);
+        metrics.record_merge(90);
+        let report = metrics.report();
+        assert_eq!(report.merges, 2);
+        assert_eq!(report.input_ids, 100);
+        assert!(report.tokens_per_sec >= 0.0);
+        #[cfg(target_os = "linux")]
+        assert!(report.peak_rss_bytes.unwrap() > 0);
+    }
+}
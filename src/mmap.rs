@@ -0,0 +1,436 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::build_vocab;
+use crate::case::Markers;
+use crate::model::Model;
+use crate::pretokenize::{self, Splitter};
+use crate::tokenizer::Tokenize;
+
+// memory-mapped models
+//
+// A layout that is used in place instead of being parsed, so opening a
+// model costs a page fault per table touched rather than a full load. All
+// integers are little-endian u32s, and every table starts 4-byte aligned:
+//
+//   magic "BPEM", version
+//   merge count, special token count, pattern length
+//   merges by rank:        (left, right)            for each merge
+//   merges by pair:        (left, right, id)        sorted, for binary search
+//   token offsets:         start of each token in the arena, plus the end
+//   arena:                 the bytes of every token, in id order
+//   pattern:               UTF-8, empty if the text is not split
+//
+// `write_stripped` leaves out of the arena the bytes of tokens that encoding
+// never emits (`prune::unreachable`), giving them empty entries; their
+// merges stay, since other tokens are built through them.
+//
+// Opening checks the counts and token offsets against the file's length,
+// a pass over the offsets table only, so a corrupt file fails to open
+// instead of panicking when a token is looked up.
+
+pub const MAGIC: &[u8; 4] = b"BPEM";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = 20;
+
+pub fn write(path: &Path, model: &Model) -> io::Result<()> {
+    write_stripped(path, model, &[])
+}
+
+/// Writes a model with no bytes in the arena for the tokens `dropped`.
+pub fn write_stripped(path: &Path, model: &Model, dropped: &[u32]) -> io::Result<()> {
+    if model.whitespace_marker {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "memory-mapped models don't support the whitespace marker",
+        ));
+    }
+    // the mapped encoder neither lowercases text nor recases it on decode
+    if Markers::find(&model.special_tokens).is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "memory-mapped models don't support case markers",
+        ));
+    }
+    let mut w = BufWriter::new(File::create(path)?);
+    let mut ranked: Vec<_> = model.merges.iter().map(|(&p, &idx)| (idx, p)).collect();
+    ranked.sort();
+    let mut by_pair: Vec<_> = model.merges.iter().map(|(&p, &idx)| (p, idx)).collect();
+    by_pair.sort();
+    let mut vocab = build_vocab(&model.merges);
+    for (token, &idx) in &model.special_tokens {
+        vocab.insert(idx, token.as_bytes().to_vec());
+    }
+    if (0..vocab.len() as u32).any(|id| !vocab.contains_key(&id)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "token ids must be contiguous to be memory-mapped",
+        ));
+    }
+    for id in dropped {
+        if let Some(bytes) = vocab.get_mut(id) {
+            bytes.clear();
+        }
+    }
+    let pattern = model.pattern.as_deref().unwrap_or("");
+
+    let put = |w: &mut BufWriter<File>, n: u32| w.write_all(&n.to_le_bytes());
+    w.write_all(MAGIC)?;
+    put(&mut w, VERSION)?;
+    put(&mut w, ranked.len() as u32)?;
+    put(&mut w, model.special_tokens.len() as u32)?;
+    put(&mut w, pattern.len() as u32)?;
+    for (_, (p0, p1)) in &ranked {
+        put(&mut w, *p0)?;
+        put(&mut w, *p1)?;
+    }
+    for ((p0, p1), idx) in &by_pair {
+        put(&mut w, *p0)?;
+        put(&mut w, *p1)?;
+        put(&mut w, *idx)?;
+    }
+    let mut offset = 0;
+    for id in 0..vocab.len() as u32 {
+        put(&mut w, offset)?;
+        offset += vocab[&id].len() as u32;
+    }
+    put(&mut w, offset)?;
+    for id in 0..vocab.len() as u32 {
+        w.write_all(&vocab[&id])?;
+    }
+    w.write_all(pattern.as_bytes())?;
+    w.flush()
+}
+
+/// A model used straight from a memory-mapped file.
+pub struct MappedModel {
+    map: Mmap,
+    num_merges: usize,
+    num_special: usize,
+    splitter: Option<Splitter>,
+}
+
+impl MappedModel {
+    pub fn open(path: &Path) -> io::Result<MappedModel> {
+        let file = File::open(path)?;
+        // SAFETY: the map is read-only; like every mmap user we rely on the
+        // file not being truncated or rewritten while it is open.
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < HEADER_LEN || &map[..4] != MAGIC {
+            return Err(invalid("not a memory-mapped model".into()));
+        }
+        let field = |i: usize| read_u32(&map, 4 + 4 * i) as usize;
+        if field(0) != VERSION as usize {
+            return Err(invalid(format!(
+                "unsupported mapped model version {}",
+                field(0)
+            )));
+        }
+        let (num_merges, num_special, pattern_len) = (field(1), field(2), field(3));
+        // the counts come from the file, so table sizes are computed in u64
+        // where no u32 count can overflow them
+        let vocab_size = 256 + num_merges as u64 + num_special as u64;
+        let arena = HEADER_LEN as u64 + 20 * num_merges as u64 + 4 * (vocab_size + 1);
+        if (map.len() as u64) < arena {
+            return Err(invalid("truncated memory-mapped model".into()));
+        }
+        let mut model = MappedModel {
+            map,
+            num_merges,
+            num_special,
+            splitter: None,
+        };
+        // token offsets must start at 0 and never decrease, so every token's
+        // bytes lie inside the arena once its end is checked
+        let mut end = 0;
+        for id in 0..=model.vocab_size() {
+            let offset = model.offset(id);
+            if offset < end || (id == 0 && offset != 0) {
+                return Err(invalid(format!("token {} has a corrupt offset", id)));
+            }
+            end = offset;
+        }
+        let arena_end = arena + end as u64;
+        if model.map.len() as u64 != arena_end + pattern_len as u64 {
+            return Err(invalid("truncated memory-mapped model".into()));
+        }
+        let pattern = std::str::from_utf8(&model.map[arena_end as usize..])
+            .map_err(|_| invalid("pattern is not UTF-8".into()))?;
+        if !pattern.is_empty() {
+            model.splitter = Some(Splitter::new(pattern)?);
+        }
+        Ok(model)
+    }
+
+    fn by_rank(&self) -> usize {
+        HEADER_LEN
+    }
+
+    fn by_pair(&self) -> usize {
+        self.by_rank() + 8 * self.num_merges
+    }
+
+    fn offsets(&self) -> usize {
+        self.by_pair() + 12 * self.num_merges
+    }
+
+    fn arena(&self) -> usize {
+        self.offsets() + 4 * (self.vocab_size() + 1)
+    }
+
+    fn offset(&self, id: usize) -> u32 {
+        read_u32(&self.map, self.offsets() + 4 * id)
+    }
+
+    /// The bytes a token id stands for, empty for an id past the vocabulary.
+    pub fn token_bytes(&self, id: u32) -> &[u8] {
+        if id as usize >= self.vocab_size() {
+            return &[];
+        }
+        let (start, end) = (self.offset(id as usize), self.offset(id as usize + 1));
+        &self.map[self.arena() + start as usize..self.arena() + end as usize]
+    }
+
+    /// The pair merged at `rank`.
+    pub fn merge_at(&self, rank: usize) -> (u32, u32) {
+        let at = self.by_rank() + 8 * rank;
+        (read_u32(&self.map, at), read_u32(&self.map, at + 4))
+    }
+
+    /// The token a pair merges into, found by binary search.
+    pub fn merged(&self, pair: (u32, u32)) -> Option<u32> {
+        let entry = |i: usize| {
+            let at = self.by_pair() + 12 * i;
+            let key = (read_u32(&self.map, at), read_u32(&self.map, at + 4));
+            (key, read_u32(&self.map, at + 8))
+        };
+        let (mut lo, mut hi) = (0, self.num_merges);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let (key, idx) = entry(mid);
+            match key.cmp(&pair) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Some(idx),
+            }
+        }
+        None
+    }
+
+    /// The model the file was written from, with a stripped model's tokens
+    /// back in place, since merges rebuild them.
+    pub fn to_model(&self) -> Model {
+        let merges = (0..self.num_merges)
+            .map(|rank| (self.merge_at(rank), 256 + rank as u32))
+            .collect();
+        let special_tokens = (256 + self.num_merges..self.vocab_size())
+            .map(|id| {
+                let bytes = self.token_bytes(id as u32);
+                (String::from_utf8_lossy(bytes).into_owned(), id as u32)
+            })
+            .collect();
+        Model {
+            merges,
+            pattern: self.splitter.as_ref().map(|s| s.pattern().to_string()),
+            special_tokens,
+            whitespace_marker: false,
+            metadata: None,
+        }
+    }
+
+    fn encode_chunk(&self, chunk: &str) -> Vec<u32> {
+        let mut ids: Vec<u32> = chunk.as_bytes().iter().map(|&b| b.into()).collect();
+        while let Some((i, idx)) = crate::lowest_rank_pair(&ids, |pair| self.merged(pair)) {
+            ids = crate::merge(&ids, (ids[i], ids[i + 1]), idx);
+        }
+        ids
+    }
+}
+
+impl Tokenize for MappedModel {
+    fn encode(&self, text: &str) -> Vec<u32> {
+        pretokenize::split(self.splitter.as_ref(), text)
+            .into_iter()
+            .flat_map(|chunk| self.encode_chunk(chunk))
+            .collect()
+    }
+
+    fn decode(&self, ids: &[u32]) -> String {
+        let bytes: Vec<u8> = ids
+            .iter()
+            .flat_map(|&id| self.token_bytes(id))
+            .copied()
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    fn vocab_size(&self) -> usize {
+        256 + self.num_merges + self.num_special
+    }
+
+    fn is_special(&self, id: u32) -> bool {
+        (256 + self.num_merges..self.vocab_size()).contains(&(id as usize))
+    }
+
+    fn id_bytes(&self, id: u32) -> Vec<u8> {
+        self.token_bytes(id).to_vec()
+    }
+
+    fn rank(&self, id: u32) -> Option<u32> {
+        (256..256 + self.num_merges)
+            .contains(&(id as usize))
+            .then(|| id - 256)
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::Tokenizer;
+
+    #[test]
+    fn test_write_open() {
+        let model = Model {
+            merges: HashMap::from([((104, 105), 256), ((256, 33), 257), ((32, 256), 258)]),
+            pattern: Some(r"\s*\S+".to_string()),
+            special_tokens: HashMap::from([("<|end|>".to_string(), 259)]),
+            whitespace_marker: false,
+            metadata: None,
+        };
+        let path = std::env::temp_dir().join(format!("bpe-test-{}.bpem", std::process::id()));
+        write(&path, &model).unwrap();
+        let mapped = MappedModel::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(mapped.vocab_size(), 260);
+        assert_eq!(mapped.token_bytes(258), b" hi");
+        assert_eq!(mapped.token_bytes(259), b"<|end|>");
+        assert_eq!(mapped.merge_at(1), (256, 33));
+        assert_eq!(mapped.merged((32, 256)), Some(258));
+        assert_eq!(mapped.merged((33, 33)), None);
+        let read = mapped.to_model();
+        assert_eq!(read.merges, model.merges);
+        assert_eq!(read.special_tokens, model.special_tokens);
+        assert_eq!(read.pattern, model.pattern);
+        let text = "hi! hi hid";
+        let tokenizer = Tokenizer::new(model).unwrap();
+        assert_eq!(mapped.encode(text), tokenizer.encode(text));
+        assert_eq!(mapped.decode(&mapped.encode(text)), text);
+    }
+
+    #[test]
+    fn test_write_cased() {
+        let model = |special_tokens| Model {
+            merges: HashMap::from([((104, 105), 256), ((72, 105), 257), ((32, 256), 258)]),
+            pattern: Some(r"\s*\S+".to_string()),
+            special_tokens,
+            whitespace_marker: false,
+            metadata: None,
+        };
+        let path = std::env::temp_dir().join(format!("bpe-test-{}-cased.bpem", std::process::id()));
+        write(&path, &model(HashMap::new())).unwrap();
+        let mapped = MappedModel::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let text = "Hi hi HI hI";
+        let tokenizer = Tokenizer::new(model(HashMap::new())).unwrap();
+        assert_eq!(mapped.encode(text), tokenizer.encode(text));
+        assert_eq!(mapped.decode(&mapped.encode(text)), text);
+
+        // with case markers the mapped encoder would disagree, so it's refused
+        let cased = model(HashMap::from([
+            (crate::case::CAPITALIZED.to_string(), 259),
+            (crate::case::UPPERCASE.to_string(), 260),
+        ]));
+        let tokenizer = Tokenizer::new(model(cased.special_tokens.clone())).unwrap();
+        assert_ne!(tokenizer.encode(text), mapped.encode(text));
+        let err = write(&path, &cased).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_write_stripped() {
+        let model = Model {
+            merges: HashMap::from([((98, 99), 256), ((97, 98), 257), ((257, 99), 258)]),
+            pattern: None,
+            special_tokens: HashMap::new(),
+            whitespace_marker: false,
+            metadata: None,
+        };
+        let dropped = crate::prune::unreachable(&model.merges);
+        let path =
+            std::env::temp_dir().join(format!("bpe-test-{}-stripped.bpem", std::process::id()));
+        write_stripped(&path, &model, &dropped).unwrap();
+        let mapped = MappedModel::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mapped.token_bytes(258), b"");
+        assert_eq!(mapped.merge_at(2), (257, 99));
+        assert_eq!(mapped.to_model().merges, model.merges);
+        let text = "abcab abc";
+        assert_eq!(mapped.decode(&mapped.encode(text)), text);
+    }
+
+    #[test]
+    fn test_open_corrupt() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tiny.bpem");
+        let bytes = std::fs::read(fixture).unwrap();
+        let path =
+            std::env::temp_dir().join(format!("bpe-test-{}-corrupt.bpem", std::process::id()));
+        let open = |bytes: &[u8]| {
+            std::fs::write(&path, bytes).unwrap();
+            MappedModel::open(&path)
+                .map(|_| ())
+                .unwrap_err()
+                .to_string()
+        };
+        let put = |at: usize, n: u32| {
+            let mut corrupt = bytes.clone();
+            corrupt[at..at + 4].copy_from_slice(&n.to_le_bytes());
+            corrupt
+        };
+        // counts too large for the file, even with overflow
+        assert!(open(&put(8, 1000)).contains("truncated"));
+        assert!(open(&put(12, u32::MAX)).contains("truncated"));
+        // the offset of token 2 before that of token 1, then past the arena
+        let offsets = HEADER_LEN + 20 * 3;
+        assert!(open(&put(offsets + 8, 0)).contains("token 2 has a corrupt offset"));
+        assert!(open(&put(offsets + 4 * 260, u32::MAX)).contains("truncated"));
+        assert!(open(&bytes[..bytes.len() - 1]).contains("truncated"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_fixture() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let path = fixtures.join("tiny.bpem");
+        let mapped = MappedModel::open(&path).unwrap();
+        assert_eq!(mapped.merge_at(2), (32, 256));
+        assert_eq!(mapped.merged((256, 33)), Some(257));
+        assert_eq!(mapped.token_bytes(258), b" hi");
+        assert_eq!(mapped.token_bytes(259), b"<|end|>");
+        assert_eq!(mapped.encode("hi! hi"), vec![257, 258]);
+
+        let model = crate::model::load(&fixtures.join("tiny.bpe")).unwrap();
+        let written =
+            std::env::temp_dir().join(format!("bpe-test-{}-fixture.bpem", std::process::id()));
+        write(&written, &model).unwrap();
+        assert_eq!(
+            std::fs::read(&written).unwrap(),
+            std::fs::read(&path).unwrap()
+        );
+        std::fs::remove_file(&written).unwrap();
+    }
+}
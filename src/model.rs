@@ -0,0 +1,579 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::Path;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::Metadata;
+
+// model files
+//
+// A model is a header line, the pre-tokenizer pattern (empty if the text is
+// not split), the number of special tokens followed by one `token id` line
+// for each, the number of metadata lines followed by one `key value` line
+// for each, and then one merge per line, in rank order:
+//
+//   bpe v3
+//   's|'t| ?\p{L}+|...
+//   1
+//   <|endoftext|> 1024
+//   4
+//   crate-version 0.1.0
+//   created 1791970432
+//   corpus 3f9a...
+//   vocab-size 1024
+//   101 32
+//   116 104
+//   ...
+//
+// The merged token id is implied by the line position (256 + rank).
+// Models trained with the whitespace marker say so after the header, as
+// `bpe v3 whitespace-marker`. Metadata (see `metadata`) is `crate-version`,
+// `created` and `corpus` followed by the training settings; models that
+// weren't trained here, such as converted ones, have none.
+//
+// The binary format holds the same fields serialized with postcard after a
+// magic number. It is smaller and faster to parse, and suits models
+// embedded in other binaries. `load` accepts either format. Postcard's wire
+// format writes integers as little-endian base-128 varints and strings as
+// a length then UTF-8 bytes, so a file doesn't depend on the byte order or
+// word size of the machine that wrote it; the files in `tests/fixtures`
+// pin the exact bytes.
+//
+// Both formats carry a version, and files of older versions are migrated to
+// the current `Model` as they are read; files of newer versions are refused
+// rather than misread. Version 2 files are version 3 ones without metadata.
+// Version 1 text files all say `bpe v1` but come in
+// three layouts, as fields were added: merges only, then the pattern line
+// before them, then the special tokens as above. They are told apart by
+// their second and third lines. Version 1 binary files end with the
+// whitespace marker setting, serialized after the rest when set.
+
+/// The format version `save` and `to_binary` write.
+pub const VERSION: u32 = 3;
+const HEADER_PREFIX: &str = "bpe v";
+const WHITESPACE_MARKER: &str = "whitespace-marker";
+/// Followed by a version byte, which was 0 in version 1 files.
+const MAGIC: &[u8; 3] = b"BPE";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Line-based text
+    #[default]
+    Text,
+    /// Compact postcard encoding
+    Binary,
+    /// Layout used in place through a memory map, without parsing
+    Mapped,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Compact {
+    pattern: Option<String>,
+    special_tokens: Vec<(String, u32)>,
+    /// In rank order.
+    merges: Vec<(u32, u32)>,
+    whitespace_marker: bool,
+    metadata: Option<Metadata>,
+}
+
+/// A version 2 binary model, without metadata.
+#[derive(Serialize, Deserialize)]
+struct CompactV2 {
+    pattern: Option<String>,
+    special_tokens: Vec<(String, u32)>,
+    merges: Vec<(u32, u32)>,
+    whitespace_marker: bool,
+}
+
+/// A version 1 binary model, without the settings that came after it.
+#[derive(Serialize, Deserialize)]
+struct CompactV1 {
+    pattern: Option<String>,
+    special_tokens: Vec<(String, u32)>,
+    merges: Vec<(u32, u32)>,
+}
+
+/// Settings added to version 1 binary models, serialized after `CompactV1`
+/// when any is set.
+#[derive(Default, Serialize, Deserialize)]
+struct OptionsV1 {
+    whitespace_marker: bool,
+}
+
+pub struct Model {
+    pub merges: HashMap<(u32, u32), u32>,
+    pub pattern: Option<String>,
+    pub special_tokens: HashMap<String, u32>,
+    /// Spaces are replaced by `pretokenize::WHITESPACE_MARKER` after
+    /// splitting, as in SentencePiece vocabularies.
+    pub whitespace_marker: bool,
+    /// How the model was trained, if it was trained by this crate.
+    pub metadata: Option<Metadata>,
+}
+
+pub fn save(path: &Path, model: &Model) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    if model.whitespace_marker {
+        writeln!(w, "{}{} {}", HEADER_PREFIX, VERSION, WHITESPACE_MARKER)?;
+    } else {
+        writeln!(w, "{}{}", HEADER_PREFIX, VERSION)?;
+    }
+    writeln!(w, "{}", model.pattern.as_deref().unwrap_or(""))?;
+    let mut special: Vec<_> = model.special_tokens.iter().collect();
+    special.sort_by_key(|&(_, idx)| idx);
+    writeln!(w, "{}", special.len())?;
+    for (token, idx) in special {
+        writeln!(w, "{} {}", token, idx)?;
+    }
+    let metadata = metadata_lines(model.metadata.as_ref())?;
+    writeln!(w, "{}", metadata.len())?;
+    for (key, value) in metadata {
+        writeln!(w, "{} {}", key, value)?;
+    }
+    let mut merges: Vec<_> = model.merges.iter().map(|(&p, &idx)| (idx, p)).collect();
+    merges.sort_by_key(|&(idx, _)| idx);
+    for (_, (p0, p1)) in merges {
+        writeln!(w, "{} {}", p0, p1)?;
+    }
+    w.flush()
+}
+
+/// The metadata of a text model, as `key value` lines.
+fn metadata_lines(metadata: Option<&Metadata>) -> io::Result<Vec<(String, String)>> {
+    let Some(metadata) = metadata else {
+        return Ok(vec![]);
+    };
+    let lines: Vec<(String, String)> = [
+        ("crate-version", metadata.crate_version.clone()),
+        ("created", metadata.created.to_string()),
+        ("corpus", metadata.corpus.clone()),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value))
+    .chain(metadata.params.iter().cloned())
+    .collect();
+    for (key, value) in &lines {
+        if key.is_empty() || key.contains([' ', '\n']) || value.contains('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("metadata {:?} = {:?} doesn't fit on a line", key, value),
+            ));
+        }
+    }
+    Ok(lines)
+}
+
+pub fn save_as(path: &Path, model: &Model, format: Format) -> io::Result<()> {
+    match format {
+        Format::Text => save(path, model),
+        Format::Binary => fs::write(path, to_binary(model)?),
+        Format::Mapped => crate::mmap::write(path, model),
+    }
+}
+
+pub fn to_binary(model: &Model) -> io::Result<Vec<u8>> {
+    let mut special_tokens: Vec<_> = model
+        .special_tokens
+        .iter()
+        .map(|(token, &idx)| (token.clone(), idx))
+        .collect();
+    special_tokens.sort_by_key(|&(_, idx)| idx);
+    let mut merges: Vec<_> = model.merges.iter().map(|(&p, &idx)| (idx, p)).collect();
+    merges.sort_by_key(|&(idx, _)| idx);
+    let compact = Compact {
+        pattern: model.pattern.clone(),
+        special_tokens,
+        merges: merges.into_iter().map(|(_, p)| p).collect(),
+        whitespace_marker: model.whitespace_marker,
+        metadata: model.metadata.clone(),
+    };
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION as u8);
+    postcard::to_io(&compact, &mut bytes).map_err(|e| invalid(e.to_string()))?;
+    Ok(bytes)
+}
+
+/// Loads a model file in any of the formats `save_as` writes.
+pub fn load(path: &Path) -> io::Result<Model> {
+    let bytes = fs::read(path)?;
+    if bytes.starts_with(crate::mmap::MAGIC) {
+        return Ok(crate::mmap::MappedModel::open(path)?.to_model());
+    }
+    from_bytes(&bytes)
+}
+
+/// Parses a text or binary model, e.g. one embedded with `include_bytes!`.
+/// Memory-mapped models are only read from files, by `load`.
+pub fn from_bytes(bytes: &[u8]) -> io::Result<Model> {
+    // checked first, as the mapped magic starts with the binary one
+    if bytes.starts_with(crate::mmap::MAGIC) {
+        return Err(invalid(
+            "memory-mapped models can't be parsed from bytes; open the file instead".into(),
+        ));
+    }
+    match bytes.strip_prefix(MAGIC) {
+        Some([version, payload @ ..]) => read_binary(*version, payload),
+        _ => read(bytes),
+    }
+}
+
+fn read_binary(version: u8, payload: &[u8]) -> io::Result<Model> {
+    let bad = |e: postcard::Error| invalid(format!("bad binary model: {}", e));
+    let compact = match version {
+        0 => {
+            let (v1, rest): (CompactV1, _) = postcard::take_from_bytes(payload).map_err(bad)?;
+            let options: OptionsV1 = match rest {
+                [] => OptionsV1::default(),
+                rest => postcard::from_bytes(rest).map_err(bad)?,
+            };
+            Compact {
+                pattern: v1.pattern,
+                special_tokens: v1.special_tokens,
+                merges: v1.merges,
+                whitespace_marker: options.whitespace_marker,
+                metadata: None,
+            }
+        }
+        2 => {
+            let v2: CompactV2 = match postcard::take_from_bytes(payload).map_err(bad)? {
+                (v2, []) => v2,
+                _ => return Err(invalid("bad binary model: trailing bytes".into())),
+            };
+            Compact {
+                pattern: v2.pattern,
+                special_tokens: v2.special_tokens,
+                merges: v2.merges,
+                whitespace_marker: v2.whitespace_marker,
+                metadata: None,
+            }
+        }
+        3 => match postcard::take_from_bytes(payload).map_err(bad)? {
+            (compact, []) => compact,
+            _ => return Err(invalid("bad binary model: trailing bytes".into())),
+        },
+        version => return Err(newer(version.into())),
+    };
+    let mut merges = HashMap::new();
+    for (rank, pair) in compact.merges.into_iter().enumerate() {
+        let idx = 256 + rank as u32;
+        if pair.0 >= idx || pair.1 >= idx {
+            return Err(invalid(format!("merge {} references unknown token", rank)));
+        }
+        merges.insert(pair, idx);
+    }
+    Ok(Model {
+        merges,
+        pattern: compact.pattern,
+        special_tokens: compact.special_tokens.into_iter().collect(),
+        whitespace_marker: compact.whitespace_marker,
+        metadata: compact.metadata,
+    })
+}
+
+fn newer(version: u32) -> io::Error {
+    invalid(format!(
+        "model format v{} is newer than this bpe reads (v{}); upgrade bpe to load it",
+        version, VERSION
+    ))
+}
+
+/// Whether a line is a merge, two ids separated by a space.
+fn is_merge_line(line: &str) -> bool {
+    line.split_once(' ')
+        .is_some_and(|(a, b)| a.parse::<u32>().is_ok() && b.parse::<u32>().is_ok())
+}
+
+fn read(reader: impl BufRead) -> io::Result<Model> {
+    let lines: Vec<String> = reader.lines().collect::<io::Result<_>>()?;
+    let header = lines
+        .first()
+        .ok_or_else(|| invalid("missing model header".into()))?;
+    let (version, flags) = header
+        .strip_prefix(HEADER_PREFIX)
+        .map(|rest| rest.split_once(' ').unwrap_or((rest, "")))
+        .and_then(|(version, flags)| Some((version.parse::<u32>().ok()?, flags)))
+        .ok_or_else(|| invalid("missing model header".into()))?;
+    let whitespace_marker = match flags {
+        "" => false,
+        WHITESPACE_MARKER => true,
+        _ => return Err(invalid(format!("unknown model flags {:?}", flags))),
+    };
+    // which fields come before the merges
+    let (has_pattern, has_special_tokens, has_metadata) = match version {
+        3 => (true, true, true),
+        2 => (true, true, false),
+        1 if lines.get(1).is_some_and(|line| is_merge_line(line)) => (false, false, false),
+        1 if lines
+            .get(2)
+            .is_some_and(|line| line.parse::<usize>().is_ok()) =>
+        {
+            (true, true, false)
+        }
+        1 => (true, false, false),
+        0 => return Err(invalid("missing model header".into())),
+        version => return Err(newer(version)),
+    };
+    let mut lines = lines.iter().map(String::as_str).zip(1..).skip(1);
+    let mut next = |what: &str| {
+        lines
+            .next()
+            .ok_or_else(|| invalid(format!("missing {}", what)))
+    };
+    let pattern = match has_pattern {
+        true => next("pattern line")?.0,
+        false => "",
+    };
+    let mut special_tokens = HashMap::new();
+    if has_special_tokens {
+        let (count, n) = next("special token count")?;
+        let count: usize = count
+            .parse()
+            .map_err(|_| invalid(format!("bad special token count on line {}", n)))?;
+        for _ in 0..count {
+            let (line, n) = next("special token")?;
+            let (token, idx) = line
+                .rsplit_once(' ')
+                .and_then(|(token, idx)| Some((token.to_string(), idx.parse().ok()?)))
+                .ok_or_else(|| invalid(format!("bad special token on line {}: {:?}", n, line)))?;
+            special_tokens.insert(token, idx);
+        }
+    }
+    let mut metadata = None;
+    if has_metadata {
+        let (count, n) = next("metadata count")?;
+        let count: usize = count
+            .parse()
+            .map_err(|_| invalid(format!("bad metadata count on line {}", n)))?;
+        for _ in 0..count {
+            let (line, n) = next("metadata")?;
+            let (key, value) = line
+                .split_once(' ')
+                .ok_or_else(|| invalid(format!("bad metadata on line {}: {:?}", n, line)))?;
+            let metadata: &mut Metadata = metadata.get_or_insert_with(Metadata::default);
+            match key {
+                "crate-version" => metadata.crate_version = value.to_string(),
+                "created" => {
+                    metadata.created = value.parse().map_err(|_| {
+                        invalid(format!("bad creation time on line {}: {:?}", n, value))
+                    })?
+                }
+                "corpus" => metadata.corpus = value.to_string(),
+                _ => metadata.params.push((key.to_string(), value.to_string())),
+            }
+        }
+    }
+    let mut merges = HashMap::new();
+    for (line, n) in lines {
+        let pair = line
+            .split_once(' ')
+            .and_then(|(a, b)| Some((a.parse().ok()?, b.parse().ok()?)))
+            .ok_or_else(|| invalid(format!("bad merge on line {}: {:?}", n, line)))?;
+        let idx = 256 + merges.len() as u32;
+        if pair.0 >= idx || pair.1 >= idx {
+            return Err(invalid(format!(
+                "merge on line {} references unknown token",
+                n
+            )));
+        }
+        merges.insert(pair, idx);
+    }
+    Ok(Model {
+        merges,
+        pattern: Some(pattern.to_string()).filter(|p| !p.is_empty()),
+        special_tokens,
+        whitespace_marker,
+        metadata,
+    })
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read() {
+        let model = read("bpe v2\n\n0\n104 105\n256 33\n".as_bytes()).unwrap();
+        assert_eq!(model.merges[&(104, 105)], 256);
+        assert_eq!(model.merges[&(256, 33)], 257);
+        assert_eq!(model.pattern, None);
+        let model = read("bpe v2\n \\w+\n1\n<|end of text|> 256\n".as_bytes()).unwrap();
+        assert_eq!(model.pattern.as_deref(), Some(" \\w+"));
+        assert_eq!(model.special_tokens["<|end of text|>"], 256);
+        assert!(read("bpe v2\n\n0\n300 1\n".as_bytes()).is_err());
+        assert!(read("bpe v2\n\n2\n<|a|> 256\n".as_bytes()).is_err());
+        assert!(read("104 105\n".as_bytes()).is_err());
+        let model = read("bpe v2 whitespace-marker\n\n0\n".as_bytes()).unwrap();
+        assert!(model.whitespace_marker);
+        assert!(read("bpe v2 other\n\n0\n".as_bytes()).is_err());
+
+        let model = read("bpe v3\n\n0\n2\ncreated 12\nseed 7 8\n104 105\n".as_bytes()).unwrap();
+        let metadata = model.metadata.unwrap();
+        assert_eq!(metadata.created, 12);
+        assert_eq!(metadata.params, [("seed".to_string(), "7 8".to_string())]);
+        assert_eq!(model.merges.len(), 1);
+        assert!(read("bpe v3\n\n0\n0\n".as_bytes())
+            .unwrap()
+            .metadata
+            .is_none());
+        assert!(read("bpe v3\n\n0\n1\ncreated soon\n".as_bytes()).is_err());
+        assert!(read("bpe v3\n\n0\n1\n".as_bytes()).is_err());
+        let bad = Metadata {
+            params: vec![("a b".to_string(), "c".to_string())],
+            ..Metadata::default()
+        };
+        assert!(metadata_lines(Some(&bad)).is_err());
+    }
+
+    #[test]
+    fn test_binary() {
+        let model = read("bpe v1\n\\w+\n1\n<|end|> 258\n104 105\n256 33\n".as_bytes()).unwrap();
+        let bytes = to_binary(&model).unwrap();
+        let loaded = from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.merges, model.merges);
+        assert_eq!(loaded.pattern, model.pattern);
+        assert_eq!(loaded.special_tokens, model.special_tokens);
+        assert!(!loaded.whitespace_marker);
+        assert!(from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let model = Model {
+            whitespace_marker: true,
+            ..model
+        };
+        assert!(
+            from_bytes(&to_binary(&model).unwrap())
+                .unwrap()
+                .whitespace_marker
+        );
+    }
+
+    #[test]
+    fn test_migrate() {
+        // the three layouts of version 1 text files
+        let model = read("bpe v1\n104 105\n256 33\n".as_bytes()).unwrap();
+        assert_eq!(model.merges[&(256, 33)], 257);
+        assert_eq!(model.pattern, None);
+        let model = read("bpe v1\n\\w+\n104 105\n".as_bytes()).unwrap();
+        assert_eq!(model.pattern.as_deref(), Some("\\w+"));
+        assert_eq!(model.merges[&(104, 105)], 256);
+        let model = read("bpe v1\n\\w+\n".as_bytes()).unwrap();
+        assert!(model.merges.is_empty());
+        let model = read("bpe v1 whitespace-marker\n\n1\n<|end|> 257\n104 105\n".as_bytes());
+        let model = model.unwrap();
+        assert!(model.whitespace_marker);
+        assert_eq!(model.special_tokens["<|end|>"], 257);
+        assert_eq!(model.merges.len(), 1);
+
+        // a version 1 binary file, with the whitespace marker after the rest
+        let mut bytes = b"BPE\0".to_vec();
+        let v1 = CompactV1 {
+            pattern: None,
+            special_tokens: vec![("<|end|>".to_string(), 257)],
+            merges: vec![(104, 105)],
+        };
+        postcard::to_io(&v1, &mut bytes).unwrap();
+        let model = from_bytes(&bytes).unwrap();
+        assert!(!model.whitespace_marker);
+        assert_eq!(model.special_tokens["<|end|>"], 257);
+        let options = OptionsV1 {
+            whitespace_marker: true,
+        };
+        postcard::to_io(&options, &mut bytes).unwrap();
+        assert!(from_bytes(&bytes).unwrap().whitespace_marker);
+
+        // newer versions are refused
+        let err = read("bpe v4\n\n0\n0\n".as_bytes()).err().unwrap();
+        assert!(err.to_string().contains("newer"));
+        let mut bytes = to_binary(&model).unwrap();
+        bytes[3] = 4;
+        assert!(from_bytes(&bytes)
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("newer"));
+    }
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    /// Checked-in files must load, and be written again, byte for byte the
+    /// same on every platform.
+    #[test]
+    fn test_fixtures() {
+        let text = fs::read(fixture("tiny.bpe")).unwrap();
+        let model = from_bytes(&text).unwrap();
+        let merges = HashMap::from([((104, 105), 256), ((256, 33), 257), ((32, 256), 258)]);
+        for name in [
+            "tiny.bpe",
+            "tiny.bin",
+            "tiny-v2.bpe",
+            "tiny-v2.bin",
+            "tiny-v1.bin",
+        ] {
+            let loaded = load(&fixture(name)).unwrap();
+            assert_eq!(loaded.merges, merges, "{}", name);
+            assert_eq!(loaded.pattern.as_deref(), Some(r"\s*\S+"));
+            assert_eq!(loaded.special_tokens["<|end|>"], 259);
+            assert!(!loaded.whitespace_marker);
+            assert_eq!(loaded.metadata.is_some(), !name.contains("-v"), "{}", name);
+        }
+        let metadata = model.metadata.as_ref().unwrap();
+        assert_eq!(metadata.crate_version, "0.1.0");
+        assert_eq!(metadata.created, 1791970432);
+        assert_eq!(metadata.params[0], ("vocab-size".into(), "259".into()));
+        assert_eq!(
+            to_binary(&model).unwrap(),
+            fs::read(fixture("tiny.bin")).unwrap()
+        );
+        let path =
+            std::env::temp_dir().join(format!("bpe-test-{}-fixture.bpe", std::process::id()));
+        save(&path, &model).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), text);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_deterministic() {
+        // the same model built in opposite orders, into maps hashed
+        // differently
+        let merges: Vec<((u32, u32), u32)> = (0..200).map(|i| ((i % 256, 97), 256 + i)).collect();
+        let special = [("<|a|>".to_string(), 456), ("<|b|>".to_string(), 457)];
+        let model = |reverse: bool| Model {
+            merges: match reverse {
+                true => merges.iter().rev().copied().collect(),
+                false => merges.iter().copied().collect(),
+            },
+            pattern: None,
+            special_tokens: match reverse {
+                true => special.iter().rev().cloned().collect(),
+                false => special.iter().cloned().collect(),
+            },
+            whitespace_marker: false,
+            metadata: None,
+        };
+        let (a, b) = (model(false), model(true));
+        assert_eq!(to_binary(&a).unwrap(), to_binary(&b).unwrap());
+        let path = |name: &str| {
+            std::env::temp_dir().join(format!("bpe-test-{}-{}.bpe", std::process::id(), name))
+        };
+        save(&path("a"), &a).unwrap();
+        save(&path("b"), &b).unwrap();
+        assert_eq!(fs::read(path("a")).unwrap(), fs::read(path("b")).unwrap());
+        assert!(fs::read_to_string(path("a"))
+            .unwrap()
+            .starts_with("bpe v3\n"));
+        fs::remove_file(path("a")).unwrap();
+        fs::remove_file(path("b")).unwrap();
+    }
+}
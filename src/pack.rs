@@ -0,0 +1,79 @@
+use std::io;
+
+// sequence packing
+//
+// Causal language models train on blocks of a fixed length rather than on
+// documents. The encoded documents are concatenated, each followed by an
+// EOS id, and the stream is cut every `block_len` ids, so no position is
+// spent on padding; a document can start in one block and end in the next,
+// and the EOS marks where it ended. The ids after the last full block are
+// dropped, as Hugging Face's `group_texts` does.
+
+pub struct Packer {
+    block_len: usize,
+    eos: u32,
+    /// Ids not yet in a full block.
+    pending: Vec<u32>,
+}
+
+impl Packer {
+    pub fn new(block_len: usize, eos: u32) -> io::Result<Packer> {
+        if block_len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "blocks must hold at least one id",
+            ));
+        }
+        Ok(Packer {
+            block_len,
+            eos,
+            pending: vec![],
+        })
+    }
+
+    /// Adds a document and its EOS, returning the blocks it completed.
+    pub fn push(&mut self, ids: &[u32]) -> Vec<Vec<u32>> {
+        self.pending.extend_from_slice(ids);
+        self.pending.push(self.eos);
+        let full = self.pending.len() - self.pending.len() % self.block_len;
+        let blocks = self.pending[..full]
+            .chunks(self.block_len)
+            .map(<[u32]>::to_vec)
+            .collect();
+        self.pending.drain(..full);
+        blocks
+    }
+
+    /// The ids that don't fill a last block, and are dropped.
+    pub fn remainder(&self) -> &[u32] {
+        &self.pending
+    }
+}
+
+/// Packs whole documents into blocks, returning them and the number of ids
+/// dropped at the end.
+pub fn pack(docs: &[Vec<u32>], block_len: usize, eos: u32) -> io::Result<(Vec<Vec<u32>>, usize)> {
+    let mut packer = Packer::new(block_len, eos)?;
+    let blocks = docs.iter().flat_map(|doc| packer.push(doc)).collect();
+    Ok((blocks, packer.remainder().len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack() {
+        let docs = vec![vec![1, 2, 3], vec![4], vec![5, 6, 7, 8, 9], vec![10]];
+        // 1 2 3 0 | 4 0 5 6 | 7 8 9 0 | 10 0 dropped
+        let (blocks, dropped) = pack(&docs, 4, 0).unwrap();
+        assert_eq!(
+            blocks,
+            vec![vec![1, 2, 3, 0], vec![4, 0, 5, 6], vec![7, 8, 9, 0]]
+        );
+        assert_eq!(dropped, 2);
+        let (blocks, dropped) = pack(&[vec![1]], 2, 0).unwrap();
+        assert_eq!((blocks, dropped), (vec![vec![1, 0]], 0));
+        assert!(Packer::new(0, 0).is_err());
+    }
+}
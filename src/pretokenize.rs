@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::io;
+
+use fancy_regex::{Regex, RegexInput};
+use tracing::warn;
+
+// pre-tokenization
+//
+// Text is split into chunks (roughly words, numbers, punctuation runs and
+// whitespace) before BPE, so merges never cross chunk boundaries.
+
+/// The split pattern used by GPT-2.
+pub const GPT2_PATTERN: &str =
+    r"'(?:[sdmt]|ll|ve|re)| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+/// The split pattern used by GPT-4 (cl100k_base).
+pub const GPT4_PATTERN: &str = r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?+\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]++[\r\n]*|\s*[\r\n]|\s+(?!\S)|\s+";
+
+pub struct Splitter {
+    pattern: String,
+    regex: Regex,
+}
+
+impl Splitter {
+    /// Builds a splitter from a regex, or one of the names `gpt2` and `gpt4`.
+    pub fn new(pattern: &str) -> io::Result<Splitter> {
+        let pattern = match pattern {
+            "gpt2" => GPT2_PATTERN,
+            "gpt4" => GPT4_PATTERN,
+            _ => pattern,
+        };
+        let regex = Regex::new(pattern)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(Splitter {
+            pattern: pattern.to_string(),
+            regex,
+        })
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Splits text into the pattern's matches. Where the regex gives up, as
+    /// fancy-regex does past its backtrack limit, the character it was at
+    /// becomes a chunk of its own and matching resumes after it, so the
+    /// text is split finer there rather than not at all.
+    pub fn split<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        let mut chunks = vec![];
+        let mut pos = 0;
+        loop {
+            let mut failed = None;
+            for m in self
+                .regex
+                .find_iter_input(RegexInput::new(text).from_pos(pos))
+            {
+                match m {
+                    Ok(m) => {
+                        chunks.push(m.as_str());
+                        pos = m.end();
+                    }
+                    Err(e) => {
+                        failed = Some(e);
+                        break;
+                    }
+                }
+            }
+            let Some(e) = failed else {
+                return chunks;
+            };
+            warn!(pos, error = %e, "pre-tokenizer regex failed; splitting off a character");
+            let Some(c) = text[pos..].chars().next() else {
+                return chunks;
+            };
+            chunks.push(&text[pos..pos + c.len_utf8()]);
+            pos += c.len_utf8();
+        }
+    }
+}
+
+/// Splits text into chunks, or returns it whole when there is no splitter.
+pub fn split<'a>(splitter: Option<&Splitter>, text: &'a str) -> Vec<&'a str> {
+    match splitter {
+        Some(splitter) => splitter.split(text),
+        None => vec![text],
+    }
+}
+
+/// The meta-symbol SentencePiece writes for a space ("▁", U+2581).
+pub const WHITESPACE_MARKER: char = '\u{2581}';
+
+/// Replaces the spaces of a chunk with `WHITESPACE_MARKER`, as models
+/// trained with the whitespace marker see them. Splitting happens before,
+/// so patterns still match on spaces.
+pub fn mark_spaces(chunk: &str) -> String {
+    chunk.replace(' ', "\u{2581}")
+}
+
+/// Turns markers back into spaces. A marker that was in the original text
+/// comes back as a space too, as with SentencePiece.
+pub fn unmark_spaces(text: &str) -> String {
+    text.replace(WHITESPACE_MARKER, " ")
+}
+
+/// Keeps at most `max_repeats` copies of each distinct chunk, so a phrase
+/// repeated a million times can't dominate the pair counts.
+pub fn cap_repeats(chunks: Vec<&str>, max_repeats: u32) -> Vec<&str> {
+    let mut seen: HashMap<&str, u32> = HashMap::new();
+    chunks
+        .into_iter()
+        .filter(|&chunk| {
+            let count = seen.entry(chunk).or_default();
+            *count += 1;
+            *count <= max_repeats
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_backtrack_limit() {
+        // a repeated alternation whose branches both match "a" backtracks
+        // exponentially, past fancy-regex's limit, on a long run of "a"s
+        // with no "b" after it
+        let splitter = Splitter::new(r"(?:(?=a)a|a)+(?=b)|\s+|\S").unwrap();
+        let text = format!("{} x", "a".repeat(40));
+        let chunks = splitter.split(&text);
+        assert_eq!(chunks.concat(), text);
+        assert_eq!(chunks.len(), 42);
+        assert_eq!(splitter.split("aab c"), vec!["aa", "b", " ", "c"]);
+    }
+
+    #[test]
+    fn test_split() {
+        let splitter = Splitter::new("gpt4").unwrap();
+        let text = "Hello've world123 how's  it\n";
+        assert_eq!(
+            splitter.split(text),
+            vec!["Hello", "'ve", " world", "123", " how", "'s", " ", " it", "\n"]
+        );
+        assert_eq!(split(None, text), vec![text]);
+    }
+
+    #[test]
+    fn test_mark_spaces() {
+        assert_eq!(mark_spaces(" new  york"), "▁new▁▁york");
+        assert_eq!(unmark_spaces("▁new▁▁york\n"), " new  york\n");
+    }
+
+    #[test]
+    fn test_cap_repeats() {
+        let chunks = vec!["a", "b", "a", "a", "b", "c"];
+        assert_eq!(cap_repeats(chunks, 2), vec!["a", "b", "a", "b", "c"]);
+    }
+}
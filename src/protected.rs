@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::history::MergeRecord;
+use crate::merge;
+use crate::pretokenize::{self, Splitter};
+use crate::render::{self, PieceStyle};
+
+// protected tokens
+//
+// Strings such as product names or control sequences that must end up as
+// single tokens. Training reserves the first merges, and so the first ids,
+// for them: each is built left to right from its bytes, sharing merges with
+// the ones before it. Those merges have the lowest ranks, so no learned
+// merge splits a protected token whose bytes fall within one chunk. The
+// training chunks are merged with them before any pair is counted, and
+// training goes on from there.
+//
+// Protected tokens that share bytes can still break each other. With `bc`
+// protected before `abc`, the merge `b c` outranks `a b`, so even `abc` on
+// its own encodes as `a` `bc`; `not_whole` finds those. And where the end of
+// one token is the start of another, as `ab` and `bc` are in `abc`, the
+// shared bytes go to whichever merge ranks first, so the other token isn't
+// produced at that occurrence.
+//
+// A seed vocabulary, such as a domain glossary, is reserved the same way
+// after the protected tokens, and BPE learns the remaining merges around it.
+//
+// A reserved token that the split pattern cuts apart is still in the
+// vocabulary but never produced by encoding; `split_apart` finds those.
+
+/// The merges that build `tokens`, in rank order from 0.
+pub fn merges(tokens: &[Vec<u8>]) -> Vec<MergeRecord> {
+    let mut ids: HashMap<(u32, u32), u32> = HashMap::new();
+    let mut records = vec![];
+    for token in tokens {
+        let Some((&first, rest)) = token.split_first() else {
+            continue;
+        };
+        let mut id = first as u32;
+        for &b in rest {
+            let pair = (id, b as u32);
+            id = *ids.entry(pair).or_insert_with(|| {
+                let rank = records.len() as u32;
+                records.push(MergeRecord {
+                    rank,
+                    pair,
+                    count: 0,
+                    runner_up: 0,
+                });
+                256 + rank
+            });
+        }
+    }
+    records
+}
+
+/// Reads a seed vocabulary: one token per line, written as `bpe vocab`
+/// prints them in the escaped style. Blank lines are skipped.
+pub fn read_seed_vocab(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let text = fs::read_to_string(path)?;
+    let mut tokens = vec![];
+    for (i, line) in text.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let token = render::parse(line, PieceStyle::Escaped).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: invalid token {:?}", path.display(), i + 1, line),
+            )
+        })?;
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Merges words, as byte ids, with `records` in order.
+pub fn apply(words: &mut [(Vec<u32>, u32)], records: &[MergeRecord]) {
+    for (ids, _) in words {
+        for record in records {
+            if ids.len() < 2 {
+                break;
+            }
+            *ids = merge(ids, record.pair, 256 + record.rank);
+        }
+    }
+}
+
+/// The tokens that `records` don't merge back into one id, because a
+/// protected token before them claims some of their bytes first.
+pub fn not_whole<'a>(tokens: &'a [Vec<u8>], records: &[MergeRecord]) -> Vec<&'a [u8]> {
+    tokens
+        .iter()
+        .filter(|token| {
+            let mut words = [(token.iter().map(|&b| b.into()).collect(), 1)];
+            apply(&mut words, records);
+            words[0].0.len() > 1
+        })
+        .map(Vec::as_slice)
+        .collect()
+}
+
+/// The tokens that `splitter` never leaves in one chunk.
+pub fn split_apart<'a>(splitter: Option<&Splitter>, tokens: &'a [Vec<u8>]) -> Vec<&'a [u8]> {
+    tokens
+        .iter()
+        .filter(|token| match std::str::from_utf8(token) {
+            Ok(text) => pretokenize::split(splitter, text).len() > 1,
+            Err(_) => false,
+        })
+        .map(Vec::as_slice)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode;
+
+    #[test]
+    fn test_merges() {
+        let tokens = vec![b"abc".to_vec(), b"ab".to_vec(), b"abd".to_vec()];
+        let records = merges(&tokens);
+        let pairs: Vec<(u32, u32)> = records.iter().map(|r| r.pair).collect();
+        assert_eq!(pairs, [(97, 98), (256, 99), (256, 100)]);
+
+        let mut words = vec![(b"xabcx".iter().map(|&b| b.into()).collect(), 1)];
+        apply(&mut words, &records);
+        assert_eq!(words[0].0, [120, 257, 120]);
+        let table = records.iter().map(|r| (r.pair, 256 + r.rank)).collect();
+        assert_eq!(encode(&table, "abd ab"), [258, 32, 256]);
+    }
+
+    #[test]
+    fn test_not_whole() {
+        let tokens = vec![b"bc".to_vec(), b"abc".to_vec(), b"ab".to_vec()];
+        let records = merges(&tokens);
+        assert_eq!(not_whole(&tokens, &records), [b"abc".as_slice()]);
+
+        // overlapping tokens are each whole alone, but not side by side
+        let tokens = vec![b"ab".to_vec(), b"bc".to_vec()];
+        let records = merges(&tokens);
+        assert!(not_whole(&tokens, &records).is_empty());
+        let mut words = vec![(b"abc".iter().map(|&b| b.into()).collect(), 1)];
+        apply(&mut words, &records);
+        assert_eq!(words[0].0, [256, 99]);
+    }
+
+    #[test]
+    fn test_read_seed_vocab() {
+        let path = std::env::temp_dir().join(format!("bpe-test-{}-seed.txt", std::process::id()));
+        fs::write(&path, " kinase\n\nATP\\x00\n").unwrap();
+        let tokens = read_seed_vocab(&path).unwrap();
+        assert_eq!(tokens, [b" kinase".to_vec(), b"ATP\x00".to_vec()]);
+        fs::write(&path, "ok\n\\xZZ\n").unwrap();
+        let err = read_seed_vocab(&path).unwrap_err();
+        assert!(err.to_string().contains(":2:"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_split_apart() {
+        let splitter = Splitter::new(pretokenize::GPT2_PATTERN).unwrap();
+        let tokens = vec![b"GPT-4o".to_vec(), b" Rust".to_vec()];
+        assert_eq!(
+            split_apart(Some(&splitter), &tokens),
+            [b"GPT-4o".as_slice()]
+        );
+        assert!(split_apart(None, &tokens).is_empty());
+    }
+}
@@ -0,0 +1,286 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io;
+
+use crate::case::{self, Markers};
+use crate::model::Model;
+use crate::pretokenize::{self, Splitter};
+use crate::{build_vocab, lowest_rank_pair, merge};
+
+// vocabulary pruning
+//
+// Merges learned from one corpus are often rarely used on the text a model
+// actually encodes. Pruning counts how many times each merge fires while
+// encoding a reference corpus, drops the ones below a threshold (and any
+// merge built on a dropped token), and renumbers the rest contiguously in
+// their original rank order, special tokens last. Trimming drops a fixed
+// number of the least used tokens instead, and says where every kept id
+// went, so embedding matrices can be shrunk to match.
+//
+// Some tokens are never emitted whatever the text: an encoder that keeps a
+// token in its output formed it from that token's bytes alone, since merges
+// with the bytes around it would have taken them, so a token whose own
+// bytes encode to something else is only ever an intermediate step. Such
+// tokens must keep their merges, but need no decode entry.
+
+/// How many times each merge is applied when encoding `docs`.
+pub fn merge_usage(
+    merges: &HashMap<(u32, u32), u32>,
+    splitter: Option<&Splitter>,
+    docs: &[String],
+) -> HashMap<(u32, u32), u64> {
+    let mut chunks: HashMap<&str, u64> = HashMap::new();
+    for doc in docs {
+        for chunk in pretokenize::split(splitter, doc) {
+            *chunks.entry(chunk).or_default() += 1;
+        }
+    }
+    let mut usage = HashMap::new();
+    for (chunk, n) in chunks {
+        let mut ids: Vec<u32> = chunk.as_bytes().iter().map(|&b| b.into()).collect();
+        while let Some((i, idx)) = lowest_rank_pair(&ids, |pair| merges.get(&pair).copied()) {
+            let pair = (ids[i], ids[i + 1]);
+            let merged = merge(&ids, pair, idx);
+            *usage.entry(pair).or_default() += n * (ids.len() - merged.len()) as u64;
+            ids = merged;
+        }
+    }
+    usage
+}
+
+/// How many times each of `model`'s merges is applied when its tokenizer
+/// encodes `docs`: each chunk is folded into segments between case markers
+/// and has its spaces marked, as the model asks, before it is merged.
+pub fn model_usage(model: &Model, docs: &[String]) -> io::Result<HashMap<(u32, u32), u64>> {
+    let splitter = model.pattern.as_deref().map(Splitter::new).transpose()?;
+    let cased = Markers::find(&model.special_tokens).is_some();
+    if !cased && !model.whitespace_marker {
+        return Ok(merge_usage(&model.merges, splitter.as_ref(), docs));
+    }
+    let mut segments = vec![];
+    for doc in docs {
+        for chunk in pretokenize::split(splitter.as_ref(), doc) {
+            let folded = match cased {
+                true => case::fold(chunk).into_iter().map(|(_, s)| s).collect(),
+                false => vec![chunk.to_string()],
+            };
+            segments.extend(
+                folded
+                    .into_iter()
+                    .map(|segment| match model.whitespace_marker {
+                        true => pretokenize::mark_spaces(&segment),
+                        false => segment,
+                    }),
+            );
+        }
+    }
+    Ok(merge_usage(&model.merges, None, &segments))
+}
+
+/// The merged tokens that encoding never emits, in id order.
+pub fn unreachable(merges: &HashMap<(u32, u32), u32>) -> Vec<u32> {
+    let vocab = build_vocab(merges);
+    let mut ids: Vec<u32> = merges.values().copied().collect();
+    ids.sort_unstable();
+    ids.retain(|&id| {
+        let mut ids: Vec<u32> = vocab[&id].iter().map(|&b| b.into()).collect();
+        while let Some((i, idx)) = lowest_rank_pair(&ids, |pair| merges.get(&pair).copied()) {
+            ids = merge(&ids, (ids[i], ids[i + 1]), idx);
+        }
+        ids != [id]
+    });
+    ids
+}
+
+/// Drops merges used fewer than `min_count` times and renumbers the rest.
+pub fn prune(model: &Model, usage: &HashMap<(u32, u32), u64>, min_count: u64) -> Trimmed {
+    compact(model, |pair| {
+        usage.get(&pair).copied().unwrap_or(0) >= min_count
+    })
+}
+
+/// A model with tokens dropped, and where the kept ones moved.
+pub struct Trimmed {
+    pub model: Model,
+    /// The new id of every kept token, bytes and special tokens included,
+    /// by old id.
+    pub remap: HashMap<u32, u32>,
+}
+
+/// Drops the `k` least used merged tokens and renumbers the rest. Only a
+/// token no kept token is built on can go, so exactly `k` are dropped (or
+/// every merge, if there are fewer); a merge fires at least as often as
+/// one built on it, so this mostly follows usage anyway. Equal usage drops
+/// the later token first.
+pub fn trim(model: &Model, usage: &HashMap<(u32, u32), u64>, k: usize) -> Trimmed {
+    let pairs: HashMap<u32, (u32, u32)> = model.merges.iter().map(|(&p, &id)| (id, p)).collect();
+    let mut children: HashMap<u32, u32> = HashMap::new();
+    for &(a, b) in pairs.values() {
+        *children.entry(a).or_default() += 1;
+        *children.entry(b).or_default() += 1;
+    }
+    let used = |id: u32| usage.get(&pairs[&id]).copied().unwrap_or(0);
+    let mut leaves: BinaryHeap<Reverse<(u64, Reverse<u32>)>> = pairs
+        .keys()
+        .filter(|id| !children.contains_key(id))
+        .map(|&id| Reverse((used(id), Reverse(id))))
+        .collect();
+    let mut dropped = HashSet::new();
+    while dropped.len() < k {
+        let Some(Reverse((_, Reverse(id)))) = leaves.pop() else {
+            break;
+        };
+        dropped.insert(id);
+        let (a, b) = pairs[&id];
+        for part in [a, b] {
+            let n = children.get_mut(&part).expect("counted above");
+            *n -= 1;
+            if *n == 0 && part >= 256 {
+                leaves.push(Reverse((used(part), Reverse(part))));
+            }
+        }
+    }
+    compact(model, |pair| !dropped.contains(&model.merges[&pair]))
+}
+
+/// Keeps the merges `keep` accepts, and any not built on a dropped token,
+/// renumbered contiguously in rank order, special tokens last.
+fn compact(model: &Model, keep: impl Fn((u32, u32)) -> bool) -> Trimmed {
+    let mut ranked: Vec<_> = model.merges.iter().map(|(&p, &idx)| (idx, p)).collect();
+    ranked.sort();
+    // old id -> new id, for the tokens that survive
+    let mut ids: HashMap<u32, u32> = (0..256).map(|b| (b, b)).collect();
+    let mut merges = HashMap::new();
+    for (idx, pair) in ranked {
+        if let (true, Some(&p0), Some(&p1)) = (keep(pair), ids.get(&pair.0), ids.get(&pair.1)) {
+            let new_idx = 256 + merges.len() as u32;
+            merges.insert((p0, p1), new_idx);
+            ids.insert(idx, new_idx);
+        }
+    }
+    let mut special: Vec<_> = model.special_tokens.iter().collect();
+    special.sort_by_key(|&(_, idx)| idx);
+    let first = 256 + merges.len() as u32;
+    let mut special_tokens = HashMap::new();
+    for ((token, &idx), new_idx) in special.into_iter().zip(first..) {
+        special_tokens.insert(token.clone(), new_idx);
+        ids.insert(idx, new_idx);
+    }
+    Trimmed {
+        model: Model {
+            merges,
+            pattern: model.pattern.clone(),
+            special_tokens,
+            whitespace_marker: model.whitespace_marker,
+            metadata: model.metadata.clone(),
+        },
+        remap: ids,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune() {
+        // "ab" is used, "xy" and "xyz" (built on it) are not
+        let model = Model {
+            merges: HashMap::from([
+                ((120, 121), 256),
+                ((97, 98), 257),
+                ((256, 122), 258),
+                ((257, 97), 259),
+            ]),
+            pattern: None,
+            special_tokens: HashMap::from([("<|end|>".to_string(), 260)]),
+            whitespace_marker: false,
+            metadata: None,
+        };
+        let docs = vec!["ab ab aba xy".to_string()];
+        let usage = merge_usage(&model.merges, None, &docs);
+        assert_eq!(usage[&(97, 98)], 3);
+        assert_eq!(usage[&(257, 97)], 1);
+        assert_eq!(usage[&(120, 121)], 1);
+
+        let pruned = prune(&model, &usage, 2).model;
+        assert_eq!(pruned.merges, HashMap::from([((97, 98), 256)]));
+        assert_eq!(pruned.special_tokens["<|end|>"], 257);
+
+        let pruned = prune(&model, &usage, 1).model;
+        let vocab = build_vocab(&pruned.merges);
+        assert_eq!(pruned.merges.len(), 3);
+        assert_eq!(vocab[&258], b"aba");
+    }
+
+    #[test]
+    fn test_model_usage() {
+        // "he", "hel" and "hello" only occur lowercased, after folding
+        let model = || Model {
+            merges: HashMap::from([
+                ((104, 101), 256),
+                ((108, 108), 257),
+                ((256, 257), 258),
+                ((258, 111), 259),
+            ]),
+            pattern: Some(r" ?\w+".to_string()),
+            special_tokens: HashMap::from([
+                (case::CAPITALIZED.to_string(), 260),
+                (case::UPPERCASE.to_string(), 261),
+            ]),
+            whitespace_marker: false,
+            metadata: None,
+        };
+        let docs = vec!["Hello HELLO".to_string()];
+        let usage = model_usage(&model(), &docs).unwrap();
+        assert_eq!(usage[&(104, 101)], 2);
+        let pruned = prune(&model(), &usage, 1).model;
+        assert_eq!(pruned.merges, model().merges);
+        let before = crate::Tokenizer::new(model()).unwrap();
+        let after = crate::Tokenizer::new(pruned).unwrap();
+        assert_eq!(after.encode(&docs[0]).len(), before.encode(&docs[0]).len());
+    }
+
+    #[test]
+    fn test_unreachable() {
+        // "bc" goes first, so "abc" never forms from "ab" and "c"
+        let merges = HashMap::from([((98, 99), 256), ((97, 98), 257), ((257, 99), 258)]);
+        assert_eq!(unreachable(&merges), [258]);
+        let merges = HashMap::from([((97, 98), 256), ((256, 99), 257)]);
+        assert!(unreachable(&merges).is_empty());
+    }
+
+    #[test]
+    fn test_trim() {
+        // "xyz" never fires, "xy" once, "aba" twice and "ab" four times
+        let model = Model {
+            merges: HashMap::from([
+                ((120, 121), 256),
+                ((97, 98), 257),
+                ((256, 122), 258),
+                ((257, 97), 259),
+            ]),
+            pattern: None,
+            special_tokens: HashMap::from([("<|end|>".to_string(), 260)]),
+            whitespace_marker: false,
+            metadata: None,
+        };
+        let usage = merge_usage(&model.merges, None, &["ab ab aba aba xy".to_string()]);
+        let trimmed = trim(&model, &usage, 2);
+        assert_eq!(
+            trimmed.model.merges,
+            HashMap::from([((97, 98), 256), ((256, 97), 257)])
+        );
+        assert_eq!(trimmed.remap[&257], 256);
+        assert_eq!(trimmed.remap[&259], 257);
+        assert_eq!(trimmed.remap[&260], 258);
+        assert_eq!(trimmed.remap[&97], 97);
+        assert!(!trimmed.remap.contains_key(&256));
+        assert_eq!(trimmed.remap.len(), 256 + 3);
+
+        // a token can't go before those built on it
+        let trimmed = trim(&model, &usage, 3);
+        assert_eq!(trimmed.model.merges, HashMap::from([((97, 98), 256)]));
+        assert_eq!(trim(&model, &usage, 10).model.merges.len(), 0);
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::io;
+
+use crate::rng::Rng;
+
+// random id sequences
+//
+// Sequences of valid ids for exercising decoders and whatever consumes their
+// output. Arbitrary sequences split characters across tokens, put special
+// tokens mid-word and start with continuation bytes far more often than
+// encoded text does. Ids are drawn uniformly, or in proportion to how often
+// each appears when a corpus is encoded, plus one so that every id can still
+// occur.
+
+pub struct IdSampler {
+    ids: Vec<u32>,
+    /// Running total of the weights, by position in `ids`.
+    cumulative: Vec<u64>,
+    rng: Rng,
+}
+
+impl IdSampler {
+    /// Draws from `ids` with the given weights.
+    pub fn new(ids: Vec<u32>, weights: &[u64], seed: u64) -> io::Result<IdSampler> {
+        let cumulative: Vec<u64> = weights
+            .iter()
+            .scan(0u64, |total, &w| {
+                *total += w;
+                Some(*total)
+            })
+            .collect();
+        if ids.len() != weights.len() || cumulative.last().copied().unwrap_or(0) == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no ids to sample from",
+            ));
+        }
+        Ok(IdSampler {
+            ids,
+            cumulative,
+            rng: Rng::new(seed),
+        })
+    }
+
+    pub fn uniform(ids: Vec<u32>, seed: u64) -> io::Result<IdSampler> {
+        let weights = vec![1; ids.len()];
+        IdSampler::new(ids, &weights, seed)
+    }
+
+    /// Draws ids in proportion to `counts` plus one.
+    pub fn weighted(ids: Vec<u32>, counts: &HashMap<u32, u64>, seed: u64) -> io::Result<IdSampler> {
+        let weights: Vec<u64> = ids
+            .iter()
+            .map(|id| counts.get(id).copied().unwrap_or(0) + 1)
+            .collect();
+        IdSampler::new(ids, &weights, seed)
+    }
+
+    pub fn sample(&mut self) -> u32 {
+        let total = *self.cumulative.last().unwrap();
+        let r = self.rng.next_u64() % total;
+        self.ids[self.cumulative.partition_point(|&c| c <= r)]
+    }
+
+    pub fn sequence(&mut self, len: usize) -> Vec<u32> {
+        (0..len).map(|_| self.sample()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample() {
+        let mut sampler = IdSampler::uniform(vec![3, 5, 7], 1).unwrap();
+        let ids = sampler.sequence(300);
+        assert!(ids.iter().all(|id| [3, 5, 7].contains(id)));
+        assert!([3, 5, 7].iter().all(|id| ids.contains(id)));
+        assert_eq!(
+            IdSampler::uniform(vec![3, 5, 7], 1).unwrap().sequence(300),
+            ids
+        );
+
+        // 5 has weight 100, 3 and 7 weight 1
+        let counts = HashMap::from([(5, 99)]);
+        let mut sampler = IdSampler::weighted(vec![3, 5, 7], &counts, 2).unwrap();
+        let fives = sampler.sequence(1000).iter().filter(|&&id| id == 5).count();
+        assert!(fives > 950, "{}", fives);
+        assert!(IdSampler::uniform(vec![], 0).is_err());
+        assert!(IdSampler::new(vec![1], &[0], 0).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt::Write;
+
+use clap::ValueEnum;
+
+// token rendering
+//
+// Token bytes are often not printable, or not even valid UTF-8 on their own,
+// so they need a text form for vocab listings and inspection output.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum PieceStyle {
+    /// UTF-8 text with control characters and invalid bytes escaped
+    #[default]
+    Escaped,
+    /// SentencePiece byte fallback: base bytes and invalid bytes as `<0xNN>`
+    ByteFallback,
+    /// GPT-2 byte-to-unicode mapping, as in vocab.json and merges.txt
+    Gpt2,
+}
+
+/// Renders the bytes of token `id` as a piece string.
+pub fn render(id: u32, bytes: &[u8], style: PieceStyle) -> String {
+    match style {
+        PieceStyle::ByteFallback if id < 256 => byte_piece(bytes[0]),
+        PieceStyle::Escaped | PieceStyle::ByteFallback => render_text(bytes, style),
+        PieceStyle::Gpt2 => crate::gpt2::encode_bytes(bytes),
+    }
+}
+
+/// Parses a piece string back into token bytes, the inverse of `render`
+/// with the same style.
+pub fn parse(piece: &str, style: PieceStyle) -> Option<Vec<u8>> {
+    match style {
+        PieceStyle::Escaped => unescape(piece),
+        PieceStyle::ByteFallback => Some(parse_byte_pieces(piece)),
+        PieceStyle::Gpt2 => crate::gpt2::decode_str(piece),
+    }
+}
+
+/// Escapes bytes the way the `Escaped` style renders a token, so they fit
+/// on one line.
+pub fn escape(bytes: &[u8]) -> String {
+    render_text(bytes, PieceStyle::Escaped)
+}
+
+pub fn byte_piece(b: u8) -> String {
+    format!("<0x{:02X}>", b)
+}
+
+fn parse_byte_pieces(mut piece: &str) -> Vec<u8> {
+    let mut bytes = vec![];
+    while let Some(c) = piece.chars().next() {
+        let byte = piece
+            .get(..6)
+            .and_then(|p| p.strip_prefix("<0x")?.strip_suffix('>'))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match byte {
+            Some(b) => {
+                bytes.push(b);
+                piece = &piece[6..];
+            }
+            None => {
+                bytes.extend(c.to_string().as_bytes());
+                piece = &piece[c.len_utf8()..];
+            }
+        }
+    }
+    bytes
+}
+
+fn unescape(piece: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut chars = piece.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            bytes.extend(c.to_string().as_bytes());
+            continue;
+        }
+        match chars.next()? {
+            '\\' => bytes.push(b'\\'),
+            'x' => bytes.push(hex(&mut chars, 2)? as u8),
+            'u' => bytes.extend(char::from_u32(hex(&mut chars, 4)?)?.to_string().as_bytes()),
+            _ => return None,
+        }
+    }
+    Some(bytes)
+}
+
+/// Renders valid UTF-8 as text and escapes the rest.
+fn render_text(mut bytes: &[u8], style: PieceStyle) -> String {
+    let escape = |out: &mut String, b: u8| match style {
+        PieceStyle::ByteFallback => out.push_str(&byte_piece(b)),
+        _ => write!(out, "\\x{:02x}", b).unwrap(),
+    };
+    let mut out = String::new();
+    while !bytes.is_empty() {
+        let (valid, rest) = match std::str::from_utf8(bytes) {
+            Ok(s) => (s, &[][..]),
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                (std::str::from_utf8(valid).unwrap(), rest)
+            }
+        };
+        for c in valid.chars() {
+            match style {
+                PieceStyle::ByteFallback if c.is_control() => {
+                    c.to_string().bytes().for_each(|b| escape(&mut out, b))
+                }
+                _ if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+                PieceStyle::Escaped if c == '\\' => out.push_str("\\\\"),
+                _ => out.push(c),
+            }
+        }
+        bytes = match rest.split_first() {
+            Some((&b, rest)) => {
+                escape(&mut out, b);
+                rest
+            }
+            None => rest,
+        };
+    }
+    out
+}
+
+fn hex(chars: &mut std::str::Chars, digits: usize) -> Option<u32> {
+    let hex: String = chars.take(digits).collect();
+    u32::from_str_radix(&hex, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(render(10, b"\n", PieceStyle::Escaped), "\\u000a");
+        assert_eq!(render(10, b"\n", PieceStyle::ByteFallback), "<0x0A>");
+        assert_eq!(render(97, b"a", PieceStyle::ByteFallback), "<0x61>");
+        assert_eq!(render(300, b" the", PieceStyle::ByteFallback), " the");
+        assert_eq!(render(300, b" the", PieceStyle::Gpt2), "\u{120}the");
+        let partial = [b'a', 0xe4, 0xbd];
+        assert_eq!(render(301, &partial, PieceStyle::Escaped), "a\\xe4\\xbd");
+        assert_eq!(
+            render(301, &partial, PieceStyle::ByteFallback),
+            "a<0xE4><0xBD>"
+        );
+        assert_eq!(render(92, b"\\", PieceStyle::Escaped), "\\\\");
+    }
+
+    #[test]
+    fn test_parse() {
+        let tokens: [&[u8]; 4] = [b"\n", b" the", b"\\x", &[b'a', 0xe4, 0xbd]];
+        for style in [
+            PieceStyle::Escaped,
+            PieceStyle::ByteFallback,
+            PieceStyle::Gpt2,
+        ] {
+            for bytes in tokens {
+                assert_eq!(parse(&render(300, bytes, style), style).unwrap(), bytes);
+            }
+        }
+        assert_eq!(parse("<0x0A>", PieceStyle::ByteFallback).unwrap(), b"\n");
+        assert!(parse("\\q", PieceStyle::Escaped).is_none());
+    }
+}
@@ -0,0 +1,202 @@
+use crate::cleanup::DecodeOptions;
+use crate::Tokenize;
+
+// self-test
+//
+// Quick checks that a model works where it was deployed, whatever its
+// algorithm: embedded texts in a range of scripts must decode back to
+// themselves, encode the same way twice and through the streaming path,
+// and use no id outside the vocabulary; special tokens must decode to their
+// text, never come out of encoding, and be left out when decoding skips
+// them. None of this checks that a model tokenizes well, only that it
+// behaves.
+
+/// Sample texts, by name.
+pub const SAMPLES: &[(&str, &str)] = &[
+    (
+        "english",
+        "The quick brown fox jumps over the lazy dog. It's 3:45pm!",
+    ),
+    ("german", "Größere Übungen für Bäckerinnen: „Straße“ und ß."),
+    (
+        "french",
+        "L'été dernier, où étiez-vous ? À Noël, peut-être.",
+    ),
+    (
+        "russian",
+        "Съешь же ещё этих мягких французских булок, да выпей чаю.",
+    ),
+    ("greek", "Ξεσκεπάζω την ψυχοφθόρα βδελυγμία."),
+    (
+        "arabic",
+        "نص حكيم له سر قاطع وذو شأن عظيم مكتوب على ثوب أخضر",
+    ),
+    ("hebrew", "דג סקרן שט בים מאוכזב ולפתע מצא חברה"),
+    (
+        "hindi",
+        "ऋषियों को सताने वाले दुष्ट राक्षसों के राजा रावण का सर्वनाश करने वाले",
+    ),
+    ("chinese", "我能吞下玻璃而不伤身体。天地玄黄，宇宙洪荒。"),
+    (
+        "japanese",
+        "いろはにほへと ちりぬるを。カタカナと漢字も混ぜる。",
+    ),
+    ("korean", "다람쥐 헌 쳇바퀴에 타고파. 키스의 고유조건은"),
+    ("thai", "เป็นมนุษย์สุดประเสริฐเลิศคุณค่า"),
+    ("emoji", "Deploy 🚀 done ✅ — 👩‍💻 family: 👨‍👩‍👧‍👦 flags 🇯🇵🇫🇷"),
+    (
+        "code",
+        "fn main() {\n    let x: Vec<u8> = vec![0x1f, 255];\n    println!(\"{:?}\", x);\n}\n",
+    ),
+    (
+        "whitespace",
+        "  leading\ttabs\t\tand   runs\r\nof\n\n\nnewlines   \u{a0}nbsp  ",
+    ),
+];
+
+/// The outcome of one check.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Check {
+    pub name: String,
+    /// What went wrong, if anything did.
+    pub failure: Option<String>,
+}
+
+impl Check {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// Runs every check on a model.
+pub fn run(tokenizer: &dyn Tokenize) -> Vec<Check> {
+    let mut checks = vec![];
+    let mut check = |name: String, failure: Option<String>| checks.push(Check { name, failure });
+    let vocab_size = tokenizer.vocab_size() as u32;
+    let special: Vec<u32> = (0..vocab_size)
+        .filter(|&id| tokenizer.is_special(id))
+        .collect();
+
+    check(
+        "empty".to_string(),
+        match (tokenizer.encode(""), tokenizer.decode(&[])) {
+            (ids, text) if ids.is_empty() && text.is_empty() => None,
+            (ids, text) => Some(format!("encoded to {:?}, decoded to {:?}", ids, text)),
+        },
+    );
+    for &(name, text) in SAMPLES {
+        let ids = tokenizer.encode(text);
+        let decoded = tokenizer.decode(&ids);
+        check(
+            format!("round-trip/{}", name),
+            (decoded != text).then(|| format!("decoded to {:?}", decoded)),
+        );
+        let again = tokenizer.encode(text);
+        let mut streamed = vec![];
+        let streaming = tokenizer.encode_reader(&mut text.as_bytes(), &mut |chunk| {
+            streamed.extend_from_slice(chunk);
+            Ok(())
+        });
+        check(
+            format!("deterministic/{}", name),
+            if again != ids {
+                Some("a second encoding differs".to_string())
+            } else if let Err(e) = streaming {
+                Some(format!("streaming encode failed: {}", e))
+            } else if streamed != ids {
+                Some("streaming encode differs".to_string())
+            } else {
+                None
+            },
+        );
+        let out_of_range = ids.iter().find(|&&id| id >= vocab_size);
+        let special_id = ids.iter().find(|id| special.contains(id));
+        check(
+            format!("ids/{}", name),
+            match (out_of_range, special_id) {
+                (Some(id), _) => Some(format!("id {} is past the vocabulary", id)),
+                (_, Some(id)) => Some(format!("special token {} came out of encoding", id)),
+                _ => None,
+            },
+        );
+    }
+    for &id in &special {
+        let text = tokenizer.decode(&[id]);
+        let ids = tokenizer.encode(&text);
+        let sample = tokenizer.encode(SAMPLES[0].1);
+        let with = [&sample[..], &[id]].concat();
+        let skip = DecodeOptions {
+            skip_special_tokens: true,
+            ..DecodeOptions::default()
+        };
+        check(
+            format!("special/{}", text),
+            if text.is_empty() {
+                Some("decodes to nothing".to_string())
+            } else if ids.contains(&id) {
+                Some("its text encodes to it".to_string())
+            } else if tokenizer.decode_with(&with, &skip) != SAMPLES[0].1 {
+                Some("decoding doesn't skip it".to_string())
+            } else {
+                None
+            },
+        );
+    }
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::model::Model;
+    use crate::pretokenize::GPT2_PATTERN;
+    use crate::Tokenizer;
+
+    #[test]
+    fn test_run() {
+        let model = Model {
+            merges: HashMap::from([((32, 116), 256), ((256, 104), 257), ((101, 114), 258)]),
+            pattern: Some(GPT2_PATTERN.to_string()),
+            special_tokens: HashMap::from([("<|endoftext|>".to_string(), 259)]),
+            whitespace_marker: false,
+            metadata: None,
+        };
+        let tokenizer = Tokenizer::new(model).unwrap();
+        let checks = run(&tokenizer);
+        assert!(checks.iter().all(Check::passed), "{:?}", checks);
+        assert_eq!(checks.len(), 1 + 3 * SAMPLES.len() + 1);
+        assert!(checks.iter().any(|c| c.name == "special/<|endoftext|>"));
+    }
+
+    /// Drops every other byte when decoding.
+    struct Lossy;
+
+    impl Tokenize for Lossy {
+        fn encode(&self, text: &str) -> Vec<u32> {
+            text.bytes().map(u32::from).collect()
+        }
+
+        fn decode(&self, ids: &[u32]) -> String {
+            let bytes: Vec<u8> = ids.iter().step_by(2).map(|&id| id as u8).collect();
+            String::from_utf8_lossy(&bytes).into_owned()
+        }
+
+        fn vocab_size(&self) -> usize {
+            256
+        }
+    }
+
+    #[test]
+    fn test_failures() {
+        let checks = run(&Lossy);
+        let failed: Vec<&str> = checks
+            .iter()
+            .filter(|c| !c.passed())
+            .map(|c| c.name.as_str())
+            .collect();
+        assert_eq!(failed.len(), SAMPLES.len());
+        assert!(failed.iter().all(|name| name.starts_with("round-trip/")));
+    }
+}
@@ -0,0 +1,293 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::export::{self, Dtype};
+use crate::fetch;
+
+// pretraining shards
+//
+// A whole corpus encoded into numbered `.bin` files (llm.c's format, see
+// `export::write_bin`) that pretraining data loaders stream from, plus a
+// `manifest.json` describing them. Documents run on from one shard into the
+// next, as in llm.c's own preprocessing, so every shard but the last holds
+// exactly the shard size; a separator id such as `<|endoftext|>` can follow
+// each document so loaders still see where one ends.
+//
+// Documents can be shuffled first, with a seed so the order can be
+// reproduced, and split by document into a training and a validation set,
+// each written to its own directory of shards and manifest. Instead of a
+// size, a number of shards can be asked for; that many are written, their
+// sizes differing by at most one id.
+
+pub const MANIFEST: &str = "manifest.json";
+
+/// One shard file, as listed in the manifest.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Shard {
+    /// File name, relative to the manifest.
+    pub file: String,
+    pub tokens: u64,
+    /// Documents that start in this shard.
+    pub documents: u64,
+    pub sha256: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub model: String,
+    pub model_sha256: String,
+    pub vocab_size: usize,
+    pub dtype: Dtype,
+    /// Id appended after each document, if any.
+    pub separator: Option<u32>,
+    /// Seed the documents were shuffled with, if they were.
+    pub shuffle_seed: Option<u64>,
+    /// Ids per shard, all but the last; when a number of shards was asked
+    /// for, the later shards can hold one fewer.
+    pub shard_tokens: u64,
+    pub tokens: u64,
+    pub documents: u64,
+    pub shards: Vec<Shard>,
+}
+
+impl Manifest {
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        fs::write(dir.join(MANIFEST), json + "\n")
+    }
+
+    pub fn read(dir: &Path) -> io::Result<Manifest> {
+        let json = fs::read_to_string(dir.join(MANIFEST))?;
+        serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// The number of documents out of `documents` that go to the validation
+/// set, rounded to the nearest.
+pub fn val_count(documents: usize, ratio: f64) -> io::Result<usize> {
+    if !(0.0..1.0).contains(&ratio) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("validation ratio {} is not in [0, 1)", ratio),
+        ));
+    }
+    Ok((documents as f64 * ratio).round() as usize)
+}
+
+/// Collects the ids of documents and writes them out a shard at a time.
+pub struct ShardWriter {
+    dir: PathBuf,
+    shard_tokens: usize,
+    /// Shards that hold `shard_tokens` ids before the rest hold one fewer.
+    full_shards: usize,
+    dtype: Dtype,
+    separator: Option<u32>,
+    ids: Vec<u32>,
+    /// Documents started in the current shard.
+    documents: u64,
+    shards: Vec<Shard>,
+}
+
+impl ShardWriter {
+    /// Writes shards of `shard_tokens` ids into `dir`, creating it.
+    pub fn new(
+        dir: &Path,
+        shard_tokens: usize,
+        dtype: Dtype,
+        separator: Option<u32>,
+    ) -> io::Result<ShardWriter> {
+        if shard_tokens == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "shards must hold at least one token",
+            ));
+        }
+        fs::create_dir_all(dir)?;
+        Ok(ShardWriter {
+            dir: dir.to_path_buf(),
+            shard_tokens,
+            full_shards: usize::MAX,
+            dtype,
+            separator,
+            ids: Vec::with_capacity(shard_tokens.min(1 << 24)),
+            documents: 0,
+            shards: vec![],
+        })
+    }
+
+    /// Writes `tokens` ids, separators included, as exactly `shards` shards
+    /// whose sizes differ by at most one, the larger first; with fewer ids
+    /// than shards, each id is a shard of its own.
+    pub fn with_shards(
+        dir: &Path,
+        tokens: usize,
+        shards: usize,
+        dtype: Dtype,
+        separator: Option<u32>,
+    ) -> io::Result<ShardWriter> {
+        let shards = shards.max(1);
+        let (size, larger) = (tokens / shards, tokens % shards);
+        let mut writer = ShardWriter::new(dir, size.max(1), dtype, separator)?;
+        if size > 0 && larger > 0 {
+            writer.shard_tokens = size + 1;
+            writer.full_shards = larger;
+        }
+        Ok(writer)
+    }
+
+    /// The most ids a shard holds.
+    pub fn shard_tokens(&self) -> usize {
+        self.shard_tokens
+    }
+
+    /// The size of the shard being filled.
+    fn limit(&self) -> usize {
+        if self.shards.len() < self.full_shards {
+            self.shard_tokens
+        } else {
+            self.shard_tokens - 1
+        }
+    }
+
+    pub fn start_document(&mut self) {
+        self.documents += 1;
+    }
+
+    /// Appends ids of the current document, which may arrive in blocks.
+    pub fn write(&mut self, mut ids: &[u32]) -> io::Result<()> {
+        while !ids.is_empty() {
+            let n = ids.len().min(self.limit() - self.ids.len());
+            self.ids.extend_from_slice(&ids[..n]);
+            ids = &ids[n..];
+            if self.ids.len() == self.limit() {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn end_document(&mut self) -> io::Result<()> {
+        match self.separator {
+            Some(separator) => self.write(&[separator]),
+            None => Ok(()),
+        }
+    }
+
+    pub fn add_document(&mut self, ids: &[u32]) -> io::Result<()> {
+        self.start_document();
+        self.write(ids)?;
+        self.end_document()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let file = format!("shard_{:05}.bin", self.shards.len());
+        let path = self.dir.join(&file);
+        export::write_bin(&path, &self.ids, self.dtype)?;
+        self.shards.push(Shard {
+            file,
+            tokens: self.ids.len() as u64,
+            documents: self.documents,
+            sha256: fetch::sha256_file(&path)?,
+        });
+        self.ids.clear();
+        self.documents = 0;
+        Ok(())
+    }
+
+    /// Writes the last, partly filled shard and returns all of them.
+    pub fn finish(mut self) -> io::Result<Vec<Shard>> {
+        if !self.ids.is_empty() || self.documents > 0 {
+            self.flush()?;
+        }
+        Ok(self.shards)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shard_writer() {
+        let dir = std::env::temp_dir().join(format!("bpe-test-{}-shards", std::process::id()));
+        let mut writer = ShardWriter::new(&dir, 4, Dtype::U16, Some(9)).unwrap();
+        writer.add_document(&[1, 2]).unwrap();
+        writer.start_document();
+        writer.write(&[3, 4]).unwrap();
+        writer.write(&[5, 6]).unwrap();
+        writer.end_document().unwrap();
+        writer.add_document(&[]).unwrap();
+        let shards = writer.finish().unwrap();
+
+        // 1 2 9 3 | 4 5 6 9 | 9
+        let summary: Vec<(&str, u64, u64)> = shards
+            .iter()
+            .map(|s| (s.file.as_str(), s.tokens, s.documents))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("shard_00000.bin", 4, 2),
+                ("shard_00001.bin", 4, 0),
+                ("shard_00002.bin", 1, 1),
+            ]
+        );
+        let bytes = fs::read(dir.join("shard_00001.bin")).unwrap();
+        assert_eq!(&bytes[1024..], &[4, 0, 5, 0, 6, 0, 9, 0]);
+        assert_eq!(
+            shards[1].sha256,
+            fetch::sha256_file(&dir.join("shard_00001.bin")).unwrap()
+        );
+
+        let manifest = Manifest {
+            model: "m.bpe".into(),
+            model_sha256: "00".into(),
+            vocab_size: 10,
+            dtype: Dtype::U16,
+            separator: Some(9),
+            shuffle_seed: None,
+            shard_tokens: 4,
+            tokens: 9,
+            documents: 3,
+            shards,
+        };
+        manifest.write(&dir).unwrap();
+        assert_eq!(Manifest::read(&dir).unwrap(), manifest);
+        assert!(fs::read_to_string(dir.join(MANIFEST))
+            .unwrap()
+            .contains("\"dtype\": \"u16\""));
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(ShardWriter::new(&dir, 0, Dtype::U16, None).is_err());
+    }
+
+    #[test]
+    fn test_splits() {
+        assert_eq!(val_count(1000, 0.01).unwrap(), 10);
+        assert_eq!(val_count(10, 0.0).unwrap(), 0);
+        assert!(val_count(10, 1.0).is_err());
+        assert!(val_count(10, -0.1).is_err());
+    }
+
+    #[test]
+    fn test_with_shards() {
+        let dir = std::env::temp_dir().join(format!("bpe-test-{}-nshards", std::process::id()));
+        let sizes = |tokens: u32, shards: usize| -> Vec<u64> {
+            let mut writer =
+                ShardWriter::with_shards(&dir, tokens as usize, shards, Dtype::U16, None).unwrap();
+            writer
+                .add_document(&(0..tokens).collect::<Vec<u32>>())
+                .unwrap();
+            let shards = writer.finish().unwrap();
+            fs::remove_dir_all(&dir).unwrap();
+            shards.iter().map(|shard| shard.tokens).collect()
+        };
+        // 10 ids in 6 shards of 2 would be only 5
+        assert_eq!(sizes(10, 6), [2, 2, 2, 2, 1, 1]);
+        assert_eq!(sizes(10, 3), [4, 3, 3]);
+        assert_eq!(sizes(12, 4), [3, 3, 3, 3]);
+        assert_eq!(sizes(2, 4), [1, 1]);
+    }
+}
@@ -0,0 +1,358 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::fetch;
+use crate::tokenizer;
+
+// installed models
+//
+// Models copied into a cache directory under a short name, so `--model
+// gpt4` finds `~/.cache/bpe/gpt4.model` wherever the command runs. The
+// directory is `$BPE_CACHE_DIR` if set, else `bpe` under `$XDG_CACHE_HOME`
+// or `~/.cache`. A model path that exists, or a URL, is used as given; only
+// a bare name that isn't a file is looked up here.
+//
+// Installing records the model's SHA-256 beside it in `<name>.sha256`, and
+// a model whose contents no longer match is refused. Models fetched from a
+// URL are checked against a `<url>.sha256` file when the server has one.
+//
+// A bare name that isn't installed may name a bundled model. Commands that
+// read a model from a path get it written out under the temporary directory
+// downloads go to.
+//
+// Library users load models with `Tokenizer::from_pretrained`, which looks
+// a name up in another order (`locate`): an installed model first, so a
+// local copy always wins, then the models bundled with the crate, and only
+// then a file path or URL.
+//
+// The one bundled model, `demo-en`, is small enough to embed in every build
+// (under 3 KB): 768 merges learned from `a-man-like-him.txt` with the GPT-2
+// pattern, and `<|endoftext|>`. It is for demos and smoke tests, such as
+// `bpe encode --text hello` with no model at hand, not for real work.
+
+const EXTENSION: &str = "model";
+
+/// The bundled English demo model.
+pub const DEMO: &str = "demo-en";
+
+/// BPE models compiled into the crate, by name, as model file bytes.
+pub const BUNDLED: &[(&str, &[u8])] = &[(DEMO, include_bytes!("../models/demo-en.bin"))];
+
+/// Where `locate` found a model.
+pub enum Located {
+    File(PathBuf),
+    Bundled(&'static [u8]),
+}
+
+/// The directory installed models are kept in.
+pub fn dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("BPE_CACHE_DIR") {
+        return PathBuf::from(dir);
+    }
+    let cache = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    cache.join("bpe")
+}
+
+/// Whether `name` can name an installed model: letters, digits, `-`, `_`
+/// and `.`, not starting with a dot.
+pub fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.{}", name, EXTENSION))
+}
+
+fn checksum_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.sha256", name))
+}
+
+/// Returns a local path for a model given as a path, a URL, or the name of
+/// an installed or bundled model.
+pub fn resolve(model: &Path, sha256: Option<&str>) -> io::Result<PathBuf> {
+    resolve_in(&dir(), model, sha256)
+}
+
+fn resolve_in(dir: &Path, model: &Path, sha256: Option<&str>) -> io::Result<PathBuf> {
+    let name = model.to_str().filter(|name| is_name(name));
+    let Some(name) = name.filter(|_| !model.exists()) else {
+        return resolve_source(model, sha256);
+    };
+    if let Some(path) = installed(dir, name, sha256)? {
+        return Ok(path);
+    }
+    match bundled(name) {
+        Some(bytes) => unpack(name, bytes, sha256),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "no model file, or installed or bundled model, named {:?}",
+                name
+            ),
+        )),
+    }
+}
+
+/// Writes a bundled model out to a file, under the temporary directory
+/// downloads go to, for commands that read models from a path.
+fn unpack(name: &str, bytes: &[u8], sha256: Option<&str>) -> io::Result<PathBuf> {
+    let dir = std::env::temp_dir().join("bpe");
+    fs::create_dir_all(&dir)?;
+    let digest = fetch::hex(&Sha256::digest(bytes));
+    let dest = dir.join(format!("{}-{}.{}", &digest[..16], name, EXTENSION));
+    if !dest.is_file() {
+        // written aside and renamed, so a concurrent run never reads half
+        let partial = dest.with_extension(format!("{}.part", std::process::id()));
+        fs::write(&partial, bytes)?;
+        fs::rename(&partial, &dest)?;
+    }
+    fetch::resolve(&dest, sha256)
+}
+
+/// Finds a model given as the name of an installed or bundled model, a
+/// path, or a URL, in that order.
+pub fn locate(model: &str) -> io::Result<Located> {
+    locate_in(&dir(), model)
+}
+
+fn locate_in(dir: &Path, model: &str) -> io::Result<Located> {
+    if is_name(model) {
+        if let Some(path) = installed(dir, model, None)? {
+            return Ok(Located::File(path));
+        }
+        if let Some(bytes) = bundled(model) {
+            return Ok(Located::Bundled(bytes));
+        }
+    }
+    let path = Path::new(model);
+    if !path.exists() && !fetch::is_url(path) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "no installed or bundled model, or model file, named {:?}",
+                model
+            ),
+        ));
+    }
+    resolve_source(path, None).map(Located::File)
+}
+
+/// The file bytes of the model bundled as `name`.
+pub fn bundled(name: &str) -> Option<&'static [u8]> {
+    BUNDLED
+        .iter()
+        .find(|&&(bundled, _)| bundled == name)
+        .map(|&(_, bytes)| bytes)
+}
+
+/// The path of the model installed under `name`, once its checksum is
+/// verified, or `None` if there is none.
+fn installed(dir: &Path, name: &str, sha256: Option<&str>) -> io::Result<Option<PathBuf>> {
+    let installed = path(dir, name);
+    if !installed.is_file() {
+        return Ok(None);
+    }
+    match fs::read_to_string(checksum_path(dir, name)) {
+        Ok(text) => {
+            let recorded = fetch::parse_checksum(&text).ok_or_else(|| {
+                invalid(format!("invalid recorded checksum for model {:?}", name))
+            })?;
+            fetch::verify(&installed, &recorded).map_err(|e| {
+                invalid(format!(
+                    "installed model {:?} is corrupt, reinstall it: {}",
+                    name, e
+                ))
+            })?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    fetch::resolve(&installed, sha256).map(Some)
+}
+
+/// Resolves a model path or URL, checking a download against the checksum
+/// published beside it unless one is given.
+fn resolve_source(model: &Path, sha256: Option<&str>) -> io::Result<PathBuf> {
+    let published = match (sha256, model.to_str()) {
+        (None, Some(url)) if fetch::is_url(model) => fetch::published_sha256(url)?,
+        _ => None,
+    };
+    fetch::resolve(model, sha256.or(published.as_deref()))
+}
+
+/// An installed model.
+#[derive(Debug, PartialEq)]
+pub struct Installed {
+    pub name: String,
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+/// The installed models, by name.
+pub fn list(dir: &Path) -> io::Result<Vec<Installed>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut models = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        models.push(Installed {
+            name: name.to_string(),
+            bytes: fs::metadata(&path)?.len(),
+            path,
+        });
+    }
+    models.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(models)
+}
+
+/// Installs a model file, or the model at a URL, under `name`, replacing
+/// any model of that name. The file must load as a model.
+pub fn add(dir: &Path, name: &str, source: &Path, sha256: Option<&str>) -> io::Result<PathBuf> {
+    if !is_name(name) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid model name {:?}", name),
+        ));
+    }
+    let source = resolve_source(source, sha256)?;
+    tokenizer::load_encode_only(&source)?;
+    fs::create_dir_all(dir)?;
+    let dest = path(dir, name);
+    // copy beside the destination first so a failed copy leaves no model
+    let partial = dest.with_extension("part");
+    fs::copy(&source, &partial)?;
+    let sha256 = fetch::sha256_file(&partial)?;
+    fs::write(
+        checksum_path(dir, name),
+        format!("{}  {}.{}\n", sha256, name, EXTENSION),
+    )?;
+    fs::rename(&partial, &dest)?;
+    Ok(dest)
+}
+
+pub fn remove(dir: &Path, name: &str) -> io::Result<()> {
+    match fs::remove_file(path(dir, name)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no installed model named {:?}", name),
+            ))
+        }
+        result => result?,
+    }
+    match fs::remove_file(checksum_path(dir, name)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{self, Model};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_add_resolve_remove() {
+        let dir = std::env::temp_dir().join(format!("bpe-test-{}-store", std::process::id()));
+        let file = std::env::temp_dir().join(format!("bpe-test-{}-store.bpe", std::process::id()));
+        let model = Model {
+            merges: HashMap::from([((104, 105), 256)]),
+            pattern: None,
+            special_tokens: HashMap::new(),
+            whitespace_marker: false,
+            metadata: None,
+        };
+        model::save(&file, &model).unwrap();
+
+        assert!(list(&dir).unwrap().is_empty());
+        let installed = add(&dir, "tiny-1", &file, None).unwrap();
+        assert!(add(&dir, "../x", &file, None).is_err());
+        assert!(add(&dir, "junk", &dir.join("tiny-1.part"), None).is_err());
+        let models = list(&dir).unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].name, "tiny-1");
+        assert_eq!(models[0].bytes, fs::metadata(&file).unwrap().len());
+
+        assert_eq!(
+            resolve_in(&dir, Path::new("tiny-1"), None).unwrap(),
+            installed
+        );
+        assert_eq!(resolve_in(&dir, &file, None).unwrap(), file);
+        assert!(resolve_in(&dir, Path::new("gpt4"), None).is_err());
+        let located = |model: &str| match locate_in(&dir, model).unwrap() {
+            Located::File(path) => path,
+            Located::Bundled(_) => unreachable!(),
+        };
+        assert_eq!(located("tiny-1"), installed);
+        assert_eq!(located(file.to_str().unwrap()), file);
+        let err = locate_in(&dir, "gpt4").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(matches!(
+            locate_in(&dir, DEMO).unwrap(),
+            Located::Bundled(_)
+        ));
+        let unpacked = resolve_in(&dir, Path::new(DEMO), None).unwrap();
+        assert_eq!(fs::read(&unpacked).unwrap(), bundled(DEMO).unwrap());
+        // an installed model of the same name wins
+        add(&dir, DEMO, &file, None).unwrap();
+        assert_eq!(located(DEMO), path(&dir, DEMO));
+        assert_eq!(
+            resolve_in(&dir, Path::new(DEMO), None).unwrap(),
+            path(&dir, DEMO)
+        );
+        remove(&dir, DEMO).unwrap();
+        let recorded = fs::read_to_string(dir.join("tiny-1.sha256")).unwrap();
+        assert_eq!(
+            fetch::parse_checksum(&recorded).unwrap(),
+            fetch::sha256_file(&file).unwrap()
+        );
+        let mut corrupt = fs::read(&installed).unwrap();
+        corrupt.push(b'\n');
+        fs::write(&installed, corrupt).unwrap();
+        let err = resolve_in(&dir, Path::new("tiny-1"), None).unwrap_err();
+        assert!(err.to_string().contains("corrupt"));
+
+        remove(&dir, "tiny-1").unwrap();
+        assert!(remove(&dir, "tiny-1").is_err());
+        assert!(list(&dir).unwrap().is_empty());
+        assert!(!dir.join("tiny-1.sha256").exists());
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_bundled() {
+        for &(name, bytes) in BUNDLED {
+            let model = model::from_bytes(bytes).unwrap();
+            assert!(!model.merges.is_empty(), "{}", name);
+        }
+        let model = model::from_bytes(bundled(DEMO).unwrap()).unwrap();
+        assert_eq!(model.merges.len(), 768);
+        assert_eq!(model.special_tokens["<|endoftext|>"], 1024);
+        assert!(bundled("gpt4").is_none());
+    }
+}
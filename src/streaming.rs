@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use crate::encode;
+
+// streaming BPE
+//
+// An experimental approximation of BPE for text too long to hold or even
+// count in full, such as an endless log stream. The text is read once, in
+// windows: each window's chunks are encoded with the merges learned so far,
+// their pairs counted, and the window's most frequent pairs become the next
+// merges. A pair table that outgrows its bound is pruned to its most
+// frequent pairs, as in lossy counting, so memory stays bounded however
+// varied the text; a pair pruned early in a window can be undercounted.
+// Training stops reading once the vocabulary is full.
+//
+// Exact BPE picks every merge from counts over the whole corpus; here each
+// is picked from a single window, and several from the same counts, so the
+// vocabulary is only as good as the windows are representative.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StreamOptions {
+    /// Bytes of text whose pairs are counted before merges are picked.
+    pub window_bytes: usize,
+    /// Merges picked at the end of each window.
+    pub merges_per_window: u32,
+    /// Distinct pairs counted before the table is pruned.
+    pub max_pairs: usize,
+    /// Pairs seen fewer times than this in a window aren't merged.
+    pub min_pair_count: u32,
+}
+
+impl Default for StreamOptions {
+    fn default() -> StreamOptions {
+        StreamOptions {
+            window_bytes: 1 << 20,
+            merges_per_window: 4,
+            max_pairs: 1 << 20,
+            min_pair_count: 2,
+        }
+    }
+}
+
+pub struct StreamingTrainer {
+    num_merges: u32,
+    options: StreamOptions,
+    merges: HashMap<(u32, u32), u32>,
+    /// Pair counts of the current window.
+    pairs: HashMap<(u32, u32), u64>,
+    /// Bytes read in the current window.
+    window: usize,
+    windows: u64,
+    prunes: u64,
+}
+
+impl StreamingTrainer {
+    pub fn new(num_merges: u32, options: StreamOptions) -> StreamingTrainer {
+        StreamingTrainer {
+            num_merges,
+            options,
+            merges: HashMap::new(),
+            pairs: HashMap::new(),
+            window: 0,
+            windows: 0,
+            prunes: 0,
+        }
+    }
+
+    /// Whether every merge has been learned, and no more text is needed.
+    pub fn is_done(&self) -> bool {
+        self.merges.len() >= self.num_merges as usize
+    }
+
+    pub fn merges(&self) -> &HashMap<(u32, u32), u32> {
+        &self.merges
+    }
+
+    /// Windows ended so far.
+    pub fn windows(&self) -> u64 {
+        self.windows
+    }
+
+    /// Times the pair table was pruned.
+    pub fn prunes(&self) -> u64 {
+        self.prunes
+    }
+
+    /// Counts the pairs of one pre-tokenized chunk, ending the window once
+    /// it holds `window_bytes`.
+    pub fn add_chunk(&mut self, chunk: &str) {
+        if self.is_done() {
+            return;
+        }
+        let ids = encode(&self.merges, chunk);
+        for pair in ids.windows(2) {
+            *self.pairs.entry((pair[0], pair[1])).or_default() += 1;
+        }
+        if self.pairs.len() > self.options.max_pairs {
+            self.prune();
+        }
+        self.window += chunk.len();
+        if self.window >= self.options.window_bytes {
+            self.end_window();
+        }
+    }
+
+    /// Keeps the pairs counted more often than the median of the table, so
+    /// at most half of it.
+    fn prune(&mut self) {
+        let mut counts: Vec<u64> = self.pairs.values().copied().collect();
+        let mid = counts.len() / 2;
+        let (_, &mut median, _) = counts.select_nth_unstable(mid);
+        self.pairs.retain(|_, n| *n > median);
+        self.prunes += 1;
+    }
+
+    /// Makes merges of the window's most frequent pairs, ties going to the
+    /// smallest pair, and starts the next window.
+    pub fn end_window(&mut self) {
+        let min = self.options.min_pair_count.max(1) as u64;
+        let mut best: Vec<((u32, u32), u64)> =
+            self.pairs.drain().filter(|&(_, n)| n >= min).collect();
+        best.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (pair, _) in best
+            .into_iter()
+            .take(self.options.merges_per_window as usize)
+        {
+            if self.is_done() {
+                break;
+            }
+            let idx = 256 + self.merges.len() as u32;
+            self.merges.insert(pair, idx);
+        }
+        self.window = 0;
+        self.windows += 1;
+    }
+
+    /// Ends a partly read window and returns the merges.
+    pub fn finish(mut self) -> HashMap<(u32, u32), u32> {
+        if self.window > 0 {
+            self.end_window();
+        }
+        self.merges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_streaming_trainer() {
+        let options = StreamOptions {
+            window_bytes: 8,
+            merges_per_window: 1,
+            max_pairs: 1 << 10,
+            min_pair_count: 2,
+        };
+        let mut trainer = StreamingTrainer::new(2, options);
+        // "ab" is the most frequent pair of the first window, then "ab"+"c"
+        for chunk in ["abab", "abc", "x"] {
+            trainer.add_chunk(chunk);
+        }
+        assert_eq!(trainer.windows(), 1);
+        assert_eq!(trainer.merges()[&(97, 98)], 256);
+        for chunk in ["abc", "abc", "de"] {
+            trainer.add_chunk(chunk);
+        }
+        assert!(trainer.is_done());
+        trainer.add_chunk("zzzzzzzzzz");
+        let merges = trainer.finish();
+        assert_eq!(merges.len(), 2);
+        assert_eq!(merges[&(256, 99)], 257);
+    }
+
+    #[test]
+    fn test_prune() {
+        let options = StreamOptions {
+            max_pairs: 4,
+            ..StreamOptions::default()
+        };
+        let mut trainer = StreamingTrainer::new(10, options);
+        trainer.add_chunk("aaaa");
+        for chunk in ["bc", "de", "fg", "hi"] {
+            trainer.add_chunk(chunk);
+        }
+        assert_eq!(trainer.prunes(), 1);
+        assert!(trainer.pairs.len() <= 4);
+        assert_eq!(trainer.pairs[&(97, 97)], 3);
+        // a window below the pair minimum makes no merges
+        let mut trainer = StreamingTrainer::new(10, StreamOptions::default());
+        trainer.add_chunk("abc");
+        assert!(trainer.finish().is_empty());
+    }
+}
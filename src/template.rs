@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::io;
+
+use crate::encoding::Encoding;
+
+// post-processing templates
+//
+// Models expect special tokens around their input, e.g. `<s> $A </s>` for a
+// single sequence or `<s> $A </s> $B </s>` for a pair. A template is a
+// whitespace separated list of `$A`, `$B` and special token names.
+//
+// Encoder models also take a type (segment) id per position. Each piece may
+// name its type id with a `:n` suffix, as in `[CLS]:0 $A:0 [SEP]:0 $B:1
+// [SEP]:1`; otherwise `$A` is 0, `$B` is 1 and a special token takes the
+// type of the sequence before it.
+//
+// A sequence too long for the model can instead be cut into overflow
+// windows, as Hugging Face's `return_overflowing_tokens` does: each window
+// holds as many tokens as fit, and every window after the first starts
+// `stride` tokens before the previous one ended, so no token loses all its
+// context at a cut.
+
+#[derive(Clone, Debug, PartialEq)]
+enum Piece {
+    A,
+    B,
+    Special(u32),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    pieces: Vec<(Piece, u32)>,
+}
+
+/// A pair of sequences encoded into one.
+#[derive(Debug, PartialEq)]
+pub struct PairEncoding {
+    pub ids: Vec<u32>,
+    /// 0 or 1 per position, telling which input it came from.
+    pub type_ids: Vec<u32>,
+}
+
+/// How to shorten a pair that doesn't fit its maximum length.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TruncationStrategy {
+    /// Repeatedly drop the last token of whichever sequence is longer.
+    LongestFirst,
+    OnlyFirst,
+    OnlySecond,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Truncation {
+    /// Maximum length of the result, special tokens included.
+    pub max_len: usize,
+    pub strategy: TruncationStrategy,
+    /// Tokens an overflow window repeats from the end of the window before
+    /// it; only used when overflow windows are returned.
+    pub stride: usize,
+}
+
+impl Template {
+    pub fn parse(spec: &str, special_tokens: &HashMap<String, u32>) -> io::Result<Template> {
+        let mut pieces = Vec::new();
+        let mut current_type = 0;
+        for item in spec.split_whitespace() {
+            let (name, type_id) = match item.rsplit_once(':') {
+                Some((name, n)) if !name.is_empty() && n.parse::<u32>().is_ok() => {
+                    (name, n.parse().ok())
+                }
+                _ => (item, None),
+            };
+            let (piece, default_type) = match name {
+                "$A" => (Piece::A, 0),
+                "$B" => (Piece::B, 1),
+                name => match special_tokens.get(name) {
+                    Some(&id) => (Piece::Special(id), current_type),
+                    None => {
+                        return Err(invalid(&format!(
+                            "template uses unknown special token {:?}",
+                            name
+                        )))
+                    }
+                },
+            };
+            let type_id = type_id.unwrap_or(default_type);
+            if !matches!(piece, Piece::Special(_)) {
+                current_type = type_id;
+            }
+            pieces.push((piece, type_id));
+        }
+        Ok(Template { pieces })
+    }
+
+    fn has(&self, piece: &Piece) -> bool {
+        self.pieces.iter().any(|(p, _)| p == piece)
+    }
+
+    /// The number of special ids the template adds.
+    pub fn num_special(&self) -> usize {
+        self.pieces
+            .iter()
+            .filter(|(p, _)| matches!(p, Piece::Special(_)))
+            .count()
+    }
+
+    pub fn apply(&self, a: &[u32], b: &[u32]) -> PairEncoding {
+        let len = a.len() + b.len() + self.num_special();
+        let mut ids = Vec::with_capacity(len);
+        let mut type_ids = Vec::with_capacity(len);
+        for (piece, type_id) in &self.pieces {
+            let before = ids.len();
+            match piece {
+                Piece::A => ids.extend(a),
+                Piece::B => ids.extend(b),
+                Piece::Special(id) => ids.push(*id),
+            }
+            type_ids.resize(type_ids.len() + ids.len() - before, *type_id);
+        }
+        PairEncoding { ids, type_ids }
+    }
+
+    /// Like `apply`, keeping the offsets and masks of `a` and `b`.
+    pub fn apply_encoding(&self, a: &Encoding, b: &Encoding) -> Encoding {
+        let mut encoding = Encoding::default();
+        for (piece, type_id) in &self.pieces {
+            let before = encoding.len();
+            match piece {
+                Piece::A => encoding.extend(a),
+                Piece::B => encoding.extend(b),
+                Piece::Special(id) => encoding.push_special(*id, *type_id),
+            }
+            encoding.type_ids[before..].fill(*type_id);
+        }
+        encoding
+    }
+}
+
+/// Shortens `a` and `b` so that they take at most `budget` tokens together.
+pub fn truncate_pair(
+    a: &mut Vec<u32>,
+    b: &mut Vec<u32>,
+    budget: usize,
+    strategy: TruncationStrategy,
+) {
+    let excess = (a.len() + b.len()).saturating_sub(budget);
+    match strategy {
+        TruncationStrategy::OnlyFirst => a.truncate(a.len().saturating_sub(excess)),
+        TruncationStrategy::OnlySecond => b.truncate(b.len().saturating_sub(excess)),
+        TruncationStrategy::LongestFirst => {
+            for _ in 0..excess {
+                if a.len() > b.len() {
+                    a.pop();
+                } else {
+                    b.pop();
+                }
+            }
+        }
+    }
+}
+
+/// Cuts `ids` into windows of at most `len` tokens, each after the first
+/// starting `stride` tokens before the previous one ended. Always returns at
+/// least one window, empty for empty `ids`.
+pub fn windows(ids: &[u32], len: usize, stride: usize) -> io::Result<Vec<&[u32]>> {
+    if stride >= len {
+        return Err(invalid(&format!(
+            "stride {} must be smaller than the {} tokens a window holds",
+            stride, len
+        )));
+    }
+    let mut windows = vec![];
+    let mut start = 0;
+    loop {
+        let end = ids.len().min(start + len);
+        windows.push(&ids[start..end]);
+        if end == ids.len() {
+            return Ok(windows);
+        }
+        start = end - stride;
+    }
+}
+
+/// Adds special tokens to encoded sequences, with one template for single
+/// sequences and one for pairs.
+#[derive(Clone, Debug)]
+pub struct PostProcessor {
+    single: Template,
+    pair: Template,
+}
+
+impl PostProcessor {
+    pub fn new(single: Template, pair: Template) -> io::Result<PostProcessor> {
+        if !single.has(&Piece::A) || single.has(&Piece::B) {
+            return Err(invalid("single template must use $A and not $B"));
+        }
+        if !pair.has(&Piece::A) || !pair.has(&Piece::B) {
+            return Err(invalid("pair template must use both $A and $B"));
+        }
+        Ok(PostProcessor { single, pair })
+    }
+
+    pub fn single(&self, a: &[u32]) -> Vec<u32> {
+        self.single.apply(a, &[]).ids
+    }
+
+    pub fn pair(&self, a: &[u32], b: &[u32]) -> PairEncoding {
+        self.pair.apply(a, b)
+    }
+
+    pub fn single_encoding(&self, a: &Encoding) -> Encoding {
+        self.single.apply_encoding(a, &Encoding::default())
+    }
+
+    pub fn pair_encoding(&self, a: &Encoding, b: &Encoding) -> Encoding {
+        self.pair.apply_encoding(a, b)
+    }
+
+    /// Special tokens added around a single sequence, which count against
+    /// its length.
+    pub fn single_overhead(&self) -> usize {
+        self.single.num_special()
+    }
+
+    /// Special tokens added around a pair, which count against its length.
+    pub fn pair_overhead(&self) -> usize {
+        self.pair.num_special()
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_pair() {
+        let (mut a, mut b) = (vec![1, 2, 3, 4], vec![5, 6]);
+        truncate_pair(&mut a, &mut b, 4, TruncationStrategy::LongestFirst);
+        assert_eq!((a, b), (vec![1, 2], vec![5, 6]));
+        let (mut a, mut b) = (vec![1, 2, 3], vec![5, 6, 7]);
+        truncate_pair(&mut a, &mut b, 3, TruncationStrategy::LongestFirst);
+        assert_eq!((a, b), (vec![1, 2], vec![5]));
+        let (mut a, mut b) = (vec![1, 2, 3], vec![5, 6, 7]);
+        truncate_pair(&mut a, &mut b, 4, TruncationStrategy::OnlySecond);
+        assert_eq!((a, b), (vec![1, 2, 3], vec![5]));
+    }
+
+    #[test]
+    fn test_windows() {
+        let ids: Vec<u32> = (0..10).collect();
+        assert_eq!(
+            windows(&ids, 4, 1).unwrap(),
+            vec![&ids[0..4], &ids[3..7], &ids[6..10]]
+        );
+        assert_eq!(windows(&ids, 4, 0).unwrap().len(), 3);
+        assert_eq!(windows(&ids, 10, 3).unwrap(), vec![&ids[..]]);
+        assert_eq!(windows(&[], 4, 2).unwrap(), vec![&[] as &[u32]]);
+        assert!(windows(&ids, 4, 4).is_err());
+        assert!(windows(&ids, 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_post_processor() {
+        let specials = HashMap::from([("<s>".to_string(), 1), ("</s>".to_string(), 2)]);
+        let single = Template::parse("<s> $A </s>", &specials).unwrap();
+        let pair = Template::parse("<s> $A </s> $B </s>", &specials).unwrap();
+        let post = PostProcessor::new(single.clone(), pair.clone()).unwrap();
+        assert_eq!(post.single(&[7, 8]), vec![1, 7, 8, 2]);
+        let encoding = post.pair(&[7], &[9]);
+        assert_eq!(encoding.ids, vec![1, 7, 2, 9, 2]);
+        assert_eq!(encoding.type_ids, vec![0, 0, 0, 1, 1]);
+        let explicit = Template::parse("<s>:0 $A:0 </s>:1 $B:1", &specials).unwrap();
+        assert_eq!(explicit.apply(&[7], &[9]).type_ids, vec![0, 0, 1, 1]);
+        assert!(Template::parse("<cls> $A", &specials).is_err());
+        assert!(PostProcessor::new(pair, single).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+use std::ops::Range;
+
+use crate::Tokenizer;
+
+// text splitting
+//
+// Splits documents into pieces of at most a given number of tokens, e.g. to
+// fit retrieval chunks into an embedding model. Cuts are made between tokens
+// of the document's encoding, and only where they also fall between UTF-8
+// characters, so every chunk is valid text and its ids are exactly the
+// document's ids for that span. Positions come from `encode_with_offsets`,
+// as token bytes don't match the text's once spaces are marked or case is
+// folded, and a case marker is never cut off from the letters it restores.
+// Consecutive chunks can overlap by a number of tokens, giving the sliding
+// windows used by embedding pipelines.
+//
+// By default a chunk is cut as late as the budget allows. With sentence or
+// paragraph boundaries it is instead cut at the last such boundary that
+// fits, and only cut mid-sentence when a whole sentence exceeds the budget.
+//
+// `split_into` instead divides a document into a given number of parts of
+// about the same token count, e.g. to shard it across parallel requests.
+
+pub struct TextSplitter<'t> {
+    tokenizer: &'t Tokenizer,
+    max_tokens: usize,
+    overlap: usize,
+    boundary: Boundary,
+}
+
+/// Where chunks prefer to end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Boundary {
+    /// Anywhere between tokens.
+    Token,
+    /// After `.`, `!` or `?` followed by whitespace, or at a line break.
+    Sentence,
+    /// At a blank line, falling back to sentence boundaries.
+    Paragraph,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Chunk<'a> {
+    pub text: &'a str,
+    /// Byte range of `text` within the document.
+    pub range: Range<usize>,
+    pub ids: Vec<u32>,
+}
+
+impl<'t> TextSplitter<'t> {
+    pub fn new(tokenizer: &'t Tokenizer, max_tokens: usize) -> TextSplitter<'t> {
+        assert!(max_tokens > 0, "max_tokens must be positive");
+        TextSplitter {
+            tokenizer,
+            max_tokens,
+            overlap: 0,
+            boundary: Boundary::Token,
+        }
+    }
+
+    /// Prefers ending chunks at sentence or paragraph boundaries.
+    pub fn with_boundary(mut self, boundary: Boundary) -> TextSplitter<'t> {
+        self.boundary = boundary;
+        self
+    }
+
+    /// Starts each chunk up to `overlap` tokens before the previous one ended.
+    pub fn with_overlap(mut self, overlap: usize) -> TextSplitter<'t> {
+        assert!(
+            overlap < self.max_tokens,
+            "overlap must be less than max_tokens"
+        );
+        self.overlap = overlap;
+        self
+    }
+
+    /// Splits `text` into chunks of at most `max_tokens` tokens. A chunk only
+    /// exceeds the budget when a single character, or a case marker and the
+    /// letters it marks, takes more tokens than that, and overlaps shrink
+    /// where they would start inside a character.
+    pub fn split<'a>(&self, text: &'a str) -> Vec<Chunk<'a>> {
+        let (ids, cuts) = cuts(self.tokenizer, text);
+        let boundary = |i: usize| cuts[i].is_some();
+
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while start < ids.len() {
+            let mut end = (start + self.max_tokens).min(ids.len());
+            while end > start && !boundary(end) {
+                end -= 1;
+            }
+            if end == start {
+                end = start + self.max_tokens;
+                while !boundary(end) {
+                    end += 1;
+                }
+            } else if end < ids.len() && self.boundary > Boundary::Token {
+                end = self.preferred_end(text, &cuts, start, end);
+            }
+            chunks.push(chunk(text, &cuts, &ids, start, end));
+            if end == ids.len() {
+                break;
+            }
+            let mut next = end - self.overlap.min(end - start - 1);
+            while !boundary(next) {
+                next += 1;
+            }
+            start = next;
+        }
+        chunks
+    }
+
+    /// The last cut in `start + 1..=end` at the best boundary level available.
+    fn preferred_end(&self, text: &str, cuts: &[Option<usize>], start: usize, end: usize) -> usize {
+        let levels: Vec<Boundary> = (start + 1..=end)
+            .map(|i| cuts[i].map_or(Boundary::Token, |at| boundary_at(text, at)))
+            .collect();
+        for want in [Boundary::Paragraph, Boundary::Sentence] {
+            if want > self.boundary {
+                continue;
+            }
+            if let Some(i) = levels.iter().rposition(|&level| level >= want) {
+                return start + 1 + i;
+            }
+        }
+        end
+    }
+}
+
+/// Splits `text` into exactly `parts` consecutive chunks with about the same
+/// number of tokens, cut between tokens and UTF-8 characters and never
+/// between a case marker and its letters. Chunks are empty when the text has
+/// fewer tokens than parts.
+pub fn split_into<'a>(tokenizer: &Tokenizer, text: &'a str, parts: usize) -> Vec<Chunk<'a>> {
+    assert!(parts > 0, "parts must be positive");
+    let (ids, cuts) = cuts(tokenizer, text);
+    let mut at = vec![0];
+    for k in 1..parts {
+        let mut i = (ids.len() * k / parts).max(*at.last().unwrap());
+        while cuts[i].is_none() {
+            i += 1;
+        }
+        at.push(i);
+    }
+    at.push(ids.len());
+    at.windows(2)
+        .map(|w| chunk(text, &cuts, &ids, w[0], w[1]))
+        .collect()
+}
+
+/// Encodes `text`, with the byte offset of each place its ids can be cut:
+/// before every token and after the last. A cut is `None` where it would
+/// fall inside a character, or between a case marker and the end of the
+/// letters it restores, as decoding the parts apart would lose the case.
+pub(crate) fn cuts(tokenizer: &Tokenizer, text: &str) -> (Vec<u32>, Vec<Option<usize>>) {
+    let (ids, ranges) = tokenizer.encode_with_offsets(text);
+    let mut cuts = Vec::with_capacity(ids.len() + 1);
+    cuts.push(Some(0));
+    // case markers are the tokens with empty ranges
+    let mut marked_end = 0;
+    for pair in ranges.windows(2) {
+        let ((start, end), (next, _)) = (pair[0], pair[1]);
+        if start == end {
+            let word = text[start..].trim_start_matches(|c: char| !c.is_alphabetic());
+            let letters = word.find(|c: char| !c.is_alphabetic());
+            marked_end = text.len() - word.len() + letters.unwrap_or(word.len());
+        }
+        cuts.push((start < end && end <= next && next >= marked_end).then_some(next));
+    }
+    if !ids.is_empty() {
+        cuts.push(Some(text.len()));
+    }
+    (ids, cuts)
+}
+
+/// The chunk of tokens `start..end`, both cuts.
+fn chunk<'a>(
+    text: &'a str,
+    cuts: &[Option<usize>],
+    ids: &[u32],
+    start: usize,
+    end: usize,
+) -> Chunk<'a> {
+    let range = cuts[start].unwrap()..cuts[end].unwrap();
+    Chunk {
+        text: &text[range.clone()],
+        range,
+        ids: ids[start..end].to_vec(),
+    }
+}
+
+/// Classifies a cut of `text` at byte offset `at`, by looking at the
+/// whitespace around it and the character before that whitespace.
+fn boundary_at(text: &str, at: usize) -> Boundary {
+    if !text.is_char_boundary(at) {
+        return Boundary::Token;
+    }
+    let (before, after) = text.split_at(at);
+    let head = before.trim_end();
+    let space = &text[head.len()..at + (after.len() - after.trim_start().len())];
+    if head.is_empty() {
+        return Boundary::Token;
+    }
+    if space.matches('\n').count() >= 2 {
+        Boundary::Paragraph
+    } else if space.contains('\n')
+        || (!space.is_empty() && head.ends_with(['.', '!', '?', '。', '！', '？']))
+    {
+        Boundary::Sentence
+    } else {
+        Boundary::Token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::case;
+    use crate::model::Model;
+
+    fn tokenizer(merges: &[(u32, u32)]) -> Tokenizer {
+        let merges = merges.iter().zip(256..).map(|(&p, i)| (p, i)).collect();
+        Tokenizer::new(Model {
+            merges,
+            pattern: None,
+            special_tokens: HashMap::new(),
+            whitespace_marker: false,
+            metadata: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_split() {
+        // "ab" and "abab" are single tokens
+        let tokenizer = tokenizer(&[(97, 98), (256, 256)]);
+        let chunks = TextSplitter::new(&tokenizer, 2).split("ababab cd");
+        let texts: Vec<_> = chunks.iter().map(|c| c.text).collect();
+        assert_eq!(texts, vec!["ababab", " c", "d"]);
+        assert_eq!(chunks[0].ids, vec![257, 256]);
+        assert_eq!(chunks[2].range, 8..9);
+    }
+
+    #[test]
+    fn test_split_overlap() {
+        let tokenizer = tokenizer(&[]);
+        let splitter = TextSplitter::new(&tokenizer, 4).with_overlap(2);
+        let texts: Vec<_> = splitter.split("abcdefgh").iter().map(|c| c.text).collect();
+        assert_eq!(texts, vec!["abcd", "cdef", "efgh"]);
+        // the overlap can't start inside "é"
+        let texts: Vec<_> = splitter.split("abéfgh").iter().map(|c| c.text).collect();
+        assert_eq!(texts, vec!["abé", "éfg", "fgh"]);
+    }
+
+    #[test]
+    fn test_split_boundaries() {
+        let tokenizer = tokenizer(&[]);
+        let text = "One two. Three four five.\n\nSix seven. Eight.";
+        let split = |boundary| -> Vec<&str> {
+            let splitter = TextSplitter::new(&tokenizer, 30).with_boundary(boundary);
+            splitter.split(text).iter().map(|c| c.text).collect()
+        };
+        assert_eq!(
+            split(Boundary::Sentence),
+            vec!["One two. Three four five.\n\n", "Six seven. Eight."]
+        );
+        assert_eq!(
+            split(Boundary::Token),
+            vec!["One two. Three four five.\n\nSix", " seven. Eight."]
+        );
+        let text = "A long sentence without an end";
+        let splitter = TextSplitter::new(&tokenizer, 10).with_boundary(Boundary::Paragraph);
+        assert_eq!(splitter.split(text)[0].text, "A long sen");
+    }
+
+    #[test]
+    fn test_split_into() {
+        let tokenizer = tokenizer(&[(97, 98)]);
+        let parts = split_into(&tokenizer, "abababcdefg", 3);
+        let texts: Vec<_> = parts.iter().map(|c| c.text).collect();
+        assert_eq!(texts, vec!["abab", "abcd", "efg"]);
+        // "é" is two tokens, so the first cut moves past it
+        let texts: Vec<_> = split_into(&tokenizer, "éa", 3)
+            .iter()
+            .map(|c| c.text)
+            .collect();
+        assert_eq!(texts, vec!["é", "", "a"]);
+        assert_eq!(split_into(&tokenizer, "", 2).len(), 2);
+    }
+
+    #[test]
+    fn test_split_whitespace_marker() {
+        // spaces are encoded as the three bytes of "▁"
+        let tokenizer = Tokenizer::new(Model {
+            merges: HashMap::new(),
+            pattern: None,
+            special_tokens: HashMap::new(),
+            whitespace_marker: true,
+            metadata: None,
+        })
+        .unwrap();
+        let chunks = TextSplitter::new(&tokenizer, 4).split("a b c d");
+        let texts: Vec<_> = chunks.iter().map(|c| c.text).collect();
+        assert_eq!(texts, vec!["a ", "b ", "c ", "d"]);
+        let ids: Vec<u32> = chunks.iter().flat_map(|c| c.ids.clone()).collect();
+        assert_eq!(ids, tokenizer.encode("a b c d"));
+        let texts: Vec<_> = TextSplitter::new(&tokenizer, 2)
+            .split("a b")
+            .iter()
+            .map(|c| c.text)
+            .collect();
+        assert_eq!(texts, vec!["a", " ", "b"]);
+    }
+
+    #[test]
+    fn test_split_case_markers() {
+        let tokenizer = Tokenizer::new(Model {
+            merges: HashMap::from([((104, 105), 256)]),
+            pattern: Some(r" ?\p{L}+|\s+|\S".to_string()),
+            special_tokens: HashMap::from([
+                (case::CAPITALIZED.to_string(), 257),
+                (case::UPPERCASE.to_string(), 258),
+            ]),
+            whitespace_marker: false,
+            metadata: None,
+        })
+        .unwrap();
+        // "<|cap|>", "hi", "<|upper|>", " ", "hi", " ", "hi"
+        let text = "Hi HI hi";
+        let parts = split_into(&tokenizer, text, 3);
+        let texts: Vec<_> = parts.iter().map(|c| c.text).collect();
+        assert_eq!(texts, vec!["Hi", " HI", " hi"]);
+        assert_eq!(parts[1].ids, vec![258, 32, 256]);
+        assert_eq!(tokenizer.decode(&parts[1].ids), " HI");
+        let ids: Vec<u32> = parts.iter().flat_map(|c| c.ids.clone()).collect();
+        assert_eq!(ids, tokenizer.encode(text));
+        // a marker stays with the letters it marks
+        let texts: Vec<_> = TextSplitter::new(&tokenizer, 1)
+            .split(text)
+            .iter()
+            .map(|c| c.text)
+            .collect();
+        assert_eq!(texts, vec!["Hi", " HI", " ", "hi"]);
+    }
+
+    #[test]
+    fn test_split_multibyte() {
+        // "é" is two byte tokens and must not be cut in half
+        let tokenizer = tokenizer(&[]);
+        let chunks = TextSplitter::new(&tokenizer, 3).split("aéé");
+        let texts: Vec<_> = chunks.iter().map(|c| c.text).collect();
+        assert_eq!(texts, vec!["aé", "é"]);
+        let chunks = TextSplitter::new(&tokenizer, 1).split("é");
+        assert_eq!(chunks[0].ids.len(), 2);
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tracing::warn;
+
+use crate::model::Model;
+use crate::pretokenize::Splitter;
+use crate::Tokenize;
+
+// tiktoken rank files
+//
+// OpenAI's encodings ship as `.tiktoken` files: one `<base64 token> <rank>`
+// line per token, the rank doubling as the token's id. Unlike this crate's
+// models they record no merge pairs. tiktoken merges by the rank of the
+// bytes a merge would produce, whichever two parts they come from, so a
+// vocabulary converted to pairs can encode some chunks differently. These
+// files are therefore encoded as tiktoken does, for ids and counts
+// identical to its `encode_ordinary`. `load_model` and `save` convert
+// between them and models of pairs anyway, for `bpe convert`.
+//
+// The files don't name their split pattern either; it is picked by the
+// number of ranks, which differs between the published encodings.
+
+/// The split pattern of r50k_base and p50k_base, as in tiktoken.
+pub const R50K_PATTERN: &str =
+    r"'(?:[sdmt]|ll|ve|re)| ?\p{L}++| ?\p{N}++| ?[^\s\p{L}\p{N}]++|\s++$|\s+(?!\S)|\s";
+
+/// The split pattern of cl100k_base, as in tiktoken.
+pub const CL100K_PATTERN: &str = r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?+\p{L}++|\p{N}{1,3}+| ?[^\s\p{L}\p{N}]++[\r\n]*+|\s++$|\s*[\r\n]|\s+(?!\S)|\s";
+
+/// The split pattern of o200k_base, as in tiktoken.
+pub const O200K_PATTERN: &str = concat!(
+    r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|\p{N}{1,3}",
+    r"| ?[^\s\p{L}\p{N}]+[\r\n/]*",
+    r"|\s*[\r\n]+",
+    r"|\s+(?!\S)",
+    r"|\s+",
+);
+
+/// The split pattern of a published encoding with this many ranks.
+pub fn pattern_for(ranks: usize) -> Option<&'static str> {
+    match ranks {
+        50256 | 50280 => Some(R50K_PATTERN),
+        100256 => Some(CL100K_PATTERN),
+        199998 => Some(O200K_PATTERN),
+        _ => None,
+    }
+}
+
+/// Whether a file's first line looks like a rank file's.
+pub fn is_rank_line(line: &[u8]) -> bool {
+    let Ok(line) = std::str::from_utf8(line) else {
+        return false;
+    };
+    line.split_once(' ').is_some_and(|(token, rank)| {
+        !token.is_empty() && STANDARD.decode(token).is_ok() && rank.parse::<u32>().is_ok()
+    })
+}
+
+pub struct TiktokenBpe {
+    ranks: HashMap<Vec<u8>, u32>,
+    /// Token bytes by rank.
+    tokens: HashMap<u32, Vec<u8>>,
+    splitter: Splitter,
+}
+
+impl TiktokenBpe {
+    pub fn new(ranks: HashMap<Vec<u8>, u32>, pattern: &str) -> io::Result<TiktokenBpe> {
+        let tokens = ranks
+            .iter()
+            .map(|(bytes, &rank)| (rank, bytes.clone()))
+            .collect();
+        Ok(TiktokenBpe {
+            ranks,
+            tokens,
+            splitter: Splitter::new(pattern)?,
+        })
+    }
+
+    /// Loads a `.tiktoken` file, splitting text with the pattern of the
+    /// published encoding of its size.
+    pub fn load(path: &Path) -> io::Result<TiktokenBpe> {
+        let ranks = read_ranks(BufReader::new(File::open(path)?))?;
+        let pattern = pattern_for(ranks.len()).ok_or_else(|| {
+            invalid(format!(
+                "{}: no known tiktoken encoding has {} ranks",
+                path.display(),
+                ranks.len()
+            ))
+        })?;
+        TiktokenBpe::new(ranks, pattern)
+    }
+
+    /// Encodes one chunk as tiktoken's `byte_pair_encode` does.
+    pub fn encode_chunk(&self, chunk: &[u8]) -> Vec<u32> {
+        if let Some(&rank) = self.ranks.get(chunk) {
+            return vec![rank];
+        }
+        let rank = |part: &[u8]| self.ranks.get(part).copied();
+        parts(chunk, rank)
+            .windows(2)
+            .map(|part| {
+                rank(&chunk[part[0]..part[1]]).expect("rank files contain every single byte")
+            })
+            .collect()
+    }
+}
+
+/// Where the parts of a chunk start, and its end, once it is merged by the
+/// ranks `rank` gives.
+fn parts(chunk: &[u8], rank: impl Fn(&[u8]) -> Option<u32>) -> Vec<usize> {
+    let rank = |start: usize, end: usize| rank(&chunk[start..end]);
+    // part starts, and the rank of each part merged with the next
+    let mut starts: Vec<usize> = (0..=chunk.len()).collect();
+    let mut merged: Vec<Option<u32>> = (0..chunk.len())
+        .map(|i| (i + 2 <= chunk.len()).then(|| rank(i, i + 2)).flatten())
+        .collect();
+    // the lowest rank wins, the leftmost among equals
+    while let Some((i, _)) = merged
+        .iter()
+        .enumerate()
+        .filter_map(|(i, r)| Some((i, (*r)?)))
+        .min_by_key(|&(i, r)| (r, i))
+    {
+        starts.remove(i + 1);
+        merged.remove(i + 1);
+        let span = |i: usize| starts.get(i + 2).and_then(|&end| rank(starts[i], end));
+        merged[i] = span(i);
+        if i > 0 {
+            merged[i - 1] = span(i - 1);
+        }
+    }
+    starts
+}
+
+/// Loads a `.tiktoken` file as a model of merge pairs, with `pattern` or
+/// else that of the published encoding of its size. Each token's pair is the two parts
+/// tiktoken merges last when encoding its bytes with the lower ranks alone;
+/// ids are renumbered, bytes first, and then keep rank order.
+pub fn load_model(path: &Path, pattern: Option<&str>) -> io::Result<Model> {
+    let ranks = read_ranks(BufReader::new(File::open(path)?))?;
+    let pattern = pattern
+        .or_else(|| pattern_for(ranks.len()))
+        .ok_or_else(|| {
+            invalid(format!(
+                "{}: no known tiktoken encoding has {} ranks; give its pattern",
+                path.display(),
+                ranks.len()
+            ))
+        })?;
+    let pattern = Splitter::new(pattern)?.pattern().to_string();
+    to_model(&ranks, Some(pattern))
+}
+
+fn to_model(ranks: &HashMap<Vec<u8>, u32>, pattern: Option<String>) -> io::Result<Model> {
+    let mut tokens: Vec<(u32, &[u8])> = ranks
+        .iter()
+        .filter(|(bytes, _)| bytes.len() > 1)
+        .map(|(bytes, &rank)| (rank, bytes.as_slice()))
+        .collect();
+    tokens.sort_unstable();
+    let mut ids: HashMap<Vec<u8>, u32> = (0..=255u8).map(|b| (vec![b], b as u32)).collect();
+    let mut merges = HashMap::new();
+    for (rank, bytes) in tokens {
+        let lower = |part: &[u8]| ranks.get(part).copied().filter(|&r| r < rank);
+        let starts = parts(bytes, lower);
+        let [_, mid, _] = starts[..] else {
+            return Err(invalid(format!(
+                "rank {}: no two lower-ranked tokens make {:?}",
+                rank,
+                String::from_utf8_lossy(bytes)
+            )));
+        };
+        let pair = (ids[&bytes[..mid]], ids[&bytes[mid..]]);
+        let id = 256 + merges.len() as u32;
+        merges.insert(pair, id);
+        ids.insert(bytes.to_vec(), id);
+    }
+    Ok(Model {
+        merges,
+        pattern,
+        special_tokens: HashMap::new(),
+        whitespace_marker: false,
+        metadata: None,
+    })
+}
+
+/// Writes a model's bytes and merged tokens as a `.tiktoken` rank file,
+/// each ranked by its id. The file has no place for special tokens, which
+/// are left out, and tiktoken may encode some chunks differently, as it
+/// merges by the rank of the result rather than of the pair.
+pub fn save(path: &Path, model: &Model) -> io::Result<()> {
+    if model.whitespace_marker {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "tiktoken files can't hold the whitespace marker",
+        ));
+    }
+    let mut vocab: Vec<(u32, Vec<u8>)> = crate::build_vocab(&model.merges).into_iter().collect();
+    vocab.sort_unstable();
+    let mut w = BufWriter::new(File::create(path)?);
+    for (id, bytes) in vocab {
+        writeln!(w, "{} {}", STANDARD.encode(bytes), id)?;
+    }
+    w.flush()
+}
+
+impl Tokenize for TiktokenBpe {
+    fn encode(&self, text: &str) -> Vec<u32> {
+        self.splitter
+            .split(text)
+            .into_iter()
+            .flat_map(|chunk| self.encode_chunk(chunk.as_bytes()))
+            .collect()
+    }
+
+    /// Ids no token has are skipped, with a warning, where tiktoken would
+    /// raise.
+    fn decode(&self, ids: &[u32]) -> String {
+        let mut bytes = vec![];
+        let mut unknown = 0;
+        for id in ids {
+            match self.tokens.get(id) {
+                Some(token) => bytes.extend_from_slice(token),
+                None => unknown += 1,
+            }
+        }
+        if unknown > 0 {
+            let first = ids.iter().find(|id| !self.tokens.contains_key(id));
+            warn!(unknown, first, "no token has these ids; skipping them");
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.tokens
+            .keys()
+            .max()
+            .map_or(0, |&rank| rank as usize + 1)
+    }
+
+    fn contains(&self, id: u32) -> bool {
+        self.tokens.contains_key(&id)
+    }
+
+    fn id_bytes(&self, id: u32) -> Vec<u8> {
+        self.tokens.get(&id).cloned().unwrap_or_default()
+    }
+
+    /// A token's id is its rank, single bytes included.
+    fn rank(&self, id: u32) -> Option<u32> {
+        self.tokens.contains_key(&id).then_some(id)
+    }
+}
+
+fn read_ranks(reader: impl BufRead) -> io::Result<HashMap<Vec<u8>, u32>> {
+    let mut ranks = HashMap::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let parsed = line.split_once(' ').and_then(|(token, rank)| {
+            Some((STANDARD.decode(token).ok()?, rank.parse::<u32>().ok()?))
+        });
+        let Some((token, rank)) = parsed else {
+            return Err(invalid(format!("line {}: invalid rank {:?}", i + 1, line)));
+        };
+        ranks.insert(token, rank);
+    }
+    if let Some(b) = (0..=255u8).find(|&b| !ranks.contains_key(&[b][..])) {
+        return Err(invalid(format!("no rank for byte {:#04x}", b)));
+    }
+    Ok(ranks)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ranks for every byte in byte order, then `extra` tokens.
+    fn ranks(extra: &[&str]) -> HashMap<Vec<u8>, u32> {
+        (0..=255u8)
+            .map(|b| vec![b])
+            .chain(extra.iter().map(|t| t.as_bytes().to_vec()))
+            .zip(0..)
+            .collect()
+    }
+
+    #[test]
+    fn test_encode_chunk() {
+        // "ab" merges first, so "bcd" is never formed
+        let bpe = TiktokenBpe::new(ranks(&["ab", "cd", "bc", "bcd"]), CL100K_PATTERN).unwrap();
+        assert_eq!(bpe.encode_chunk(b"abcd"), vec![256, 257]);
+        // "abc" comes from whichever two parts are next to each other, here
+        // "a" and "bc"; a pair model records only one way to build it
+        let bpe = TiktokenBpe::new(ranks(&["bc", "ab", "abc"]), CL100K_PATTERN).unwrap();
+        assert_eq!(bpe.encode_chunk(b"abc"), vec![258]);
+        assert_eq!(bpe.encode_chunk(b"xabcab"), vec![120, 258, 257]);
+        assert_eq!(bpe.encode_chunk(b"a"), vec![97]);
+        assert_eq!(bpe.encode_chunk(b""), Vec::<u32>::new());
+        assert_eq!(bpe.decode(&bpe.encode("abc bcab")), "abc bcab");
+        assert_eq!(bpe.decode(&[97, 300, 98]), "ab");
+        assert!(!bpe.contains(300));
+    }
+
+    #[test]
+    fn test_to_model() {
+        // "abc" is built from "a" and "bc", as tiktoken would
+        let model = to_model(&ranks(&["bc", "ab", "abc"]), None).unwrap();
+        assert_eq!(
+            model.merges,
+            HashMap::from([((98, 99), 256), ((97, 98), 257), ((97, 256), 258)])
+        );
+        // no two lower ranks make "abc"
+        assert!(to_model(&ranks(&["abc"]), None).is_err());
+
+        let path = std::env::temp_dir().join(format!("bpe-test-{}.tiktoken", std::process::id()));
+        save(&path, &model).unwrap();
+        let ranks = read_ranks(BufReader::new(File::open(&path).unwrap())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(ranks.len(), 259);
+        assert_eq!(ranks[&b"abc"[..]], 258);
+        assert_eq!(to_model(&ranks, None).unwrap().merges, model.merges);
+    }
+
+    #[test]
+    fn test_read_ranks() {
+        let text: String = (0..=255u8)
+            .map(|b| format!("{} {}\n", STANDARD.encode([b]), b))
+            .collect();
+        let extra = format!("{}{} 256\n", text, STANDARD.encode("hi"));
+        let ranks = read_ranks(extra.as_bytes()).unwrap();
+        assert_eq!(ranks[&b"hi"[..]], 256);
+        assert!(read_ranks("aGk= 0\n".as_bytes()).is_err());
+        assert!(read_ranks(format!("{}!! 3\n", text).as_bytes()).is_err());
+        assert!(is_rank_line(b"IQ== 0"));
+        assert!(!is_rank_line(b"bpe v1"));
+    }
+
+    #[test]
+    fn test_patterns() {
+        let split = |pattern, text| Splitter::new(pattern).unwrap().split(text).join("|");
+        assert_eq!(
+            split(CL100K_PATTERN, "Hello world's 12345  \n\n x"),
+            "Hello| world|'s| |123|45|  \n\n| x"
+        );
+        assert_eq!(
+            split(O200K_PATTERN, "HelloWorld's CamelCase 1234 a/b\n"),
+            "Hello|World's| Camel|Case| |123|4| a|/b|\n"
+        );
+        assert_eq!(split(R50K_PATTERN, "it's 42!  "), "it|'s| 42|!|  ");
+    }
+
+    #[test]
+    fn test_encode_matches_tiktoken_fixture() {
+        #[derive(serde::Deserialize)]
+        struct Fixture {
+            cases: Vec<(String, Vec<u32>)>,
+        }
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let ranks = read_ranks(BufReader::new(
+            File::open(dir.join("tiny.tiktoken")).unwrap(),
+        ));
+        let bpe = TiktokenBpe::new(ranks.unwrap(), CL100K_PATTERN).unwrap();
+        let json = std::fs::read(dir.join("tiktoken-encode.json")).unwrap();
+        let fixture: Fixture = serde_json::from_slice(&json).unwrap();
+        assert_eq!(fixture.cases.len(), 131);
+        for (text, ids) in &fixture.cases {
+            assert_eq!(&bpe.encode(text), ids, "{:?}", text);
+            assert_eq!(bpe.decode(ids), *text);
+        }
+    }
+
+    /// Counts from tiktoken, checked against the published files in
+    /// `$BPE_TIKTOKEN_DIR` (cl100k_base.tiktoken and friends), which are
+    /// too large to check in: `cargo test -- --ignored` with it set.
+    #[test]
+    #[ignore = "needs the published rank files in $BPE_TIKTOKEN_DIR"]
+    fn test_published_encodings() {
+        let dir = std::env::var_os("BPE_TIKTOKEN_DIR").expect("BPE_TIKTOKEN_DIR is not set");
+        let dir = Path::new(&dir);
+        let cl100k = TiktokenBpe::load(&dir.join("cl100k_base.tiktoken")).unwrap();
+        assert_eq!(cl100k.encode("hello world"), vec![15339, 1917]);
+        assert_eq!(
+            cl100k.encode("tiktoken is great!"),
+            vec![83, 1609, 5963, 374, 2294, 0]
+        );
+    }
+}
@@ -0,0 +1,1659 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::thread;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::arena::TokenArena;
+use crate::batch::{self, PaddedBatch, PaddingSide};
+use crate::case::{self, Markers};
+use crate::cleanup::DecodeOptions;
+use crate::context;
+use crate::encoding::Encoding;
+use crate::healing::{Healing, PrefixIndex};
+use crate::mmap::{self, MappedModel};
+use crate::model::{self, Model};
+use crate::pretokenize::{self, Splitter};
+use crate::render::{self, PieceStyle};
+use crate::store::{self, Located};
+use crate::template::{self, PairEncoding, PostProcessor, Truncation, TruncationStrategy};
+use crate::text_splitter;
+use crate::tiktoken::{self, TiktokenBpe};
+use crate::unigram::{self, Unigram};
+use crate::wordpiece::{self, WordPiece};
+use crate::{build_vocab, encode, encode_text_into};
+
+/// Encoding and decoding, whatever the algorithm behind it.
+pub trait Tokenize {
+    fn encode(&self, text: &str) -> Vec<u32>;
+    fn decode(&self, ids: &[u32]) -> String;
+    /// Number of token ids, including special tokens: one past the largest.
+    fn vocab_size(&self) -> usize;
+
+    /// Whether a token has this id. Ids below `vocab_size` can be gaps,
+    /// which decoding skips.
+    fn contains(&self, id: u32) -> bool {
+        (id as usize) < self.vocab_size()
+    }
+
+    /// Whether `id` is a special token that `decode_with` can leave out.
+    fn is_special(&self, _id: u32) -> bool {
+        false
+    }
+
+    /// The bytes of one token id; by default the text it decodes to.
+    fn id_bytes(&self, id: u32) -> Vec<u8> {
+        self.decode(&[id]).into_bytes()
+    }
+
+    /// The rank a model merges a token at, 0 for the first merge learned;
+    /// None for bytes, special tokens and models that don't merge.
+    fn rank(&self, _id: u32) -> Option<u32> {
+        None
+    }
+
+    /// Decodes with the cleanups in `options`.
+    fn decode_with(&self, ids: &[u32], options: &DecodeOptions) -> String {
+        let text = if options.skip_special_tokens {
+            let ids: Vec<u32> = ids
+                .iter()
+                .copied()
+                .filter(|&id| !self.is_special(id))
+                .collect();
+            self.decode(&ids)
+        } else {
+            self.decode(ids)
+        };
+        options.clean(text)
+    }
+
+    /// Encodes all the text of a reader, passing ids to `sink` as they are
+    /// produced, and returns how many there were. Reads everything first
+    /// unless the model can encode in bounded memory.
+    fn encode_reader(
+        &self,
+        reader: &mut dyn Read,
+        sink: &mut dyn FnMut(&[u32]) -> io::Result<()>,
+    ) -> io::Result<usize> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        let ids = self.encode(&text);
+        sink(&ids)?;
+        Ok(ids.len())
+    }
+
+    /// Like `encode_reader`, over UTF-8 text in memory such as a mapped file.
+    fn encode_slice(
+        &self,
+        bytes: &[u8],
+        sink: &mut dyn FnMut(&[u32]) -> io::Result<()>,
+    ) -> io::Result<usize> {
+        let text = std::str::from_utf8(bytes)
+            .map_err(|e| context::invalid_utf8(bytes, e.valid_up_to(), 0))?;
+        let ids = self.encode(text);
+        sink(&ids)?;
+        Ok(ids.len())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    /// Byte pair encoding merges
+    #[default]
+    Bpe,
+    /// Unigram language model with Viterbi encoding
+    Unigram,
+    /// BERT-style WordPiece with `##` continuation pieces
+    #[value(name = "wordpiece")]
+    WordPiece,
+}
+
+/// Loads a model file of any algorithm, telling them apart by the header.
+pub fn load(path: &Path) -> io::Result<Box<dyn Tokenize>> {
+    load_with(path, false, |tokenizer| tokenizer)
+}
+
+/// Like `load`, but defers building BPE vocabulary tables until something
+/// other than encoding needs them.
+pub fn load_encode_only(path: &Path) -> io::Result<Box<dyn Tokenize>> {
+    load_with(path, true, |tokenizer| tokenizer)
+}
+
+/// Like `load_encode_only`, passing a BPE tokenizer through `configure`,
+/// e.g. to set its threads; other models are loaded as they are.
+pub fn load_encode_only_with(
+    path: &Path,
+    configure: impl FnOnce(Tokenizer) -> Tokenizer,
+) -> io::Result<Box<dyn Tokenize>> {
+    load_with(path, true, configure)
+}
+
+/// Loads a model of any algorithm given as the name of an installed or
+/// bundled model, a path, or a URL, as `store::locate` finds it.
+pub fn from_pretrained(model: &str) -> io::Result<Box<dyn Tokenize>> {
+    match store::locate(model)? {
+        Located::File(path) => load(&path),
+        Located::Bundled(bytes) => Ok(Box::new(Tokenizer::new(model::from_bytes(bytes)?)?)),
+    }
+}
+
+fn load_with(
+    path: &Path,
+    encode_only: bool,
+    configure: impl FnOnce(Tokenizer) -> Tokenizer,
+) -> io::Result<Box<dyn Tokenize>> {
+    // binary models need not hold a newline or valid UTF-8
+    let mut header = vec![];
+    BufReader::new(File::open(path)?).read_until(b'\n', &mut header)?;
+    let header = header.strip_suffix(b"\n").unwrap_or(&header);
+    Ok(if header.starts_with(mmap::MAGIC) {
+        Box::new(MappedModel::open(path)?)
+    } else if header == unigram::HEADER.as_bytes() {
+        Box::new(Unigram::load(path)?)
+    } else if header == wordpiece::HEADER.as_bytes() {
+        Box::new(WordPiece::load(path)?)
+    } else if tiktoken::is_rank_line(header) {
+        Box::new(TiktokenBpe::load(path)?)
+    } else if encode_only {
+        Box::new(configure(Tokenizer::load_encode_only(path)?))
+    } else {
+        Box::new(configure(Tokenizer::load(path)?))
+    })
+}
+
+/// Bytes read at a time by `Tokenizer::encode_reader`.
+#[cfg(not(test))]
+const STREAM_BLOCK: usize = 1 << 20;
+/// Small in tests, so short texts span several blocks.
+#[cfg(test)]
+const STREAM_BLOCK: usize = 16;
+
+/// The length of the valid UTF-8 at the start of `bytes`, which may stop
+/// short of a character cut off at the end unless the input ends there.
+/// `offset` is where `bytes` starts in the input, for errors.
+fn utf8_len(bytes: &[u8], eof: bool, offset: usize) -> io::Result<usize> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Ok(text.len()),
+        Err(e) if e.error_len().is_none() && !eof => Ok(e.valid_up_to()),
+        Err(e) => Err(context::invalid_utf8(bytes, e.valid_up_to(), offset)),
+    }
+}
+
+/// A trained BPE model ready for encoding and decoding.
+pub struct Tokenizer {
+    merges: HashMap<(u32, u32), u32>,
+    vocab: OnceLock<Vocab>,
+    splitter: Option<Splitter>,
+    special_tokens: HashMap<String, u32>,
+    /// Set when the model has the case marker special tokens: text is
+    /// lowercased with markers on encode and recased on decode.
+    case_markers: Option<Markers>,
+    /// Spaces are replaced by `pretokenize::WHITESPACE_MARKER` in each
+    /// chunk before encoding, and restored on decode.
+    whitespace_marker: bool,
+    /// A space is put before the text on encode and removed on decode.
+    add_prefix_space: bool,
+    /// Threads sharing the chunks of each block in `encode_reader`.
+    threads: usize,
+    /// One past the largest id.
+    vocab_size: usize,
+    pad_id: u32,
+    post_processor: Option<PostProcessor>,
+}
+
+impl FromStr for Tokenizer {
+    type Err = io::Error;
+
+    fn from_str(model: &str) -> io::Result<Tokenizer> {
+        Tokenizer::from_pretrained(model)
+    }
+}
+
+/// Tables derived from the merges, needed to decode and look up tokens but
+/// not to encode.
+struct Vocab {
+    bytes: TokenArena,
+    ids: HashMap<Vec<u8>, u32>,
+    max_token_len: usize,
+    prefix_index: PrefixIndex,
+}
+
+impl Vocab {
+    fn new(merges: &HashMap<(u32, u32), u32>, special_tokens: &HashMap<String, u32>) -> Vocab {
+        let mut bytes = build_vocab(merges);
+        let mut ids = HashMap::new();
+        for (&id, token) in &bytes {
+            let known = ids.entry(token.clone()).or_insert(id);
+            *known = id.min(*known);
+        }
+        let max_token_len = ids.keys().map(Vec::len).max().unwrap_or(1);
+        for (token, &idx) in special_tokens {
+            bytes.insert(idx, token.as_bytes().to_vec());
+        }
+        let special: HashSet<u32> = special_tokens.values().copied().collect();
+        let prefix_index = PrefixIndex::new(&bytes, |id| special.contains(&id));
+        Vocab {
+            bytes: TokenArena::new(&bytes),
+            ids,
+            max_token_len,
+            prefix_index,
+        }
+    }
+}
+
+impl Tokenizer {
+    pub fn new(model: Model) -> io::Result<Tokenizer> {
+        let tokenizer = Tokenizer::new_encode_only(model)?;
+        tokenizer.vocab();
+        Ok(tokenizer)
+    }
+
+    /// Builds a tokenizer without the vocabulary tables, which are only
+    /// materialized if a method other than encoding needs them. Saves memory
+    /// in services that only encode or count.
+    pub fn new_encode_only(model: Model) -> io::Result<Tokenizer> {
+        let splitter = model.pattern.as_deref().map(Splitter::new).transpose()?;
+        let largest = model
+            .merges
+            .values()
+            .chain(model.special_tokens.values())
+            .max();
+        Ok(Tokenizer {
+            vocab_size: largest.map_or(256, |&id| (id as usize + 1).max(256)),
+            merges: model.merges,
+            vocab: OnceLock::new(),
+            splitter,
+            case_markers: Markers::find(&model.special_tokens),
+            special_tokens: model.special_tokens,
+            whitespace_marker: model.whitespace_marker,
+            add_prefix_space: false,
+            threads: 1,
+            pad_id: 0,
+            post_processor: None,
+        })
+    }
+
+    pub fn load_encode_only(path: &Path) -> io::Result<Tokenizer> {
+        Tokenizer::new_encode_only(model::load(path)?)
+    }
+
+    fn vocab(&self) -> &Vocab {
+        self.vocab
+            .get_or_init(|| Vocab::new(&self.merges, &self.special_tokens))
+    }
+
+    /// Sets the id used for padding batches (0 by default; padded positions
+    /// are masked out either way).
+    pub fn with_pad_id(mut self, pad_id: u32) -> Tokenizer {
+        self.pad_id = pad_id;
+        self
+    }
+
+    pub fn load(path: &Path) -> io::Result<Tokenizer> {
+        Tokenizer::new(model::load(path)?)
+    }
+
+    /// Loads a BPE model given as the name of an installed or bundled
+    /// model, a path, or a URL, as `store::locate` finds it. Also available
+    /// as `"name".parse::<Tokenizer>()`.
+    pub fn from_pretrained(model: &str) -> io::Result<Tokenizer> {
+        Tokenizer::new(match store::locate(model)? {
+            Located::File(path) => model::load(&path)?,
+            Located::Bundled(bytes) => model::from_bytes(bytes)?,
+        })
+    }
+
+    /// Encodes the blocks read by `encode_reader` on this many threads (1 by
+    /// default). A block is split into chunks first and their ids are joined
+    /// in order, so the result doesn't depend on it.
+    pub fn with_threads(mut self, threads: usize) -> Tokenizer {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Encodes every text as if it followed a space, as GPT-2 and RoBERTa
+    /// tokenizers can, so a sentence-initial word gets the same tokens as
+    /// mid-sentence. The space is added even if the text already starts with
+    /// one, and decoding removes it, so texts round-trip exactly.
+    pub fn with_add_prefix_space(mut self, add_prefix_space: bool) -> Tokenizer {
+        self.add_prefix_space = add_prefix_space;
+        self
+    }
+
+    /// Sets the templates that add special tokens in `encode_with_special_tokens`
+    /// and `encode_pair`.
+    pub fn with_post_processor(mut self, post_processor: PostProcessor) -> Tokenizer {
+        self.post_processor = Some(post_processor);
+        self
+    }
+
+    pub fn merges(&self) -> &HashMap<(u32, u32), u32> {
+        &self.merges
+    }
+
+    pub fn special_tokens(&self) -> &HashMap<String, u32> {
+        &self.special_tokens
+    }
+
+    /// The bytes a token id stands for, empty for an id no token has.
+    pub fn token_bytes(&self, id: u32) -> &[u8] {
+        self.id_to_token(id).unwrap_or_default()
+    }
+
+    /// Number of token ids: one past the largest, which is the number of
+    /// bytes, merges and special tokens unless special ids leave gaps.
+    pub fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    /// Every `(id, bytes)` entry of the vocabulary, in increasing id order.
+    pub fn tokens(&self) -> impl Iterator<Item = (u32, &[u8])> {
+        self.vocab().bytes.iter()
+    }
+
+    pub fn is_special(&self, id: u32) -> bool {
+        self.special_tokens.values().any(|&s| s == id)
+    }
+
+    /// The rank of a merge (0 for the first one learned), if the pair is
+    /// merged at all.
+    pub fn merge_rank(&self, pair: (u32, u32)) -> Option<u32> {
+        self.merges.get(&pair).map(|idx| idx - 256)
+    }
+
+    /// The bytes of a token id, if it is in the vocabulary.
+    pub fn id_to_token(&self, id: u32) -> Option<&[u8]> {
+        self.vocab().bytes.get(id)
+    }
+
+    /// The id of the token with exactly these bytes. Regular tokens win over
+    /// special tokens with the same text.
+    pub fn token_to_id(&self, bytes: &[u8]) -> Option<u32> {
+        self.vocab().ids.get(bytes).copied().or_else(|| {
+            let token = std::str::from_utf8(bytes).ok()?;
+            self.special_tokens.get(token).copied()
+        })
+    }
+
+    /// The piece string of a token id, if it is in the vocabulary.
+    pub fn id_to_piece(&self, id: u32, style: PieceStyle) -> Option<String> {
+        self.id_to_token(id).map(|_| self.render_token(id, style))
+    }
+
+    /// The id of a piece string as printed by `id_to_piece`.
+    pub fn piece_to_id(&self, piece: &str, style: PieceStyle) -> Option<u32> {
+        match self.special_tokens.get(piece) {
+            Some(&id) => Some(id),
+            None => self.token_to_id(&render::parse(piece, style)?),
+        }
+    }
+
+    /// A printable piece string for a token id; special tokens are shown as is.
+    pub fn render_token(&self, id: u32, style: PieceStyle) -> String {
+        if self.is_special(id) {
+            return String::from_utf8_lossy(self.token_bytes(id)).into();
+        }
+        render::render(id, self.token_bytes(id), style)
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        let mut ids = vec![];
+        self.encode_into(text, &mut ids);
+        ids
+    }
+
+    /// Appends the ids of `text` to `ids`, so a loop encoding many short
+    /// strings can reuse one vector.
+    pub fn encode_into(&self, text: &str, ids: &mut Vec<u32>) {
+        if self.add_prefix_space {
+            self.encode_unprefixed_into(&format!(" {}", text), ids);
+        } else {
+            self.encode_unprefixed_into(text, ids);
+        }
+    }
+
+    /// Encodes text read from `reader` in bounded memory, passing the ids to
+    /// `sink` a block at a time, and returns how many there were. Each block
+    /// is cut before its last pre-tokenized chunk, which is carried over to
+    /// the next one, so the ids are exactly those of encoding the whole text
+    /// at once. Without a pattern the text is a single chunk and is buffered
+    /// whole.
+    pub fn encode_reader(
+        &self,
+        mut reader: impl Read,
+        mut sink: impl FnMut(&[u32]) -> io::Result<()>,
+    ) -> io::Result<usize> {
+        let mut buf = vec![];
+        let mut prefix = self.add_prefix_space;
+        let mut drained = 0;
+        // how much of `buf` is known to be valid UTF-8, so that only what
+        // was read since is checked
+        let mut checked = 0;
+        let mut ids = vec![];
+        let mut count = 0;
+        loop {
+            let len = buf.len();
+            buf.resize(len + STREAM_BLOCK * self.threads, 0);
+            let n = loop {
+                match reader.read(&mut buf[len..]) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    result => break result?,
+                }
+            };
+            buf.truncate(len + n);
+            let eof = n == 0;
+            let valid = match utf8_len(&buf[checked..], eof, drained + checked) {
+                Ok(len) => checked + len,
+                // again in full, for the text before the error
+                Err(_) => utf8_len(&buf, eof, drained)?,
+            };
+            // SAFETY: `buf[..checked]` was checked before, and the rest just
+            // now, starting on a character boundary
+            let text = unsafe { std::str::from_utf8_unchecked(&buf[..valid]) };
+            let end = self
+                .encode_window(text, eof, &mut prefix, &mut ids)
+                .unwrap_or(0);
+            checked = valid - end;
+            if !ids.is_empty() {
+                sink(&ids)?;
+                count += ids.len();
+                ids.clear();
+            }
+            buf.drain(..end);
+            drained += end;
+            if eof {
+                return Ok(count);
+            }
+        }
+    }
+
+    /// Encodes UTF-8 text in place, such as a memory-mapped file, like
+    /// `encode_reader` but without copying it: blocks are windows into
+    /// `bytes`, widened when a chunk is longer than a block.
+    pub fn encode_slice(
+        &self,
+        bytes: &[u8],
+        mut sink: impl FnMut(&[u32]) -> io::Result<()>,
+    ) -> io::Result<usize> {
+        let block = STREAM_BLOCK * self.threads;
+        let (mut start, mut len) = (0, block);
+        let mut prefix = self.add_prefix_space;
+        let mut ids = vec![];
+        let mut count = 0;
+        while start < bytes.len() || prefix {
+            let eof = start + len >= bytes.len();
+            let window = &bytes[start..bytes.len().min(start + len)];
+            let valid = utf8_len(window, eof, start)?;
+            let text = std::str::from_utf8(&window[..valid]).expect("checked above");
+            let consumed = self.encode_window(text, eof, &mut prefix, &mut ids);
+            if !ids.is_empty() {
+                sink(&ids)?;
+                count += ids.len();
+                ids.clear();
+            }
+            match consumed {
+                Some(consumed) => {
+                    start += consumed;
+                    len = block;
+                }
+                // a single chunk fills the window
+                None => len *= 2,
+            }
+        }
+        Ok(count)
+    }
+
+    /// Encodes the chunks of the text read so far that more input can't
+    /// change (all of them at the end of the input), and returns how much of
+    /// `text` they took, or `None` if there were none. While `prefix` is set,
+    /// the text is encoded after the prefix space, which is copied in.
+    fn encode_window(
+        &self,
+        text: &str,
+        eof: bool,
+        prefix: &mut bool,
+        ids: &mut Vec<u32>,
+    ) -> Option<usize> {
+        // without a pattern the text is one chunk, complete only at the end;
+        // returning early keeps a long input from being copied or scanned
+        // again with every block
+        if self.splitter.is_none() && !eof {
+            return None;
+        }
+        let complete = |text: &str| {
+            if eof {
+                text.len()
+            } else {
+                self.chunks(text)
+                    .last()
+                    .map_or(0, |last| last.as_ptr() as usize - text.as_ptr() as usize)
+            }
+        };
+        if *prefix {
+            let text = format!(" {}", text);
+            let end = complete(&text);
+            self.encode_block(&text[..end], ids);
+            *prefix = end == 0;
+            return (end > 0).then(|| end - 1);
+        }
+        let end = complete(text);
+        self.encode_block(&text[..end], ids);
+        (end > 0).then_some(end)
+    }
+
+    /// Encodes like `encode_unprefixed_into`, sharing the chunks out among
+    /// the tokenizer's threads.
+    fn encode_block(&self, text: &str, ids: &mut Vec<u32>) {
+        if self.threads == 1 {
+            return self.encode_unprefixed_into(text, ids);
+        }
+        let chunks = self.chunks(text);
+        let shard_len = chunks.len().div_ceil(self.threads).max(1);
+        let shards: Vec<Vec<u32>> = thread::scope(|s| {
+            let handles: Vec<_> = chunks
+                .chunks(shard_len)
+                .map(|shard| {
+                    s.spawn(move || {
+                        let mut ids = vec![];
+                        for chunk in shard {
+                            ids.extend(self.encode_chunk(chunk));
+                        }
+                        ids
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("encoding thread panicked"))
+                .collect()
+        });
+        ids.extend(shards.into_iter().flatten());
+    }
+
+    fn encode_unprefixed_into(&self, text: &str, ids: &mut Vec<u32>) {
+        if self.case_markers.is_none() && !self.whitespace_marker {
+            return encode_text_into(&self.merges, self.splitter.as_ref(), text, ids);
+        }
+        for chunk in self.chunks(text) {
+            ids.extend(self.encode_chunk(chunk));
+        }
+    }
+
+    /// Encodes one pre-tokenized chunk. With case markers, the chunk is
+    /// folded on its own and each of its segments encoded after its marker,
+    /// so markers never cross chunks; with the whitespace marker, spaces are
+    /// marked first.
+    pub fn encode_chunk(&self, chunk: &str) -> Vec<u32> {
+        if self.case_markers.is_none() && !self.whitespace_marker {
+            return encode(&self.merges, chunk);
+        }
+        let mut ids = vec![];
+        for (marker, segment) in self.segments(chunk) {
+            ids.extend(marker);
+            ids.extend(encode(&self.merges, &segment));
+        }
+        ids
+    }
+
+    /// A chunk as the merges see it: folded into segments, each after the id
+    /// of its case marker if any, and with spaces marked, as the model asks.
+    pub fn segments(&self, chunk: &str) -> Vec<(Option<u32>, String)> {
+        let mark = |segment: String| {
+            if self.whitespace_marker {
+                pretokenize::mark_spaces(&segment)
+            } else {
+                segment
+            }
+        };
+        let Some(markers) = self.case_markers else {
+            return vec![(None, mark(chunk.to_string()))];
+        };
+        case::fold(chunk)
+            .into_iter()
+            .map(|(case, segment)| (case.map(|case| markers.id(case)), mark(segment)))
+            .collect()
+    }
+
+    /// Encodes text along with the byte range of `text` each id came from.
+    /// A token that is part of a character spans the whole character; case
+    /// markers are empty ranges at the start of the text they mark, and with
+    /// `add_prefix_space` the added space belongs to the first token.
+    pub fn encode_with_offsets(&self, text: &str) -> (Vec<u32>, Vec<(usize, usize)>) {
+        let prefixed;
+        let (source, shift) = if self.add_prefix_space {
+            prefixed = format!(" {}", text);
+            (prefixed.as_str(), 1)
+        } else {
+            (text, 0)
+        };
+        let (mut ids, mut offsets) = (vec![], vec![]);
+        for chunk in self.chunks(source) {
+            let chunk_start = chunk.as_ptr() as usize - source.as_ptr() as usize;
+            let segments = match self.case_markers {
+                Some(_) => case::fold(chunk),
+                None => vec![(None, chunk.to_string())],
+            };
+            // folded and marked text map back to the chunk char by char
+            let folded: String = segments.iter().map(|(_, s)| s.as_str()).collect();
+            let mut rest = folded.as_str();
+            let fold_map = align(chunk, |c| {
+                let len = if rest.starts_with(c) {
+                    c.len_utf8()
+                } else {
+                    c.to_lowercase().map(char::len_utf8).sum()
+                };
+                rest = rest.get(len..).unwrap_or("");
+                len
+            });
+            let end_of_chunk = (chunk.len(), chunk.len());
+            let to_text = |(folded_start, folded_end): (usize, usize)| {
+                let start = fold_map.get(folded_start).unwrap_or(&end_of_chunk).0;
+                let end = fold_map.get(folded_end).unwrap_or(&end_of_chunk).1;
+                (
+                    (chunk_start + start).saturating_sub(shift),
+                    (chunk_start + end).saturating_sub(shift),
+                )
+            };
+            let mut segment_start = 0;
+            for (case, segment) in &segments {
+                if let (Some(case), Some(markers)) = (case, self.case_markers) {
+                    ids.push(markers.id(*case));
+                    offsets.push(to_text((segment_start, segment_start)));
+                }
+                let (segment_ids, mark_map) = if self.whitespace_marker {
+                    let marked = pretokenize::mark_spaces(segment);
+                    let map = align(segment, |c| match c {
+                        ' ' => pretokenize::WHITESPACE_MARKER.len_utf8(),
+                        c => c.len_utf8(),
+                    });
+                    (encode(&self.merges, &marked), map)
+                } else {
+                    let map = (0..=segment.len()).map(|p| (p, p)).collect();
+                    (encode(&self.merges, segment), map)
+                };
+                let mut pos = 0;
+                for id in segment_ids {
+                    let next = pos + self.token_bytes(id).len();
+                    let end_of_segment = (segment.len(), segment.len());
+                    let (mut start, mut end) = to_text((
+                        segment_start + mark_map.get(pos).unwrap_or(&end_of_segment).0,
+                        segment_start + mark_map.get(next).unwrap_or(&end_of_segment).1,
+                    ));
+                    while !text.is_char_boundary(start) {
+                        start -= 1;
+                    }
+                    while !text.is_char_boundary(end) {
+                        end += 1;
+                    }
+                    offsets.push((start, end));
+                    ids.push(id);
+                    pos = next;
+                }
+                segment_start += segment.len();
+            }
+        }
+        (ids, offsets)
+    }
+
+    /// Encodes text into an `Encoding` with the single-sequence template
+    /// applied. With `truncation`, the encoding is the first window that fits
+    /// in `truncation.max_len` and the rest are its overflow windows, as
+    /// `encode_overflowing` cuts them.
+    pub fn encode_full(&self, text: &str, truncation: Option<Truncation>) -> io::Result<Encoding> {
+        let (ids, offsets) = self.encode_with_offsets(text);
+        let tokens = Encoding::from_tokens(ids, offsets);
+        let post = |window: &Encoding| match &self.post_processor {
+            Some(post) => post.single_encoding(window),
+            None => window.clone(),
+        };
+        let Some(truncation) = truncation else {
+            return Ok(post(&tokens));
+        };
+        let overhead = self
+            .post_processor
+            .as_ref()
+            .map_or(0, |p| p.single_overhead());
+        let len = truncation.max_len.saturating_sub(overhead);
+        let mut start = 0;
+        let mut windows = vec![];
+        for window in template::windows(&tokens.ids, len, truncation.stride)? {
+            windows.push(post(&tokens.slice(start..start + window.len())));
+            start += window.len().saturating_sub(truncation.stride);
+        }
+        let mut encoding = windows.remove(0);
+        encoding.overflowing = windows;
+        Ok(encoding)
+    }
+
+    /// Whether spaces are encoded as `pretokenize::WHITESPACE_MARKER`.
+    pub fn has_whitespace_marker(&self) -> bool {
+        self.whitespace_marker
+    }
+
+    /// Whether the model is uncased with case markers.
+    pub fn has_case_markers(&self) -> bool {
+        self.case_markers.is_some()
+    }
+
+    /// The pre-tokenized chunks that are encoded independently.
+    pub fn chunks<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        pretokenize::split(self.splitter.as_ref(), text)
+    }
+
+    /// Encodes each chunk by repeatedly taking the longest token that
+    /// prefixes the rest, instead of applying merges in rank order. The two
+    /// usually agree but can differ in either direction.
+    pub fn encode_greedy(&self, text: &str) -> Vec<u32> {
+        let vocab = self.vocab();
+        let mut ids = vec![];
+        for (marker, segment) in self.chunks(text).into_iter().flat_map(|c| self.segments(c)) {
+            ids.extend(marker);
+            let mut rest = segment.as_bytes();
+            while !rest.is_empty() {
+                let (len, id) = (1..=rest.len().min(vocab.max_token_len))
+                    .rev()
+                    .find_map(|len| Some((len, *vocab.ids.get(&rest[..len])?)))
+                    .expect("single bytes are tokens");
+                ids.push(id);
+                rest = &rest[len..];
+            }
+        }
+        ids
+    }
+
+    /// The longest prefix of `text` made of at most `max_tokens` of its
+    /// tokens, cut only between tokens and never inside a UTF-8 character.
+    /// The prefix's ids are the first ids of `encode(text)`.
+    pub fn truncate_to_tokens<'a>(&self, text: &'a str, max_tokens: usize) -> &'a str {
+        let (_, cuts) = text_splitter::cuts(self, text);
+        let cut = cuts.iter().take(max_tokens + 1).flatten().last();
+        &text[..cut.copied().unwrap_or(0)]
+    }
+
+    /// Removes up to `back_off` trailing tokens from a prompt for token
+    /// healing, stopping at special tokens. Generate from `Healing::ids`,
+    /// restricting each step to `healing_allowed` until the healing is done.
+    pub fn heal(&self, ids: &[u32], back_off: usize) -> Healing {
+        let keep = ids.len()
+            - ids
+                .iter()
+                .rev()
+                .take(back_off)
+                .take_while(|&&id| !self.is_special(id))
+                .count();
+        Healing {
+            ids: ids[..keep].to_vec(),
+            prefix: ids[keep..]
+                .iter()
+                .flat_map(|&id| self.token_bytes(id))
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// The token ids allowed as the next generated token while healing.
+    pub fn healing_allowed(&self, healing: &Healing) -> Vec<u32> {
+        self.vocab().prefix_index.allowed(&healing.prefix)
+    }
+
+    /// Encodes text and wraps it in the single-sequence template, if any.
+    pub fn encode_with_special_tokens(&self, text: &str) -> Vec<u32> {
+        let ids = self.encode(text);
+        match &self.post_processor {
+            Some(post) => post.single(&ids),
+            None => ids,
+        }
+    }
+
+    /// Encodes two texts into one sequence with type ids, using the pair
+    /// template or simply concatenating them when there is none. With
+    /// `truncation`, the texts are shortened so the result, special tokens
+    /// included, fits in `max_len` (unless the strategy may only shorten a
+    /// sequence that is too short to make room).
+    pub fn encode_pair(&self, a: &str, b: &str, truncation: Option<Truncation>) -> PairEncoding {
+        let (mut a, mut b) = (self.encode(a), self.encode(b));
+        let overhead = self
+            .post_processor
+            .as_ref()
+            .map_or(0, |p| p.pair_overhead());
+        if let Some(t) = truncation {
+            let budget = t.max_len.saturating_sub(overhead);
+            template::truncate_pair(&mut a, &mut b, budget, t.strategy);
+        }
+        match &self.post_processor {
+            Some(post) => post.pair(&a, &b),
+            None => PairEncoding {
+                type_ids: [vec![0; a.len()], vec![1; b.len()]].concat(),
+                ids: [a, b].concat(),
+            },
+        }
+    }
+
+    /// Encodes text into overflow windows that each fit in
+    /// `truncation.max_len` with the single-sequence template applied, rather
+    /// than dropping what doesn't fit. The first window is what
+    /// `encode_with_special_tokens` would give truncated; each one after it
+    /// repeats `truncation.stride` tokens of the one before.
+    pub fn encode_overflowing(
+        &self,
+        text: &str,
+        truncation: Truncation,
+    ) -> io::Result<Vec<Vec<u32>>> {
+        let ids = self.encode(text);
+        let overhead = self
+            .post_processor
+            .as_ref()
+            .map_or(0, |p| p.single_overhead());
+        let len = truncation.max_len.saturating_sub(overhead);
+        let windows = template::windows(&ids, len, truncation.stride)?;
+        Ok(windows
+            .into_iter()
+            .map(|window| match &self.post_processor {
+                Some(post) => post.single(window),
+                None => window.to_vec(),
+            })
+            .collect())
+    }
+
+    /// Like `encode_pair`, but returns overflow windows of the sequence the
+    /// strategy shortens, each paired with the whole other sequence, instead
+    /// of dropping what doesn't fit. As in Hugging Face tokenizers, this
+    /// needs `OnlyFirst` or `OnlySecond`: with `LongestFirst` it is unclear
+    /// which sequence overflows.
+    pub fn encode_pair_overflowing(
+        &self,
+        a: &str,
+        b: &str,
+        truncation: Truncation,
+    ) -> io::Result<Vec<PairEncoding>> {
+        let (a, b) = (self.encode(a), self.encode(b));
+        let overhead = self
+            .post_processor
+            .as_ref()
+            .map_or(0, |p| p.pair_overhead());
+        let budget = truncation.max_len.saturating_sub(overhead);
+        let pair = |a: &[u32], b: &[u32]| match &self.post_processor {
+            Some(post) => post.pair(a, b),
+            None => PairEncoding {
+                ids: [a, b].concat(),
+                type_ids: [vec![0; a.len()], vec![1; b.len()]].concat(),
+            },
+        };
+        let stride = truncation.stride;
+        Ok(match truncation.strategy {
+            TruncationStrategy::OnlyFirst => {
+                let len = budget.saturating_sub(b.len());
+                let windows = template::windows(&a, len, stride)?;
+                windows.into_iter().map(|a| pair(a, &b)).collect()
+            }
+            TruncationStrategy::OnlySecond => {
+                let len = budget.saturating_sub(a.len());
+                let windows = template::windows(&b, len, stride)?;
+                windows.into_iter().map(|b| pair(&a, b)).collect()
+            }
+            TruncationStrategy::LongestFirst => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "overflow windows of a pair need OnlyFirst or OnlySecond truncation",
+                ))
+            }
+        })
+    }
+
+    /// Encodes a batch of texts into a rectangular matrix plus attention
+    /// masks, truncating to `max_len` and padding on the given side.
+    pub fn encode_batch_padded(
+        &self,
+        texts: &[&str],
+        max_len: Option<usize>,
+        padding_side: PaddingSide,
+    ) -> PaddedBatch {
+        let seqs = texts.iter().map(|text| self.encode(text)).collect();
+        batch::pad(seqs, max_len, padding_side, self.pad_id)
+    }
+
+    pub fn decode(&self, ids: &[u32]) -> String {
+        let mut text = String::new();
+        self.decode_str_into(ids, &mut text);
+        text
+    }
+
+    /// Appends the bytes of `ids` to `out`, so a loop decoding many
+    /// sequences can reuse one buffer. Ids no token has are skipped.
+    pub fn decode_into(&self, ids: &[u32], out: &mut Vec<u8>) {
+        let vocab = &self.vocab().bytes;
+        for &id in ids {
+            out.extend_from_slice(vocab.get(id).unwrap_or_default());
+        }
+    }
+
+    /// Appends the decoded text of `ids` to `out`, replacing invalid UTF-8
+    /// like `decode` does.
+    pub fn decode_str_into(&self, ids: &[u32], out: &mut String) {
+        let start = out.len();
+        self.decode_cased_into(ids, out);
+        if self.add_prefix_space && out[start..].starts_with(' ') {
+            out.remove(start);
+        }
+    }
+
+    fn decode_cased_into(&self, ids: &[u32], out: &mut String) {
+        let Some(markers) = self.case_markers else {
+            return self.decode_lossy_into(ids, out);
+        };
+        // each marker recases the text up to the next one
+        let mut case = None;
+        let mut segment = String::new();
+        for part in ids.split_inclusive(|&id| markers.case(id).is_some()) {
+            let (last, text) = part.split_last().expect("parts are not empty");
+            let next = markers.case(*last);
+            let text = if next.is_some() { text } else { part };
+            segment.clear();
+            self.decode_lossy_into(text, &mut segment);
+            match case {
+                Some(case) => out.push_str(&case::unfold(case, &segment)),
+                None => out.push_str(&segment),
+            }
+            case = next;
+        }
+    }
+
+    fn decode_lossy_into(&self, ids: &[u32], out: &mut String) {
+        if self.whitespace_marker {
+            let mut text = String::new();
+            self.decode_bytes_lossy_into(ids, &mut text);
+            out.push_str(&pretokenize::unmark_spaces(&text));
+        } else {
+            self.decode_bytes_lossy_into(ids, out);
+        }
+    }
+
+    fn decode_bytes_lossy_into(&self, ids: &[u32], out: &mut String) {
+        let start = out.len();
+        let mut bytes = std::mem::take(out).into_bytes();
+        self.decode_into(ids, &mut bytes);
+        *out = match String::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(e) => {
+                let mut bytes = e.into_bytes();
+                let tail = bytes.split_off(start);
+                let mut text = String::from_utf8(bytes).expect("valid before decoding");
+                text.push_str(&String::from_utf8_lossy(&tail));
+                text
+            }
+        };
+    }
+}
+
+/// Maps each byte position of text derived from `source` char by char to
+/// the positions in `source` it came from, given how many bytes each char
+/// became: the start of a char twice, and a position inside what a char
+/// became to the char's start and end, for the start and end of a token.
+fn align(source: &str, mut derived_len: impl FnMut(char) -> usize) -> Vec<(usize, usize)> {
+    let mut map = Vec::with_capacity(source.len() + 1);
+    for (start, c) in source.char_indices() {
+        let end = start + c.len_utf8();
+        map.push((start, start));
+        map.extend((1..derived_len(c)).map(|_| (start, end)));
+    }
+    map.push((source.len(), source.len()));
+    map
+}
+
+impl Tokenize for Tokenizer {
+    fn encode(&self, text: &str) -> Vec<u32> {
+        Tokenizer::encode(self, text)
+    }
+
+    fn decode(&self, ids: &[u32]) -> String {
+        Tokenizer::decode(self, ids)
+    }
+
+    fn vocab_size(&self) -> usize {
+        Tokenizer::vocab_size(self)
+    }
+
+    fn contains(&self, id: u32) -> bool {
+        self.id_to_token(id).is_some()
+    }
+
+    /// Case markers aren't, as decoding needs them to restore case.
+    fn is_special(&self, id: u32) -> bool {
+        Tokenizer::is_special(self, id) && self.case_markers.is_none_or(|m| m.case(id).is_none())
+    }
+
+    fn id_bytes(&self, id: u32) -> Vec<u8> {
+        self.id_to_token(id).unwrap_or_default().to_vec()
+    }
+
+    fn rank(&self, id: u32) -> Option<u32> {
+        (256..256 + self.merges.len() as u32)
+            .contains(&id)
+            .then(|| id - 256)
+    }
+
+    fn encode_reader(
+        &self,
+        reader: &mut dyn Read,
+        sink: &mut dyn FnMut(&[u32]) -> io::Result<()>,
+    ) -> io::Result<usize> {
+        Tokenizer::encode_reader(self, reader, sink)
+    }
+
+    fn encode_slice(
+        &self,
+        bytes: &[u8],
+        sink: &mut dyn FnMut(&[u32]) -> io::Result<()>,
+    ) -> io::Result<usize> {
+        Tokenizer::encode_slice(self, bytes, sink)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use proptest::sample::select;
+
+    use super::*;
+
+    fn model(merges: &[((u32, u32), u32)], special: &[(&str, u32)]) -> Model {
+        Model {
+            merges: merges.iter().copied().collect(),
+            pattern: None,
+            special_tokens: special.iter().map(|&(t, id)| (t.to_string(), id)).collect(),
+            whitespace_marker: false,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn test_encode_greedy() {
+        let tokenizer = Tokenizer::new(model(&[((98, 99), 256), ((97, 98), 257)], &[])).unwrap();
+        assert_eq!(tokenizer.encode("abc"), vec![97, 256]);
+        assert_eq!(tokenizer.encode_greedy("abc"), vec![257, 99]);
+        assert_eq!(tokenizer.encode_greedy("xbc"), vec![120, 256]);
+
+        // greedy matches the marked and folded text, as merging does
+        let tokenizer = Tokenizer::new(Model {
+            pattern: Some(r" ?\w+".to_string()),
+            whitespace_marker: true,
+            ..model(
+                &[((0xe2, 0x96), 256), ((256, 0x81), 257), ((257, 104), 258)],
+                &[(case::CAPITALIZED, 259), (case::UPPERCASE, 260)],
+            )
+        })
+        .unwrap();
+        let text = "hi Hi HI";
+        assert_eq!(
+            tokenizer.encode(text),
+            [104, 105, 259, 258, 105, 260, 258, 105]
+        );
+        assert_eq!(tokenizer.encode_greedy(text), tokenizer.encode(text));
+    }
+
+    #[test]
+    fn test_from_pretrained() {
+        let path =
+            std::env::temp_dir().join(format!("bpe-test-{}-pretrained.bpe", std::process::id()));
+        let model = model(&[((104, 105), 256)], &[]);
+        model::save(&path, &model).unwrap();
+        let name = path.to_str().unwrap();
+        let tokenizer: Tokenizer = name.parse().unwrap();
+        assert_eq!(tokenizer.encode("hi"), [256]);
+        assert_eq!(from_pretrained(name).unwrap().encode("hi!"), [256, 33]);
+        // a memory-mapped file loads as a model too, but not from its bytes
+        model::save_as(&path, &model, model::Format::Mapped).unwrap();
+        assert_eq!(
+            Tokenizer::from_pretrained(name).unwrap().encode("hi"),
+            [256]
+        );
+        let err = model::from_bytes(&std::fs::read(&path).unwrap())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("memory-mapped"));
+        std::fs::remove_file(&path).unwrap();
+        // a path, not a name, so the installed models aren't consulted
+        let err = name.parse::<Tokenizer>().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_encode_only() {
+        let model = model(&[((104, 105), 256)], &[]);
+        let tokenizer = Tokenizer::new_encode_only(model).unwrap();
+        assert_eq!(tokenizer.encode("hi!"), vec![256, 33]);
+        assert_eq!(tokenizer.vocab_size(), 257);
+        assert!(tokenizer.vocab.get().is_none());
+        assert_eq!(tokenizer.decode(&[256, 33]), "hi!");
+    }
+
+    #[test]
+    fn test_case_markers() {
+        let tokenizer = Tokenizer::new(Model {
+            pattern: Some(r" ?\p{L}+|\s+|\S".to_string()),
+            ..model(
+                &[((104, 105), 256), ((32, 256), 257)],
+                &[(case::CAPITALIZED, 258), (case::UPPERCASE, 259)],
+            )
+        })
+        .unwrap();
+        assert!(tokenizer.has_case_markers());
+        // " Hi" and " HI" share the " hi" token
+        assert_eq!(tokenizer.encode("hi Hi HI"), vec![256, 258, 257, 259, 257]);
+        for text in ["hi Hi HI", "HiHI, McHi!", "İ ß", ""] {
+            assert_eq!(tokenizer.decode(&tokenizer.encode(text)), text);
+        }
+        assert_eq!(tokenizer.decode(&[258]), "");
+        let text = "Hi HI hi, McHi";
+        let mut ids = vec![];
+        let tokenizer = tokenizer.with_threads(2);
+        tokenizer
+            .encode_reader(text.as_bytes(), |block| {
+                ids.extend_from_slice(block);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(ids, tokenizer.encode(text));
+    }
+
+    #[test]
+    fn test_whitespace_marker() {
+        // "▁" is e2 96 81: 256 = e2 96, 257 = "▁", 258 = "▁a"
+        let tokenizer = Tokenizer::new(Model {
+            pattern: Some(r" ?\p{L}+|\s+|\S".to_string()),
+            whitespace_marker: true,
+            ..model(
+                &[((0xe2, 0x96), 256), ((256, 0x81), 257), ((257, 97), 258)],
+                &[],
+            )
+        })
+        .unwrap();
+        assert_eq!(tokenizer.encode("a a"), vec![97, 258]);
+        // a marker already in the text decodes as a space
+        assert_eq!(tokenizer.encode("a\u{2581}a"), vec![97, 257, 97]);
+        assert_eq!(tokenizer.decode(&[97, 257, 97]), "a a");
+        assert_eq!(tokenizer.decode(&[97, 258, 10]), "a a\n");
+        assert_eq!(tokenizer.token_bytes(258), "▁a".as_bytes());
+    }
+
+    #[test]
+    fn test_add_prefix_space() {
+        let tokenizer = Tokenizer::new(Model {
+            pattern: Some(r" ?\p{L}+|\s+|\S".to_string()),
+            ..model(&[((32, 104), 256), ((256, 105), 257)], &[])
+        })
+        .unwrap();
+        assert_eq!(tokenizer.encode("hi hi"), vec![104, 105, 257]);
+        let tokenizer = tokenizer.with_add_prefix_space(true);
+        assert_eq!(tokenizer.encode("hi hi"), vec![257, 257]);
+        assert_eq!(tokenizer.decode(&[257, 257]), "hi hi");
+        assert_eq!(tokenizer.decode(&tokenizer.encode(" hi")), " hi");
+    }
+
+    #[test]
+    fn test_encode_reader() {
+        // yields a few bytes per read, cutting chunks and characters
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.0.len().min(buf.len()).min(3);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+        let tokenizer = Tokenizer::new(Model {
+            pattern: Some("gpt4".to_string()),
+            ..model(&[((32, 104), 256), ((256, 105), 257), ((10, 10), 258)], &[])
+        })
+        .unwrap();
+        let text = "hi hi   hi\n\n\nhé hi123456 hiii  ";
+        let mut ids = vec![];
+        let count = tokenizer
+            .encode_reader(Trickle(text.as_bytes()), |block| {
+                ids.extend_from_slice(block);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(ids, tokenizer.encode(text));
+        assert_eq!(count, ids.len());
+
+        // across blocks, reading and in place, with a chunk wider than a block
+        let long = format!("{}{}{}", text, "hi".repeat(2 * STREAM_BLOCK), text);
+        let collect = |result: &mut Vec<u32>, block: &[u32]| {
+            result.extend_from_slice(block);
+            Ok(())
+        };
+        let expected = tokenizer.encode(&long);
+        let (mut read, mut sliced) = (vec![], vec![]);
+        tokenizer
+            .encode_reader(long.as_bytes(), |b| collect(&mut read, b))
+            .unwrap();
+        tokenizer
+            .encode_slice(long.as_bytes(), |b| collect(&mut sliced, b))
+            .unwrap();
+        assert!(read == expected && sliced == expected);
+        let prefixed = tokenizer.with_add_prefix_space(true);
+        let mut sliced = vec![];
+        prefixed
+            .encode_slice(text.as_bytes(), |b| collect(&mut sliced, b))
+            .unwrap();
+        assert_eq!(sliced, prefixed.encode(text));
+        let mut empty = vec![];
+        prefixed
+            .encode_slice(b"", |b| collect(&mut empty, b))
+            .unwrap();
+        assert_eq!(empty, vec![32]);
+        let tokenizer = prefixed.with_add_prefix_space(false);
+
+        let tokenizer = tokenizer.with_threads(3);
+        let mut threaded = vec![];
+        tokenizer
+            .encode_reader(text.as_bytes(), |block| {
+                threaded.extend_from_slice(block);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(threaded, ids);
+
+        let err = tokenizer
+            .encode_reader(Trickle(b"hi hi \xff"), |_| Ok(()))
+            .unwrap_err();
+        assert_eq!(err.to_string(), r#"invalid UTF-8 at byte 6 near " \xff""#);
+
+        // without a pattern the text is one chunk, buffered to the end
+        let tokenizer = Tokenizer::new(model(&[((104, 105), 256), ((32, 256), 257)], &[]))
+            .unwrap()
+            .with_add_prefix_space(true);
+        let long = "hi hé ".repeat(3 * STREAM_BLOCK);
+        let mut ids = vec![];
+        tokenizer
+            .encode_reader(Trickle(long.as_bytes()), |b| collect(&mut ids, b))
+            .unwrap();
+        assert_eq!(ids, tokenizer.encode(&long));
+        let err = tokenizer
+            .encode_reader(Trickle(b"hi h\xc3i"), |_| Ok(()))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("invalid UTF-8 at byte 4"));
+    }
+
+    #[test]
+    fn test_into_buffers() {
+        let tokenizer = Tokenizer::new(model(&[((104, 105), 256)], &[])).unwrap();
+        let mut ids = vec![104];
+        tokenizer.encode_into("hi!", &mut ids);
+        assert_eq!(ids, vec![104, 256, 33]);
+        let mut bytes = b">".to_vec();
+        tokenizer.decode_into(&[256, 33], &mut bytes);
+        assert_eq!(bytes, b">hi!");
+        let mut text = "é".to_string();
+        tokenizer.decode_str_into(&[256, 0xe4], &mut text);
+        assert_eq!(text, "éhi\u{fffd}");
+        text.clear();
+        tokenizer.decode_str_into(&[256], &mut text);
+        assert_eq!(text, "hi");
+    }
+
+    #[test]
+    fn test_lookups() {
+        let tokenizer = Tokenizer::new(model(&[((104, 105), 256)], &[("<|end|>", 257)])).unwrap();
+        assert_eq!(tokenizer.token_to_id(b"hi"), Some(256));
+        assert_eq!(tokenizer.token_to_id(b"<|end|>"), Some(257));
+        assert_eq!(tokenizer.token_to_id(b"hey"), None);
+        assert_eq!(tokenizer.id_to_token(256), Some(&b"hi"[..]));
+        assert_eq!(tokenizer.id_to_token(258), None);
+        assert_eq!(
+            tokenizer.piece_to_id("<0x0A>", PieceStyle::ByteFallback),
+            Some(10)
+        );
+        assert_eq!(
+            tokenizer.piece_to_id("<|end|>", PieceStyle::Gpt2),
+            Some(257)
+        );
+        let piece = tokenizer.id_to_piece(10, PieceStyle::Escaped).unwrap();
+        assert_eq!(tokenizer.piece_to_id(&piece, PieceStyle::Escaped), Some(10));
+    }
+
+    #[test]
+    fn test_introspection() {
+        let tokenizer = Tokenizer::new(model(
+            &[((104, 105), 256), ((256, 33), 257)],
+            &[("<|end|>", 258)],
+        ))
+        .unwrap();
+        assert_eq!(tokenizer.vocab_size(), 259);
+        assert!(tokenizer.is_special(258));
+        assert!(!tokenizer.is_special(257));
+        assert_eq!(tokenizer.merge_rank((256, 33)), Some(1));
+        assert_eq!(tokenizer.merge_rank((33, 33)), None);
+        let tokens: Vec<_> = tokenizer.tokens().skip(256).collect();
+        assert_eq!(
+            tokens,
+            vec![(256, &b"hi"[..]), (257, b"hi!"), (258, b"<|end|>")]
+        );
+    }
+
+    #[test]
+    fn test_unknown_ids() {
+        // special token ids can leave gaps after the merges
+        let tokenizer = Tokenizer::new(model(&[((104, 105), 256)], &[("<|end|>", 300)])).unwrap();
+        assert_eq!(tokenizer.vocab_size(), 301);
+        assert!(Tokenize::contains(&tokenizer, 300));
+        assert!(!Tokenize::contains(&tokenizer, 299));
+        assert!(!Tokenize::contains(&tokenizer, 301));
+        assert_eq!(tokenizer.token_bytes(299), b"");
+        assert_eq!(tokenizer.decode(&[256, 299, 300, 5000]), "hi<|end|>");
+    }
+
+    #[test]
+    fn test_decode_with() {
+        let tokenizer = Tokenizer::new(model(
+            &[((104, 105), 256)],
+            &[
+                ("<|end|>", 257),
+                (case::CAPITALIZED, 258),
+                (case::UPPERCASE, 259),
+            ],
+        ))
+        .unwrap();
+        let mut ids = tokenizer.encode("  Hi  hi");
+        ids.push(257);
+        let skip = DecodeOptions {
+            skip_special_tokens: true,
+            ..DecodeOptions::default()
+        };
+        assert_eq!(
+            tokenizer.decode_with(&ids, &DecodeOptions::default()),
+            "  Hi  hi<|end|>"
+        );
+        assert_eq!(tokenizer.decode_with(&ids, &skip), "  Hi  hi");
+        let clean = DecodeOptions {
+            strip_prefix_space: true,
+            collapse_spaces: true,
+            ..skip
+        };
+        assert_eq!(tokenizer.decode_with(&ids, &clean), " Hi hi");
+
+        let tokenize: &dyn Tokenize = &tokenizer;
+        assert_eq!(tokenize.rank(256), Some(0));
+        assert_eq!(tokenize.rank(104), None);
+        assert_eq!(tokenize.rank(257), None);
+        assert_eq!(tokenize.id_bytes(256), b"hi");
+        assert_eq!(tokenize.id_bytes(257), b"<|end|>");
+    }
+
+    #[test]
+    fn test_truncate_to_tokens() {
+        let tokenizer = Tokenizer::new(model(&[((104, 105), 256)], &[])).unwrap();
+        assert_eq!(tokenizer.truncate_to_tokens("hi hi", 2), "hi ");
+        assert_eq!(tokenizer.truncate_to_tokens("hi hi", 10), "hi hi");
+        assert_eq!(tokenizer.truncate_to_tokens("hi hi", 0), "");
+        // "é" is two byte tokens and is dropped rather than cut
+        assert_eq!(tokenizer.truncate_to_tokens("hié", 2), "hi");
+        assert_eq!(tokenizer.truncate_to_tokens("hié", 3), "hié");
+
+        // with the whitespace marker a space is "▁", three bytes, one token
+        let tokenizer = Tokenizer::new(Model {
+            whitespace_marker: true,
+            ..model(&[((0xe2, 0x96), 256), ((256, 0x81), 257)], &[])
+        })
+        .unwrap();
+        assert_eq!(tokenizer.truncate_to_tokens("Hello world", 7), "Hello w");
+        assert_eq!(tokenizer.truncate_to_tokens("a b c d", 2), "a ");
+        let tokenizer = Tokenizer::new(Model {
+            whitespace_marker: true,
+            ..model(&[], &[])
+        })
+        .unwrap();
+        assert_eq!(tokenizer.truncate_to_tokens("a b c d", 2), "a");
+    }
+
+    #[test]
+    fn test_encode_overflowing() {
+        let tokenizer = Tokenizer::new(model(&[], &[("<s>", 256), ("</s>", 257)])).unwrap();
+        let specials = tokenizer.special_tokens().clone();
+        let post = PostProcessor::new(
+            template::Template::parse("<s> $A </s>", &specials).unwrap(),
+            template::Template::parse("<s> $A </s> $B </s>", &specials).unwrap(),
+        )
+        .unwrap();
+        let tokenizer = tokenizer.with_post_processor(post);
+        let truncation = Truncation {
+            max_len: 5,
+            strategy: TruncationStrategy::OnlyFirst,
+            stride: 1,
+        };
+        // three tokens fit between <s> and </s>, one repeated in the next
+        assert_eq!(
+            tokenizer.encode_overflowing("abcdefg", truncation).unwrap(),
+            vec![
+                vec![256, 97, 98, 99, 257],
+                vec![256, 99, 100, 101, 257],
+                vec![256, 101, 102, 103, 257],
+            ]
+        );
+        let truncation = Truncation {
+            max_len: 8,
+            ..truncation
+        };
+        let pairs = tokenizer
+            .encode_pair_overflowing("abcdef", "xy", truncation)
+            .unwrap();
+        assert_eq!(pairs.len(), 3);
+        assert_eq!(pairs[0].ids, vec![256, 97, 98, 99, 257, 120, 121, 257]);
+        assert_eq!(pairs[0].type_ids, vec![0, 0, 0, 0, 0, 1, 1, 1]);
+        assert_eq!(pairs[2].ids, vec![256, 101, 102, 257, 120, 121, 257]);
+        let second = Truncation {
+            strategy: TruncationStrategy::OnlySecond,
+            ..truncation
+        };
+        assert!(tokenizer
+            .encode_pair_overflowing("abcdef", "xy", second)
+            .is_err());
+        let longest = Truncation {
+            strategy: TruncationStrategy::LongestFirst,
+            ..truncation
+        };
+        assert!(tokenizer
+            .encode_pair_overflowing("ab", "xy", longest)
+            .is_err());
+    }
+
+    #[test]
+    fn test_encode_with_offsets() {
+        let model = || Model {
+            pattern: Some(r" ?\p{L}+|\s+|\S".to_string()),
+            ..model(&[((104, 105), 256), ((32, 256), 257)], &[])
+        };
+        let tokenizer = Tokenizer::new(model()).unwrap();
+        let (ids, offsets) = tokenizer.encode_with_offsets("hi é hi");
+        assert_eq!(ids, vec![256, 32, 195, 169, 257]);
+        assert_eq!(offsets, vec![(0, 2), (2, 3), (3, 5), (3, 5), (5, 8)]);
+        let (_, offsets) = tokenizer
+            .with_add_prefix_space(true)
+            .encode_with_offsets("hi");
+        assert_eq!(offsets, vec![(0, 2)]);
+
+        let cased = Model {
+            special_tokens: HashMap::from([
+                (case::CAPITALIZED.to_string(), 258),
+                (case::UPPERCASE.to_string(), 259),
+            ]),
+            ..model()
+        };
+        let tokenizer = Tokenizer::new(cased).unwrap();
+        let (ids, offsets) = tokenizer.encode_with_offsets("Hi HI!");
+        assert_eq!(ids, vec![258, 256, 259, 257, 33]);
+        assert_eq!(offsets, vec![(0, 0), (0, 2), (2, 2), (2, 5), (5, 6)]);
+
+        // "▁" is e2 96 81: 258 = e2 96, 259 = "▁", 260 = "▁a"
+        let marked = Model {
+            merges: HashMap::from([((0xe2, 0x96), 258), ((258, 0x81), 259), ((259, 97), 260)]),
+            whitespace_marker: true,
+            ..model()
+        };
+        let tokenizer = Tokenizer::new(marked).unwrap();
+        let (ids, offsets) = tokenizer.encode_with_offsets("a a");
+        assert_eq!(ids, vec![97, 260]);
+        assert_eq!(offsets, vec![(0, 1), (1, 3)]);
+    }
+
+    #[test]
+    fn test_encode_full() {
+        let tokenizer = Tokenizer::new(model(&[], &[("<s>", 256), ("</s>", 257)])).unwrap();
+        let specials = tokenizer.special_tokens().clone();
+        let post = PostProcessor::new(
+            template::Template::parse("<s> $A </s>", &specials).unwrap(),
+            template::Template::parse("<s> $A </s> $B </s>", &specials).unwrap(),
+        )
+        .unwrap();
+        let tokenizer = tokenizer.with_post_processor(post);
+        let encoding = tokenizer.encode_full("abc", None).unwrap();
+        assert_eq!(encoding.ids, tokenizer.encode_with_special_tokens("abc"));
+        assert_eq!(
+            encoding.offsets,
+            vec![(0, 0), (0, 1), (1, 2), (2, 3), (0, 0)]
+        );
+        assert_eq!(encoding.special_tokens_mask, vec![1, 0, 0, 0, 1]);
+        assert_eq!(encoding.attention_mask, vec![1; 5]);
+        assert!(encoding.overflowing.is_empty());
+
+        let truncation = Truncation {
+            max_len: 5,
+            strategy: TruncationStrategy::OnlyFirst,
+            stride: 1,
+        };
+        let encoding = tokenizer.encode_full("abcdefg", Some(truncation)).unwrap();
+        let windows = tokenizer.encode_overflowing("abcdefg", truncation).unwrap();
+        assert_eq!(encoding.ids, windows[0]);
+        let overflowing: Vec<_> = encoding.overflowing.iter().map(|e| e.ids.clone()).collect();
+        assert_eq!(overflowing, windows[1..]);
+        assert_eq!(encoding.overflowing[1].offsets[1], (4, 5));
+    }
+
+    #[test]
+    fn test_heal() {
+        let tokenizer = Tokenizer::new(model(
+            &[((58, 47), 256), ((256, 47), 257)],
+            &[("<|end|>", 258)],
+        ))
+        .unwrap();
+        // "a:" backs off to "a", and "://" may now be generated
+        let mut healing = tokenizer.heal(&[97, 58], 1);
+        assert_eq!(healing.ids, vec![97]);
+        assert_eq!(healing.prefix, b":");
+        assert_eq!(tokenizer.healing_allowed(&healing), vec![58, 256, 257]);
+        healing.advance(b"://");
+        assert!(healing.is_done());
+        // special tokens are never backed off
+        assert_eq!(tokenizer.heal(&[258, 58], 2).ids, vec![258]);
+    }
+
+    /// Characters texts are also drawn from: ASCII, whitespace, multi-byte
+    /// letters, and the code points around the surrogate gap and at the ends
+    /// of the range, whose UTF-8 sits next to bytes no valid text has.
+    const CHARS: &str = "abensT07'!.  \n\t\réßİж中文🦀\u{0}\u{7f}\u{80}\u{7ff}\u{800}\
+                         \u{d7ff}\u{e000}\u{fffd}\u{ffff}\u{10000}\u{10ffff}";
+
+    /// Any text, or text of `CHARS`, which repeats enough to be merged.
+    fn text(max_len: usize) -> impl Strategy<Value = String> {
+        let chars: Vec<char> = CHARS.chars().collect();
+        prop_oneof![
+            vec(any::<char>(), 0..=max_len).prop_map(String::from_iter),
+            vec(select(chars), 0..=max_len).prop_map(String::from_iter),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_round_trip_random(
+            corpus in vec(text(60), 1..8),
+            texts in vec(text(40), 0..16),
+            num_merges in 0u32..64,
+            gpt2 in any::<bool>(),
+            whitespace_marker in any::<bool>(),
+        ) {
+            let pattern = gpt2.then(|| pretokenize::GPT2_PATTERN.to_string());
+            let splitter = pattern.as_deref().map(Splitter::new).transpose().unwrap();
+            let words: Vec<(Vec<u32>, u32)> = corpus
+                .iter()
+                .flat_map(|doc| pretokenize::split(splitter.as_ref(), doc))
+                .map(|chunk| match whitespace_marker {
+                    true => pretokenize::mark_spaces(chunk).into_bytes(),
+                    false => chunk.as_bytes().to_vec(),
+                })
+                .map(|bytes| (bytes.into_iter().map(u32::from).collect(), 1))
+                .collect();
+            let merges = crate::train_words(
+                words,
+                num_merges,
+                &mut crate::metrics::Metrics::new(0, None),
+            );
+            let tokenizer = Tokenizer::new(Model {
+                merges,
+                pattern,
+                whitespace_marker,
+                ..model(&[], &[])
+            })
+            .unwrap();
+            for text in corpus.iter().chain(&texts).chain([&String::new()]) {
+                let ids = tokenizer.encode(text);
+                prop_assert_eq!(&tokenizer.decode(&ids), text);
+                prop_assert!(ids.iter().all(|&id| (id as usize) < tokenizer.vocab_size()));
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_encode_slice_random_bytes(
+            bytes in prop_oneof![
+                vec(any::<u8>(), 0..64),
+                text(40).prop_map(String::into_bytes),
+                // encoded surrogates, truncated sequences and stray
+                // continuation bytes among valid text
+                vec(
+                    select(vec![
+                        &b" a"[..],
+                        "\u{d7ff}".as_bytes(),
+                        "\u{e000}".as_bytes(),
+                        b"\xed\xa0\x80",
+                        b"\xed\xbf\xbf",
+                        b"\xf0\x9f",
+                        b"\x80",
+                        b"\xff",
+                    ]),
+                    0..12,
+                )
+                .prop_map(|pieces| pieces.concat()),
+            ],
+        ) {
+            let tokenizer = Tokenizer::new(Model {
+                pattern: Some(pretokenize::GPT2_PATTERN.to_string()),
+                ..model(&[((0xed, 0x9f), 256), ((0xee, 0x80), 257), ((32, 97), 258)], &[])
+            })
+            .unwrap();
+            let mut ids = vec![];
+            let result = tokenizer.encode_slice(&bytes, |block| {
+                ids.extend_from_slice(block);
+                Ok(())
+            });
+            match std::str::from_utf8(&bytes) {
+                Ok(text) => {
+                    prop_assert!(result.is_ok());
+                    prop_assert_eq!(ids, tokenizer.encode(text));
+                }
+                Err(_) => prop_assert!(result.is_err()),
+            }
+        }
+    }
+}
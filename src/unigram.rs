@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use tracing::{debug, info_span};
+
+use crate::gpt2;
+use crate::pretokenize::{self, Splitter};
+use crate::tokenizer::Tokenize;
+
+// unigram language model
+//
+// Instead of merges, a unigram model gives every piece a log probability and
+// encodes each chunk as its most probable segmentation (Viterbi). Training
+// seeds the vocabulary with the most frequent substrings of the corpus,
+// re-estimates the scores with EM, and repeatedly drops the pieces whose
+// removal costs the least likelihood until the vocabulary fits. The 256
+// single bytes are always kept as ids 0-255, so any input can be encoded.
+//
+// Model files hold a header, the pattern line, then one `score piece` line
+// per id, with pieces in their GPT-2 string form:
+//
+//   unigram v1
+//   's|'t| ?\p{L}+|...
+//   -14.2 Ā
+//   ...
+//   -6.1 Ġthe
+
+pub const HEADER: &str = "unigram v1";
+
+/// Longest seed piece, in bytes.
+const MAX_PIECE_LEN: usize = 16;
+/// Seed candidates per vocabulary slot.
+const SEED_FACTOR: usize = 4;
+/// Fraction of pieces kept by each pruning round.
+const SHRINK: f64 = 0.75;
+const EM_ITERATIONS: usize = 2;
+/// Floor for expected counts, so unused bytes keep a finite score.
+const MIN_COUNT: f64 = 1e-3;
+
+pub struct Unigram {
+    pieces: Vec<Vec<u8>>,
+    scores: Vec<f64>,
+    index: HashMap<Vec<u8>, u32>,
+    max_len: usize,
+    splitter: Option<Splitter>,
+}
+
+impl Unigram {
+    /// Builds a model from `(piece, score)` pairs in id order; the first 256
+    /// must be the single bytes.
+    pub fn new(pieces: Vec<(Vec<u8>, f64)>, splitter: Option<Splitter>) -> io::Result<Unigram> {
+        if pieces.len() < 256 || (0..256).any(|b| pieces[b].0 != [b as u8]) {
+            return Err(invalid(
+                "the first 256 pieces must be the single bytes".into(),
+            ));
+        }
+        let (pieces, scores): (Vec<_>, Vec<_>) = pieces.into_iter().unzip();
+        let index = pieces.iter().cloned().zip(0..).collect();
+        let max_len = pieces.iter().map(Vec::len).max().unwrap_or(1);
+        Ok(Unigram {
+            pieces,
+            scores,
+            index,
+            max_len,
+            splitter,
+        })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Unigram> {
+        read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        writeln!(w, "{}", HEADER)?;
+        writeln!(w, "{}", self.splitter.as_ref().map_or("", |s| s.pattern()))?;
+        for (piece, score) in self.pieces.iter().zip(&self.scores) {
+            writeln!(w, "{} {}", score, gpt2::encode_bytes(piece))?;
+        }
+        w.flush()
+    }
+
+    /// The bytes a token id stands for.
+    /// The bytes of a piece, empty for an id past the vocabulary.
+    pub fn token_bytes(&self, id: u32) -> &[u8] {
+        self.pieces.get(id as usize).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn score(&self, id: u32) -> f64 {
+        self.scores[id as usize]
+    }
+
+    /// All `(start, end, id)` pieces found in `bytes`.
+    fn lattice(&self, bytes: &[u8]) -> Vec<(usize, usize, u32)> {
+        let mut edges = vec![];
+        for start in 0..bytes.len() {
+            for end in start + 1..=bytes.len().min(start + self.max_len) {
+                if let Some(&id) = self.index.get(&bytes[start..end]) {
+                    edges.push((start, end, id));
+                }
+            }
+        }
+        edges
+    }
+
+    /// The most probable segmentation of `bytes` and its score, optionally
+    /// without using piece `exclude`.
+    fn viterbi(&self, bytes: &[u8], exclude: Option<u32>) -> (Vec<u32>, f64) {
+        // best[end] = (score, start, id) of the best segmentation of bytes[..end]
+        let mut best = vec![(f64::NEG_INFINITY, 0, 0); bytes.len() + 1];
+        best[0].0 = 0.0;
+        for (start, end, id) in self.lattice(bytes) {
+            if Some(id) == exclude {
+                continue;
+            }
+            let score = best[start].0 + self.score(id);
+            if score > best[end].0 {
+                best[end] = (score, start, id);
+            }
+        }
+        let mut ids = vec![];
+        let mut end = bytes.len();
+        while end > 0 {
+            let (_, start, id) = best[end];
+            ids.push(id);
+            end = start;
+        }
+        ids.reverse();
+        (ids, best[bytes.len()].0)
+    }
+
+    /// Expected piece counts over all segmentations of the weighted words
+    /// (the E step), with the total log likelihood.
+    fn expected_counts(&self, words: &[(&[u8], f64)]) -> (Vec<f64>, f64) {
+        let mut counts = vec![0.0; self.pieces.len()];
+        let mut likelihood = 0.0;
+        for &(word, freq) in words {
+            let edges = self.lattice(word);
+            let n = word.len();
+            let mut alpha = vec![f64::NEG_INFINITY; n + 1];
+            let mut beta = vec![f64::NEG_INFINITY; n + 1];
+            alpha[0] = 0.0;
+            beta[n] = 0.0;
+            // edges are ordered by start, so alpha[start] is complete when used
+            for &(start, end, id) in &edges {
+                alpha[end] = log_add(alpha[end], alpha[start] + self.score(id));
+            }
+            for &(start, end, id) in edges.iter().rev() {
+                beta[start] = log_add(beta[start], self.score(id) + beta[end]);
+            }
+            let z = alpha[n];
+            likelihood += freq * z;
+            for &(start, end, id) in &edges {
+                let p = (alpha[start] + self.score(id) + beta[end] - z).exp();
+                counts[id as usize] += freq * p;
+            }
+        }
+        (counts, likelihood)
+    }
+
+    /// One EM iteration: re-estimates the scores from expected counts.
+    fn em_step(&mut self, words: &[(&[u8], f64)]) -> f64 {
+        let (counts, likelihood) = self.expected_counts(words);
+        let total: f64 = counts.iter().map(|c| c.max(MIN_COUNT)).sum();
+        for (score, count) in self.scores.iter_mut().zip(&counts) {
+            *score = count.max(MIN_COUNT).ln() - total.ln();
+        }
+        likelihood
+    }
+
+    /// Keeps the `keep` multi-byte pieces whose removal would lose the most
+    /// likelihood, re-segmenting each piece without itself to price it.
+    fn prune(self, words: &[(&[u8], f64)], keep: usize) -> Unigram {
+        let (counts, _) = self.expected_counts(words);
+        let mut candidates: Vec<(f64, u32)> = (256..self.pieces.len() as u32)
+            .map(|id| {
+                let (_, alternative) = self.viterbi(self.token_bytes(id), Some(id));
+                let loss = counts[id as usize] * (self.score(id) - alternative);
+                (loss, id)
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+        candidates.truncate(keep);
+        self.select(candidates.into_iter().map(|(_, id)| id))
+    }
+
+    /// Rebuilds the model from the bytes and the given multi-byte pieces,
+    /// ordered by descending score.
+    fn select(self, ids: impl Iterator<Item = u32>) -> Unigram {
+        let mut ids: Vec<u32> = ids.collect();
+        ids.sort_by(|&a, &b| self.score(b).total_cmp(&self.score(a)).then(a.cmp(&b)));
+        let pieces = (0..256)
+            .chain(ids)
+            .map(|id| (self.pieces[id as usize].clone(), self.score(id)))
+            .collect();
+        Unigram::new(pieces, self.splitter).expect("bytes are kept")
+    }
+}
+
+/// Trains a unigram model with `vocab_size` pieces (including the 256 bytes)
+/// on pre-tokenized chunks.
+pub fn train(chunks: &[&str], vocab_size: u32, splitter: Option<Splitter>) -> Unigram {
+    let _span = info_span!("train_unigram", vocab_size).entered();
+    let mut counts: HashMap<&str, u32> = HashMap::new();
+    for &chunk in chunks {
+        *counts.entry(chunk).or_default() += 1;
+    }
+    let mut words: Vec<(&str, f64)> = counts.into_iter().map(|(w, n)| (w, n as f64)).collect();
+    words.sort_by(|a, b| a.0.cmp(b.0));
+
+    // seed with the most frequent substrings that start and end on char boundaries
+    let mut byte_counts = [0.0; 256];
+    let mut substrings: HashMap<&[u8], f64> = HashMap::new();
+    for &(word, freq) in &words {
+        for &b in word.as_bytes() {
+            byte_counts[b as usize] += freq;
+        }
+        let bounds: Vec<usize> = word
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain([word.len()])
+            .collect();
+        for (i, &start) in bounds.iter().enumerate() {
+            for &end in &bounds[i + 1..] {
+                if end - start > MAX_PIECE_LEN {
+                    break;
+                }
+                if end - start > 1 {
+                    *substrings.entry(&word.as_bytes()[start..end]).or_default() += freq;
+                }
+            }
+        }
+    }
+    let target = (vocab_size as usize).saturating_sub(256);
+    let mut seeds: Vec<(&[u8], f64)> = substrings.into_iter().collect();
+    seeds.sort_by(|a, b| {
+        let score = |&(s, f): &(&[u8], f64)| f * s.len() as f64;
+        score(b).total_cmp(&score(a)).then(a.0.cmp(b.0))
+    });
+    seeds.truncate(target * SEED_FACTOR);
+
+    let total: f64 = byte_counts.iter().sum::<f64>() + seeds.iter().map(|s| s.1).sum::<f64>();
+    let pieces = (0..=255u8)
+        .map(|b| (vec![b], byte_counts[b as usize]))
+        .chain(seeds.into_iter().map(|(s, f)| (s.to_vec(), f)))
+        .map(|(piece, freq)| (piece, freq.max(MIN_COUNT).ln() - total.ln()))
+        .collect();
+    let mut model = Unigram::new(pieces, splitter).expect("bytes come first");
+
+    let words: Vec<(&[u8], f64)> = words.iter().map(|&(w, f)| (w.as_bytes(), f)).collect();
+    loop {
+        for _ in 0..EM_ITERATIONS {
+            let likelihood = model.em_step(&words);
+            debug!(pieces = model.pieces.len(), likelihood, "em step");
+        }
+        let size = model.pieces.len() - 256;
+        if size <= target {
+            break;
+        }
+        let keep = target.max((size as f64 * SHRINK) as usize);
+        model = model.prune(&words, keep);
+    }
+    let size = model.pieces.len() as u32;
+    model.select(256..size)
+}
+
+impl Tokenize for Unigram {
+    fn encode(&self, text: &str) -> Vec<u32> {
+        pretokenize::split(self.splitter.as_ref(), text)
+            .into_iter()
+            .flat_map(|chunk| self.viterbi(chunk.as_bytes(), None).0)
+            .collect()
+    }
+
+    fn decode(&self, ids: &[u32]) -> String {
+        let bytes: Vec<u8> = ids
+            .iter()
+            .flat_map(|&id| self.token_bytes(id))
+            .copied()
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.pieces.len()
+    }
+}
+
+fn log_add(a: f64, b: f64) -> f64 {
+    if a == f64::NEG_INFINITY {
+        return b;
+    }
+    let (hi, lo) = if a > b { (a, b) } else { (b, a) };
+    hi + (lo - hi).exp().ln_1p()
+}
+
+fn read(reader: impl BufRead) -> io::Result<Unigram> {
+    let mut lines = reader.lines().zip(1..);
+    let mut next = |what: &str| match lines.next() {
+        Some((line, _)) => line,
+        None => Err(invalid(format!("missing {}", what))),
+    };
+    if next("model header")? != HEADER {
+        return Err(invalid("missing unigram model header".into()));
+    }
+    let pattern = next("pattern line")?;
+    let mut pieces = vec![];
+    for (line, n) in lines {
+        let line = line?;
+        let piece = line
+            .split_once(' ')
+            .and_then(|(score, piece)| Some((gpt2::decode_str(piece)?, score.parse().ok()?)))
+            .ok_or_else(|| invalid(format!("bad piece on line {}: {:?}", n, line)))?;
+        pieces.push(piece);
+    }
+    let splitter = Some(pattern)
+        .filter(|p| !p.is_empty())
+        .map(|p| Splitter::new(&p))
+        .transpose()?;
+    Unigram::new(pieces, splitter)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_train_encode() {
+        let text = "the cat sat on the mat with the hat. ".repeat(50);
+        let splitter = Splitter::new("gpt2").unwrap();
+        let chunks = splitter.split(&text);
+        let model = train(&chunks, 280, Some(Splitter::new("gpt2").unwrap()));
+        assert_eq!(model.vocab_size(), 280);
+        let ids = model.encode("the cat sat on the hat");
+        assert!(ids.len() < 10, "{:?}", ids);
+        assert_eq!(model.decode(&ids), "the cat sat on the hat");
+        // unseen bytes still encode through the byte pieces
+        assert_eq!(model.decode(&model.encode("zebra 李")), "zebra 李");
+    }
+
+    #[test]
+    fn test_read() {
+        let mut text = format!("{}\n\n", HEADER);
+        for b in 0..=255u8 {
+            text += &format!("-10 {}\n", gpt2::encode_bytes(&[b]));
+        }
+        text += "-1 \u{120}hi\n";
+        let model = read(text.as_bytes()).unwrap();
+        assert_eq!(model.encode(" hi"), vec![256]);
+        assert_eq!(model.encode("hi"), vec![104, 105]);
+        assert!(read(format!("{}\n\n-1 a\n", HEADER).as_bytes()).is_err());
+    }
+}
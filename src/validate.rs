@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::mmap::MappedModel;
+use crate::model::Model;
+use crate::render;
+use crate::Tokenize;
+
+// model validation
+//
+// Structural checks on a model, for files written by other tools or edited
+// by hand. Ids must run from 0 without gaps or repeats: the 256 bytes, the
+// merges, then the special tokens. Every merge must join two tokens made
+// before it, no two tokens may stand for the same bytes, and a stored
+// decode table, as memory-mapped models have, must hold each token's pair
+// concatenated (or nothing, for a token stripped as unreachable). Loading
+// numbers merges by position, so a repeated merge line shows up here as an
+// id two merges share.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// A merge or special token has the id of a byte or token before it.
+    IdCollision {
+        token: String,
+        id: u32,
+        with: String,
+    },
+    /// No token has this id, though later ones exist.
+    MissingId(u32),
+    /// A merge joins a token that doesn't exist before it.
+    UnknownPart { id: u32, pair: (u32, u32) },
+    /// Two tokens stand for the same bytes.
+    DuplicateBytes { ids: [u32; 2], bytes: Vec<u8> },
+    /// The stored bytes of a token aren't its pair's.
+    BytesMismatch {
+        id: u32,
+        stored: Vec<u8>,
+        expected: Vec<u8>,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::IdCollision { token, id, with } => {
+                write!(f, "{} has id {}, as {} does", token, id, with)
+            }
+            Problem::MissingId(id) => write!(f, "no token has id {}", id),
+            Problem::UnknownPart { id, pair } => {
+                write!(
+                    f,
+                    "merge {:?} -> {} joins a token not made before it",
+                    pair, id
+                )
+            }
+            Problem::DuplicateBytes { ids, bytes } => write!(
+                f,
+                "tokens {} and {} are both {:?}",
+                ids[0],
+                ids[1],
+                render::escape(bytes)
+            ),
+            Problem::BytesMismatch {
+                id,
+                stored,
+                expected,
+            } => write!(
+                f,
+                "token {} is stored as {:?} but its merge makes {:?}",
+                id,
+                render::escape(stored),
+                render::escape(expected)
+            ),
+        }
+    }
+}
+
+/// Checks a model, and the decode table stored with it if any (token
+/// bytes by id), returning every problem found.
+pub fn check(model: &Model, stored: Option<&[&[u8]]>) -> Vec<Problem> {
+    let mut problems = vec![];
+    let mut ranked: Vec<(u32, (u32, u32))> = model.merges.iter().map(|(&p, &id)| (id, p)).collect();
+    ranked.sort_unstable();
+
+    // what has each id: a byte, a merge or a special token
+    let mut owners: HashMap<u32, String> = (0..256).map(|b| (b, format!("byte {}", b))).collect();
+    let mut special: Vec<(&String, &u32)> = model.special_tokens.iter().collect();
+    special.sort_unstable_by_key(|&(token, &id)| (id, token));
+    let tokens = ranked
+        .iter()
+        .map(|&(id, pair)| (format!("merge {:?}", pair), id))
+        .chain(
+            special
+                .into_iter()
+                .map(|(token, &id)| (format!("special token {:?}", token), id)),
+        );
+    for (token, id) in tokens {
+        match owners.get(&id) {
+            Some(owner) => problems.push(Problem::IdCollision {
+                token,
+                id,
+                with: owner.clone(),
+            }),
+            None => {
+                owners.insert(id, token);
+            }
+        }
+    }
+    let end = owners.keys().max().map_or(0, |&id| id + 1);
+    problems.extend(
+        (0..end)
+            .filter(|id| !owners.contains_key(id))
+            .map(Problem::MissingId),
+    );
+
+    // token bytes, for merges that join tokens made before them
+    let mut bytes: HashMap<u32, Vec<u8>> = (0..=255u8).map(|b| (b as u32, vec![b])).collect();
+    let mut seen: HashMap<Vec<u8>, u32> = bytes.iter().map(|(&id, b)| (b.clone(), id)).collect();
+    for &(id, pair) in &ranked {
+        let (Some(left), Some(right)) = (
+            bytes.get(&pair.0).filter(|_| pair.0 < id),
+            bytes.get(&pair.1).filter(|_| pair.1 < id),
+        ) else {
+            problems.push(Problem::UnknownPart { id, pair });
+            continue;
+        };
+        let merged = [&left[..], right].concat();
+        match seen.get(&merged) {
+            Some(&other) if other != id => problems.push(Problem::DuplicateBytes {
+                ids: [other, id],
+                bytes: merged.clone(),
+            }),
+            _ => {
+                seen.insert(merged.clone(), id);
+            }
+        }
+        bytes.entry(id).or_insert(merged);
+    }
+
+    if let Some(stored) = stored {
+        let mut ids: Vec<u32> = bytes.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let expected = &bytes[&id];
+            match stored.get(id as usize) {
+                Some(s) if *s == &expected[..] || (s.is_empty() && id >= 256) => {}
+                other => problems.push(Problem::BytesMismatch {
+                    id,
+                    stored: other.map_or(vec![], |s| s.to_vec()),
+                    expected: expected.clone(),
+                }),
+            }
+        }
+    }
+    problems
+}
+
+/// Checks a memory-mapped model against the decode table it stores.
+pub fn check_mapped(mapped: &MappedModel) -> Vec<Problem> {
+    let stored: Vec<&[u8]> = (0..mapped.vocab_size() as u32)
+        .map(|id| mapped.token_bytes(id))
+        .collect();
+    check(&mapped.to_model(), Some(&stored))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+    use crate::{mmap, store};
+
+    fn model(merges: &[((u32, u32), u32)], special: &[(&str, u32)]) -> Model {
+        Model {
+            merges: merges.iter().copied().collect(),
+            pattern: None,
+            special_tokens: special.iter().map(|&(t, id)| (t.to_string(), id)).collect(),
+            whitespace_marker: false,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn test_valid() {
+        let m = model(&[((97, 98), 256), ((256, 99), 257)], &[("<|end|>", 258)]);
+        assert!(check(&m, None).is_empty());
+        let mut stored: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+        stored.extend([b"ab".to_vec(), vec![], b"<|end|>".to_vec()]);
+        let slices: Vec<&[u8]> = stored.iter().map(Vec::as_slice).collect();
+        assert!(check(&m, Some(&slices)).is_empty());
+        stored[256] = b"ba".to_vec();
+        let slices: Vec<&[u8]> = stored.iter().map(Vec::as_slice).collect();
+        assert_eq!(
+            check(&m, Some(&slices)),
+            [Problem::BytesMismatch {
+                id: 256,
+                stored: b"ba".to_vec(),
+                expected: b"ab".to_vec()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_problems() {
+        let m = model(
+            &[
+                ((97, 98), 256),
+                ((256, 99), 258),
+                ((300, 97), 259),
+                ((98, 99), 260),
+                ((97, 260), 261),
+            ],
+            &[("<|end|>", 258), ("<|pad|>", 263)],
+        );
+        let problems = check(&m, None);
+        assert!(problems.contains(&Problem::IdCollision {
+            token: "special token \"<|end|>\"".to_string(),
+            id: 258,
+            with: "merge (256, 99)".to_string(),
+        }));
+        assert!(problems.contains(&Problem::MissingId(257)));
+        assert!(problems.contains(&Problem::MissingId(262)));
+        assert!(problems.contains(&Problem::UnknownPart {
+            id: 259,
+            pair: (300, 97)
+        }));
+        assert!(problems.contains(&Problem::DuplicateBytes {
+            ids: [258, 261],
+            bytes: b"abc".to_vec()
+        }));
+        assert_eq!(problems.len(), 5);
+
+        let m = model(&[((97, 98), 256), ((97, 99), 256)], &[]);
+        assert_eq!(
+            check(&m, None)[0].to_string(),
+            "merge (97, 99) has id 256, as merge (97, 98) does"
+        );
+    }
+
+    #[test]
+    fn test_corrupt_mapped() {
+        // a corrupt file fails to open or shows problems, and never panics
+        let model = crate::model::from_bytes(store::bundled(store::DEMO).unwrap()).unwrap();
+        let path =
+            std::env::temp_dir().join(format!("bpe-test-{}-validate.bpem", std::process::id()));
+        mmap::write(&path, &model).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(check_mapped(&MappedModel::open(&path).unwrap()).is_empty());
+        let mut rng = Rng::new(0);
+        let mut opened = 0;
+        for _ in 0..200 {
+            let mut corrupt = bytes.clone();
+            for _ in 0..1 + rng.next_u64() % 8 {
+                let at = (rng.next_u64() % bytes.len() as u64) as usize;
+                corrupt[at] = rng.next_u64() as u8;
+            }
+            std::fs::write(&path, &corrupt).unwrap();
+            match MappedModel::open(&path) {
+                Ok(mapped) => {
+                    check_mapped(&mapped);
+                    opened += 1;
+                }
+                Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert!(opened > 0);
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use tracing::{debug, info_span};
+
+use crate::tokenizer::Tokenize;
+
+// WordPiece
+//
+// The BERT-style alternative to BPE. Text is split on whitespace and
+// punctuation into words; a word's first piece is stored as is and the
+// pieces continuing it carry a `##` prefix. Training starts from the
+// characters of the corpus and merges the pair with the highest likelihood
+// gain, count(ab) / (count(a) * count(b)), rather than the most frequent
+// one. Encoding takes the longest matching piece from the left, and a word
+// that can't be covered becomes `[UNK]`.
+//
+// Model files hold a header and then one token per line, in id order, like
+// BERT's vocab.txt:
+//
+//   wordpiece v1
+//   [UNK]
+//   a
+//   ##a
+//   ...
+
+pub const HEADER: &str = "wordpiece v1";
+pub const UNK: &str = "[UNK]";
+pub const PREFIX: &str = "##";
+
+/// Longer words are encoded as `[UNK]` without trying.
+const MAX_WORD_CHARS: usize = 100;
+
+pub struct WordPiece {
+    tokens: Vec<String>,
+    ids: HashMap<String, u32>,
+}
+
+impl WordPiece {
+    /// Builds a model from its tokens in id order; the first must be `[UNK]`.
+    pub fn new(tokens: Vec<String>) -> io::Result<WordPiece> {
+        if tokens.first().map(String::as_str) != Some(UNK) {
+            return Err(invalid(format!("the first token must be {}", UNK)));
+        }
+        let ids = tokens.iter().cloned().zip(0..).collect();
+        Ok(WordPiece { tokens, ids })
+    }
+
+    pub fn load(path: &Path) -> io::Result<WordPiece> {
+        read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        writeln!(w, "{}", HEADER)?;
+        for token in &self.tokens {
+            writeln!(w, "{}", token)?;
+        }
+        w.flush()
+    }
+
+    /// The token with an id, empty for an id past the vocabulary.
+    pub fn token(&self, id: u32) -> &str {
+        self.tokens.get(id as usize).map_or("", String::as_str)
+    }
+
+    pub fn token_to_id(&self, token: &str) -> Option<u32> {
+        self.ids.get(token).copied()
+    }
+
+    /// Appends tokens that are never produced by encoding, such as `[CLS]`.
+    pub fn add_special_tokens(&mut self, tokens: &[String]) {
+        for token in tokens {
+            if !self.ids.contains_key(token) {
+                self.ids.insert(token.clone(), self.tokens.len() as u32);
+                self.tokens.push(token.clone());
+            }
+        }
+    }
+
+    /// Greedy longest-match encoding of one word.
+    fn encode_word(&self, word: &str, ids: &mut Vec<u32>) {
+        let unk = 0;
+        if word.chars().count() > MAX_WORD_CHARS {
+            ids.push(unk);
+            return;
+        }
+        let first = ids.len();
+        let mut start = 0;
+        while start < word.len() {
+            let ends: Vec<usize> = word[start..]
+                .char_indices()
+                .skip(1)
+                .map(|(i, _)| start + i)
+                .chain([word.len()])
+                .collect();
+            let piece = ends.into_iter().rev().find_map(|end| {
+                let name = piece_name(&word[start..end], start > 0);
+                self.ids.get(&name).map(|&id| (id, end))
+            });
+            match piece {
+                Some((id, end)) => {
+                    ids.push(id);
+                    start = end;
+                }
+                None => {
+                    ids.truncate(first);
+                    ids.push(unk);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Tokenize for WordPiece {
+    fn encode(&self, text: &str) -> Vec<u32> {
+        let mut ids = vec![];
+        for word in words(text) {
+            self.encode_word(word, &mut ids);
+        }
+        ids
+    }
+
+    /// Joins words with single spaces, so spacing around punctuation is not
+    /// preserved.
+    fn decode(&self, ids: &[u32]) -> String {
+        let mut text = String::new();
+        for &id in ids.iter().filter(|&&id| self.contains(id)) {
+            let token = self.token(id);
+            match token.strip_prefix(PREFIX) {
+                Some(rest) => text.push_str(rest),
+                None => {
+                    if !text.is_empty() {
+                        text.push(' ');
+                    }
+                    text.push_str(token);
+                }
+            }
+        }
+        text
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.tokens.len()
+    }
+}
+
+/// Splits text on whitespace, with each punctuation character a word of
+/// its own.
+pub fn words(text: &str) -> Vec<&str> {
+    let mut words = vec![];
+    for word in text.split_whitespace() {
+        let mut start = 0;
+        for (i, c) in word.char_indices() {
+            if c.is_ascii_punctuation() || (!c.is_alphanumeric() && !c.is_ascii()) {
+                if start < i {
+                    words.push(&word[start..i]);
+                }
+                words.push(&word[i..i + c.len_utf8()]);
+                start = i + c.len_utf8();
+            }
+        }
+        if start < word.len() {
+            words.push(&word[start..]);
+        }
+    }
+    words
+}
+
+fn piece_name(s: &str, continuation: bool) -> String {
+    if continuation {
+        format!("{}{}", PREFIX, s)
+    } else {
+        s.to_string()
+    }
+}
+
+/// Trains a WordPiece model with `vocab_size` tokens, counting `[UNK]` and
+/// the initial characters.
+pub fn train(docs: &[String], vocab_size: u32) -> WordPiece {
+    let _span = info_span!("train_wordpiece", vocab_size).entered();
+    let mut counts: HashMap<&str, u32> = HashMap::new();
+    for doc in docs {
+        for word in words(doc) {
+            *counts.entry(word).or_default() += 1;
+        }
+    }
+    let mut counts: Vec<(&str, u32)> = counts.into_iter().collect();
+    counts.sort();
+
+    let mut tokens = vec![UNK.to_string()];
+    let mut ids: HashMap<String, u32> = HashMap::from([(UNK.to_string(), 0)]);
+    let mut id = |name: String, tokens: &mut Vec<String>| -> u32 {
+        *ids.entry(name.clone()).or_insert_with(|| {
+            tokens.push(name);
+            tokens.len() as u32 - 1
+        })
+    };
+    let mut alphabet: Vec<String> = counts
+        .iter()
+        .flat_map(|(word, _)| {
+            word.char_indices()
+                .map(|(i, c)| piece_name(&c.to_string(), i > 0))
+        })
+        .collect();
+    alphabet.sort();
+    alphabet.dedup();
+    for name in alphabet {
+        id(name, &mut tokens);
+    }
+    let mut words: Vec<(Vec<u32>, u32)> = counts
+        .iter()
+        .map(|&(word, n)| {
+            let pieces = word
+                .char_indices()
+                .map(|(i, c)| id(piece_name(&c.to_string(), i > 0), &mut tokens))
+                .collect();
+            (pieces, n)
+        })
+        .collect();
+
+    while tokens.len() < vocab_size as usize {
+        let mut pairs: HashMap<(u32, u32), u64> = HashMap::new();
+        let mut singles: HashMap<u32, u64> = HashMap::new();
+        for (pieces, n) in &words {
+            for &p in pieces {
+                *singles.entry(p).or_default() += *n as u64;
+            }
+            for pair in pieces.windows(2) {
+                *pairs.entry((pair[0], pair[1])).or_default() += *n as u64;
+            }
+        }
+        let score = |&(pair, count): &((u32, u32), u64)| {
+            count as f64 / (singles[&pair.0] as f64 * singles[&pair.1] as f64)
+        };
+        let Some(best) = pairs.into_iter().max_by(|a, b| {
+            score(a)
+                .total_cmp(&score(b))
+                .then(a.1.cmp(&b.1))
+                .then(b.0.cmp(&a.0))
+        }) else {
+            break;
+        };
+        let (left, right) = best.0;
+        let name = format!(
+            "{}{}",
+            tokens[left as usize],
+            &tokens[right as usize][PREFIX.len()..]
+        );
+        let merged = id(name, &mut tokens);
+        debug!(token = tokens[merged as usize], count = best.1, "merge");
+        for (pieces, _) in &mut words {
+            let mut i = 0;
+            while i + 1 < pieces.len() {
+                if (pieces[i], pieces[i + 1]) == (left, right) {
+                    pieces[i] = merged;
+                    pieces.remove(i + 1);
+                }
+                i += 1;
+            }
+        }
+    }
+    WordPiece::new(tokens).expect("[UNK] comes first")
+}
+
+fn read(reader: impl BufRead) -> io::Result<WordPiece> {
+    let mut lines = reader.lines();
+    if lines.next().transpose()?.as_deref() != Some(HEADER) {
+        return Err(invalid("missing wordpiece model header".into()));
+    }
+    WordPiece::new(lines.collect::<io::Result<_>>()?)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_words() {
+        assert_eq!(
+            words("Hello, world!  It's"),
+            vec!["Hello", ",", "world", "!", "It", "'", "s"]
+        );
+    }
+
+    #[test]
+    fn test_train_encode() {
+        let docs = vec!["the cat sat on the mat, the hat sat on the cat. ".repeat(20)];
+        let model = train(&docs, 18);
+        assert_eq!(model.vocab_size(), 18);
+        let ids = model.encode("the cat sat");
+        assert!(ids.iter().all(|&id| id != 0));
+        assert_eq!(model.decode(&ids), "the cat sat");
+        // training stops early once every word is a single token
+        let model = train(&docs, 40);
+        let ids = model.encode("the cat sat");
+        let tokens: Vec<&str> = ids.iter().map(|&id| model.token(id)).collect();
+        assert_eq!(tokens, vec!["the", "cat", "sat"]);
+        assert_eq!(
+            model.encode("the dog"),
+            vec![model.token_to_id("the").unwrap(), 0]
+        );
+    }
+}
@@ -0,0 +1,90 @@
+"""Writes minbpe-encode.json: merges trained by minbpe's BasicTokenizer and
+the ids its encode gives, for test_encode_matches_minbpe_fixture.
+
+    python3 tests/fixtures/minbpe_encode.py > tests/fixtures/minbpe-encode.json
+
+Uses the minbpe package when it is importable, else the copy of its
+get_stats, merge, train and encode below (minbpe/base.py and basic.py).
+"""
+
+import json
+import random
+import sys
+
+
+def get_stats(ids, counts=None):
+    counts = {} if counts is None else counts
+    for pair in zip(ids, ids[1:]):
+        counts[pair] = counts.get(pair, 0) + 1
+    return counts
+
+
+def merge(ids, pair, idx):
+    newids = []
+    i = 0
+    while i < len(ids):
+        if ids[i] == pair[0] and i < len(ids) - 1 and ids[i + 1] == pair[1]:
+            newids.append(idx)
+            i += 2
+        else:
+            newids.append(ids[i])
+            i += 1
+    return newids
+
+
+class BasicTokenizer:
+    def __init__(self):
+        self.merges = {}
+
+    def train(self, text, vocab_size, verbose=False):
+        ids = list(text.encode("utf-8"))
+        merges = {}
+        for i in range(vocab_size - 256):
+            stats = get_stats(ids)
+            pair = max(stats, key=stats.get)
+            idx = 256 + i
+            ids = merge(ids, pair, idx)
+            merges[pair] = idx
+        self.merges = merges
+
+    def encode(self, text):
+        ids = list(text.encode("utf-8"))
+        while len(ids) >= 2:
+            stats = get_stats(ids)
+            pair = min(stats, key=lambda p: self.merges.get(p, float("inf")))
+            if pair not in self.merges:
+                break
+            ids = merge(ids, pair, self.merges[pair])
+        return ids
+
+
+try:
+    from minbpe import BasicTokenizer  # noqa: F811
+
+    generator = "minbpe"
+except ImportError:
+    generator = "copy of minbpe base.py and basic.py"
+
+rng = random.Random(168)
+
+
+def text(alphabet, max_len):
+    return "".join(rng.choice(alphabet) for _ in range(rng.randrange(max_len)))
+
+
+# a small alphabet, so equal counts and overlapping pairs are common
+training = " ".join(text("aab", 12) for _ in range(60))
+tokenizer = BasicTokenizer()
+tokenizer.train(training, 256 + 40)
+cases = [training[:80], "aaa", "aaaaa", "ababab", "bab", "", "a"]
+cases += [text("aab ", 40) for _ in range(100)]
+cases += ["héllo wörld " + text("aab", 10) for _ in range(5)]
+json.dump(
+    {
+        "generator": generator,
+        "merges": [[a, b, idx] for (a, b), idx in tokenizer.merges.items()],
+        "cases": [[case, tokenizer.encode(case)] for case in cases],
+    },
+    sys.stdout,
+)
+print()
@@ -0,0 +1,135 @@
+"""Writes tiny.tiktoken, ranks trained as tiktoken's educational trainer
+does, and tiktoken-encode.json, the ids tiktoken's encode_ordinary gives
+for a varied set of texts, for test_encode_matches_tiktoken_fixture.
+
+    python3 tests/fixtures/tiktoken_encode.py
+
+Uses the tiktoken package when it is importable, else the copy below of
+bpe_train (tiktoken/_educational.py) and of encode_ordinary and
+byte_pair_encode (tiktoken's src/lib.rs). Both split with the `regex`
+module.
+"""
+
+import base64
+import json
+import os
+import random
+
+import regex
+
+# cl100k_base's, as in tiktoken_ext/openai_public.py
+PATTERN = r"""'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?+\p{L}++|\p{N}{1,3}+| ?[^\s\p{L}\p{N}]++[\r\n]*+|\s++$|\s*[\r\n]|\s+(?!\S)|\s"""
+
+
+def bpe_train(data, vocab_size, pat_str):
+    ranks = {bytes([i]): i for i in range(256)}
+    words = [[bytes([b]) for b in word.encode("utf-8")] for word in regex.findall(pat_str, data)]
+    while len(ranks) < vocab_size:
+        stats = {}
+        for piece in words:
+            for pair in zip(piece[:-1], piece[1:]):
+                stats[pair] = stats.get(pair, 0) + 1
+        if not stats:
+            break
+        most_common_pair = max(stats, key=lambda x: stats[x])
+        token_bytes = most_common_pair[0] + most_common_pair[1]
+        ranks[token_bytes] = len(ranks)
+        new_words = []
+        for word in words:
+            new_word = []
+            i = 0
+            while i < len(word) - 1:
+                if (word[i], word[i + 1]) == most_common_pair:
+                    new_word.append(token_bytes)
+                    i += 2
+                else:
+                    new_word.append(word[i])
+                    i += 1
+            if i == len(word) - 1:
+                new_word.append(word[i])
+            new_words.append(new_word)
+        words = new_words
+    return ranks
+
+
+def byte_pair_encode(piece, ranks):
+    # part starts, merging the adjacent two whose bytes rank lowest, the
+    # leftmost among equals
+    parts = list(range(len(piece) + 1))
+    while True:
+        best = None
+        for i in range(len(parts) - 2):
+            rank = ranks.get(piece[parts[i] : parts[i + 2]])
+            if rank is not None and (best is None or rank < best[0]):
+                best = (rank, i)
+        if best is None:
+            break
+        del parts[best[1] + 1]
+    return [ranks[piece[a:b]] for a, b in zip(parts, parts[1:])]
+
+
+class Encoding:
+    def __init__(self, name, pat_str, mergeable_ranks, special_tokens):
+        self.pat_str = pat_str
+        self.ranks = mergeable_ranks
+
+    def encode_ordinary(self, text):
+        ids = []
+        for piece in regex.findall(self.pat_str, text):
+            piece = piece.encode("utf-8")
+            if piece in self.ranks:
+                ids.append(self.ranks[piece])
+            else:
+                ids.extend(byte_pair_encode(piece, self.ranks))
+        return ids
+
+
+try:
+    from tiktoken import Encoding  # noqa: F811
+    from tiktoken._educational import bpe_train  # noqa: F811
+
+    generator = "tiktoken"
+except ImportError:
+    generator = "copy of tiktoken _educational.py bpe_train and src/lib.rs encode_ordinary"
+
+here = os.path.dirname(os.path.abspath(__file__))
+with open(os.path.join(here, "..", "..", "a-man-like-him.txt"), encoding="utf-8") as f:
+    corpus = f.read()[:12000]
+corpus += "\nnaïve café déjà vu 東京 über 😀 I'LL we've 2024-01-02 3.14159\n" * 3
+ranks = bpe_train(corpus, 256 + 300, PATTERN)
+with open(os.path.join(here, "tiny.tiktoken"), "w") as f:
+    for token, rank in sorted(ranks.items(), key=lambda item: item[1]):
+        f.write("{} {}\n".format(base64.b64encode(token).decode(), rank))
+
+encoding = Encoding(name="tiny", pat_str=PATTERN, mergeable_ranks=ranks, special_tokens={})
+rng = random.Random(163)
+cases = [
+    "",
+    "hello world",
+    "Hello World! HELLO world?",
+    "they'll, she's, WE'RE, I'd, you've",
+    "1234567 89 0.5 3.14159 2024-01-02",
+    "  leading, trailing  \n\n\n  and\tmixed \r\n whitespace   ",
+    "naïve café déjà vu, über 東京 😀🎉",
+    "á é (combining marks)",
+    "<|endoftext|> is plain text to encode_ordinary",
+    "!!! ??? ... --- ### $$$",
+    corpus[:200],
+]
+words = corpus.split()
+for _ in range(80):
+    n = rng.randrange(1, 12)
+    cases.append(" ".join(rng.choice(words) for _ in range(n)))
+alphabet = "ab \n'1é東😀.,"
+for _ in range(40):
+    cases.append("".join(rng.choice(alphabet) for _ in range(rng.randrange(20))))
+with open(os.path.join(here, "tiktoken-encode.json"), "w") as f:
+    json.dump(
+        {
+            "generator": generator,
+            "cases": [[case, encoding.encode_ordinary(case)] for case in cases],
+        },
+        f,
+        ensure_ascii=False,
+    )
+    f.write("\n")